@@ -3,31 +3,80 @@
 use std::{
     cmp::{max, min},
     collections::HashMap,
-    fs::File,
-    io::{self, BufWriter, Write},
+    fs, io,
+    ops::Range,
+    path::{Path, PathBuf},
 };
 
 use backend::{
-    buffer::{Buffer, BufferPath},
-    marks::Mark,
+    buffer::{Buffer, BufferPath, TextChange},
+    history::HistoryMode,
+    marks::{Mark, MarkSet},
+    project::{ProjectIndex, ProjectSet},
+    session::Session,
+    transaction::Transaction,
 };
+use regex::Regex;
+use ropey::Rope;
 
 use crate::{
     formatter::LineFormatter,
     graphemes::{
         is_grapheme_boundary, nth_next_grapheme_boundary, nth_prev_grapheme_boundary, RopeGraphemes,
     },
-    string_utils::{rope_slice_to_line_ending, LineEnding},
+    search::{for_each_match, SearchPattern},
+    string_utils::{
+        char_count, char_to_line_ending, count_line_endings, for_each_line_ending, is_line_ending,
+        is_whitespace, line_ending_to_str, rope_slice_to_line_ending, LineEnding, ALL_LINE_ENDINGS,
+    },
     utils::digit_count,
 };
 
 pub struct Editor {
-    pub buffer: Buffer,
+    pub buffers: backend::editor::Editor,
+    pub views: Vec<View>,
+    pub focus: usize, // Index of the view currently being edited in.
+
+    // The files and directories of the project being worked on, and an
+    // index of the files in them.
+    pub project: ProjectIndex,
+
+    // Files opened so far, most recently opened first.
+    recent_files: Vec<PathBuf>,
+
+    // Whether to convert mixed line endings to the buffer's line ending
+    // when saving.
+    pub normalize_line_endings: bool,
+
+    // What's being searched for, if a search is active, and the char
+    // ranges of its matches in each viewed buffer, as of the
+    // `edit_count` of the buffer they were found at.
+    search_pattern: Option<SearchPattern>,
+    search_matches: HashMap<usize, (u64, Vec<Range<usize>>)>,
+
+    // View and cursor mark sets that were used by a view but no longer are,
+    // as `(buf_idx, v_msi, c_msi)`.  These are reused before creating new
+    // ones.
+    spare_mark_sets: Vec<(usize, usize, usize)>,
+}
+
+/// A view onto one of the open buffers.
+///
+/// Each view has its own view position and cursors, stored as mark sets in
+/// the buffer, so several views of the same buffer each keep their own
+/// place while seeing each other's edits.
+pub struct View {
+    pub buf_idx: usize, // Index of the buffer being viewed.
     pub formatter: LineFormatter,
     pub line_ending_type: LineEnding,
     pub soft_tabs: bool,
     pub soft_tab_width: u8,
 
+    // Whether the buffer has more than one kind of line ending, as of the
+    // `(buf_idx, edit_count)` it was last checked at.
+    pub mixed_line_endings: bool,
+    line_endings_checked: Option<(usize, u64)>,
+
     // The dimensions of the total editor in screen space, including the
     // header, gutter, etc.
     pub editor_dim: (usize, usize),
@@ -40,56 +89,639 @@ pub struct Editor {
     pub c_msi: usize, // Cursors MarkSet index.
 }
 
-impl Editor {
-    /// Create a new blank editor
-    pub fn new(buffer: Buffer, formatter: LineFormatter) -> Editor {
-        let mut buffer = buffer;
+// The indices of the view and cursor mark sets each buffer starts out
+// with.  These are used by the first view of a buffer, so that a buffer
+// keeps its own view position and cursors when no view is showing it.
+const HOME_V_MSI: usize = 0;
+const HOME_C_MSI: usize = 1;
 
-        // Create appropriate mark sets for view positions and cursors.
-        let v_msi = buffer.add_mark_set();
-        let c_msi = buffer.add_mark_set();
-        buffer.mark_sets[v_msi].add_mark(Mark::new(0, 0));
-        buffer.mark_sets[c_msi].add_mark(Mark::new(0, 0));
+// How much of a buffer is checked for mixed line endings.
+const MIXED_LINE_ENDING_CHECK_CHARS: usize = 1 << 20;
+
+// How many recently opened files are remembered.
+const MAX_RECENT_FILES: usize = 100;
+
+impl Editor {
+    /// Create a new editor for the given open buffers, with a single view
+    /// of the first one.
+    ///
+    /// If there are no open buffers, a new temporary buffer is created.
+    pub fn new(buffers: backend::editor::Editor, formatter: LineFormatter) -> Editor {
+        let mut buffers = buffers;
+        if buffers.is_empty() {
+            buffers.new_temp_buffer();
+        }
 
         let mut ed = Editor {
-            buffer: buffer,
-            formatter: formatter,
-            line_ending_type: LineEnding::LF,
-            soft_tabs: false,
-            soft_tab_width: 4,
-            editor_dim: (0, 0),
-            view_dim: (0, 0),
-            v_msi: v_msi,
-            c_msi: c_msi,
+            buffers,
+            views: vec![View {
+                buf_idx: 0,
+                formatter,
+                line_ending_type: LineEnding::LF,
+                soft_tabs: false,
+                soft_tab_width: 4,
+                mixed_line_endings: false,
+                line_endings_checked: None,
+                editor_dim: (0, 0),
+                view_dim: (0, 0),
+                v_msi: HOME_V_MSI,
+                c_msi: HOME_C_MSI,
+            }],
+            focus: 0,
+            project: ProjectIndex::new(ProjectSet::new()),
+            recent_files: Vec::new(),
+            normalize_line_endings: false,
+            search_pattern: None,
+            search_matches: HashMap::new(),
+            spare_mark_sets: Vec::new(),
         };
 
-        ed.auto_detect_line_ending();
-        ed.auto_detect_indentation_style();
+        for idx in 0..ed.buffers.len() {
+            ed.prepare_buffer(idx);
+            if let BufferPath::File(ref path) = ed.buffers[idx].path {
+                let path = path.clone();
+                ed.add_recent_file(&path);
+            }
+
+            // Buffers restored from a previous session may also have mark
+            // sets left over from other views.
+            for k in 1..(ed.buffers[idx].mark_sets.len() / 2) {
+                ed.spare_mark_sets.push((idx, k * 2, k * 2 + 1));
+            }
+        }
+        ed.auto_detect_line_ending(ed.focus);
+        ed.auto_detect_indentation_style(ed.focus);
 
         ed
     }
 
-    pub fn save_if_dirty(&mut self) -> io::Result<()> {
-        if let BufferPath::File(ref file_path) = self.buffer.path {
-            if self.buffer.is_dirty {
-                let mut f = BufWriter::new(File::create(file_path)?);
+    /// Returns the view currently being edited in.
+    pub fn view(&self) -> &View {
+        &self.views[self.focus]
+    }
+
+    /// Returns the buffer currently being edited.
+    pub fn buffer(&self) -> &Buffer {
+        &self.buffers[self.view().buf_idx]
+    }
+
+    /// Opens the file at the given path if it isn't already open, and
+    /// switches the current view to it.
+    pub fn open_file(&mut self, path: &Path) -> io::Result<()> {
+        let idx = self.buffers.open_file(path)?;
+        self.prepare_buffer(idx);
+        self.switch_to_buffer(idx);
+        self.add_recent_file(path);
+        Ok(())
+    }
+
+    /// Returns the absolute paths of the files opened so far, most recently
+    /// opened first.
+    pub fn recent_files(&self) -> &[PathBuf] {
+        &self.recent_files
+    }
+
+    /// Like `open_file()`, but also selects the given char range with the
+    /// main cursor, e.g. to show a search match.
+    pub fn open_file_at(&mut self, path: &Path, range: Range<usize>) -> io::Result<()> {
+        self.open_file(path)?;
+        let view = &self.views[self.focus];
+        let buffer = &mut self.buffers[view.buf_idx];
+        let len = buffer.text.len_chars();
+        let cursors = &mut buffer.mark_sets[view.c_msi];
+        cursors.reduce_to_main();
+        cursors[0] = Mark::new(range.end.min(len), range.start.min(len));
+
+        // Adjust view
+        self.move_view_to_cursor();
+        Ok(())
+    }
+
+    /// Switches the current view to the buffer with the given index.
+    ///
+    /// Each buffer keeps its own cursors and view position, so switching
+    /// back later picks up where things were left.
+    pub fn switch_to_buffer(&mut self, idx: usize) {
+        if idx >= self.buffers.len() || idx == self.view().buf_idx {
+            return;
+        }
+
+        self.release_mark_sets(self.focus);
+        let (v_msi, c_msi) = self.claim_mark_sets(idx);
+        let view = &mut self.views[self.focus];
+        view.buf_idx = idx;
+        view.v_msi = v_msi;
+        view.c_msi = c_msi;
+
+        self.auto_detect_line_ending(self.focus);
+        self.auto_detect_indentation_style(self.focus);
+        self.move_view_to_cursor();
+    }
+
+    /// Switches the current view to the next open buffer, wrapping around
+    /// at the end.
+    pub fn next_buffer(&mut self) {
+        self.switch_to_buffer((self.view().buf_idx + 1) % self.buffers.len());
+    }
+
+    /// Switches the current view to the previous open buffer, wrapping
+    /// around at the start.
+    pub fn prev_buffer(&mut self) {
+        let len = self.buffers.len();
+        self.switch_to_buffer((self.view().buf_idx + len - 1) % len);
+    }
+
+    /// Adds a new view of the current view's buffer, starting at the same
+    /// position, and switches to it.
+    pub fn add_view(&mut self) {
+        let view = self.view();
+        let (buf_idx, old_v_msi, old_c_msi) = (view.buf_idx, view.v_msi, view.c_msi);
+        let (v_msi, c_msi) = self.claim_mark_sets(buf_idx);
+
+        let buffer = &mut self.buffers[buf_idx];
+        buffer.mark_sets[v_msi] = buffer.mark_sets[old_v_msi].clone();
+        buffer.mark_sets[c_msi] = buffer.mark_sets[old_c_msi].clone();
+
+        let view = self.view();
+        let new_view = View {
+            buf_idx,
+            formatter: view.formatter.clone(),
+            line_ending_type: view.line_ending_type,
+            soft_tabs: view.soft_tabs,
+            soft_tab_width: view.soft_tab_width,
+            mixed_line_endings: view.mixed_line_endings,
+            line_endings_checked: view.line_endings_checked,
+            editor_dim: view.editor_dim,
+            view_dim: view.view_dim,
+            v_msi,
+            c_msi,
+        };
+        self.views.insert(self.focus + 1, new_view);
+        self.focus += 1;
+    }
+
+    /// Closes the current view, unless it's the only one.
+    pub fn close_view(&mut self) {
+        if self.views.len() <= 1 {
+            return;
+        }
+
+        self.release_mark_sets(self.focus);
+        self.views.remove(self.focus);
+        self.focus = self.focus.min(self.views.len() - 1);
+    }
+
+    /// Switches to the next view, wrapping around at the end.
+    pub fn focus_next_view(&mut self) {
+        self.focus = (self.focus + 1) % self.views.len();
+    }
+
+    /// Switches to the previous view, wrapping around at the start.
+    pub fn focus_prev_view(&mut self) {
+        self.focus = (self.focus + self.views.len() - 1) % self.views.len();
+    }
+
+    /// Starts saving the current buffer in the background, if it's a file
+    /// with unsaved changes.
+    pub fn save_if_dirty(&mut self) {
+        let buf_idx = self.views[self.focus].buf_idx;
+        self.save_buffer(buf_idx);
+    }
+
+    /// Starts saving the given buffer in the background, if it's a file
+    /// with unsaved changes.
+    ///
+    /// If `normalize_line_endings` is set, mixed line endings are first
+    /// converted to the buffer's line ending, as an undo step of its own.
+    pub fn save_buffer(&mut self, buf_idx: usize) {
+        let buffer = &self.buffers[buf_idx];
+        if self.normalize_line_endings && buffer.is_dirty && buffer.is_editable() {
+            // Use the line ending of a view of the buffer, preferring the
+            // current one.  Otherwise go with the most common one.
+            let ending = if self.view().buf_idx == buf_idx {
+                Some(self.view().line_ending_type)
+            } else {
+                self.views
+                    .iter()
+                    .find(|v| v.buf_idx == buf_idx)
+                    .map(|v| v.line_ending_type)
+            };
+            let ending = ending.unwrap_or_else(|| {
+                let counts = count_line_endings(&buffer.text.slice(..));
+                let most = (1..counts.len()).max_by_key(|&i| counts[i]).unwrap();
+                ALL_LINE_ENDINGS[most]
+            });
+            if ending != LineEnding::None {
+                self.replace_line_endings(buf_idx, ending, None);
+            }
+        }
+        self.buffers.save_buffer(buf_idx);
+    }
+
+    /// Starts saving all file buffers with unsaved changes in the
+    /// background, like `save_buffer()`.
+    pub fn save_all(&mut self) {
+        for buf_idx in 0..self.buffers.len() {
+            self.save_buffer(buf_idx);
+        }
+    }
+
+    /// Converts all line endings in the current buffer to the given one,
+    /// as a single undo step.
+    ///
+    /// The line ending also becomes the one used for new lines in all views
+    /// of the buffer, unless it's `LineEnding::None`, which just joins all
+    /// lines.
+    pub fn convert_line_endings(&mut self, ending: LineEnding) {
+        let view = &self.views[self.focus];
+        let (buf_idx, c_msi) = (view.buf_idx, view.c_msi);
+        self.replace_line_endings(buf_idx, ending, Some(c_msi));
+        if ending != LineEnding::None && self.buffers[buf_idx].is_editable() {
+            for view in self.views.iter_mut().filter(|v| v.buf_idx == buf_idx) {
+                view.line_ending_type = ending;
+            }
+        }
 
-                for c in self.buffer.text.chunks() {
-                    f.write(c.as_bytes())?;
+        // Adjust view
+        self.move_view_to_cursor();
+    }
+
+    /// Rewrites the indentation of the lines touched by selections, or of
+    /// the whole buffer if nothing is selected, as a single undo step.
+    ///
+    /// Leading whitespace is measured in indent levels of `from_width`
+    /// columns, with tabs going to the next multiple of it.  Each level is
+    /// then rewritten as a tab, or as `to_width` spaces if `to_soft_tabs`
+    /// is set.  Columns left over that don't make up a whole level stay
+    /// spaces.
+    ///
+    /// All views of the buffer are switched to the new indentation style,
+    /// so that new indentation matches.
+    pub fn convert_indentation(&mut self, from_width: usize, to_soft_tabs: bool, to_width: usize) {
+        let from_width = from_width.max(1);
+        let to_width = to_width.max(1);
+        let lines = self.selected_lines();
+        let view = &self.views[self.focus];
+        let (buf_idx, c_msi) = (view.buf_idx, view.c_msi);
+        let buffer = &mut self.buffers[buf_idx];
+        if !buffer.is_editable() {
+            return;
+        }
+
+        let mut edits = Vec::new(); // (byte_idx, old_indent, new_indent)
+        for line_idx in lines {
+            let mut old = String::new();
+            let mut cols = 0;
+            for c in buffer.text.line(line_idx).chars() {
+                match c {
+                    ' ' => cols += 1,
+                    '\t' => cols = (cols / from_width + 1) * from_width,
+                    _ => break,
                 }
+                old.push(c);
+            }
+
+            let (levels, extra) = (cols / from_width, cols % from_width);
+            let new = if to_soft_tabs {
+                " ".repeat(levels * to_width + extra)
+            } else {
+                format!("{}{}", "\t".repeat(levels), " ".repeat(extra))
+            };
+            if new != old {
+                let byte_idx = buffer.text.line_to_byte(line_idx);
+                edits.push((byte_idx, old, new));
+            }
+        }
+
+        if !edits.is_empty() {
+            let trans = Transaction::from_ordered_edit_set(
+                edits
+                    .iter()
+                    .map(|(byte_idx, old, new)| (*byte_idx, &old[..], &new[..])),
+            );
+            apply_undo_step(buffer, trans, Some(c_msi));
+        }
 
-                self.buffer.is_dirty = false;
+        for view in self.views.iter_mut().filter(|v| v.buf_idx == buf_idx) {
+            view.soft_tabs = to_soft_tabs;
+            view.soft_tab_width = to_width.min(u8::MAX as usize) as u8;
+            if !to_soft_tabs {
+                view.formatter.tab_width = to_width;
             }
         }
 
-        Ok(())
+        // Adjust view
+        self.move_view_to_cursor();
+    }
+
+    /// Updates whether the buffer of each view has mixed line endings, if
+    /// it changed since last checked.  Returns whether any view's status
+    /// changed.
+    ///
+    /// Only the start of very big buffers is checked, so that this stays
+    /// cheap enough to do after every edit.
+    pub fn update_line_ending_status(&mut self) -> bool {
+        let mut changed = false;
+        for view in self.views.iter_mut() {
+            let buffer = &self.buffers[view.buf_idx];
+            let checked = Some((view.buf_idx, buffer.edit_count()));
+            if view.line_endings_checked == checked {
+                continue;
+            }
+
+            let end = buffer.text.len_chars().min(MIXED_LINE_ENDING_CHECK_CHARS);
+            let counts = count_line_endings(&buffer.text.slice(..end));
+            let mixed = counts.iter().filter(|&&n| n > 0).count() > 1;
+            changed |= mixed != view.mixed_line_endings;
+            view.mixed_line_endings = mixed;
+            view.line_endings_checked = checked;
+        }
+        changed
+    }
+
+    /// Searches for `pattern`, and selects the first match at or after the
+    /// char index `from` with the main cursor, wrapping around to the start
+    /// of the buffer if needed.  Returns whether there was a match.
+    ///
+    /// The search stays active, with its matches kept up to date, until
+    /// `clear_search()` is called.  An empty pattern clears it right away.
+    pub fn search(&mut self, pattern: &str, from: usize) -> bool {
+        if pattern.is_empty() {
+            self.clear_search();
+            return false;
+        }
+        self.start_search(SearchPattern::Text(pattern.into()), from)
+    }
+
+    /// Like `search()`, but searches for matches of a regex.
+    pub fn search_regex(&mut self, regex: Regex, from: usize) -> bool {
+        self.start_search(SearchPattern::Regex(regex), from)
     }
 
-    pub fn auto_detect_line_ending(&mut self) {
+    /// Ends the current search, if any.
+    pub fn clear_search(&mut self) {
+        self.search_pattern = None;
+        self.search_matches.clear();
+    }
+
+    /// Returns what's being searched for, if a search is active.
+    pub fn search_pattern(&self) -> Option<&SearchPattern> {
+        self.search_pattern.as_ref()
+    }
+
+    /// Selects the next match of the current search after the main cursor
+    /// with the main cursor, wrapping around to the start of the buffer if
+    /// needed.  Returns whether there was a match.
+    pub fn next_search_match(&mut self) -> bool {
+        self.update_search_matches();
+        let view = self.view();
+        let start = self.buffer().mark_sets[view.c_msi]
+            .main()
+            .unwrap()
+            .range()
+            .start;
+        let matches = self.search_matches(view.buf_idx);
+        if matches.is_empty() {
+            return false;
+        }
+        let idx = matches.iter().position(|m| m.start > start).unwrap_or(0);
+        self.select_search_match(idx);
+        true
+    }
+
+    /// Like `next_search_match()`, but selects the previous match before the
+    /// main cursor, wrapping around to the end of the buffer if needed.
+    pub fn prev_search_match(&mut self) -> bool {
+        self.update_search_matches();
+        let view = self.view();
+        let start = self.buffer().mark_sets[view.c_msi]
+            .main()
+            .unwrap()
+            .range()
+            .start;
+        let matches = self.search_matches(view.buf_idx);
+        if matches.is_empty() {
+            return false;
+        }
+        let idx = matches
+            .iter()
+            .rposition(|m| m.start < start)
+            .unwrap_or(matches.len() - 1);
+        self.select_search_match(idx);
+        true
+    }
+
+    /// Selects the first match of the current search that starts at or
+    /// after the char index `from` with the main cursor, without wrapping
+    /// around, and returns its char range.
+    pub fn select_search_match_after(&mut self, from: usize) -> Option<Range<usize>> {
+        self.update_search_matches();
+        let matches = self.search_matches(self.view().buf_idx);
+        let idx = matches.iter().position(|m| m.start >= from)?;
+        let range = matches[idx].clone();
+        self.select_search_match(idx);
+        Some(range)
+    }
+
+    /// Replaces the match of the current search that's selected by the
+    /// main cursor with `replacement`, as an undo step of its own, and
+    /// leaves the cursor after it.  Returns the char range of the new text.
+    ///
+    /// See `SearchPattern::for_each_replacement()` for how `replacement`
+    /// is expanded.
+    pub fn replace_selected_search_match(&mut self, replacement: &str) -> Option<Range<usize>> {
+        self.update_search_matches();
+        self.search_position(self.focus)?.0?;
+        let pattern = self.search_pattern.clone()?;
+        let view = &self.views[self.focus];
+        let buffer = &mut self.buffers[view.buf_idx];
+        if !buffer.is_editable() {
+            return None;
+        }
+
+        let range = buffer.mark_sets[view.c_msi].main().unwrap().range();
+        let new = pattern.replacement_at(&buffer.text, range.clone(), replacement)?;
+        let old: String = buffer.text.slice(range.clone()).into();
+        let byte_idx = buffer.text.char_to_byte(range.start);
+        let trans =
+            Transaction::from_ordered_edit_set(std::iter::once((byte_idx, &old[..], &new[..])));
+        apply_undo_step(buffer, trans, Some(view.c_msi));
+
+        let end = range.start + char_count(&new);
+        let cursors = &mut buffer.mark_sets[view.c_msi];
+        cursors.reduce_to_main();
+        cursors[0] = Mark::new(end, end);
+
+        // Adjust view
+        self.move_view_to_cursor();
+        Some(range.start..end)
+    }
+
+    /// Replaces every match of the current search in the current buffer
+    /// that starts at a char index accepted by `filter` with
+    /// `replacement`, as a single undo step.  Returns how many matches were
+    /// replaced.
+    ///
+    /// See `SearchPattern::for_each_replacement()` for how `replacement`
+    /// is expanded.
+    pub fn replace_search_matches<F>(&mut self, replacement: &str, filter: F) -> usize
+    where
+        F: Fn(usize) -> bool,
+    {
+        let pattern = match self.search_pattern {
+            Some(ref pattern) => pattern,
+            None => return 0,
+        };
+        let view = &self.views[self.focus];
+        let buffer = &mut self.buffers[view.buf_idx];
+        if !buffer.is_editable() {
+            return 0;
+        }
+
+        let mut edits = Vec::new(); // (byte_idx, old, new)
+        let text = &buffer.text;
+        pattern.for_each_replacement(&text.slice(..), replacement, |range, new| {
+            if filter(range.start) {
+                let old: String = text.slice(range.clone()).into();
+                edits.push((text.char_to_byte(range.start), old, new));
+            }
+            true
+        });
+
+        if !edits.is_empty() {
+            let trans = Transaction::from_ordered_edit_set(
+                edits
+                    .iter()
+                    .map(|(byte_idx, old, new)| (*byte_idx, &old[..], &new[..])),
+            );
+            apply_undo_step(buffer, trans, Some(view.c_msi));
+        }
+
+        // Adjust view
+        self.move_view_to_cursor();
+        edits.len()
+    }
+
+    /// Finds the matches of the current search in each viewed buffer whose
+    /// text changed since they were last found.  Returns whether any
+    /// buffer's matches were updated.
+    ///
+    /// Where the changes to the text are known, only the text around them
+    /// is searched again.
+    pub fn update_search_matches(&mut self) -> bool {
+        let pattern = match self.search_pattern {
+            Some(ref pattern) => pattern,
+            None => return false,
+        };
+
+        let mut changed = false;
+        for view in self.views.iter() {
+            let buffer = &self.buffers[view.buf_idx];
+            let edit_count = buffer.edit_count();
+            let matches = match self.search_matches.remove(&view.buf_idx) {
+                Some((found_at, matches)) if found_at == edit_count => matches,
+                Some((found_at, matches)) => {
+                    changed = true;
+                    match buffer.changes_since(found_at) {
+                        Some(changes) => update_matches(pattern, &buffer.text, matches, changes),
+                        None => pattern.find_all(&buffer.text.slice(..)),
+                    }
+                }
+                None => {
+                    changed = true;
+                    pattern.find_all(&buffer.text.slice(..))
+                }
+            };
+            self.search_matches
+                .insert(view.buf_idx, (edit_count, matches));
+        }
+        changed
+    }
+
+    /// Returns the char ranges of the current search's matches in the given
+    /// buffer, in order, as of when they were last updated.
+    pub fn search_matches(&self, buf_idx: usize) -> &[Range<usize>] {
+        match self.search_matches.get(&buf_idx) {
+            Some((_, matches)) => &matches[..],
+            None => &[],
+        }
+    }
+
+    /// If a search is active, returns the index of the match selected by
+    /// the main cursor of the given view, if any, and the number of matches
+    /// in the view's buffer.
+    pub fn search_position(&self, view_idx: usize) -> Option<(Option<usize>, usize)> {
+        self.search_pattern.as_ref()?;
+        let view = &self.views[view_idx];
+        let range = self.buffers[view.buf_idx].mark_sets[view.c_msi]
+            .main()
+            .unwrap()
+            .range();
+        let matches = self.search_matches(view.buf_idx);
+        let idx = matches
+            .binary_search_by_key(&range.start, |m| m.start)
+            .ok()
+            .filter(|&i| matches[i].end == range.end);
+        Some((idx, matches.len()))
+    }
+
+    /// Returns the current view's cursors and view position, so they can
+    /// be put back with `restore_cursors()`.
+    pub fn save_cursors(&self) -> (MarkSet, Mark) {
+        let view = self.view();
+        let buffer = self.buffer();
+        (
+            buffer.mark_sets[view.c_msi].clone(),
+            buffer.mark_sets[view.v_msi][0],
+        )
+    }
+
+    /// Puts back cursors and a view position saved with `save_cursors()`.
+    pub fn restore_cursors(&mut self, saved: (MarkSet, Mark)) {
+        let view = &self.views[self.focus];
+        let buffer = &mut self.buffers[view.buf_idx];
+        buffer.mark_sets[view.c_msi] = saved.0;
+        buffer.mark_sets[view.v_msi][0] = saved.1;
+    }
+
+    /// Toggles whether the current buffer is read-only, and returns
+    /// whether it now is.
+    pub fn toggle_read_only(&mut self) -> bool {
+        let buf_idx = self.views[self.focus].buf_idx;
+        let buffer = &mut self.buffers[buf_idx];
+        buffer.read_only = !buffer.read_only;
+        buffer.read_only
+    }
+
+    /// Saves the unsaved edits of all buffers, to be restored the next
+    /// time the editor starts.
+    pub fn save_session(&self) -> io::Result<()> {
+        backend::session::save_session(&self.buffers)
+    }
+
+    /// Takes a snapshot of the unsaved edits of all buffers, for saving
+    /// them in the background.  See `save_session()`.
+    pub fn session(&self) -> Session {
+        Session::new(&self.buffers)
+    }
+
+    /// Re-detects the line ending and indentation style of every view of
+    /// the given buffer, e.g. once the start of its text has loaded.
+    pub fn redetect_buffer_style(&mut self, buf_idx: usize) {
+        for view_idx in 0..self.views.len() {
+            if self.views[view_idx].buf_idx == buf_idx {
+                self.auto_detect_line_ending(view_idx);
+                self.auto_detect_indentation_style(view_idx);
+            }
+        }
+    }
+
+    pub fn auto_detect_line_ending(&mut self, view_idx: usize) {
+        let view = &mut self.views[view_idx];
         let mut line_ending_histogram: [usize; 8] = [0, 0, 0, 0, 0, 0, 0, 0];
 
         // Collect statistics on the first 100 lines
-        for line in self.buffer.text.lines().take(100) {
+        for line in self.buffers[view.buf_idx].text.lines().take(100) {
             // Get the line ending
             let ending = if line.len_chars() == 1 {
                 let g = RopeGraphemes::new(&line.slice((line.len_chars() - 1)..))
@@ -138,15 +770,15 @@ impl Editor {
         // Analyze stats and make a determination
         let mut lei = 0;
         let mut le_count = 0;
-        for i in 0usize..8 {
-            if line_ending_histogram[i] >= le_count {
+        for (i, &count) in line_ending_histogram.iter().enumerate() {
+            if count >= le_count {
                 lei = i;
-                le_count = line_ending_histogram[i];
+                le_count = count;
             }
         }
 
         if le_count > 0 {
-            self.line_ending_type = match lei {
+            view.line_ending_type = match lei {
                 0 => LineEnding::CRLF,
                 1 => LineEnding::LF,
                 2 => LineEnding::VT,
@@ -161,7 +793,8 @@ impl Editor {
         }
     }
 
-    pub fn auto_detect_indentation_style(&mut self) {
+    pub fn auto_detect_indentation_style(&mut self, view_idx: usize) {
+        let view = &mut self.views[view_idx];
         let mut tab_blocks: usize = 0;
         let mut space_blocks: usize = 0;
         let mut space_histogram: HashMap<usize, usize> = HashMap::new();
@@ -169,7 +802,7 @@ impl Editor {
         let mut last_indent = (false, 0usize); // (was_tabs, indent_count)
 
         // Collect statistics on the first 1000 lines
-        for line in self.buffer.text.lines().take(1000) {
+        for line in self.buffers[view.buf_idx].text.lines().take(1000) {
             let mut c_iter = line.chars();
             match c_iter.next() {
                 Some('\t') => {
@@ -233,351 +866,1305 @@ impl Editor {
                 }
             }
 
-            self.soft_tabs = true;
-            self.soft_tab_width = width as u8;
+            view.soft_tabs = true;
+            view.soft_tab_width = width as u8;
         } else {
-            self.soft_tabs = false;
+            view.soft_tabs = false;
         }
     }
 
-    /// Updates the view dimensions.
-    pub fn update_dim(&mut self, h: usize, w: usize) {
-        let line_count_digits = digit_count(self.buffer.text.len_lines() as u32, 10) as usize;
-        self.editor_dim = (h, w);
+    /// Updates the dimensions of the given view, and its formatter's wrap
+    /// width to match.
+    pub fn update_dim(&mut self, view_idx: usize, h: usize, w: usize) {
+        let view = &mut self.views[view_idx];
+        let line_count_digits =
+            digit_count(self.buffers[view.buf_idx].text.len_lines() as u32, 10) as usize;
+        view.editor_dim = (h, w);
 
         // Minus 1 vertically for the header, minus two more than the digits in
         // the line count for the gutter.
-        self.view_dim = (
-            self.editor_dim.0 - 1,
-            self.editor_dim.1 - line_count_digits - 2,
+        view.view_dim = (
+            view.editor_dim.0.saturating_sub(1),
+            view.editor_dim.1.saturating_sub(line_count_digits + 2),
         );
+        view.formatter.wrap_width = view.view_dim.1;
     }
 
     pub fn undo(&mut self) {
-        // TODO: handle multiple cursors properly
-        if let Some((_start, end)) = self.buffer.undo() {
-            self.buffer.mark_sets[self.c_msi].reduce_to_main();
-            self.buffer.mark_sets[self.c_msi][0].head = end;
-            self.buffer.mark_sets[self.c_msi][0].tail = end;
-            self.buffer.mark_sets[self.c_msi][0].hh_pos = None;
-
+        let view = &self.views[self.focus];
+        if self.buffers[view.buf_idx].undo(view.c_msi).is_some() {
             self.move_view_to_cursor();
         }
     }
 
     pub fn redo(&mut self) {
-        // TODO: handle multiple cursors properly
-        if let Some((_start, end)) = self.buffer.redo() {
-            self.buffer.mark_sets[self.c_msi].reduce_to_main();
-            self.buffer.mark_sets[self.c_msi][0].head = end;
-            self.buffer.mark_sets[self.c_msi][0].tail = end;
-            self.buffer.mark_sets[self.c_msi][0].hh_pos = None;
+        let view = &self.views[self.focus];
+        if self.buffers[view.buf_idx].redo(view.c_msi).is_some() {
+            self.move_view_to_cursor();
+        }
+    }
 
+    /// Un-does the most recent edit within the main cursor's selection (or
+    /// at the main cursor, if there's no selection), leaving edits
+    /// elsewhere intact.
+    pub fn undo_in_selection(&mut self) {
+        let view = &self.views[self.focus];
+        let buffer = &mut self.buffers[view.buf_idx];
+        let range = buffer.mark_sets[view.c_msi].main().unwrap().range();
+        if buffer
+            .undo_in_range(view.c_msi, (range.start, range.end))
+            .is_some()
+        {
+            self.move_view_to_cursor();
+        }
+    }
+
+    /// Moves to the given state of the buffer's undo history.
+    pub fn jump_to_history_state(&mut self, state: usize) {
+        let view = &self.views[self.focus];
+        if self.buffers[view.buf_idx]
+            .jump_to_history_state(view.c_msi, state)
+            .is_some()
+        {
             self.move_view_to_cursor();
         }
     }
 
     /// Moves the editor's view the minimum amount to show the cursor
     pub fn move_view_to_cursor(&mut self) {
+        let view = &self.views[self.focus];
+        let buffer = &mut self.buffers[view.buf_idx];
         // Find the first and last char index visible within the editor.
-        let c_first = self.formatter.set_horizontal(
-            &self.buffer.text,
-            self.buffer.mark_sets[self.v_msi][0].head,
-            0,
-        );
-        let mut c_last = self.formatter.offset_vertical(
-            &self.buffer.text,
-            c_first,
-            self.view_dim.0 as isize - 1,
-        );
-        c_last = self
+        let c_first =
+            view.formatter
+                .set_horizontal(&buffer.text, buffer.mark_sets[view.v_msi][0].head, 0);
+        let mut c_last =
+            view.formatter
+                .offset_vertical(&buffer.text, c_first, view.view_dim.0 as isize - 1);
+        c_last = view
             .formatter
-            .set_horizontal(&self.buffer.text, c_last, self.view_dim.1);
+            .set_horizontal(&buffer.text, c_last, view.view_dim.1);
 
         // Adjust the view depending on where the cursor is
-        let cursor_head = self.buffer.mark_sets[self.c_msi].main().unwrap().head;
+        let cursor_head = buffer.mark_sets[view.c_msi].main().unwrap().head;
         if cursor_head < c_first {
-            self.buffer.mark_sets[self.v_msi][0].head = cursor_head;
+            buffer.mark_sets[view.v_msi][0].head = cursor_head;
         } else if cursor_head > c_last {
-            self.buffer.mark_sets[self.v_msi][0].head = self.formatter.offset_vertical(
-                &self.buffer.text,
+            buffer.mark_sets[view.v_msi][0].head = view.formatter.offset_vertical(
+                &buffer.text,
                 cursor_head,
-                -(self.view_dim.0 as isize),
+                -(view.view_dim.0 as isize),
             );
         }
     }
 
     pub fn insert_text_at_cursor(&mut self, text: &str) {
-        // TODO: handle multiple cursors.
-        let range = self.buffer.mark_sets[self.c_msi][0].range();
+        let view = &self.views[self.focus];
+        let buffer = &mut self.buffers[view.buf_idx];
+        let edits = buffer.mark_sets[view.c_msi]
+            .iter()
+            .map(|mark| {
+                let range = mark.range();
+                ((range.start, range.end), text.into())
+            })
+            .collect();
+
+        buffer.edit_at_cursors(view.c_msi, edits);
+
+        // Adjust view
+        self.move_view_to_cursor();
+    }
 
-        // Do the edit.
-        self.buffer.edit((range.start, range.end), text);
+    /// Inserts a tab, or spaces to the next tab stop in soft-tab mode, at
+    /// each cursor.  If a selection spans more than one line, the selected
+    /// lines are indented instead.
+    pub fn insert_tab_at_cursor(&mut self) {
+        if self.selection_spans_lines() {
+            self.indent_lines();
+            return;
+        }
+
+        let view = &self.views[self.focus];
+        let buffer = &self.buffers[view.buf_idx];
+        let edits = buffer.mark_sets[view.c_msi]
+            .iter()
+            .map(|mark| {
+                let range = mark.range();
+
+                if view.soft_tabs {
+                    // Figure out how many spaces to insert
+                    let vis_pos = view.formatter.get_horizontal(&buffer.text, range.start);
+                    // TODO: handle tab settings
+                    let next_tab_stop = ((vis_pos / view.soft_tab_width as usize) + 1)
+                        * view.soft_tab_width as usize;
+                    let space_count = min(next_tab_stop - vis_pos, 8);
+
+                    // Insert spaces
+                    let space_strs = [
+                        "", " ", "  ", "   ", "    ", "     ", "      ", "       ", "        ",
+                    ];
+                    ((range.start, range.end), space_strs[space_count].into())
+                } else {
+                    ((range.start, range.end), "\t".into())
+                }
+            })
+            .collect();
 
-        // Adjust cursor position.
-        let len = text.chars().count();
-        self.buffer.mark_sets[self.c_msi][0].head = range.start + len;
-        self.buffer.mark_sets[self.c_msi][0].tail = range.start + len;
-        self.buffer.mark_sets[self.c_msi][0].hh_pos = None;
+        self.buffers[view.buf_idx].edit_at_cursors(view.c_msi, edits);
 
         // Adjust view
         self.move_view_to_cursor();
     }
 
-    pub fn insert_tab_at_cursor(&mut self) {
-        // TODO: handle multiple cursors.
-        let range = self.buffer.mark_sets[self.c_msi][0].range();
+    /// Indents every line touched by the cursors by one level, as a single
+    /// undo step.  Lines with nothing on them are left alone.
+    pub fn indent_lines(&mut self) {
+        let lines = self.cursor_lines();
+        let view = &self.views[self.focus];
+        let buffer = &mut self.buffers[view.buf_idx];
+        if !buffer.is_editable() {
+            return;
+        }
 
-        if self.soft_tabs {
-            // Figure out how many spaces to insert
-            let vis_pos = self
-                .formatter
-                .get_horizontal(&self.buffer.text, range.start);
-            // TODO: handle tab settings
-            let next_tab_stop =
-                ((vis_pos / self.soft_tab_width as usize) + 1) * self.soft_tab_width as usize;
-            let space_count = min(next_tab_stop - vis_pos, 8);
-
-            // Insert spaces
-            let space_strs = [
-                "", " ", "  ", "   ", "    ", "     ", "      ", "       ", "        ",
-            ];
-            self.buffer
-                .edit((range.start, range.end), space_strs[space_count]);
-
-            // Adjust cursor position.
-            self.buffer.mark_sets[self.c_msi][0].head = range.start + space_count;
-            self.buffer.mark_sets[self.c_msi][0].tail = range.start + space_count;
-            self.buffer.mark_sets[self.c_msi][0].hh_pos = None;
+        let indent = if view.soft_tabs {
+            " ".repeat(view.soft_tab_width.max(1) as usize)
         } else {
-            self.buffer.edit((range.start, range.end), "\t");
-
-            // Adjust cursor position.
-            self.buffer.mark_sets[self.c_msi][0].head = range.start + 1;
-            self.buffer.mark_sets[self.c_msi][0].tail = range.start + 1;
-            self.buffer.mark_sets[self.c_msi][0].hh_pos = None;
+            "\t".into()
+        };
+        let edits: Vec<usize> = lines
+            .into_iter()
+            .filter(|&line_idx| {
+                let line = buffer.text.line(line_idx);
+                line.len_chars() > 0 && char_to_line_ending(line.char(0)) == LineEnding::None
+            })
+            .map(|line_idx| buffer.text.line_to_byte(line_idx))
+            .collect();
+
+        if !edits.is_empty() {
+            let trans = Transaction::from_ordered_edit_set(
+                edits.iter().map(|&byte_idx| (byte_idx, "", &indent[..])),
+            );
+            apply_undo_step(buffer, trans, Some(view.c_msi));
         }
 
         // Adjust view
         self.move_view_to_cursor();
     }
 
-    pub fn remove_text_behind_cursor(&mut self, grapheme_count: usize) {
-        // TODO: handle multiple cursors.
-        let mark = self.buffer.mark_sets[self.c_msi].main().unwrap();
-        let range = mark.range();
-
-        // Do nothing if there's nothing to delete.
-        if range.start == 0 {
+    /// Removes one level of indentation from every line touched by the
+    /// cursors, as a single undo step.  A level is a leading tab, or up to
+    /// a tab width of leading spaces.
+    pub fn dedent_lines(&mut self) {
+        let lines = self.cursor_lines();
+        let view = &self.views[self.focus];
+        let buffer = &mut self.buffers[view.buf_idx];
+        if !buffer.is_editable() {
             return;
         }
 
-        let pre =
-            nth_prev_grapheme_boundary(&self.buffer.text.slice(..), range.start, grapheme_count);
+        let width = if view.soft_tabs {
+            view.soft_tab_width as usize
+        } else {
+            view.formatter.tab_width
+        }
+        .max(1);
+        let mut edits = Vec::new(); // (byte_idx, old_indent)
+        for line_idx in lines {
+            let mut old = String::new();
+            for c in buffer.text.line(line_idx).chars() {
+                if c == '\t' && old.is_empty() {
+                    old.push(c);
+                    break;
+                } else if c == ' ' && old.len() < width {
+                    old.push(c);
+                } else {
+                    break;
+                }
+            }
+            if !old.is_empty() {
+                edits.push((buffer.text.line_to_byte(line_idx), old));
+            }
+        }
 
-        // Remove text
-        self.buffer.edit((pre, range.start), "");
+        if !edits.is_empty() {
+            let trans = Transaction::from_ordered_edit_set(
+                edits
+                    .iter()
+                    .map(|(byte_idx, old)| (*byte_idx, &old[..], "")),
+            );
+            apply_undo_step(buffer, trans, Some(view.c_msi));
+        }
+
+        // Adjust view
+        self.move_view_to_cursor();
+    }
+
+    pub fn remove_text_behind_cursor(&mut self, grapheme_count: usize) {
+        let view = &self.views[self.focus];
+        let buffer = &mut self.buffers[view.buf_idx];
+        let edits = buffer.mark_sets[view.c_msi]
+            .iter()
+            .map(|mark| {
+                let range = mark.range();
+                if range.start < range.end {
+                    // Remove the selection instead.
+                    return ((range.start, range.end), String::new());
+                }
+                if view.soft_tabs && grapheme_count == 1 {
+                    if let Some(pre) = prev_soft_tab_stop(&buffer.text, view, range.start) {
+                        return ((pre, range.start), String::new());
+                    }
+                }
+                let pre =
+                    nth_prev_grapheme_boundary(&buffer.text.slice(..), range.start, grapheme_count);
+                ((pre, range.start), String::new())
+            })
+            .collect();
+
+        buffer.edit_at_cursors(view.c_msi, edits);
 
         // Adjust view
         self.move_view_to_cursor();
     }
 
     pub fn remove_text_in_front_of_cursor(&mut self, grapheme_count: usize) {
-        // TODO: handle multiple cursors.
-        let mark = self.buffer.mark_sets[self.c_msi].main().unwrap();
-        let range = mark.range();
+        let view = &self.views[self.focus];
+        let buffer = &mut self.buffers[view.buf_idx];
+        let edits = buffer.mark_sets[view.c_msi]
+            .iter()
+            .map(|mark| {
+                let range = mark.range();
+                if range.start < range.end {
+                    // Remove the selection instead.
+                    return ((range.start, range.end), String::new());
+                }
+                let post =
+                    nth_next_grapheme_boundary(&buffer.text.slice(..), range.end, grapheme_count);
+                ((range.end, post), String::new())
+            })
+            .collect();
 
-        // Do nothing if there's nothing to delete.
-        if range.end == self.buffer.text.len_chars() {
-            return;
+        buffer.edit_at_cursors(view.c_msi, edits);
+
+        // Adjust view
+        self.move_view_to_cursor();
+    }
+
+    pub fn remove_text_inside_cursor(&mut self) {
+        let view = &self.views[self.focus];
+        let buffer = &mut self.buffers[view.buf_idx];
+        let edits = buffer.mark_sets[view.c_msi]
+            .iter()
+            .map(|mark| {
+                let range = mark.range();
+                ((range.start, range.end), String::new())
+            })
+            .collect();
+
+        buffer.edit_at_cursors(view.c_msi, edits);
+
+        // Adjust view
+        self.move_view_to_cursor();
+    }
+
+    /// Adds a new cursor on the visual line above the top-most cursor, and
+    /// makes it the main cursor.
+    pub fn add_cursor_above(&mut self) {
+        let view = &self.views[self.focus];
+        let buffer = &self.buffers[view.buf_idx];
+        let mut mark = buffer.mark_sets[view.c_msi][0];
+        if mark.hh_pos.is_none() {
+            mark.hh_pos = Some(view.formatter.get_horizontal(&buffer.text, mark.head));
+        }
+
+        let pos = self.vertical_pos(mark.head, mark.hh_pos.unwrap(), -1);
+        if pos != mark.head {
+            let cursors = &mut self.buffers[view.buf_idx].mark_sets[view.c_msi];
+            cursors.main_mark_idx = cursors.add_mark(Mark {
+                head: pos,
+                tail: pos,
+                hh_pos: mark.hh_pos,
+            });
+            cursors.make_consistent();
         }
 
-        let post =
-            nth_next_grapheme_boundary(&self.buffer.text.slice(..), range.end, grapheme_count);
+        // Adjust view
+        self.move_view_to_cursor();
+    }
+
+    /// Adds a new cursor on the visual line below the bottom-most cursor,
+    /// and makes it the main cursor.
+    pub fn add_cursor_below(&mut self) {
+        let view = &self.views[self.focus];
+        let buffer = &self.buffers[view.buf_idx];
+        let mut mark = *buffer.mark_sets[view.c_msi].marks.last().unwrap();
+        if mark.hh_pos.is_none() {
+            mark.hh_pos = Some(view.formatter.get_horizontal(&buffer.text, mark.head));
+        }
 
-        // Remove text
-        self.buffer.edit((range.end, post), "");
+        let pos = self.vertical_pos(mark.head, mark.hh_pos.unwrap(), 1);
+        if pos != mark.head {
+            let cursors = &mut self.buffers[view.buf_idx].mark_sets[view.c_msi];
+            cursors.main_mark_idx = cursors.add_mark(Mark {
+                head: pos,
+                tail: pos,
+                hh_pos: mark.hh_pos,
+            });
+            cursors.make_consistent();
+        }
 
         // Adjust view
         self.move_view_to_cursor();
     }
 
-    pub fn remove_text_inside_cursor(&mut self) {
-        // TODO: handle multiple cursors.
-        let mark = self.buffer.mark_sets[self.c_msi].main().unwrap();
-        let range = mark.range();
+    /// Adds a new cursor selecting the next occurance of the text selected
+    /// by the main cursor, wrapping around to the start of the buffer if
+    /// needed, and makes it the main cursor.
+    ///
+    /// Does nothing if the main cursor has no selection, or if all
+    /// occurances are already selected.
+    pub fn add_cursor_at_next_match(&mut self) {
+        let view = &self.views[self.focus];
+        let buffer = &mut self.buffers[view.buf_idx];
+        let cursors = &buffer.mark_sets[view.c_msi];
+        let main = cursors.main().unwrap();
+        let range = main.range();
+        if range.start == range.end {
+            return;
+        }
+        let pattern: String = buffer.text.slice(range.clone()).into();
+
+        // Search from the end of the main cursor, wrapping around if needed.
+        let text = &buffer.text;
+        let found = find_next(text, &pattern, range.end, text.len_chars())
+            .or_else(|| find_next(text, &pattern, 0, range.start));
+
+        if let Some(start) = found {
+            let end = start + (range.end - range.start);
+            let already_selected = cursors
+                .iter()
+                .any(|m| m.range().start == start && m.range().end == end);
+            if already_selected {
+                return;
+            }
 
-        if range.start < range.end {
-            self.buffer.edit((range.start, range.end), "");
+            let mark = if main.head < main.tail {
+                Mark::new(start, end)
+            } else {
+                Mark::new(end, start)
+            };
+            let cursors = &mut buffer.mark_sets[view.c_msi];
+            cursors.main_mark_idx = cursors.add_mark(mark);
+            cursors.make_consistent();
         }
 
         // Adjust view
         self.move_view_to_cursor();
     }
 
+    /// Removes all cursors except the main one.
+    pub fn collapse_to_main_cursor(&mut self) {
+        let view = &self.views[self.focus];
+        self.buffers[view.buf_idx].mark_sets[view.c_msi].reduce_to_main();
+    }
+
     pub fn cursor_to_beginning_of_buffer(&mut self) {
-        self.buffer.mark_sets[self.c_msi].clear();
-        self.buffer.mark_sets[self.c_msi].add_mark(Mark::new(0, 0));
+        let view = &self.views[self.focus];
+        let buffer = &mut self.buffers[view.buf_idx];
+        buffer.mark_sets[view.c_msi].clear();
+        buffer.mark_sets[view.c_msi].add_mark(Mark::new(0, 0));
 
         // Adjust view.
         self.move_view_to_cursor();
     }
 
     pub fn cursor_to_end_of_buffer(&mut self) {
-        let end = self.buffer.text.len_chars();
+        let view = &self.views[self.focus];
+        let buffer = &mut self.buffers[view.buf_idx];
+        let end = buffer.text.len_chars();
 
-        self.buffer.mark_sets[self.c_msi].clear();
-        self.buffer.mark_sets[self.c_msi].add_mark(Mark::new(end, end));
+        buffer.mark_sets[view.c_msi].clear();
+        buffer.mark_sets[view.c_msi].add_mark(Mark::new(end, end));
 
         // Adjust view.
         self.move_view_to_cursor();
     }
 
-    pub fn cursor_left(&mut self, n: usize) {
-        for mark in self.buffer.mark_sets[self.c_msi].iter_mut() {
-            mark.head = nth_prev_grapheme_boundary(&self.buffer.text.slice(..), mark.head, n);
-            mark.tail = mark.head;
+    pub fn cursor_left(&mut self, n: usize, extend_selection: bool) {
+        let view = &self.views[self.focus];
+        let buffer = &mut self.buffers[view.buf_idx];
+        for mark in buffer.mark_sets[view.c_msi].iter_mut() {
+            mark.head = nth_prev_grapheme_boundary(&buffer.text.slice(..), mark.head, n);
+            if !extend_selection {
+                mark.tail = mark.head;
+            }
             mark.hh_pos = None;
         }
-        self.buffer.mark_sets[self.c_msi].make_consistent();
+        buffer.mark_sets[view.c_msi].make_consistent();
 
         // Adjust view
         self.move_view_to_cursor();
     }
 
-    pub fn cursor_right(&mut self, n: usize) {
-        for mark in self.buffer.mark_sets[self.c_msi].iter_mut() {
-            mark.head = nth_next_grapheme_boundary(&self.buffer.text.slice(..), mark.head, n);
-            mark.tail = mark.head;
+    pub fn cursor_right(&mut self, n: usize, extend_selection: bool) {
+        let view = &self.views[self.focus];
+        let buffer = &mut self.buffers[view.buf_idx];
+        for mark in buffer.mark_sets[view.c_msi].iter_mut() {
+            mark.head = nth_next_grapheme_boundary(&buffer.text.slice(..), mark.head, n);
+            if !extend_selection {
+                mark.tail = mark.head;
+            }
             mark.hh_pos = None;
         }
-        self.buffer.mark_sets[self.c_msi].make_consistent();
+        buffer.mark_sets[view.c_msi].make_consistent();
 
         // Adjust view
         self.move_view_to_cursor();
     }
 
-    pub fn cursor_up(&mut self, n: usize) {
-        for mark in self.buffer.mark_sets[self.c_msi].iter_mut() {
-            if mark.hh_pos == None {
-                mark.hh_pos = Some(self.formatter.get_horizontal(&self.buffer.text, mark.head));
+    pub fn cursor_up(&mut self, n: usize, extend_selection: bool) {
+        let view = &self.views[self.focus];
+        let buffer = &mut self.buffers[view.buf_idx];
+        for mark in buffer.mark_sets[view.c_msi].iter_mut() {
+            if mark.hh_pos.is_none() {
+                mark.hh_pos = Some(view.formatter.get_horizontal(&buffer.text, mark.head));
             }
 
-            let vmove = -1 * n as isize;
+            let vmove = -(n as isize);
 
-            let mut temp_index =
-                self.formatter
-                    .offset_vertical(&self.buffer.text, mark.head, vmove);
+            let mut temp_index = view
+                .formatter
+                .offset_vertical(&buffer.text, mark.head, vmove);
             temp_index =
-                self.formatter
-                    .set_horizontal(&self.buffer.text, temp_index, mark.hh_pos.unwrap());
+                view.formatter
+                    .set_horizontal(&buffer.text, temp_index, mark.hh_pos.unwrap());
 
-            if !is_grapheme_boundary(&self.buffer.text.slice(..), temp_index) {
-                temp_index = nth_prev_grapheme_boundary(&self.buffer.text.slice(..), temp_index, 1);
+            if !is_grapheme_boundary(&buffer.text.slice(..), temp_index) {
+                temp_index = nth_prev_grapheme_boundary(&buffer.text.slice(..), temp_index, 1);
             }
 
             if temp_index == mark.head {
                 // We were already at the top.
                 mark.head = 0;
-                mark.tail = 0;
                 mark.hh_pos = None;
             } else {
                 mark.head = temp_index;
-                mark.tail = temp_index;
+            }
+            if !extend_selection {
+                mark.tail = mark.head;
             }
         }
-        self.buffer.mark_sets[self.c_msi].make_consistent();
+        buffer.mark_sets[view.c_msi].make_consistent();
 
         // Adjust view
         self.move_view_to_cursor();
     }
 
-    pub fn cursor_down(&mut self, n: usize) {
-        for mark in self.buffer.mark_sets[self.c_msi].iter_mut() {
-            if mark.hh_pos == None {
-                mark.hh_pos = Some(self.formatter.get_horizontal(&self.buffer.text, mark.head));
+    pub fn cursor_down(&mut self, n: usize, extend_selection: bool) {
+        let view = &self.views[self.focus];
+        let buffer = &mut self.buffers[view.buf_idx];
+        for mark in buffer.mark_sets[view.c_msi].iter_mut() {
+            if mark.hh_pos.is_none() {
+                mark.hh_pos = Some(view.formatter.get_horizontal(&buffer.text, mark.head));
             }
 
             let vmove = n as isize;
 
-            let mut temp_index =
-                self.formatter
-                    .offset_vertical(&self.buffer.text, mark.head, vmove);
+            let mut temp_index = view
+                .formatter
+                .offset_vertical(&buffer.text, mark.head, vmove);
             temp_index =
-                self.formatter
-                    .set_horizontal(&self.buffer.text, temp_index, mark.hh_pos.unwrap());
+                view.formatter
+                    .set_horizontal(&buffer.text, temp_index, mark.hh_pos.unwrap());
 
-            if !is_grapheme_boundary(&self.buffer.text.slice(..), temp_index) {
-                temp_index = nth_prev_grapheme_boundary(&self.buffer.text.slice(..), temp_index, 1);
+            if !is_grapheme_boundary(&buffer.text.slice(..), temp_index) {
+                temp_index = nth_prev_grapheme_boundary(&buffer.text.slice(..), temp_index, 1);
             }
 
             if temp_index == mark.head {
                 // We were already at the bottom.
-                mark.head = self.buffer.text.len_chars();
-                mark.tail = self.buffer.text.len_chars();
+                mark.head = buffer.text.len_chars();
                 mark.hh_pos = None;
             } else {
                 mark.head = temp_index;
-                mark.tail = temp_index;
             }
+            if !extend_selection {
+                mark.tail = mark.head;
+            }
+        }
+        buffer.mark_sets[view.c_msi].make_consistent();
+
+        // Adjust view
+        self.move_view_to_cursor();
+    }
+
+    /// Moves the cursors to the start of their lines.
+    pub fn cursor_home(&mut self, extend_selection: bool) {
+        let view = &self.views[self.focus];
+        let buffer = &mut self.buffers[view.buf_idx];
+        for mark in buffer.mark_sets[view.c_msi].iter_mut() {
+            let line_idx = buffer.text.char_to_line(mark.head);
+            mark.head = buffer.text.line_to_char(line_idx);
+            if !extend_selection {
+                mark.tail = mark.head;
+            }
+            mark.hh_pos = None;
+        }
+        buffer.mark_sets[view.c_msi].make_consistent();
+
+        // Adjust view
+        self.move_view_to_cursor();
+    }
+
+    /// Moves the cursors to the end of their lines, just before the line
+    /// ending.
+    pub fn cursor_end(&mut self, extend_selection: bool) {
+        let view = &self.views[self.focus];
+        let buffer = &mut self.buffers[view.buf_idx];
+        for mark in buffer.mark_sets[view.c_msi].iter_mut() {
+            let line_idx = buffer.text.char_to_line(mark.head);
+            mark.head = line_end_char_idx(&buffer.text, line_idx);
+            if !extend_selection {
+                mark.tail = mark.head;
+            }
+            mark.hh_pos = None;
         }
-        self.buffer.mark_sets[self.c_msi].make_consistent();
+        buffer.mark_sets[view.c_msi].make_consistent();
 
         // Adjust view
         self.move_view_to_cursor();
     }
 
-    pub fn page_up(&mut self) {
-        let move_amount = self.view_dim.0 - max(self.view_dim.0 / 8, 1);
-        self.buffer.mark_sets[self.v_msi][0].head = self.formatter.offset_vertical(
-            &self.buffer.text,
-            self.buffer.mark_sets[self.v_msi][0].head,
-            -1 * move_amount as isize,
+    pub fn page_up(&mut self, extend_selection: bool) {
+        let view = &self.views[self.focus];
+        let buffer = &mut self.buffers[view.buf_idx];
+        let move_amount = view.view_dim.0 - max(view.view_dim.0 / 8, 1);
+        buffer.mark_sets[view.v_msi][0].head = view.formatter.offset_vertical(
+            &buffer.text,
+            buffer.mark_sets[view.v_msi][0].head,
+            -(move_amount as isize),
         );
 
-        self.cursor_up(move_amount);
+        self.cursor_up(move_amount, extend_selection);
 
         // Adjust view
         self.move_view_to_cursor();
     }
 
-    pub fn page_down(&mut self) {
-        let move_amount = self.view_dim.0 - max(self.view_dim.0 / 8, 1);
-        self.buffer.mark_sets[self.v_msi][0].head = self.formatter.offset_vertical(
-            &self.buffer.text,
-            self.buffer.mark_sets[self.v_msi][0].head,
+    pub fn page_down(&mut self, extend_selection: bool) {
+        let view = &self.views[self.focus];
+        let buffer = &mut self.buffers[view.buf_idx];
+        let move_amount = view.view_dim.0 - max(view.view_dim.0 / 8, 1);
+        buffer.mark_sets[view.v_msi][0].head = view.formatter.offset_vertical(
+            &buffer.text,
+            buffer.mark_sets[view.v_msi][0].head,
             move_amount as isize,
         );
 
-        self.cursor_down(move_amount);
+        self.cursor_down(move_amount, extend_selection);
 
         // Adjust view
         self.move_view_to_cursor();
     }
 
     pub fn jump_to_line(&mut self, n: usize) {
-        self.buffer.mark_sets[self.c_msi].reduce_to_main();
-        if self.buffer.mark_sets[self.c_msi][0].hh_pos == None {
-            self.buffer.mark_sets[self.c_msi][0].hh_pos = Some(
-                self.formatter
-                    .get_horizontal(&self.buffer.text, self.buffer.mark_sets[self.c_msi][0].head),
+        let view = &self.views[self.focus];
+        let buffer = &mut self.buffers[view.buf_idx];
+        buffer.mark_sets[view.c_msi].reduce_to_main();
+        if buffer.mark_sets[view.c_msi][0].hh_pos.is_none() {
+            buffer.mark_sets[view.c_msi][0].hh_pos = Some(
+                view.formatter
+                    .get_horizontal(&buffer.text, buffer.mark_sets[view.c_msi][0].head),
             );
         }
 
-        let pos = self
-            .buffer
-            .text
-            .line_to_char(n.min(self.buffer.text.len_lines()));
-        let pos = self.formatter.set_horizontal(
-            &self.buffer.text,
+        let pos = buffer.text.line_to_char(n.min(buffer.text.len_lines()));
+        let pos = view.formatter.set_horizontal(
+            &buffer.text,
             pos,
-            self.buffer.mark_sets[self.c_msi][0].hh_pos.unwrap(),
+            buffer.mark_sets[view.c_msi][0].hh_pos.unwrap(),
         );
 
-        self.buffer.mark_sets[self.c_msi][0].head = pos;
-        self.buffer.mark_sets[self.c_msi][0].tail = pos;
+        buffer.mark_sets[view.c_msi][0].head = pos;
+        buffer.mark_sets[view.c_msi][0].tail = pos;
 
         // Adjust view
         self.move_view_to_cursor();
     }
+
+    /// Expands each cursor to select the word under its head.
+    ///
+    /// If the head isn't on a word character, the run of non-word,
+    /// non-whitespace characters (or the run of whitespace) under it is
+    /// selected instead.
+    pub fn select_word(&mut self) {
+        let view = &self.views[self.focus];
+        let buffer = &mut self.buffers[view.buf_idx];
+        for mark in buffer.mark_sets[view.c_msi].iter_mut() {
+            let (start, end) = word_range(&buffer.text, mark.head);
+            mark.tail = start;
+            mark.head = end;
+            mark.hh_pos = None;
+        }
+        buffer.mark_sets[view.c_msi].make_consistent();
+
+        // Adjust view
+        self.move_view_to_cursor();
+    }
+
+    /// Expands each cursor to select the full lines it touches, including
+    /// their line endings.
+    ///
+    /// If a cursor already selects exactly full lines, its selection is
+    /// extended to the next line as well.
+    pub fn select_line(&mut self) {
+        let view = &self.views[self.focus];
+        let buffer = &mut self.buffers[view.buf_idx];
+        for mark in buffer.mark_sets[view.c_msi].iter_mut() {
+            let range = mark.range();
+            let text = &buffer.text;
+            let start_line = text.char_to_line(range.start);
+            let end_line = if range.end > range.start
+                && text.line_to_char(text.char_to_line(range.end)) == range.end
+            {
+                // The selection already ends at a line start, so keep that
+                // line out of the selection unless all lines are already
+                // fully selected.
+                let line = text.char_to_line(range.end);
+                if text.line_to_char(start_line) == range.start {
+                    line + 1
+                } else {
+                    line
+                }
+            } else {
+                text.char_to_line(range.end) + 1
+            };
+
+            mark.tail = text.line_to_char(start_line);
+            mark.head = text.line_to_char(end_line.min(text.len_lines()));
+            mark.hh_pos = None;
+        }
+        buffer.mark_sets[view.c_msi].make_consistent();
+
+        // Adjust view
+        self.move_view_to_cursor();
+    }
+
+    /// Selects the entire buffer with a single cursor.
+    pub fn select_all(&mut self) {
+        let view = &self.views[self.focus];
+        let buffer = &mut self.buffers[view.buf_idx];
+        let end = buffer.text.len_chars();
+
+        buffer.mark_sets[view.c_msi].clear();
+        buffer.mark_sets[view.c_msi].add_mark(Mark::new(end, 0));
+
+        // Adjust view.
+        self.move_view_to_cursor();
+    }
+
+    //----------------------------------------------------
+    // Helper methods
+
+    /// Replaces all line endings in the given buffer that aren't `ending`
+    /// with it, as a single undo step.  If `cursors_msi` is given, those
+    /// cursors are recorded in the undo history.
+    fn replace_line_endings(
+        &mut self,
+        buf_idx: usize,
+        ending: LineEnding,
+        cursors_msi: Option<usize>,
+    ) {
+        let buffer = &mut self.buffers[buf_idx];
+        if !buffer.is_editable() {
+            return;
+        }
+
+        let mut edits = Vec::new(); // (byte_idx, old_ending)
+        for_each_line_ending(&buffer.text.slice(..), |(start, _), old| {
+            if old != ending {
+                edits.push((buffer.text.char_to_byte(start), line_ending_to_str(old)));
+            }
+        });
+        if edits.is_empty() {
+            return;
+        }
+
+        let new = line_ending_to_str(ending);
+        let trans = Transaction::from_ordered_edit_set(
+            edits.iter().map(|&(byte_idx, old)| (byte_idx, old, new)),
+        );
+        apply_undo_step(buffer, trans, cursors_msi);
+    }
+
+    /// Returns the indices of the lines touched by the current view's
+    /// cursors and selections, in order.
+    fn cursor_lines(&self) -> Vec<usize> {
+        let view = &self.views[self.focus];
+        let buffer = &self.buffers[view.buf_idx];
+        let mut lines = Vec::new();
+        for mark in buffer.mark_sets[view.c_msi].iter() {
+            lines.extend(selection_lines(&buffer.text, mark.range()));
+        }
+        lines.sort_unstable();
+        lines.dedup();
+        lines
+    }
+
+    /// Returns whether any of the current view's selections spans more
+    /// than one line.
+    fn selection_spans_lines(&self) -> bool {
+        let view = &self.views[self.focus];
+        let buffer = &self.buffers[view.buf_idx];
+        buffer.mark_sets[view.c_msi]
+            .iter()
+            .any(|mark| selection_lines(&buffer.text, mark.range()).count() > 1)
+    }
+
+    /// Returns the indices of the lines touched by the current view's
+    /// selections, in order, or of all lines if nothing is selected.
+    fn selected_lines(&self) -> Vec<usize> {
+        let view = &self.views[self.focus];
+        let buffer = &self.buffers[view.buf_idx];
+        let mut lines = Vec::new();
+        for mark in buffer.mark_sets[view.c_msi].iter() {
+            let range = mark.range();
+            if range.start == range.end {
+                continue;
+            }
+            lines.extend(selection_lines(&buffer.text, range));
+        }
+
+        if lines.is_empty() {
+            (0..buffer.text.len_lines()).collect()
+        } else {
+            lines.sort_unstable();
+            lines.dedup();
+            lines
+        }
+    }
+
+    /// Makes `pattern` the current search, and selects its first match at
+    /// or after the char index `from` with the main cursor, wrapping around
+    /// if needed.  Returns whether there was a match.
+    fn start_search(&mut self, pattern: SearchPattern, from: usize) -> bool {
+        self.search_pattern = Some(pattern);
+        self.search_matches.clear();
+        self.update_search_matches();
+
+        let matches = self.search_matches(self.view().buf_idx);
+        if matches.is_empty() {
+            return false;
+        }
+        let idx = matches.iter().position(|m| m.start >= from).unwrap_or(0);
+        self.select_search_match(idx);
+        true
+    }
+
+    /// Selects the match of the current search with the given index in the
+    /// current buffer with the main cursor, dropping any other cursors.
+    fn select_search_match(&mut self, idx: usize) {
+        let view = &self.views[self.focus];
+        let range = self.search_matches(view.buf_idx)[idx].clone();
+        let cursors = &mut self.buffers[view.buf_idx].mark_sets[view.c_msi];
+        cursors.reduce_to_main();
+        cursors[0] = Mark::new(range.end, range.start);
+
+        // Adjust view
+        self.move_view_to_cursor();
+    }
+
+    /// Moves `path` to the front of the recently opened files.
+    fn add_recent_file(&mut self, path: &Path) {
+        let path = fs::canonicalize(path).unwrap_or_else(|_| path.into());
+        self.recent_files.retain(|p| *p != path);
+        self.recent_files.insert(0, path);
+        self.recent_files.truncate(MAX_RECENT_FILES);
+    }
+
+    /// Sets up the given buffer for editing, if it isn't already: creates
+    /// its home view and cursor mark sets, and sets its history mode.
+    fn prepare_buffer(&mut self, idx: usize) {
+        let buffer = &mut self.buffers[idx];
+        buffer.set_history_mode(HistoryMode::WalkBack);
+        if !buffer.mark_sets.is_empty() {
+            return;
+        }
+
+        // Create appropriate mark sets for view positions and cursors.
+        let v_msi = buffer.add_mark_set();
+        let c_msi = buffer.add_mark_set();
+        debug_assert!(v_msi == HOME_V_MSI && c_msi == HOME_C_MSI);
+        buffer.mark_sets[v_msi].add_mark(Mark::new(0, 0));
+        buffer.mark_sets[c_msi].add_mark(Mark::new(0, 0));
+    }
+
+    /// Finds or creates a view and cursor mark set pair in the given buffer
+    /// that isn't used by any view, and returns their indices.
+    ///
+    /// The buffer's home mark sets are preferred.  Newly created mark sets
+    /// start out as copies of the home ones.
+    fn claim_mark_sets(&mut self, buf_idx: usize) -> (usize, usize) {
+        let home_in_use = self
+            .views
+            .iter()
+            .any(|v| v.buf_idx == buf_idx && v.v_msi == HOME_V_MSI);
+        if !home_in_use {
+            return (HOME_V_MSI, HOME_C_MSI);
+        }
+
+        if let Some(i) = self
+            .spare_mark_sets
+            .iter()
+            .position(|&(b, _, _)| b == buf_idx)
+        {
+            let (_, v_msi, c_msi) = self.spare_mark_sets.swap_remove(i);
+            return (v_msi, c_msi);
+        }
+
+        let buffer = &mut self.buffers[buf_idx];
+        let v_msi = buffer.add_mark_set();
+        let c_msi = buffer.add_mark_set();
+        buffer.mark_sets[v_msi] = buffer.mark_sets[HOME_V_MSI].clone();
+        buffer.mark_sets[c_msi] = buffer.mark_sets[HOME_C_MSI].clone();
+        (v_msi, c_msi)
+    }
+
+    /// Makes the given view's mark sets available for reuse by other views.
+    /// Does nothing for the home mark sets, which are kept for the buffer.
+    fn release_mark_sets(&mut self, view_idx: usize) {
+        let view = &self.views[view_idx];
+        if view.v_msi != HOME_V_MSI {
+            self.spare_mark_sets
+                .push((view.buf_idx, view.v_msi, view.c_msi));
+        }
+    }
+
+    /// Returns the char index `v_offset` visual lines away from `char_idx`,
+    /// at visual horizontal position `hh_pos` (or as close as possible).
+    fn vertical_pos(&self, char_idx: usize, hh_pos: usize, v_offset: isize) -> usize {
+        let view = &self.views[self.focus];
+        let buffer = &self.buffers[view.buf_idx];
+        let mut temp_index = view
+            .formatter
+            .offset_vertical(&buffer.text, char_idx, v_offset);
+        temp_index = view
+            .formatter
+            .set_horizontal(&buffer.text, temp_index, hh_pos);
+
+        if !is_grapheme_boundary(&buffer.text.slice(..), temp_index) {
+            temp_index = nth_prev_grapheme_boundary(&buffer.text.slice(..), temp_index, 1);
+        }
+
+        temp_index
+    }
+}
+
+//----------------------------------------------------------------------
+
+/// Applies `trans` to `buffer` as an undo step of its own, recording the
+/// cursors of the mark set `cursors_msi` in the undo history if given.
+fn apply_undo_step(buffer: &mut Buffer, trans: Transaction, cursors_msi: Option<usize>) {
+    buffer.break_undo_group();
+    match cursors_msi {
+        Some(c_msi) => buffer.apply_transaction_at_cursors(trans, c_msi, None),
+        None => buffer.apply_transaction(trans),
+    }
+    buffer.break_undo_group();
+}
+
+/// Returns the lines touched by the char range `range` of `text`.
+fn selection_lines(text: &Rope, range: std::ops::Range<usize>) -> std::ops::RangeInclusive<usize> {
+    let first = text.char_to_line(range.start);
+    // A selection ending right at the start of a line doesn't include
+    // that line.
+    let last = text.char_to_line(max(range.start, range.end.saturating_sub(1)));
+    first..=last
+}
+
+/// If `char_idx` is in the leading whitespace of its line and just after
+/// a space, returns where deleting back to the previous tab stop of the
+/// view would start.  Only spaces are deleted.
+fn prev_soft_tab_stop(text: &Rope, view: &View, char_idx: usize) -> Option<usize> {
+    let line_start = text.line_to_char(text.char_to_line(char_idx));
+    let leading = text.slice(line_start..char_idx);
+    if char_idx == line_start
+        || text.char(char_idx - 1) != ' '
+        || leading.chars().any(|c| c != ' ' && c != '\t')
+    {
+        return None;
+    }
+
+    let width = max(view.soft_tab_width as usize, 1);
+    let vis_pos = view.formatter.get_horizontal(text, char_idx);
+    if vis_pos == 0 {
+        // Soft-wrapped right before the cursor.
+        return None;
+    }
+    let tab_stop = (vis_pos - 1) / width * width;
+    let mut pre = char_idx;
+    while pre > line_start && text.char(pre - 1) == ' ' && char_idx - pre < vis_pos - tab_stop {
+        pre -= 1;
+    }
+    Some(pre)
+}
+
+/// Finds the first occurance of `pattern` in `text` that starts within
+/// the char range `[start, end)`, and returns its starting char index.
+fn find_next(text: &Rope, pattern: &str, start: usize, end: usize) -> Option<usize> {
+    let mut found = None;
+    for_each_match(&text.slice(start..), pattern, |range| {
+        if start + range.start < end {
+            found = Some(start + range.start);
+        }
+        false
+    });
+    found
+}
+
+/// Updates `matches` of `pattern` in `text` for the given changes to the
+/// text since they were found, searching again only around the changes.
+///
+/// The search is redone from the start of the line the changes begin on,
+/// or of an earlier line if matches can span lines and so start before
+/// that, and stops once past the changes and back in step with the old
+/// matches.
+fn update_matches<I>(
+    pattern: &SearchPattern,
+    text: &Rope,
+    mut matches: Vec<Range<usize>>,
+    changes: I,
+) -> Vec<Range<usize>>
+where
+    I: Iterator<Item = TextChange>,
+{
+    // Drop the matches touching the changes, move the ones after them, and
+    // find the span of the text that changed.
+    let mut changed: Option<Range<usize>> = None;
+    for change in changes {
+        let map = |i: usize| {
+            if i <= change.start {
+                i
+            } else if i >= change.old_end {
+                i - change.old_end + change.new_end
+            } else {
+                change.new_end
+            }
+        };
+        matches.retain(|m| m.end < change.start || m.start > change.old_end);
+        for m in matches.iter_mut().filter(|m| m.start > change.old_end) {
+            *m = map(m.start)..map(m.end);
+        }
+        changed = Some(match changed {
+            Some(range) => range.start.min(change.start)..map(range.end).max(change.new_end),
+            None => change.start..change.new_end,
+        });
+    }
+    let changed = match changed {
+        Some(range) => range,
+        None => return matches,
+    };
+
+    // Regexes can depend on the text around a match, so search from the
+    // start of the line, or of the first match ending there or after.
+    // Matches that can span lines may start on an earlier line, as far back
+    // as they can reach, and ones with no limit from the very start.
+    let reach_start = match pattern.reach_across_lines() {
+        Some(reach) => {
+            let byte_idx = text.char_to_byte(changed.start).saturating_sub(reach);
+            text.byte_to_char(byte_idx)
+        }
+        None => 0,
+    };
+    let line_start = text.line_to_char(text.char_to_line(reach_start));
+    let first = matches.partition_point(|m| m.end < line_start);
+    let from = matches
+        .get(first)
+        .map_or(line_start, |m| m.start.min(line_start));
+
+    let mut updated = matches[..first].to_vec();
+    let mut in_step_at = None;
+    pattern.for_each_match_from(&text.slice(..), from, |range| {
+        if range.start >= changed.end {
+            let rest = &matches[first..];
+            if let Ok(i) = rest.binary_search_by_key(&range.start, |m| m.start) {
+                if rest[i] == range {
+                    in_step_at = Some(first + i);
+                    return false;
+                }
+            }
+        }
+        updated.push(range);
+        true
+    });
+    if let Some(i) = in_step_at {
+        updated.extend_from_slice(&matches[i..]);
+    }
+    updated
+}
+
+/// Returns the char index of the end of the given line, excluding its line
+/// ending.
+fn line_end_char_idx(text: &Rope, line_idx: usize) -> usize {
+    let line = text.line(line_idx);
+    let mut end = text.line_to_char(line_idx) + line.len_chars();
+    let mut chars = line.chars_at(line.len_chars());
+    match chars.prev() {
+        Some('\u{000A}') => {
+            end -= 1;
+            if chars.prev() == Some('\u{000D}') {
+                end -= 1;
+            }
+        }
+        Some(c) if is_line_ending(&c.to_string()) => {
+            end -= 1;
+        }
+        _ => {}
+    }
+    end
+}
+
+/// Returns the char range of the word (or run of similar characters) at
+/// the given char index.
+fn word_range(text: &Rope, char_idx: usize) -> (usize, usize) {
+    #[derive(PartialEq)]
+    enum CharKind {
+        Word,
+        Whitespace,
+        LineEnding,
+        Other,
+    }
+    fn kind(c: char) -> CharKind {
+        if c.is_alphanumeric() || c == '_' {
+            CharKind::Word
+        } else if is_line_ending(&c.to_string()) {
+            CharKind::LineEnding
+        } else if is_whitespace(c) {
+            CharKind::Whitespace
+        } else {
+            CharKind::Other
+        }
+    }
+
+    // Figure out which kind of run we're selecting.  If we're at the end
+    // of a line or the buffer, prefer the run just before the index.
+    let target = match text.get_char(char_idx).map(kind) {
+        Some(CharKind::LineEnding) | None => match char_idx.checked_sub(1).map(|i| text.char(i)) {
+            Some(c) if kind(c) != CharKind::LineEnding => kind(c),
+            _ => return (char_idx, char_idx),
+        },
+        Some(k) => k,
+    };
+
+    let mut start = char_idx;
+    let mut chars = text.chars_at(char_idx);
+    while let Some(c) = chars.prev() {
+        if kind(c) != target {
+            break;
+        }
+        start -= 1;
+    }
+
+    let mut end = char_idx;
+    let mut chars = text.chars_at(char_idx);
+    for c in &mut chars {
+        if kind(c) != target {
+            break;
+        }
+        end += 1;
+    }
+
+    (start, end)
+}
+
+//----------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use backend::buffer::BufferPath;
+
+    fn editor_with_text(text: &str) -> Editor {
+        let mut buffers = backend::editor::Editor::new();
+        buffers.add_buffer(Buffer::new(text.into(), BufferPath::Temp(0)));
+        Editor::new(buffers, LineFormatter::new(4))
+    }
+
+    #[test]
+    fn convert_indentation() {
+        let mut ed = editor_with_text("a\n  b\n    c\n   d\n\te\n");
+        ed.convert_indentation(2, false, 4);
+        assert_eq!(ed.buffer().text, "a\n\tb\n\t\tc\n\t d\n\te\n");
+        assert!(!ed.view().soft_tabs);
+        assert_eq!(ed.view().soft_tab_width, 4);
+
+        ed.convert_indentation(4, true, 4);
+        assert_eq!(ed.buffer().text, "a\n    b\n        c\n     d\n    e\n");
+
+        // Each conversion is a single undo step.
+        ed.undo();
+        assert_eq!(ed.buffer().text, "a\n\tb\n\t\tc\n\t d\n\te\n");
+        ed.undo();
+        assert_eq!(ed.buffer().text, "a\n  b\n    c\n   d\n\te\n");
+    }
+
+    #[test]
+    fn convert_indentation_selection() {
+        let mut ed = editor_with_text("\ta\n\tb\n\tc\n");
+        let (buf_idx, c_msi) = (ed.view().buf_idx, ed.view().c_msi);
+        ed.buffers[buf_idx].mark_sets[c_msi][0] = Mark::new(3, 6);
+        ed.convert_indentation(4, true, 2);
+        assert_eq!(ed.buffer().text, "\ta\n  b\n\tc\n");
+    }
+
+    #[test]
+    fn indent_dedent_selection() {
+        let mut ed = editor_with_text("a\n\n  b\nc\n");
+        ed.views[0].soft_tabs = true;
+        ed.views[0].soft_tab_width = 2;
+        let (buf_idx, c_msi) = (ed.view().buf_idx, ed.view().c_msi);
+        ed.buffers[buf_idx].mark_sets[c_msi][0] = Mark::new(0, 7);
+        ed.insert_tab_at_cursor();
+        assert_eq!(ed.buffer().text, "  a\n\n    b\nc\n");
+        ed.dedent_lines();
+        ed.dedent_lines();
+        assert_eq!(ed.buffer().text, "a\n\nb\nc\n");
+        ed.undo();
+        assert_eq!(ed.buffer().text, "a\n\n  b\nc\n");
+
+        ed.views[0].soft_tabs = false;
+        ed.buffers[buf_idx].mark_sets[c_msi][0] = Mark::new(0, 7);
+        ed.insert_tab_at_cursor();
+        assert_eq!(ed.buffer().text, "\ta\n\n\t  b\nc\n");
+    }
+
+    #[test]
+    fn backspace_soft_tabs() {
+        let mut ed = editor_with_text("      a\n");
+        ed.views[0].soft_tabs = true;
+        ed.views[0].soft_tab_width = 4;
+        let (buf_idx, c_msi) = (ed.view().buf_idx, ed.view().c_msi);
+        ed.buffers[buf_idx].mark_sets[c_msi][0] = Mark::new(6, 6);
+        ed.remove_text_behind_cursor(1);
+        assert_eq!(ed.buffer().text, "    a\n");
+        ed.remove_text_behind_cursor(1);
+        assert_eq!(ed.buffer().text, "a\n");
+
+        // Outside of leading whitespace it's a normal backspace.
+        ed.buffers[buf_idx].mark_sets[c_msi][0] = Mark::new(1, 1);
+        ed.insert_text_at_cursor("   ");
+        ed.remove_text_behind_cursor(1);
+        assert_eq!(ed.buffer().text, "a  \n");
+    }
+
+    #[test]
+    fn search() {
+        let mut ed = editor_with_text("foo bar\nfoo baz\nfoo\n");
+        assert!(ed.search("foo", 1));
+        assert_eq!(ed.search_position(0), Some((Some(1), 3)));
+        assert_eq!(ed.buffer().mark_sets[ed.view().c_msi][0].range(), 8..11);
+
+        assert!(ed.next_search_match());
+        assert!(ed.next_search_match());
+        assert_eq!(ed.search_position(0), Some((Some(0), 3)));
+        assert!(ed.prev_search_match());
+        assert_eq!(ed.search_position(0), Some((Some(2), 3)));
+
+        // Matches are kept up to date with edits.
+        ed.insert_text_at_cursor("x");
+        assert!(ed.update_search_matches());
+        assert_eq!(ed.search_position(0), Some((None, 2)));
+
+        assert!(!ed.search("qux", 0));
+        assert_eq!(ed.search_position(0), Some((None, 0)));
+        ed.clear_search();
+        assert_eq!(ed.search_position(0), None);
+    }
+
+    #[test]
+    fn search_matches_follow_edits() {
+        let mut ed = editor_with_text(&"foo bar\nbaz foo\n".repeat(100));
+        let (buf_idx, c_msi) = (ed.view().buf_idx, ed.view().c_msi);
+        for pattern in [r"foo", r"^foo|o\nb|foo$|(?m)^baz"].iter() {
+            assert!(ed.search_regex(Regex::new(pattern).unwrap(), 0));
+            let edits = [(800, "fo"), (802, "o "), (0, "x"), (1599, "foo\nfoo")];
+            for &(char_idx, text) in edits.iter() {
+                ed.buffers[buf_idx].mark_sets[c_msi][0] = Mark::new(char_idx, char_idx);
+                ed.insert_text_at_cursor(text);
+                assert!(ed.update_search_matches());
+                let all = ed
+                    .search_pattern()
+                    .unwrap()
+                    .find_all(&ed.buffer().text.slice(..));
+                assert_eq!(ed.search_matches(buf_idx), &all[..]);
+            }
+            for _ in 0..edits.len() {
+                ed.undo();
+                assert!(ed.update_search_matches());
+                let all = ed
+                    .search_pattern()
+                    .unwrap()
+                    .find_all(&ed.buffer().text.slice(..));
+                assert_eq!(ed.search_matches(buf_idx), &all[..]);
+            }
+        }
+    }
+
+    #[test]
+    fn search_matches_completed_across_lines() {
+        // Each edit completes a match that starts on the line before it.
+        let patterns = [
+            SearchPattern::Text("foo\nbar".into()),
+            SearchPattern::Regex(Regex::new(r"foo\nbar").unwrap()),
+            SearchPattern::Regex(Regex::new(r"(?s)o.{1,3}ar").unwrap()),
+            SearchPattern::Regex(Regex::new(r"foo\s+bar").unwrap()),
+        ];
+        for pattern in patterns.iter() {
+            let mut ed = editor_with_text(&"foo\nba\n".repeat(100));
+            let (buf_idx, c_msi) = (ed.view().buf_idx, ed.view().c_msi);
+            match *pattern {
+                SearchPattern::Text(ref text) => assert!(!ed.search(text, 0)),
+                SearchPattern::Regex(ref regex) => assert!(!ed.search_regex(regex.clone(), 0)),
+            }
+            for &char_idx in [699, 405, 6].iter() {
+                ed.buffers[buf_idx].mark_sets[c_msi][0] = Mark::new(char_idx, char_idx);
+                ed.insert_text_at_cursor("r");
+                assert!(ed.update_search_matches());
+                let all = pattern.find_all(&ed.buffer().text.slice(..));
+                assert_eq!(ed.search_matches(buf_idx), &all[..]);
+            }
+            assert_eq!(ed.search_matches(buf_idx).len(), 3);
+        }
+    }
+
+    #[test]
+    fn regex_replace() {
+        let mut ed = editor_with_text("x = 1;\ny = 22;\nz = 3;\n");
+        let regex = Regex::new(r"(\w) = (\d+)").unwrap();
+        assert!(ed.search_regex(regex, 0));
+
+        let new = ed.replace_selected_search_match("$2 = $1");
+        assert_eq!(new, Some(0..5));
+        assert_eq!(ed.buffer().text, "1 = x;\ny = 22;\nz = 3;\n");
+        assert_eq!(ed.select_search_match_after(5), Some(7..13));
+
+        // Replacing all is a single undo step.
+        assert_eq!(ed.replace_search_matches("[$2]", |_| true), 2);
+        assert_eq!(ed.buffer().text, "1 = x;\n[22];\n[3];\n");
+        ed.undo();
+        assert_eq!(ed.buffer().text, "1 = x;\ny = 22;\nz = 3;\n");
+    }
+
+    #[test]
+    fn convert_line_endings() {
+        let mut ed = editor_with_text("a\r\nb\nc\rd\u{2028}");
+        ed.convert_line_endings(LineEnding::LF);
+        assert_eq!(ed.buffer().text, "a\nb\nc\nd\n");
+        assert!(ed.view().line_ending_type == LineEnding::LF);
+
+        ed.convert_line_endings(LineEnding::CRLF);
+        assert_eq!(ed.buffer().text, "a\r\nb\r\nc\r\nd\r\n");
+        ed.undo();
+        assert_eq!(ed.buffer().text, "a\nb\nc\nd\n");
+    }
 }
@@ -31,7 +31,7 @@ pub struct LineFormatter {
 impl LineFormatter {
     pub fn new(tab_width: usize) -> LineFormatter {
         LineFormatter {
-            tab_width: tab_width,
+            tab_width,
             wrap_width: 80,
             maintain_indent: true,
             wrap_extra_indent: 2,
@@ -57,7 +57,7 @@ impl LineFormatter {
         (
             Blocks {
                 formatter: self,
-                buf: buf,
+                buf,
                 line_idx: line_i,
                 line_block_count: block_count(&line),
                 block_idx: block_index,
@@ -88,7 +88,7 @@ impl LineFormatter {
 
         // If we went off the end, calculate the position of the end of the
         // block.
-        return hpos + last_width;
+        hpos + last_width
     }
 
     /// Takes a char index and a desired visual horizontal position, and
@@ -119,14 +119,16 @@ impl LineFormatter {
 
             // Check if we found the horizontal position on this line,
             // and set it if so.
-            if hpos_char_idx == None && (pos.1 + width) > horizontal {
+            if hpos_char_idx.is_none() && (pos.1 + width) > horizontal {
                 hpos_char_idx = Some(i);
             }
 
             // Check if we've found the horizontal position _and_ the passed
             // char_idx on the same line, and return if so.
-            if (i + char_count(&g)) > char_offset && hpos_char_idx != None {
-                return char_idx - char_offset + hpos_char_idx.unwrap();
+            if let Some(hpos_char_idx) = hpos_char_idx {
+                if (i + char_count(&g)) > char_offset {
+                    return char_idx - char_offset + hpos_char_idx;
+                }
             }
 
             last_pos = pos;
@@ -138,9 +140,9 @@ impl LineFormatter {
         let end_i = char_idx - char_offset + i;
         let end_last_i = char_idx - char_offset + last_i;
         if buf.len_chars() == end_i {
-            return end_i;
+            end_i
         } else {
-            return end_last_i;
+            end_last_i
         }
     }
 
@@ -195,7 +197,7 @@ impl LineFormatter {
             }
         }
 
-        return char_idx;
+        char_idx
     }
 
     //----------------------------------------------------
@@ -274,7 +276,7 @@ impl LineFormatter {
             if is_line_start {
                 0
             } else {
-                self.get_line_indent(&line) + self.wrap_extra_indent
+                self.get_line_indent(line) + self.wrap_extra_indent
             },
             if is_line_start {
                 self.wrap_extra_indent
@@ -371,11 +373,11 @@ impl<'a> BlockVisIter<'a> {
         }
 
         BlockVisIter {
-            grapheme_itr: grapheme_itr,
-            wrap_width: wrap_width,
-            tab_width: tab_width,
+            grapheme_itr,
+            wrap_width,
+            tab_width,
             indent: starting_indent,
-            wrap_extra_indent: wrap_extra_indent,
+            wrap_extra_indent,
             finding_indent: find_indent,
 
             word_buf: Vec::new(),
@@ -414,7 +416,7 @@ impl<'a> Iterator for BlockVisIter<'a> {
             let mut word_width = 0;
             self.word_buf.truncate(0);
 
-            while let Some(g) = self.grapheme_itr.next().map(|g| Cow::<str>::from(g)) {
+            while let Some(g) = self.grapheme_itr.next().map(Cow::<str>::from) {
                 let width =
                     grapheme_vis_width_at_vis_pos(&g, self.pos.1 + word_width, self.tab_width);
                 self.word_buf.push((g.clone(), width));
@@ -437,15 +439,16 @@ impl<'a> Iterator for BlockVisIter<'a> {
                 }
             }
 
-            if self.word_buf.len() == 0 {
+            if self.word_buf.is_empty() {
                 return None;
             }
 
             // Move to next line if necessary
-            if (self.pos.1 + word_width) > self.wrap_width && (self.pos.1 > self.indent) {
-                if self.pos.1 > 0 {
-                    self.pos = (self.pos.0 + 1, self.indent + self.wrap_extra_indent);
-                }
+            if (self.pos.1 + word_width) > self.wrap_width
+                && (self.pos.1 > self.indent)
+                && self.pos.1 > 0
+            {
+                self.pos = (self.pos.0 + 1, self.indent + self.wrap_extra_indent);
             }
 
             self.word_i = 0;
@@ -455,7 +458,7 @@ impl<'a> Iterator for BlockVisIter<'a> {
         let (g, g_width) = {
             let (ref g, mut width) = self.word_buf[self.word_i];
             if g == "\t" {
-                width = grapheme_vis_width_at_vis_pos(&g, self.pos.1, self.tab_width);
+                width = grapheme_vis_width_at_vis_pos(g, self.pos.1, self.tab_width);
             }
             (g, width)
         };
@@ -471,7 +474,7 @@ impl<'a> Iterator for BlockVisIter<'a> {
 
         // Increment index and return.
         self.word_i += 1;
-        return Some((g.clone(), pos, g_width));
+        Some((g.clone(), pos, g_width))
     }
 }
 
@@ -482,7 +485,7 @@ fn grapheme_vis_width_at_vis_pos(g: &str, pos: usize, tab_width: usize) -> usize
         // Tabs are special.
         tab_stop_from_vis_pos(pos, tab_width) - pos
     } else {
-        return grapheme_width(&g);
+        grapheme_width(g)
     }
 }
 
@@ -0,0 +1,224 @@
+//! Fuzzy matching of short queries against paths, for picking files.
+//!
+//! A query matches a path if its chars appear in the path in order, ignoring
+//! case.  Of all the ways they can appear, the best scoring one is used,
+//! which favors matches at the starts of path segments and words, runs of
+//! consecutive chars, and matches in the file name.
+
+const SCORE_MATCH: i64 = 16;
+const BONUS_SEGMENT_START: i64 = 32; // At the start, or after a `/`.
+const BONUS_WORD_START: i64 = 24; // After `_`, `-`, `.`, or a space, or at a case change.
+const BONUS_CONSECUTIVE: i64 = 24;
+const BONUS_FILE_NAME: i64 = 8; // In the last path segment.
+const BONUS_CASE: i64 = 1; // Same case as in the query.
+const PENALTY_GAP: i64 = 2; // Per skipped char between two matched chars.
+
+const NO_MATCH: i64 = i64::MIN / 2;
+
+/// How well a query matches a path, and where.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FuzzyMatch {
+    pub score: i64,
+    pub positions: Vec<usize>, // Char indices of the matched chars.
+}
+
+/// Matches `query` against `path`.  Whitespace in the query is ignored, so
+/// an empty query matches everything, with a score of zero.
+pub fn fuzzy_match(query: &str, path: &str) -> Option<FuzzyMatch> {
+    let query: Vec<char> = query.chars().filter(|c| !c.is_whitespace()).collect();
+    let path: Vec<char> = path.chars().collect();
+    let folded: Vec<char> = path.iter().map(|&c| fold_case(c)).collect();
+    let (m, n) = (query.len(), path.len());
+
+    // Cheap check before doing the real work.
+    let mut rest = folded.iter();
+    if !query.iter().all(|&q| rest.any(|&c| c == fold_case(q))) {
+        return None;
+    }
+    if m == 0 {
+        return Some(FuzzyMatch {
+            score: 0,
+            positions: Vec::new(),
+        });
+    }
+
+    // What matching each of the path's chars is worth on its own.
+    let file_name_start = path.iter().rposition(|&c| c == '/').map_or(0, |i| i + 1);
+    let bonuses: Vec<i64> = (0..n)
+        .map(|j| {
+            let prev = if j == 0 { '/' } else { path[j - 1] };
+            let mut bonus = SCORE_MATCH;
+            if prev == '/' {
+                bonus += BONUS_SEGMENT_START;
+            } else if "_-. ".contains(prev) || (prev.is_lowercase() && path[j].is_uppercase()) {
+                bonus += BONUS_WORD_START;
+            }
+            if j >= file_name_start {
+                bonus += BONUS_FILE_NAME;
+            }
+            bonus
+        })
+        .collect();
+
+    // `scores[i * n + j]` is the best score for matching the first `i + 1`
+    // query chars with the last of them at `j`, and `prevs[i * n + j]`
+    // where the one before it is then matched.
+    let mut scores = vec![NO_MATCH; m * n];
+    let mut prevs = vec![0; m * n];
+    for i in 0..m {
+        let q = fold_case(query[i]);
+
+        // The best score of the previous row, adjusted so that subtracting
+        // `PENALTY_GAP * (j - 1)` gives its score with the gap up to `j`.
+        let mut best = NO_MATCH;
+        let mut best_k = 0;
+        for j in i..n {
+            if i > 0 && j >= 2 {
+                let k = j - 2;
+                let s = scores[(i - 1) * n + k];
+                if s > NO_MATCH && s + PENALTY_GAP * k as i64 > best {
+                    best = s + PENALTY_GAP * k as i64;
+                    best_k = k;
+                }
+            }
+            if folded[j] != q {
+                continue;
+            }
+
+            let mut score = bonuses[j];
+            if path[j] == query[i] {
+                score += BONUS_CASE;
+            }
+            if i == 0 {
+                scores[j] = score;
+                continue;
+            }
+            let consecutive = scores[(i - 1) * n + j - 1];
+            let gap = best - PENALTY_GAP * (j as i64 - 1);
+            let (prev_score, prev) =
+                if consecutive > NO_MATCH && consecutive + BONUS_CONSECUTIVE >= gap {
+                    (consecutive + BONUS_CONSECUTIVE, j - 1)
+                } else if best > NO_MATCH {
+                    (gap, best_k)
+                } else {
+                    continue;
+                };
+            scores[i * n + j] = score + prev_score;
+            prevs[i * n + j] = prev;
+        }
+    }
+
+    // Pick the best end, and trace back where the rest were matched.
+    let last_row = &scores[(m - 1) * n..];
+    let (mut j, &score) = last_row
+        .iter()
+        .enumerate()
+        .max_by(|a, b| a.1.cmp(b.1).then(b.0.cmp(&a.0)))
+        .unwrap();
+    if score <= NO_MATCH {
+        return None;
+    }
+    let mut positions = vec![0; m];
+    for i in (0..m).rev() {
+        positions[i] = j;
+        j = prevs[i * n + j];
+    }
+
+    Some(FuzzyMatch {
+        score,
+        positions,
+    })
+}
+
+/// Matches `query` against the `paths` with the given indices, and returns
+/// the indices and matches of those that match, best first.  Ties go to
+/// shorter paths, and then to earlier ones.  An empty query matches all of
+/// them, in the given order.
+///
+/// Narrowing a query down (e.g. by typing another char) can only remove
+/// matches, so the indices from the previous query can be passed to only
+/// search among those.
+pub fn filter<I>(query: &str, paths: &[String], indices: I) -> Vec<(usize, FuzzyMatch)>
+where
+    I: Iterator<Item = usize>,
+{
+    let mut matches: Vec<(usize, FuzzyMatch)> = indices
+        .filter_map(|idx| fuzzy_match(query, &paths[idx]).map(|m| (idx, m)))
+        .collect();
+    if query.trim().is_empty() {
+        return matches;
+    }
+    matches.sort_by(|a, b| {
+        b.1.score
+            .cmp(&a.1.score)
+            .then(paths[a.0].len().cmp(&paths[b.0].len()))
+            .then(a.0.cmp(&b.0))
+    });
+    matches
+}
+
+fn fold_case(c: char) -> char {
+    c.to_lowercase().next().unwrap_or(c)
+}
+
+//--------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fuzzy_match_1() {
+        assert_eq!(
+            fuzzy_match("", "src/main.rs"),
+            Some(FuzzyMatch {
+                score: 0,
+                positions: vec![],
+            })
+        );
+        assert_eq!(
+            fuzzy_match("ms", "src/main.rs").unwrap().positions,
+            vec![4, 10]
+        );
+        assert_eq!(
+            fuzzy_match("MAIN", "src/main.rs").unwrap().positions,
+            vec![4, 5, 6, 7]
+        );
+        assert_eq!(
+            fuzzy_match("edm", "src/editor/mod.rs").unwrap().positions,
+            vec![4, 5, 11]
+        );
+        assert_eq!(
+            fuzzy_match("s m", "src/main.rs").unwrap().positions,
+            vec![0, 4]
+        );
+        assert!(fuzzy_match("sm", "main.rs").is_none());
+        assert!(fuzzy_match("x", "").is_none());
+    }
+
+    #[test]
+    fn filter_ranking() {
+        let paths: Vec<String> = vec![
+            "src/formatted.rs".into(),
+            "src/editor/mod.rs".into(),
+            "docs/readme.md".into(),
+            "src/editor.rs".into(),
+        ];
+
+        // An empty query keeps the given order.
+        let matches = filter(" ", &paths, vec![2, 0, 3].into_iter());
+        let order: Vec<usize> = matches.iter().map(|m| m.0).collect();
+        assert_eq!(order, vec![2, 0, 3]);
+
+        // Segment starts and file names beat the middles of words, and
+        // consecutive chars beat scattered ones.
+        let matches = filter("ed", &paths, 0..paths.len());
+        let order: Vec<usize> = matches.iter().map(|m| m.0).collect();
+        assert_eq!(order, vec![3, 1, 0, 2]);
+
+        // Only the given paths are matched against.
+        let matches = filter("edm", &paths, vec![3, 0, 2].into_iter());
+        let order: Vec<usize> = matches.iter().map(|m| m.0).collect();
+        assert_eq!(order, vec![2]);
+    }
+}
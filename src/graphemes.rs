@@ -2,6 +2,8 @@ use ropey::{iter::Chunks, str_utils::byte_to_char_idx, RopeSlice};
 use unicode_segmentation::{GraphemeCursor, GraphemeIncomplete};
 use unicode_width::UnicodeWidthStr;
 
+use backend::encoding::char_to_raw_byte;
+
 pub fn grapheme_width(g: &str) -> usize {
     if g.as_bytes()[0] <= 127 {
         // Fast-path ascii.
@@ -17,6 +19,9 @@ pub fn grapheme_width(g: &str) -> usize {
         // Point 3: we're only examining the first _byte_.  But for utf8, when
         // checking for ascii range values only, that works.
         1
+    } else if g.chars().next().and_then(char_to_raw_byte).is_some() {
+        // Raw bytes from invalid utf8 are displayed as "\xHH" escapes.
+        4
     } else {
         // We use max(1) here because all grapeheme clusters--even illformed
         // ones--should have at least some width so they can be edited
@@ -25,19 +30,12 @@ pub fn grapheme_width(g: &str) -> usize {
     }
 }
 
+/// Finds the `n`th grapheme boundary before the given char position, or the
+/// start of the slice if there are fewer than `n` of them.
+///
+/// The rope is only looked up once, and then walked chunk by chunk, so
+/// this is cheap for small `n` regardless of the size of the text.
 pub fn nth_prev_grapheme_boundary(slice: &RopeSlice, char_idx: usize, n: usize) -> usize {
-    // TODO: implement this more efficiently.  This has to do a lot of
-    // re-scanning of rope chunks.  Probably move the main implementation here,
-    // and have prev_grapheme_boundary call this instead.
-    let mut char_idx = char_idx;
-    for _ in 0..n {
-        char_idx = prev_grapheme_boundary(slice, char_idx);
-    }
-    char_idx
-}
-
-/// Finds the previous grapheme boundary before the given char position.
-pub fn prev_grapheme_boundary(slice: &RopeSlice, char_idx: usize) -> usize {
     // Bounds check
     debug_assert!(char_idx <= slice.len_chars());
 
@@ -50,42 +48,45 @@ pub fn prev_grapheme_boundary(slice: &RopeSlice, char_idx: usize) -> usize {
     // Set up the grapheme cursor.
     let mut gc = GraphemeCursor::new(byte_idx, slice.len_bytes(), true);
 
-    // Find the previous grapheme cluster boundary.
-    loop {
-        match gc.prev_boundary(chunk, chunk_byte_idx) {
-            Ok(None) => return 0,
-            Ok(Some(n)) => {
-                let tmp = byte_to_char_idx(chunk, n - chunk_byte_idx);
-                return chunk_char_idx + tmp;
-            }
-            Err(GraphemeIncomplete::PrevChunk) => {
-                let (a, b, c, _) = slice.chunk_at_byte(chunk_byte_idx - 1);
-                chunk = a;
-                chunk_byte_idx = b;
-                chunk_char_idx = c;
-            }
-            Err(GraphemeIncomplete::PreContext(n)) => {
-                let ctx_chunk = slice.chunk_at_byte(n - 1).0;
-                gc.provide_context(ctx_chunk, n - ctx_chunk.len());
+    // Find the nth previous grapheme cluster boundary.
+    let mut boundary_byte_idx = byte_idx;
+    for _ in 0..n {
+        loop {
+            match gc.prev_boundary(chunk, chunk_byte_idx) {
+                Ok(None) => return 0,
+                Ok(Some(n)) => {
+                    boundary_byte_idx = n;
+                    break;
+                }
+                Err(GraphemeIncomplete::PrevChunk) => {
+                    let (a, b, c, _) = slice.chunk_at_byte(chunk_byte_idx - 1);
+                    chunk = a;
+                    chunk_byte_idx = b;
+                    chunk_char_idx = c;
+                }
+                Err(GraphemeIncomplete::PreContext(n)) => {
+                    let ctx_chunk = slice.chunk_at_byte(n - 1).0;
+                    gc.provide_context(ctx_chunk, n - ctx_chunk.len());
+                }
+                _ => unreachable!(),
             }
-            _ => unreachable!(),
         }
     }
+
+    chunk_char_idx + byte_to_char_idx(chunk, boundary_byte_idx - chunk_byte_idx)
 }
 
-pub fn nth_next_grapheme_boundary(slice: &RopeSlice, char_idx: usize, n: usize) -> usize {
-    // TODO: implement this more efficiently.  This has to do a lot of
-    // re-scanning of rope chunks.  Probably move the main implementation here,
-    // and have next_grapheme_boundary call this instead.
-    let mut char_idx = char_idx;
-    for _ in 0..n {
-        char_idx = next_grapheme_boundary(slice, char_idx);
-    }
-    char_idx
+/// Finds the previous grapheme boundary before the given char position.
+pub fn prev_grapheme_boundary(slice: &RopeSlice, char_idx: usize) -> usize {
+    nth_prev_grapheme_boundary(slice, char_idx, 1)
 }
 
-/// Finds the next grapheme boundary after the given char position.
-pub fn next_grapheme_boundary(slice: &RopeSlice, char_idx: usize) -> usize {
+/// Finds the `n`th grapheme boundary after the given char position, or the
+/// end of the slice if there are fewer than `n` of them.
+///
+/// Like `nth_prev_grapheme_boundary()`, this walks the rope chunk by chunk
+/// rather than looking up each boundary separately.
+pub fn nth_next_grapheme_boundary(slice: &RopeSlice, char_idx: usize, n: usize) -> usize {
     // Bounds check
     debug_assert!(char_idx <= slice.len_chars());
 
@@ -98,27 +99,32 @@ pub fn next_grapheme_boundary(slice: &RopeSlice, char_idx: usize) -> usize {
     // Set up the grapheme cursor.
     let mut gc = GraphemeCursor::new(byte_idx, slice.len_bytes(), true);
 
-    // Find the next grapheme cluster boundary.
-    loop {
-        match gc.next_boundary(chunk, chunk_byte_idx) {
-            Ok(None) => return slice.len_chars(),
-            Ok(Some(n)) => {
-                let tmp = byte_to_char_idx(chunk, n - chunk_byte_idx);
-                return chunk_char_idx + tmp;
-            }
-            Err(GraphemeIncomplete::NextChunk) => {
-                chunk_byte_idx += chunk.len();
-                let (a, _, c, _) = slice.chunk_at_byte(chunk_byte_idx);
-                chunk = a;
-                chunk_char_idx = c;
-            }
-            Err(GraphemeIncomplete::PreContext(n)) => {
-                let ctx_chunk = slice.chunk_at_byte(n - 1).0;
-                gc.provide_context(ctx_chunk, n - ctx_chunk.len());
+    // Find the nth next grapheme cluster boundary.
+    let mut boundary_byte_idx = byte_idx;
+    for _ in 0..n {
+        loop {
+            match gc.next_boundary(chunk, chunk_byte_idx) {
+                Ok(None) => return slice.len_chars(),
+                Ok(Some(n)) => {
+                    boundary_byte_idx = n;
+                    break;
+                }
+                Err(GraphemeIncomplete::NextChunk) => {
+                    chunk_byte_idx += chunk.len();
+                    let (a, _, c, _) = slice.chunk_at_byte(chunk_byte_idx);
+                    chunk = a;
+                    chunk_char_idx = c;
+                }
+                Err(GraphemeIncomplete::PreContext(n)) => {
+                    let ctx_chunk = slice.chunk_at_byte(n - 1).0;
+                    gc.provide_context(ctx_chunk, n - ctx_chunk.len());
+                }
+                _ => unreachable!(),
             }
-            _ => unreachable!(),
         }
     }
+
+    chunk_char_idx + byte_to_char_idx(chunk, boundary_byte_idx - chunk_byte_idx)
 }
 
 /// Returns whether the given char position is a grapheme boundary.
@@ -164,7 +170,7 @@ impl<'a> RopeGraphemes<'a> {
         let first_chunk = chunks.next().unwrap_or("");
         RopeGraphemes {
             text: *slice,
-            chunks: chunks,
+            chunks,
             cur_chunk: first_chunk,
             cur_chunk_start: 0,
             cursor: GraphemeCursor::new(0, slice.len_bytes(), true),
@@ -194,6 +200,10 @@ impl<'a> Iterator for RopeGraphemes<'a> {
                     self.cur_chunk_start += self.cur_chunk.len();
                     self.cur_chunk = self.chunks.next().unwrap_or("");
                 }
+                Err(GraphemeIncomplete::PreContext(n)) => {
+                    let (ctx_chunk, ctx_byte_start, _, _) = self.text.chunk_at_byte(n - 1);
+                    self.cursor.provide_context(ctx_chunk, ctx_byte_start);
+                }
                 _ => unreachable!(),
             }
         }
@@ -210,3 +220,41 @@ impl<'a> Iterator for RopeGraphemes<'a> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ropey::Rope;
+
+    #[test]
+    fn nth_grapheme_boundaries_across_chunks() {
+        // Enough text for many rope chunks, with graphemes of several chars
+        // that end up straddling chunk boundaries.
+        let text = "a\r\ne\u{301}\u{302}x\u{1F469}\u{200D}\u{1F52C}".repeat(500);
+        let rope = Rope::from_str(&text);
+        let slice = rope.slice(..);
+        assert!(rope.chunks().count() > 1);
+
+        let mut boundaries = vec![0];
+        for g in RopeGraphemes::new(&slice) {
+            boundaries.push(boundaries.last().unwrap() + g.len_chars());
+        }
+
+        for (i, &b) in boundaries.iter().enumerate() {
+            for n in 0..4 {
+                let next = boundaries[(i + n).min(boundaries.len() - 1)];
+                let prev = boundaries[i.saturating_sub(n)];
+                assert_eq!(nth_next_grapheme_boundary(&slice, b, n), next);
+                assert_eq!(nth_prev_grapheme_boundary(&slice, b, n), prev);
+            }
+        }
+        assert_eq!(
+            nth_next_grapheme_boundary(&slice, 0, 100_000),
+            rope.len_chars()
+        );
+        assert_eq!(
+            nth_prev_grapheme_boundary(&slice, rope.len_chars(), 100_000),
+            0
+        );
+    }
+}
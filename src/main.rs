@@ -1,15 +1,20 @@
-use std::{fs::File, io::BufReader};
+use std::{path::Path, time::Duration};
 
-use backend::buffer::{Buffer, BufferPath};
-use clap::{App, Arg};
+use backend::{
+    buffer::BufferPath,
+    project::{ProjectIndex, ProjectSet},
+};
+use clap::{value_t, App, Arg, ArgMatches};
 use editor::Editor;
 use formatter::LineFormatter;
-use ropey::Rope;
-use term_ui::TermUI;
+use term_ui::{layout::LayoutConfig, TermUI};
 
 mod editor;
 mod formatter;
+mod fuzzy;
 mod graphemes;
+mod project_search;
+mod search;
 mod string_utils;
 mod term_ui;
 mod utils;
@@ -23,33 +28,154 @@ fn main() -> std::io::Result<()> {
         .about("A text editor")
         .arg(
             Arg::with_name("file")
-                .help("Text file to open")
+                .help("Text files to open, or directories to add to the project")
                 .required(false)
+                .multiple(true)
                 .index(1),
         )
+        .arg(
+            Arg::with_name("project")
+                .long("project")
+                .value_name("FILE")
+                .help("Project file listing the files and directories of the project"),
+        )
+        .arg(
+            Arg::with_name("views")
+                .long("views")
+                .value_name("N")
+                .help("Number of views to show, cycling through the files"),
+        )
+        .arg(
+            Arg::with_name("min_view_width")
+                .long("min-view-width")
+                .value_name("COLUMNS")
+                .help("Narrowest a view is made before stacking views instead"),
+        )
+        .arg(
+            Arg::with_name("min_view_height")
+                .long("min-view-height")
+                .value_name("ROWS")
+                .help("Shortest a view is made before placing views side by side instead"),
+        )
+        .arg(
+            Arg::with_name("view_aspect")
+                .long("view-aspect")
+                .value_name("RATIO")
+                .help("Preferred width-to-height ratio of views, in terminal cells"),
+        )
+        .arg(
+            Arg::with_name("auto_save_delay")
+                .long("auto-save-delay")
+                .value_name("SECONDS")
+                .help("Idle time after which modified files are saved automatically [default: 1]"),
+        )
+        .arg(
+            Arg::with_name("no_auto_save")
+                .long("no-auto-save")
+                .conflicts_with("auto_save_delay")
+                .help("Only save files when asked to"),
+        )
+        .arg(
+            Arg::with_name("normalize_line_endings")
+                .long("normalize-line-endings")
+                .help("Convert mixed line endings to the file's line ending when saving"),
+        )
         .get_matches();
 
-    // Load file, if specified
-    let buffer = if let Some(filepath) = args.value_of("file") {
-        Buffer::new(
-            Rope::from_reader(BufReader::new(File::open(filepath)?))?,
-            BufferPath::File(filepath.into()),
-        )
+    let default_layout = LayoutConfig::default();
+    let layout_config = LayoutConfig {
+        min_view_width: arg_value(&args, "min_view_width", default_layout.min_view_width),
+        min_view_height: arg_value(&args, "min_view_height", default_layout.min_view_height),
+        preferred_aspect: arg_value(&args, "view_aspect", default_layout.preferred_aspect),
+    };
+    let view_count = arg_value(&args, "views", 1usize).max(1);
+    let auto_save_delay = if args.is_present("no_auto_save") {
+        None
+    } else {
+        let seconds = arg_value(&args, "auto_save_delay", 1.0f64).max(0.0);
+        Some(Duration::from_secs_f64(seconds))
+    };
+
+    // Load files, if specified, and add directories to the project.
+    // Without any, the project is the current directory.
+    let mut project = match args.value_of("project") {
+        Some(path) => ProjectSet::load(Path::new(path))?,
+        None => ProjectSet::new(),
+    };
+    let mut buffers = backend::editor::Editor::new();
+    if let Some(filepaths) = args.values_of("file") {
+        for filepath in filepaths {
+            let path = Path::new(filepath);
+            if path.is_dir() {
+                project.add(path)?;
+            } else {
+                buffers.open_file(path)?;
+            }
+        }
+    }
+    if project.is_empty() {
+        project.add(&std::env::current_dir()?)?;
+    }
+
+    // Restore unsaved edits from the previous session.
+    let session_message = match backend::session::restore_session(&mut buffers) {
+        Ok(ref changed) if changed.is_empty() => None,
+        Ok(changed) => Some(format!(
+            "Changed on disk since last session, unsaved edits restored to temp buffers: {}",
+            changed
+                .iter()
+                .map(|p| p.to_string_lossy())
+                .collect::<Vec<_>>()
+                .join(", ")
+        )),
+        Err(e) => Some(format!("Couldn't restore unsaved edits: {}", e)),
+    };
+
+    // Warn about files that were opened read-only for looking binary.
+    let binary_files: Vec<String> = buffers
+        .iter()
+        .filter(|b| b.read_only)
+        .map(|b| match b.path {
+            BufferPath::File(ref p) => p.to_string_lossy().into_owned(),
+            BufferPath::Temp(_) => String::new(),
+        })
+        .collect();
+    let binary_message = if binary_files.is_empty() {
+        None
     } else {
-        Buffer::new("".into(), BufferPath::Temp(0))
+        Some(format!(
+            "Looks binary, so opened read-only (Alt-R to allow editing): {}",
+            binary_files.join(", ")
+        ))
+    };
+    let message = match (session_message, binary_message) {
+        (Some(a), Some(b)) => Some(format!("{}  {}", a, b)),
+        (a, b) => a.or(b),
     };
 
-    let editor = Editor::new(buffer, LineFormatter::new(4));
+    let mut editor = Editor::new(buffers, LineFormatter::new(4));
+    for i in 1..view_count {
+        editor.add_view();
+        editor.switch_to_buffer(i % editor.buffers.len());
+    }
+    editor.focus = 0;
+    editor.normalize_line_endings = args.is_present("normalize_line_endings");
+    editor.project = ProjectIndex::new(project);
 
     // Holds stderr output in an internal buffer, and prints it when dropped.
     // This keeps stderr from being swallowed by the TUI.
     let stderr_hold = gag::Hold::stderr().unwrap();
 
-    // Initialize and start UI.
-    let exec_result = std::panic::catch_unwind(|| {
-        let mut ui = TermUI::new_from_editor(editor);
+    // Initialize and start UI.  Nothing is used after a panic except to
+    // clean up the terminal, so unwind safety isn't a concern.
+    let exec_result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        let mut ui = TermUI::new_from_editor(editor, layout_config);
+        ui.set_auto_save_delay(auto_save_delay);
+        if let Some(message) = message {
+            ui.show_message(message);
+        }
         ui.main_ui_loop();
-    });
+    }));
 
     // If we panicked, ensure that we've exited from raw mode and the alternate
     // screen before printing the error and resuming the panic.
@@ -67,3 +193,13 @@ fn main() -> std::io::Result<()> {
 
     Ok(())
 }
+
+/// Returns the parsed value of the given argument, or `default` if it
+/// wasn't specified.  Exits with an error message if it can't be parsed.
+fn arg_value<T: std::str::FromStr>(args: &ArgMatches, name: &str, default: T) -> T {
+    if args.is_present(name) {
+        value_t!(args, name, T).unwrap_or_else(|e| e.exit())
+    } else {
+        default
+    }
+}
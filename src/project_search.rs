@@ -0,0 +1,468 @@
+//! Searching and replacing across all the files of a project.
+//!
+//! Searches run on a worker thread, and their matches are streamed back
+//! to be shown as they're found.  Open buffers are searched instead of
+//! their files, so that unsaved edits are taken into account.
+
+use std::{
+    collections::HashSet,
+    fs, io,
+    ops::Range,
+    path::{Path, PathBuf},
+    sync::mpsc::{channel, Receiver, Sender, TryRecvError},
+    thread,
+};
+
+use backend::{
+    buffer::{BufferPath, LoadedFile},
+    editor::Editor,
+    project::ProjectSet,
+    transaction::Transaction,
+};
+use ropey::Rope;
+
+use crate::{
+    search::SearchPattern,
+    string_utils::{char_to_line_ending, LineEnding},
+};
+
+/// A search stops after finding this many matches.
+pub const MAX_MATCHES: usize = 10000;
+
+/// How much of a line is kept with each match, for showing it.
+const MAX_LINE_CHARS: usize = 500;
+
+/// A match found by a project search.
+#[derive(Debug, Clone)]
+pub struct ProjectMatch {
+    pub path: PathBuf,
+    pub range: Range<usize>, // Char range of the match in the file.
+    pub line_idx: usize,
+
+    // The start of the line the match is on, without its line ending, and
+    // the char range of the match within it.
+    pub line: String,
+    pub line_range: Range<usize>,
+}
+
+/// A project search running in the background.
+pub struct ProjectSearch {
+    pub pattern: SearchPattern,
+
+    // The matches found so far, in the order they were found, and how many
+    // files have been searched.
+    pub matches: Vec<ProjectMatch>,
+    pub files_searched: usize,
+
+    // Whether the search is done, either because all files were searched
+    // or because it found `MAX_MATCHES` matches.
+    pub is_done: bool,
+
+    messages: Receiver<SearchMessage>,
+}
+
+enum SearchMessage {
+    Match(ProjectMatch),
+    FileSearched,
+    Finished,
+}
+
+impl ProjectSearch {
+    /// Starts searching the open file buffers in `buffers`, and the other
+    /// files of `project`, for `pattern`.
+    ///
+    /// Files that look binary, or that can't be read, are skipped.  The
+    /// worker thread stops early if the `ProjectSearch` is dropped.
+    pub fn start(project: &ProjectSet, buffers: &Editor, pattern: SearchPattern) -> ProjectSearch {
+        let open: Vec<(PathBuf, Rope)> = buffers
+            .iter()
+            .filter_map(|buffer| match buffer.path {
+                BufferPath::File(ref path) => Some((path.clone(), buffer.text.clone())),
+                BufferPath::Temp(_) => None,
+            })
+            .collect();
+
+        let (send, recv) = channel();
+        let project = project.clone();
+        let worker_pattern = pattern.clone();
+        thread::spawn(move || {
+            search_files(&project, open, &worker_pattern, &send).ok();
+            send.send(SearchMessage::Finished).ok();
+        });
+
+        ProjectSearch {
+            pattern,
+            matches: Vec::new(),
+            files_searched: 0,
+            is_done: false,
+            messages: recv,
+        }
+    }
+
+    /// Adds any matches found since last polled to `matches`.  Doesn't
+    /// block.  Returns whether anything changed.
+    pub fn poll(&mut self) -> bool {
+        let mut changed = false;
+        loop {
+            match self.messages.try_recv() {
+                Ok(SearchMessage::Match(m)) => self.matches.push(m),
+                Ok(SearchMessage::FileSearched) => self.files_searched += 1,
+                Ok(SearchMessage::Finished) | Err(TryRecvError::Disconnected) => {
+                    changed |= !self.is_done;
+                    self.is_done = true;
+                    break;
+                }
+                Err(TryRecvError::Empty) => break,
+            }
+            changed = true;
+        }
+        changed
+    }
+
+    /// Returns the paths of the files with matches, in the order they were
+    /// found.
+    pub fn matched_files(&self) -> Vec<PathBuf> {
+        let mut files: Vec<PathBuf> = Vec::new();
+        for m in self.matches.iter() {
+            if files.last() != Some(&m.path) {
+                files.push(m.path.clone());
+            }
+        }
+        files
+    }
+}
+
+/// The replacements to make in one file, worked out ahead of time so that
+/// they can be previewed.
+#[derive(Debug, Clone)]
+pub struct FileReplacement {
+    pub path: PathBuf,
+
+    // The char range, old text, and new text of each replacement, in order.
+    pub edits: Vec<(Range<usize>, String, String)>,
+
+    // The index of each changed line, with its text before and after the
+    // replacements.  Lines joined by a replacement are shown as one.
+    pub lines: Vec<(usize, String, String)>,
+}
+
+/// Works out the replacements of `pattern` with `replacement` to make in
+/// each of the files at `paths`, taking the text of open buffers from
+/// `buffers`.  Files without matches, binary files, and files that can't
+/// be read are left out.
+///
+/// See `SearchPattern::for_each_replacement()` for how `replacement` is
+/// expanded.
+pub fn plan_replacements(
+    buffers: &Editor,
+    paths: &[PathBuf],
+    pattern: &SearchPattern,
+    replacement: &str,
+) -> Vec<FileReplacement> {
+    let mut plans = Vec::new();
+    for path in paths {
+        let text = match buffers.get(&BufferPath::File(path.clone())) {
+            Some(buffer) if buffer.is_editable() => buffer.text.clone(),
+            Some(_) => continue,
+            None => match LoadedFile::load(path) {
+                Ok(file) if !file.is_binary => file.text,
+                _ => continue,
+            },
+        };
+
+        let mut edits = Vec::new();
+        pattern.for_each_replacement(&text.slice(..), replacement, |range, new| {
+            edits.push((range.clone(), text.slice(range).into(), new));
+            true
+        });
+        if !edits.is_empty() {
+            let lines = changed_lines(&text, &edits);
+            plans.push(FileReplacement {
+                path: path.clone(),
+                edits,
+                lines,
+            });
+        }
+    }
+    plans
+}
+
+/// Makes the planned replacements, each file's as a single undo step, and
+/// returns the files that couldn't be changed, with why.
+///
+/// Open buffers are edited, but not saved.  Other files are opened, saved,
+/// and closed again, unless the save fails, in which case they're left open
+/// with the replacements made.  A file is left alone if its text no longer
+/// matches the plan.
+pub fn apply_replacements(
+    buffers: &mut Editor,
+    plans: &[FileReplacement],
+) -> Vec<(PathBuf, io::Error)> {
+    let mut errors = Vec::new();
+    let mut opened = Vec::new();
+    for plan in plans {
+        let was_open = buffers.find(&BufferPath::File(plan.path.clone())).is_some();
+        let result = buffers.open_file(&plan.path).and_then(|idx| {
+            if !was_open {
+                opened.push(idx);
+                buffers.wait_for_load(idx)?;
+            }
+            apply_replacement(buffers, idx, plan)?;
+            if !was_open {
+                buffers.save_buffer(idx);
+            }
+            Ok(())
+        });
+        if let Err(e) = result {
+            errors.push((plan.path.clone(), e));
+        }
+    }
+
+    // Only the saves started here are waited for, so that the results of
+    // any others still reach `Editor::finish_saves()`.
+    for idx in opened.into_iter().rev() {
+        match buffers.wait_for_save(idx).map(|r| (r.path, r.result)) {
+            Some((path, Err(e))) => {
+                // Closing the buffer would lose the replacements.
+                let e = io::Error::new(
+                    e.kind(),
+                    format!(
+                        "{} (the replacements are left unsaved in an open buffer)",
+                        e
+                    ),
+                );
+                errors.push((path, e));
+            }
+            _ => {
+                buffers.close_buffer(idx);
+            }
+        }
+    }
+
+    errors
+}
+
+//----------------------------------------------------------------------
+
+/// Searches each of the `open` buffer texts, and then the rest of the
+/// project's files, sending what's found.  Fails once the receiving
+/// `ProjectSearch` has been dropped.
+fn search_files(
+    project: &ProjectSet,
+    open: Vec<(PathBuf, Rope)>,
+    pattern: &SearchPattern,
+    send: &Sender<SearchMessage>,
+) -> Result<(), ()> {
+    let mut match_count = 0;
+    let mut open_files = HashSet::new();
+    for (path, text) in open.iter() {
+        open_files.insert(fs::canonicalize(path).unwrap_or_else(|_| path.clone()));
+        search_text(path, text, pattern, &mut match_count, send)?;
+    }
+
+    for path in project.list_files() {
+        if match_count >= MAX_MATCHES {
+            break;
+        }
+        let canonical = fs::canonicalize(&path).unwrap_or_else(|_| path.clone());
+        if open_files.contains(&canonical) {
+            continue;
+        }
+        match LoadedFile::load(&path) {
+            Ok(file) if !file.is_binary => {
+                search_text(&path, &file.text, pattern, &mut match_count, send)?;
+            }
+            _ => {}
+        }
+    }
+
+    Ok(())
+}
+
+/// Sends the matches of `pattern` in `text`, the contents of the file at
+/// `path`, stopping once `match_count` reaches `MAX_MATCHES`.
+fn search_text(
+    path: &Path,
+    text: &Rope,
+    pattern: &SearchPattern,
+    match_count: &mut usize,
+    send: &Sender<SearchMessage>,
+) -> Result<(), ()> {
+    let mut result = Ok(());
+    pattern.for_each_replacement(&text.slice(..), "", |range, _| {
+        let line_idx = text.char_to_line(range.start);
+        let line_start = text.line_to_char(line_idx);
+        let line: String = text
+            .line(line_idx)
+            .chars()
+            .take_while(|&c| char_to_line_ending(c) == LineEnding::None)
+            .take(MAX_LINE_CHARS)
+            .collect();
+        let m = ProjectMatch {
+            path: path.into(),
+            range: range.clone(),
+            line_idx,
+            line,
+            line_range: (range.start - line_start)..(range.end - line_start),
+        };
+        result = send.send(SearchMessage::Match(m)).map_err(|_| ());
+        *match_count += 1;
+        result.is_ok() && *match_count < MAX_MATCHES
+    });
+    result?;
+    send.send(SearchMessage::FileSearched).map_err(|_| ())
+}
+
+/// Makes the planned replacements in the buffer at `buf_idx`, as a single
+/// undo step.
+fn apply_replacement(
+    buffers: &mut Editor,
+    buf_idx: usize,
+    plan: &FileReplacement,
+) -> io::Result<()> {
+    let buffer = &mut buffers[buf_idx];
+    if !buffer.is_editable() {
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            "the file is read-only or still loading",
+        ));
+    }
+    let unchanged = plan.edits.iter().all(|(range, old, _)| {
+        range.end <= buffer.text.len_chars() && buffer.text.slice(range.clone()) == old.as_str()
+    });
+    if !unchanged {
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            "the file changed since the replacements were previewed",
+        ));
+    }
+
+    let text = &buffer.text;
+    let trans = Transaction::from_ordered_edit_set(
+        plan.edits
+            .iter()
+            .map(|(range, old, new)| (text.char_to_byte(range.start), &old[..], &new[..])),
+    );
+    buffer.break_undo_group();
+    buffer.apply_transaction(trans);
+    buffer.break_undo_group();
+    Ok(())
+}
+
+/// Returns the lines of `text` changed by `edits`, with their text before
+/// and after the edits.  See `FileReplacement::lines`.
+fn changed_lines(
+    text: &Rope,
+    edits: &[(Range<usize>, String, String)],
+) -> Vec<(usize, String, String)> {
+    let mut lines = Vec::new();
+    let mut i = 0;
+    while i < edits.len() {
+        // Gather the edits touching the same lines.
+        let first_line = text.char_to_line(edits[i].0.start);
+        let mut last_line = text.char_to_line(edits[i].0.end);
+        let mut j = i + 1;
+        while j < edits.len() && text.char_to_line(edits[j].0.start) <= last_line {
+            last_line = last_line.max(text.char_to_line(edits[j].0.end));
+            j += 1;
+        }
+
+        let start = text.line_to_char(first_line);
+        let end = text.line_to_char(last_line) + text.line(last_line).len_chars();
+        let mut old = String::new();
+        let mut new = String::new();
+        let mut pos = start;
+        for (range, _, replacement) in edits[i..j].iter() {
+            let before: String = text.slice(pos..range.start).into();
+            old.push_str(&before);
+            new.push_str(&before);
+            old.push_str(&String::from(text.slice(range.clone())));
+            new.push_str(replacement);
+            pos = range.end;
+        }
+        let after: String = text.slice(pos..end).into();
+        old.push_str(&after);
+        new.push_str(&after);
+
+        lines.push((first_line, single_line(&old), single_line(&new)));
+        i = j;
+    }
+    lines
+}
+
+/// Joins the lines of `text` into one for showing, marking where its line
+/// endings were.
+fn single_line(text: &str) -> String {
+    let text = text.trim_end_matches(|c| char_to_line_ending(c) != LineEnding::None);
+    text.chars()
+        .map(|c| {
+            if char_to_line_ending(c) != LineEnding::None {
+                '⏎'
+            } else {
+                c
+            }
+        })
+        .collect()
+}
+
+//----------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use regex::Regex;
+
+    #[test]
+    fn replace_in_project() {
+        let dir =
+            std::env::temp_dir().join(format!("led_test_project_replace_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let (a, b) = (dir.join("a.txt"), dir.join("b.txt"));
+        fs::write(&a, "foo1\nbar\n").unwrap();
+        fs::write(&b, "foo2 foo3\n").unwrap();
+
+        // `b.txt` is open, with unsaved edits.
+        let mut buffers = Editor::new();
+        let b_idx = buffers.open_file(&b).unwrap();
+        buffers[b_idx].edit((0, 0), "foo4\n");
+
+        // `c.txt` is open without matches, and being saved.
+        let c = dir.join("c.txt");
+        fs::write(&c, "bar\n").unwrap();
+        let c_idx = buffers.open_file(&c).unwrap();
+        buffers[c_idx].edit((0, 0), "baz\n");
+        buffers.save_buffer(c_idx);
+
+        let project = ProjectSet {
+            directories: vec![dir.clone()],
+            files: Vec::new(),
+        };
+        let pattern = SearchPattern::Regex(Regex::new(r"foo(\d)").unwrap());
+        let mut search = ProjectSearch::start(&project, &buffers, pattern.clone());
+        while !search.is_done {
+            search.poll();
+        }
+        assert_eq!(search.matches.len(), 4);
+        assert_eq!(search.matches[2].line, "foo2 foo3");
+        assert_eq!(search.matches[2].line_range, 5..9);
+        assert_eq!(search.matched_files(), vec![b.clone(), a.clone()]);
+
+        let plans = plan_replacements(&buffers, &search.matched_files(), &pattern, "x$1");
+        assert_eq!(plans[0].lines[1], (1, "foo2 foo3".into(), "x2 x3".into()));
+
+        let errors = apply_replacements(&mut buffers, &plans);
+        assert!(errors.is_empty());
+        assert_eq!(buffers.len(), 2);
+        assert_eq!(buffers[b_idx].text, "x4\nx2 x3\n");
+        assert_eq!(fs::read_to_string(&b).unwrap(), "foo2 foo3\n");
+        assert_eq!(fs::read_to_string(&a).unwrap(), "x1\nbar\n");
+
+        // The save of `c.txt` is still reported the usual way.
+        let results = buffers.wait_for_saves();
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].path, c);
+        assert!(results[0].result.is_ok());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
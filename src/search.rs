@@ -0,0 +1,502 @@
+//! Searching text in a `Rope` without copying it into a `String`.
+
+use std::ops::Range;
+
+use regex::{Captures, Regex};
+use regex_syntax::hir::{Class, Hir, HirKind, Literal};
+use ropey::{Rope, RopeSlice};
+
+/// How many bytes of text are matched against a regex at once, at least.
+const REGEX_WINDOW_SIZE: usize = 1 << 16;
+
+/// How many bytes of a single line are matched against a regex at once, at
+/// most.  Longer lines are split up, which can miss matches at the split.
+const MAX_REGEX_WINDOW_SIZE: usize = 1 << 20;
+
+/// What to search for.
+#[derive(Debug, Clone)]
+pub enum SearchPattern {
+    Text(String),
+    Regex(Regex),
+}
+
+impl SearchPattern {
+    /// Returns the char ranges of all non-overlapping matches in `text`,
+    /// in order.
+    pub fn find_all(&self, text: &RopeSlice) -> Vec<Range<usize>> {
+        match *self {
+            SearchPattern::Text(ref pattern) => find_all(text, pattern),
+            SearchPattern::Regex(ref regex) => {
+                let mut matches = Vec::new();
+                for_each_regex_match(text, regex, |range, _| {
+                    matches.push(range);
+                    true
+                });
+                matches
+            }
+        }
+    }
+
+    /// Calls `f` with the char range of each non-overlapping match in
+    /// `text` that starts at or after char index `from`, in order, until
+    /// `f` returns false.
+    ///
+    /// For regexes, the text before `from` still counts for anchors like
+    /// `^` and `\b`.
+    pub fn for_each_match_from<F>(&self, text: &RopeSlice, from: usize, mut f: F)
+    where
+        F: FnMut(Range<usize>) -> bool,
+    {
+        match *self {
+            SearchPattern::Text(ref pattern) => {
+                for_each_match(&text.slice(from..), pattern, |range| {
+                    f((range.start + from)..(range.end + from))
+                })
+            }
+            SearchPattern::Regex(ref regex) => {
+                for_each_regex_match_from(text, regex, from, |range, _| f(range))
+            }
+        }
+    }
+
+    /// Calls `f` with the char range of each non-overlapping match in
+    /// `text`, in order, and what `replacement` expands to for it, until
+    /// `f` returns false.
+    ///
+    /// For regexes, `$1`, `${1}`, and `$name` in `replacement` are replaced
+    /// with the text of the corresponding capture group, and `$$` with a
+    /// `$`.  Text patterns are replaced with `replacement` as-is.
+    pub fn for_each_replacement<F>(&self, text: &RopeSlice, replacement: &str, mut f: F)
+    where
+        F: FnMut(Range<usize>, String) -> bool,
+    {
+        match *self {
+            SearchPattern::Text(ref pattern) => {
+                for_each_match(text, pattern, |range| f(range, replacement.into()))
+            }
+            SearchPattern::Regex(ref regex) => {
+                for_each_regex_match(text, regex, |range, captures| {
+                    let mut expanded = String::new();
+                    captures.expand(replacement, &mut expanded);
+                    f(range, expanded)
+                })
+            }
+        }
+    }
+
+    /// Returns what `replacement` expands to for the match at the char
+    /// range `range` of `text`, or `None` if that isn't a match.
+    pub fn replacement_at(
+        &self,
+        text: &Rope,
+        range: Range<usize>,
+        replacement: &str,
+    ) -> Option<String> {
+        let regex = match *self {
+            SearchPattern::Text(ref pattern) => {
+                if text.slice(range) != pattern.as_str() {
+                    return None;
+                }
+                return Some(replacement.into());
+            }
+            SearchPattern::Regex(ref regex) => regex,
+        };
+
+        // Regexes can depend on the text around a match, so match from the
+        // start of its line, with the text before it as context.
+        let line_start = text.line_to_char(text.char_to_line(range.start));
+        let mut expanded = None;
+        for_each_regex_match_from(&text.slice(..), regex, line_start, |found, captures| {
+            if found == range {
+                let mut new = String::new();
+                captures.expand(replacement, &mut new);
+                expanded = Some(new);
+            }
+            found.start < range.start
+        });
+        expanded
+    }
+
+    /// Returns how many bytes before the start of a line a match that
+    /// reaches into that line can start, at most.  That's zero if matches
+    /// can't contain line breaks, and `None` if there's no limit.
+    pub fn reach_across_lines(&self) -> Option<usize> {
+        match *self {
+            SearchPattern::Text(ref pattern) => {
+                if pattern.chars().any(is_line_break) {
+                    Some(pattern.len())
+                } else {
+                    Some(0)
+                }
+            }
+            SearchPattern::Regex(ref regex) => {
+                // If the regex can't be analyzed, assume the worst.
+                let hir = regex_syntax::parse(regex.as_str()).ok()?;
+                if hir_has_line_break(&hir) {
+                    hir.properties().maximum_len()
+                } else {
+                    Some(0)
+                }
+            }
+        }
+    }
+}
+
+/// Calls `f` with the char range of each non-overlapping occurance of
+/// `pattern` in `text`, in order, until it returns false.
+///
+/// The text is searched a chunk at a time.  Only the end of the previous
+/// chunk that a match could still start in is carried over, so matches
+/// spanning chunk boundaries are found without flattening the text.
+pub fn for_each_match<F>(text: &RopeSlice, pattern: &str, mut f: F)
+where
+    F: FnMut(Range<usize>) -> bool,
+{
+    if pattern.is_empty() {
+        return;
+    }
+
+    let mut window = String::new();
+    let mut window_byte_idx = 0; // Where `window` starts in the text.
+    for chunk in text.chunks() {
+        window.push_str(chunk);
+
+        let mut searched = 0;
+        while let Some(i) = window[searched..].find(pattern) {
+            let start = window_byte_idx + searched + i;
+            let end = start + pattern.len();
+            if !f(text.byte_to_char(start)..text.byte_to_char(end)) {
+                return;
+            }
+            searched += i + pattern.len();
+        }
+
+        // Keep just the part of the window that a match could still start
+        // in, which is less than the pattern's length.
+        let mut keep_from = searched.max(window.len().saturating_sub(pattern.len() - 1));
+        while !window.is_char_boundary(keep_from) {
+            keep_from += 1;
+        }
+        window.drain(..keep_from);
+        window_byte_idx += keep_from;
+    }
+}
+
+/// Calls `f` with the char range and capture groups of each match of
+/// `regex` in `text`, in order, until it returns false.
+///
+/// The text is matched a window of whole lines at a time, built up from its
+/// chunks, so it's never flattened into one big string.  Each window
+/// overlaps the next by a line, so matches spanning two lines are always
+/// found.  Matches that contain a whole line and then some can be missed or
+/// cut short at the edge of a window, and so can matches around the splits
+/// in lines longer than `MAX_REGEX_WINDOW_SIZE` bytes.
+pub fn for_each_regex_match<F>(text: &RopeSlice, regex: &Regex, f: F)
+where
+    F: FnMut(Range<usize>, &Captures) -> bool,
+{
+    for_each_regex_match_from(text, regex, 0, f);
+}
+
+/// Like `for_each_regex_match()`, but only for matches starting at or
+/// after char index `from`.  The text before `from` still counts for
+/// anchors like `^` and `\b`.
+fn for_each_regex_match_from<F>(text: &RopeSlice, regex: &Regex, from: usize, mut f: F)
+where
+    F: FnMut(Range<usize>, &Captures) -> bool,
+{
+    // Each window starts with the char before the text to search in it,
+    // if any.  Regex anchors only ever look at one char on either side,
+    // so that's enough for e.g. `(?m)^` and `\b` to work at the start of
+    // the window, and keeps `\A` and plain `^` from matching there.
+    let context_start = from.saturating_sub(1);
+    let mut window = String::new();
+    // Where `window` starts in the text, where in `window` to start
+    // searching, and where in `window` the last match ended.
+    let mut window_byte_idx = text.char_to_byte(context_start);
+    let mut search_from = text.char_to_byte(from) - window_byte_idx;
+    let mut last_match_end = None;
+    let mut chunks = text.slice(context_start..).chunks();
+    loop {
+        let chunk = chunks.next();
+        let is_last = chunk.is_none();
+        if let Some(chunk) = chunk {
+            window.push_str(chunk);
+            if window.len() - search_from < REGEX_WINDOW_SIZE {
+                continue;
+            }
+        }
+
+        // Only report matches that start before the window's last line,
+        // so that the next window starts at the start of a line and
+        // matches running from the last line into the next one are found
+        // there in one piece.  The last line is still part of the text
+        // searched, so matches starting earlier can run into it.  A window
+        // needs to get to at least two lines for that, unless that makes it
+        // too big, in which case the end of its only line or the window's
+        // end will have to do.  Chunks end on char boundaries, so a window
+        // can be cut off at its end.
+        let report_end = if is_last {
+            window.len()
+        } else {
+            let searchable = &window[search_from..];
+            match searchable.rfind('\n') {
+                Some(i) => match searchable[..i].rfind('\n') {
+                    Some(j) => search_from + j + 1,
+                    None if window.len() >= MAX_REGEX_WINDOW_SIZE => search_from + i + 1,
+                    None => continue,
+                },
+                None if window.len() >= MAX_REGEX_WINDOW_SIZE => window.len(),
+                None => continue,
+            }
+        };
+
+        // Where the next window needs to start searching from.
+        let mut next_from = report_end;
+
+        let mut pos = search_from;
+        while pos <= report_end {
+            let captures = match regex.captures_at(&window, pos) {
+                Some(captures) => captures,
+                None => break,
+            };
+            let m = captures.get(0).unwrap();
+            if !is_last && m.start() >= report_end {
+                break;
+            }
+
+            // Like `Regex::captures_iter()`, skip empty matches right
+            // after the previous match.
+            if m.start() == m.end() && Some(m.start()) == last_match_end {
+                pos = m.start()
+                    + window[m.start()..]
+                        .chars()
+                        .next()
+                        .map_or(1, |c| c.len_utf8());
+                continue;
+            }
+
+            // Matches that run up to the end of the window may be cut
+            // short, or only match because the window ends there (e.g.
+            // with `$` or `\z`), so they're left for the next window.
+            // Unless they're too long to ever fit in one.
+            if !is_last
+                && m.end() == window.len()
+                && m.end() - m.start() < MAX_REGEX_WINDOW_SIZE / 2
+            {
+                next_from = m.start();
+                break;
+            }
+
+            let start = window_byte_idx + m.start();
+            let range = text.byte_to_char(start)..text.byte_to_char(start + m.len());
+            if !f(range, &captures) {
+                return;
+            }
+            last_match_end = Some(m.end());
+            next_from = next_from.max(m.end());
+            pos = m.end();
+        }
+
+        if is_last {
+            break;
+        }
+
+        // Drop what's been searched, except for the context char.
+        let mut keep_from = next_from.saturating_sub(1);
+        while !window.is_char_boundary(keep_from) {
+            keep_from -= 1;
+        }
+        window.drain(..keep_from);
+        window_byte_idx += keep_from;
+        search_from = next_from - keep_from;
+        last_match_end = last_match_end
+            .filter(|&i| i >= keep_from)
+            .map(|i| i - keep_from);
+    }
+}
+
+/// Returns whether a regex could match text with a line break in it.
+fn hir_has_line_break(hir: &Hir) -> bool {
+    match *hir.kind() {
+        HirKind::Empty | HirKind::Look(_) => false,
+        HirKind::Literal(Literal(ref bytes)) => {
+            String::from_utf8_lossy(bytes).chars().any(is_line_break)
+        }
+        HirKind::Class(Class::Unicode(ref class)) => class.ranges().iter().any(|range| {
+            LINE_BREAKS
+                .iter()
+                .any(|&c| range.start() <= c && c <= range.end())
+        }),
+        // Byte classes in regexes for text are ASCII-only, so just check
+        // the ASCII line breaks, which are contiguous.
+        HirKind::Class(Class::Bytes(ref class)) => class
+            .ranges()
+            .iter()
+            .any(|range| range.start() <= b'\r' && range.end() >= b'\n'),
+        HirKind::Repetition(ref repetition) => hir_has_line_break(&repetition.sub),
+        HirKind::Capture(ref capture) => hir_has_line_break(&capture.sub),
+        HirKind::Concat(ref hirs) | HirKind::Alternation(ref hirs) => {
+            hirs.iter().any(hir_has_line_break)
+        }
+    }
+}
+
+/// The chars that Ropey breaks lines at.
+const LINE_BREAKS: [char; 7] = [
+    '\u{000A}', '\u{000B}', '\u{000C}', '\u{000D}', '\u{0085}', '\u{2028}', '\u{2029}',
+];
+
+fn is_line_break(c: char) -> bool {
+    LINE_BREAKS.contains(&c)
+}
+
+/// Returns the char ranges of all non-overlapping occurances of `pattern`
+/// in `text`, in order.
+pub fn find_all(text: &RopeSlice, pattern: &str) -> Vec<Range<usize>> {
+    let mut matches = Vec::new();
+    for_each_match(text, pattern, |range| {
+        matches.push(range);
+        true
+    });
+    matches
+}
+
+//--------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ropey::Rope;
+
+    #[test]
+    fn find_all_1() {
+        let text = Rope::from_str("foo bar foo\nfoofoo");
+        let matches = find_all(&text.slice(..), "foo");
+        assert_eq!(matches, vec![0..3, 8..11, 12..15, 15..18]);
+        assert!(find_all(&text.slice(..), "").is_empty());
+        assert!(find_all(&text.slice(..), "baz").is_empty());
+    }
+
+    #[test]
+    fn find_all_across_chunks() {
+        // Big enough to be split into many chunks, with matches that
+        // straddle chunk boundaries and multi-byte chars around them.
+        let mut s = String::new();
+        for i in 0..2000 {
+            s.push_str(if i % 3 == 0 { "αβγ needle " } else { "hay " });
+        }
+        let text = Rope::from_str(&s);
+        assert!(text.chunks().count() > 1);
+
+        let matches = find_all(&text.slice(..), "needle");
+        assert_eq!(matches.len(), 667);
+        for range in matches {
+            assert_eq!(text.slice(range), "needle");
+        }
+
+        let matches = find_all(&text.slice(..), "γ ne");
+        assert_eq!(matches.len(), 667);
+    }
+
+    #[test]
+    fn regex_matches_across_windows() {
+        let mut s = String::new();
+        for i in 0..20000 {
+            s.push_str(&format!("line {}\n", i));
+        }
+        let text = Rope::from_str(&s);
+        let pattern = SearchPattern::Regex(Regex::new(r"(?m)^line (\d*7)$").unwrap());
+
+        let matches = pattern.find_all(&text.slice(..));
+        assert_eq!(matches.len(), 2000);
+        assert_eq!(text.slice(matches[1999].clone()), "line 19997");
+
+        // Empty matches at window boundaries are only found once.
+        let pattern = SearchPattern::Regex(Regex::new(r"(?m)^").unwrap());
+        assert_eq!(pattern.find_all(&text.slice(..)).len(), 20001);
+    }
+
+    #[test]
+    fn regex_matches_spanning_lines_across_windows() {
+        let mut s = String::new();
+        for i in 0..20000 {
+            s.push_str(&format!("line {}\nfoo\n", i));
+        }
+        let text = Rope::from_str(&s);
+
+        // Some of these straddle the last line break of a window.
+        let pattern = SearchPattern::Regex(Regex::new(r"foo\nline").unwrap());
+        assert_eq!(pattern.find_all(&text.slice(..)).len(), 19999);
+        let pattern = SearchPattern::Regex(Regex::new(r"(?m)\d+$\n^foo").unwrap());
+        assert_eq!(pattern.find_all(&text.slice(..)).len(), 20000);
+    }
+
+    #[test]
+    fn regex_anchors_across_windows() {
+        let text = Rope::from_str(&"line 1\n".repeat(20000));
+        let find = |pattern: &str| {
+            SearchPattern::Regex(Regex::new(pattern).unwrap()).find_all(&text.slice(..))
+        };
+
+        // Without `(?m)`, `^`, `$`, `\A` and `\z` only match at the very
+        // start and end of the text, not at the edges of each window.
+        assert!(find(r"^line 1$").is_empty());
+        assert_eq!(find(r"^line 1"), vec![0..6]);
+        assert_eq!(find(r"\Aline"), vec![0..4]);
+        assert_eq!(find(r"line 1\n$"), vec![139993..140000]);
+        assert_eq!(find(r"\n\z"), vec![139999..140000]);
+        assert_eq!(find(r"(?m)^line 1$").len(), 20000);
+        assert_eq!(find(r"\bine").len(), 0);
+    }
+
+    #[test]
+    fn regex_matches_in_long_line() {
+        // A single line much longer than a window still gets searched, in
+        // pieces.
+        let piece = "x".repeat(1023) + "y";
+        let count = MAX_REGEX_WINDOW_SIZE * 3 / piece.len();
+        let text = Rope::from_str(&piece.repeat(count));
+        let pattern = SearchPattern::Regex(Regex::new(r"y").unwrap());
+        assert_eq!(pattern.find_all(&text.slice(..)).len(), count);
+    }
+
+    #[test]
+    fn reach_across_lines() {
+        let text = |pattern: &str| SearchPattern::Text(pattern.into()).reach_across_lines();
+        let regex =
+            |pattern: &str| SearchPattern::Regex(Regex::new(pattern).unwrap()).reach_across_lines();
+        assert_eq!(text("foo"), Some(0));
+        assert_eq!(text("a\nb"), Some(3));
+        assert_eq!(regex(r"(?m)^\w+$"), Some(0));
+        assert_eq!(regex(r"a\r\nb"), Some(4));
+        assert_eq!(regex(r"[^x]"), Some(4));
+        assert_eq!(regex(r"a\s+b"), None);
+    }
+
+    #[test]
+    fn replacements() {
+        let text = Rope::from_str("a=1, b=22");
+        let pattern = SearchPattern::Regex(Regex::new(r"(\w)=(?P<n>\d+)").unwrap());
+        let mut replacements = Vec::new();
+        pattern.for_each_replacement(&text.slice(..), "$n=${1}$$", |range, new| {
+            replacements.push((range, new));
+            true
+        });
+        assert_eq!(
+            replacements,
+            vec![(0..3, "1=a$".to_string()), (5..9, "22=b$".to_string())]
+        );
+
+        let pattern = SearchPattern::Text("=".into());
+        let mut replacements = Vec::new();
+        pattern.for_each_replacement(&text.slice(..), "$1", |range, new| {
+            replacements.push((range, new));
+            true
+        });
+        assert_eq!(
+            replacements,
+            vec![(1..2, "$1".to_string()), (6..7, "$1".to_string())]
+        );
+    }
+}
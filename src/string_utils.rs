@@ -3,15 +3,15 @@
 use ropey::{str_utils::byte_to_char_idx, RopeSlice};
 
 pub fn is_line_ending(text: &str) -> bool {
-    match text.chars().nth(0) {
-        Some(c) if (c >= '\u{000A}' && c <= '\u{000D}') => true,
+    match text.chars().next() {
+        Some(c) if ('\u{000A}'..='\u{000D}').contains(&c) => true,
         Some('\u{0085}') | Some('\u{2028}') | Some('\u{2029}') => true,
         _ => false,
     }
 }
 
 pub fn str_is_whitespace(text: &str) -> bool {
-    if let Some(c) = text.chars().nth(0) {
+    if let Some(c) = text.chars().next() {
         is_whitespace(c)
     } else {
         false
@@ -37,7 +37,7 @@ pub fn is_whitespace(c: char) -> bool {
         // EN QUAD, EM QUAD, EN SPACE, EM SPACE, THREE-PER-EM SPACE,
         // FOUR-PER-EM SPACE, SIX-PER-EM SPACE, FIGURE SPACE,
         // PUNCTUATION SPACE, THIN SPACE, HAIR SPACE, ZERO WIDTH SPACE.
-        c if c >= '\u{2000}' && c <= '\u{200B}' => true,
+        c if ('\u{2000}'..='\u{200B}').contains(&c) => true,
 
         // Not a matching whitespace character.
         _ => false,
@@ -50,6 +50,7 @@ pub fn char_count(text: &str) -> usize {
 
 /// Represents one of the valid Unicode line endings.
 /// Also acts as an index into `LINE_ENDINGS`.
+#[allow(clippy::upper_case_acronyms)] // Named by their usual abbreviations.
 #[derive(PartialEq, Copy, Clone)]
 pub enum LineEnding {
     None = 0, // No line ending
@@ -94,9 +95,88 @@ pub fn line_ending_to_str(ending: LineEnding) -> &'static str {
     LINE_ENDINGS[ending as usize]
 }
 
+/// Returns the short name of a line ending, for display.
+pub fn line_ending_name(ending: LineEnding) -> &'static str {
+    match ending {
+        LineEnding::None => "None",
+        LineEnding::CRLF => "CRLF",
+        LineEnding::LF => "LF",
+        LineEnding::VT => "VT",
+        LineEnding::FF => "FF",
+        LineEnding::CR => "CR",
+        LineEnding::NEL => "NEL",
+        LineEnding::LS => "LS",
+        LineEnding::PS => "PS",
+    }
+}
+
+/// Returns the line ending that the given char is by itself, or
+/// `LineEnding::None` if it isn't one.  Note that a CR may also be the
+/// start of a CRLF.
+pub fn char_to_line_ending(c: char) -> LineEnding {
+    match c {
+        '\u{000A}' => LineEnding::LF,
+        '\u{000B}' => LineEnding::VT,
+        '\u{000C}' => LineEnding::FF,
+        '\u{000D}' => LineEnding::CR,
+        '\u{0085}' => LineEnding::NEL,
+        '\u{2028}' => LineEnding::LS,
+        '\u{2029}' => LineEnding::PS,
+        _ => LineEnding::None,
+    }
+}
+
+/// Calls `f` with the char range and kind of each line ending in `text`,
+/// in order.  A CR followed by an LF counts as a single CRLF.
+pub fn for_each_line_ending<F>(text: &RopeSlice, mut f: F)
+where
+    F: FnMut((usize, usize), LineEnding),
+{
+    let mut pending_cr = None;
+    for (i, c) in text.chars().enumerate() {
+        let ending = char_to_line_ending(c);
+        if let Some(cr_idx) = pending_cr.take() {
+            if ending == LineEnding::LF {
+                f((cr_idx, i + 1), LineEnding::CRLF);
+                continue;
+            }
+            f((cr_idx, cr_idx + 1), LineEnding::CR);
+        }
+        match ending {
+            LineEnding::None => {}
+            LineEnding::CR => pending_cr = Some(i),
+            _ => f((i, i + 1), ending),
+        }
+    }
+    if let Some(cr_idx) = pending_cr {
+        f((cr_idx, cr_idx + 1), LineEnding::CR);
+    }
+}
+
+/// Counts the line endings of each kind in `text`, indexed by
+/// `LineEnding as usize`.
+pub fn count_line_endings(text: &RopeSlice) -> [usize; 9] {
+    let mut counts = [0; 9];
+    for_each_line_ending(text, |_, ending| counts[ending as usize] += 1);
+    counts
+}
+
+/// All line endings, in the same order as `LINE_ENDINGS`.
+pub const ALL_LINE_ENDINGS: [LineEnding; 9] = [
+    LineEnding::None,
+    LineEnding::CRLF,
+    LineEnding::LF,
+    LineEnding::VT,
+    LineEnding::FF,
+    LineEnding::CR,
+    LineEnding::NEL,
+    LineEnding::LS,
+    LineEnding::PS,
+];
+
 /// An array of string literals corresponding to the possible
 /// unicode line endings.
-pub const LINE_ENDINGS: [&'static str; 9] = [
+pub const LINE_ENDINGS: [&str; 9] = [
     "",
     "\u{000D}\u{000A}",
     "\u{000A}",
@@ -124,4 +204,11 @@ mod tests {
         assert_eq!(12, char_count(text_1));
         assert_eq!(9, char_count(text_2));
     }
+
+    #[test]
+    fn count_line_endings_1() {
+        let text = ropey::Rope::from_str("a\r\nb\nc\rd\r\re\u{2028}f\u{85}\u{b}\u{c}\u{2029}\r");
+        let counts = count_line_endings(&text.slice(..));
+        assert_eq!(counts, [0, 1, 1, 1, 1, 4, 1, 1, 1]);
+    }
 }
@@ -0,0 +1,154 @@
+//! Automatic layout of views on screen.
+//!
+//! The user only chooses how many views they want, and the layout is
+//! worked out from the screen size: views are tiled in columns, each
+//! column split into rows, with the number of columns picked so that the
+//! views come out as close as possible to a preferred shape.
+
+/// Knobs for how views are automatically laid out.
+#[derive(Debug, Copy, Clone)]
+pub struct LayoutConfig {
+    /// The narrowest a view should be made, in columns.  Views are stacked
+    /// vertically rather than made narrower than this, if possible.
+    pub min_view_width: usize,
+
+    /// The shortest a view should be made, in rows.  Views are placed side
+    /// by side rather than made shorter than this, if possible.
+    pub min_view_height: usize,
+
+    /// The preferred width-to-height ratio of views, measured in terminal
+    /// cells.  Larger values favor placing views side by side, smaller
+    /// values favor stacking them.
+    pub preferred_aspect: f32,
+}
+
+impl Default for LayoutConfig {
+    fn default() -> LayoutConfig {
+        LayoutConfig {
+            min_view_width: 60,
+            min_view_height: 8,
+            preferred_aspect: 3.0,
+        }
+    }
+}
+
+/// Lays out `view_count` views in an area of the given `(height, width)`.
+///
+/// Returns one `(top_left, bottom_right)` rectangle per view, with both
+/// corners inclusive and given as `(row, column)`.  The rectangles tile the
+/// whole area, and are ordered column by column, top to bottom.
+pub fn layout(
+    view_count: usize,
+    size: (usize, usize),
+    config: &LayoutConfig,
+) -> Vec<((usize, usize), (usize, usize))> {
+    let (height, width) = size;
+    if view_count == 0 || height == 0 || width == 0 {
+        return Vec::new();
+    }
+
+    // Pick the number of columns that gives the best view shape.
+    let mut columns = 1;
+    let mut best_score = f32::INFINITY;
+    for c in 1..=view_count.min(width) {
+        let rows = view_count.div_ceil(c);
+        if rows > height {
+            continue;
+        }
+        let view_w = width / c;
+        let view_h = height / rows;
+
+        // How far off the preferred shape the views are, with breaking the
+        // minimum sizes penalized enough that it only happens when every
+        // option does.
+        let aspect = view_w as f32 / view_h as f32;
+        let mut score = (aspect / config.preferred_aspect).ln().abs();
+        if view_w < config.min_view_width {
+            score += 100.0 + (config.min_view_width - view_w) as f32;
+        }
+        if view_h < config.min_view_height {
+            score += 100.0 + (config.min_view_height - view_h) as f32;
+        }
+
+        if score < best_score {
+            columns = c;
+            best_score = score;
+        }
+    }
+
+    // Distribute the views among the columns, with any extras going to the
+    // leftmost columns.
+    let mut rects = Vec::with_capacity(view_count);
+    let mut col_start = 0;
+    for (col, col_width) in split(width, columns).enumerate() {
+        let rows = view_count / columns + if col < view_count % columns { 1 } else { 0 };
+        let mut row_start = 0;
+        for row_height in split(height, rows) {
+            rects.push((
+                (row_start, col_start),
+                (row_start + row_height - 1, col_start + col_width - 1),
+            ));
+            row_start += row_height;
+        }
+        col_start += col_width;
+    }
+
+    rects
+}
+
+/// Splits `total` into `parts` nearly-equal sizes, with the remainder
+/// going to the first parts.
+fn split(total: usize, parts: usize) -> impl Iterator<Item = usize> {
+    (0..parts).map(move |i| total / parts + if i < total % parts { 1 } else { 0 })
+}
+
+//===========================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn single_view() {
+        let rects = layout(1, (40, 120), &LayoutConfig::default());
+        assert_eq!(rects, vec![((0, 0), (39, 119))]);
+    }
+
+    #[test]
+    fn wide_screen_side_by_side() {
+        let rects = layout(2, (50, 240), &LayoutConfig::default());
+        assert_eq!(rects, vec![((0, 0), (49, 119)), ((0, 120), (49, 239))]);
+    }
+
+    #[test]
+    fn narrow_screen_stacked() {
+        let rects = layout(2, (50, 80), &LayoutConfig::default());
+        assert_eq!(rects, vec![((0, 0), (24, 79)), ((25, 0), (49, 79))]);
+    }
+
+    #[test]
+    fn uneven_columns() {
+        let rects = layout(3, (50, 200), &LayoutConfig::default());
+        assert_eq!(
+            rects,
+            vec![
+                ((0, 0), (24, 99)),
+                ((25, 0), (49, 99)),
+                ((0, 100), (49, 199)),
+            ]
+        );
+    }
+
+    #[test]
+    fn tiles_whole_area() {
+        for n in 1..10 {
+            let rects = layout(n, (37, 151), &LayoutConfig::default());
+            assert_eq!(rects.len(), n);
+            let area: usize = rects
+                .iter()
+                .map(|(c1, c2)| (c2.0 - c1.0 + 1) * (c2.1 - c1.1 + 1))
+                .sum();
+            assert_eq!(area, 37 * 151);
+        }
+    }
+}
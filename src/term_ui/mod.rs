@@ -1,27 +1,59 @@
 #![allow(dead_code)]
 
+pub mod layout;
 mod screen;
 pub mod smallstring;
 
-use std::time::Duration;
+use std::{
+    collections::HashSet,
+    fs, io,
+    ops::Range,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant, SystemTime},
+};
 
 use crossterm::{
     event::{Event, KeyCode, KeyEvent, KeyModifiers},
     style::Color,
 };
-
-use backend::buffer::BufferPath;
+use regex::Regex;
+
+use backend::{
+    buffer::{Buffer, BufferPath},
+    diff::{line_diff, DiffLine},
+    editor::DiskChange,
+    encoding::{char_to_raw_byte, Encoding, ALL_ENCODINGS},
+    loader::LoadEvent,
+};
 
 use crate::{
     editor::Editor,
-    string_utils::{char_count, is_line_ending, line_ending_to_str, LineEnding},
+    fuzzy::{filter, FuzzyMatch},
+    project_search::{apply_replacements, plan_replacements, ProjectSearch, MAX_MATCHES},
+    search::SearchPattern,
+    string_utils::{
+        char_count, is_line_ending, line_ending_name, line_ending_to_str, LineEnding,
+        ALL_LINE_ENDINGS,
+    },
     utils::{digit_count, Timer},
 };
 
-use self::screen::{Screen, Style};
+use self::{
+    layout::{layout, LayoutConfig},
+    screen::{Screen, Style},
+};
 
 const EMPTY_MOD: KeyModifiers = KeyModifiers::empty();
+const CTRL_SHIFT_MOD: KeyModifiers =
+    KeyModifiers::from_bits_truncate(KeyModifiers::CONTROL.bits() | KeyModifiers::SHIFT.bits());
 const UPDATE_TICK_MS: u64 = 10;
+const DISK_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+const SESSION_SAVE_INTERVAL: Duration = Duration::from_secs(2);
 
 // Color theme.
 // Styles are (FG, BG).
@@ -49,6 +81,18 @@ const STYLE_CURSOR: Style = Style(
         b: 0xD0,
     },
 );
+const STYLE_SELECTION: Style = Style(
+    Color::Rgb {
+        r: 0xD0,
+        g: 0xD0,
+        b: 0xD0,
+    },
+    Color::Rgb {
+        r: 0x50,
+        g: 0x58,
+        b: 0x68,
+    },
+);
 const STYLE_GUTTER_LINE_START: Style = Style(
     Color::Rgb {
         r: 0x78,
@@ -90,6 +134,66 @@ const STYLE_INFO: Style = Style(
         b: 0x14,
     },
 );
+const STYLE_DIFF_REMOVED: Style = Style(
+    Color::Rgb {
+        r: 0xE0,
+        g: 0x90,
+        b: 0x90,
+    },
+    Color::Rgb {
+        r: 0x30,
+        g: 0x30,
+        b: 0x30,
+    },
+);
+const STYLE_DIFF_ADDED: Style = Style(
+    Color::Rgb {
+        r: 0x98,
+        g: 0xD0,
+        b: 0x88,
+    },
+    Color::Rgb {
+        r: 0x30,
+        g: 0x30,
+        b: 0x30,
+    },
+);
+const STYLE_ESCAPE: Style = Style(
+    Color::Rgb {
+        r: 0xD0,
+        g: 0x98,
+        b: 0x50,
+    },
+    Color::Rgb {
+        r: 0x30,
+        g: 0x30,
+        b: 0x30,
+    },
+);
+const STYLE_SEARCH_MATCH: Style = Style(
+    Color::Rgb {
+        r: 0xF0,
+        g: 0xF0,
+        b: 0xF0,
+    },
+    Color::Rgb {
+        r: 0x68,
+        g: 0x58,
+        b: 0x30,
+    },
+);
+const STYLE_INFO_UNFOCUSED: Style = Style(
+    Color::Rgb {
+        r: 0x78,
+        g: 0x78,
+        b: 0x78,
+    },
+    Color::Rgb {
+        r: 0x14,
+        g: 0x14,
+        b: 0x14,
+    },
+);
 
 /// Generalized ui loop.
 macro_rules! ui_loop {
@@ -108,14 +212,24 @@ macro_rules! ui_loop {
             let mut should_redraw = false;
 
             // Handle input.
-            // Doing this as a polled loop isn't necessary in the current
-            // implementation, but it will be useful in the future when we may
-            // want to re-draw on e.g. async syntax highlighting updates, or
-            // update based on a file being modified outside our process.
+            // Doing this as a polled loop lets us do work in-between input
+            // events, such as finishing background saves and noticing files
+            // being modified outside our process.  It will also be useful in
+            // the future when we may want to re-draw on e.g. async syntax
+            // highlighting updates.
             loop {
-                if crossterm::event::poll(Duration::from_millis(UPDATE_TICK_MS)).unwrap() {
-                    match crossterm::event::read().unwrap() {
-                        Event::Key($key) => {
+                // Errors mean the terminal is gone, e.g. because it was
+                // closed, so treat them like a request to quit.
+                let has_input = crossterm::event::poll(Duration::from_millis(UPDATE_TICK_MS))
+                    .unwrap_or_else(|_| {
+                        $term_ui.quit_signal.store(true, Ordering::Relaxed);
+                        false
+                    });
+                if has_input {
+                    match crossterm::event::read() {
+                        Ok(Event::Key($key)) => {
+                            $term_ui.last_input = Instant::now();
+                            $term_ui.auto_saved = false;
                             let (status, state_changed) = || -> (LoopStatus, bool) { $key_press }();
                             should_redraw |= state_changed;
                             if status == LoopStatus::Done {
@@ -124,17 +238,22 @@ macro_rules! ui_loop {
                             }
                         }
 
-                        Event::Mouse(_) => {
+                        Ok(Event::Mouse(_)) => {
                             break;
                         }
 
-                        Event::Resize(w, h) => {
+                        Ok(Event::Resize(w, h)) => {
                             $term_ui.width = w as usize;
                             $term_ui.height = h as usize;
                             $term_ui.screen.resize(w as usize, h as usize);
                             should_redraw = true;
                             break;
                         }
+
+                        Err(_) => {
+                            $term_ui.quit_signal.store(true, Ordering::Relaxed);
+                            break;
+                        }
                     }
 
                     // If too much time has passed since the last redraw,
@@ -150,6 +269,8 @@ macro_rules! ui_loop {
                 }
             }
 
+            should_redraw |= $term_ui.poll_background();
+
             // Check if we're done
             if stop || $term_ui.quit {
                 break;
@@ -158,10 +279,7 @@ macro_rules! ui_loop {
             // Draw the editor to screen
             if should_redraw {
                 // Make sure display dimensions are up-to-date.
-                $term_ui
-                    .editor
-                    .update_dim($term_ui.height - 1, $term_ui.width);
-                $term_ui.editor.formatter.wrap_width = $term_ui.editor.view_dim.1;
+                $term_ui.update_view_dims();
 
                 // Draw!
                 {
@@ -176,9 +294,48 @@ macro_rules! ui_loop {
 pub struct TermUI {
     screen: Screen,
     editor: Editor,
+    layout_config: LayoutConfig,
     width: usize,
     height: usize,
     quit: bool,
+
+    // A message for the user, shown until the next key press.
+    message: Option<String>,
+
+    // Whether the user has been warned that quitting will lose their
+    // unsaved edits.
+    quit_warned: bool,
+
+    // How long to wait after the last key press before automatically
+    // saving, or `None` to not save automatically.
+    auto_save_delay: Option<Duration>,
+    last_input: Instant,
+    auto_saved: bool, // Whether we've auto-saved since the last key press.
+
+    // The buffer of the focused view as of the last poll, for noticing
+    // when a buffer loses focus.
+    focused_buf_idx: usize,
+
+    // When open files were last checked for changes made outside the
+    // editor, and whether the user is currently being asked about one.
+    last_disk_check: Instant,
+    in_disk_change_prompt: bool,
+
+    // The most recent project search, kept so its results can be shown
+    // again.
+    project_search: Option<ProjectSearch>,
+
+    // The session is saved in the background every so often while
+    // editing, so that unsaved edits survive a crash.  This is the key of
+    // the last saved session (`None` if unknown), and the save in
+    // progress, if any.
+    session_key: Option<Vec<(BufferPath, u64)>>,
+    session_save: Option<JoinHandle<io::Result<()>>>,
+    last_session_check: Instant,
+
+    // Set when the editor should save the session and quit without
+    // asking, i.e. on SIGHUP or SIGTERM, or if the terminal goes away.
+    quit_signal: Arc<AtomicBool>,
 }
 
 #[derive(Debug, Copy, Clone, PartialEq)]
@@ -188,18 +345,51 @@ enum LoopStatus {
 }
 
 impl TermUI {
-    pub fn new_from_editor(ed: Editor) -> TermUI {
+    pub fn new_from_editor(ed: Editor, layout_config: LayoutConfig) -> TermUI {
         let (w, h) = crossterm::terminal::size().unwrap();
-        let mut editor = ed;
-        editor.update_dim(h as usize - 1, w as usize);
-
-        TermUI {
+        let mut ui = TermUI {
             screen: Screen::new(),
-            editor: editor,
+            editor: ed,
+            layout_config,
             width: w as usize,
             height: h as usize,
             quit: false,
+            message: None,
+            quit_warned: false,
+            auto_save_delay: None,
+            last_input: Instant::now(),
+            auto_saved: false,
+            focused_buf_idx: 0,
+            last_disk_check: Instant::now(),
+            in_disk_change_prompt: false,
+            project_search: None,
+            session_key: None,
+            session_save: None,
+            last_session_check: Instant::now(),
+            quit_signal: Arc::new(AtomicBool::new(false)),
+        };
+        ui.update_view_dims();
+
+        #[cfg(unix)]
+        for &signal in [signal_hook::consts::SIGHUP, signal_hook::consts::SIGTERM].iter() {
+            signal_hook::flag::register(signal, Arc::clone(&ui.quit_signal)).ok();
         }
+
+        ui
+    }
+
+    /// Shows a message to the user until the next key press.
+    pub fn show_message(&mut self, message: String) {
+        self.message = Some(message);
+    }
+
+    /// Sets how long to wait after the last key press before saving all
+    /// modified files, or `None` to disable auto-saving.
+    ///
+    /// When enabled, files are also saved when their buffer loses focus,
+    /// and before quitting.
+    pub fn set_auto_save_delay(&mut self, delay: Option<Duration>) {
+        self.auto_save_delay = delay;
     }
 
     pub fn main_ui_loop(&mut self) {
@@ -210,8 +400,7 @@ impl TermUI {
         let (w, h) = crossterm::terminal::size().unwrap();
         self.width = w as usize;
         self.height = h as usize;
-        self.editor.update_dim(self.height - 1, self.width);
-        self.editor.formatter.wrap_width = self.editor.view_dim.1;
+        self.update_view_dims();
         self.screen.resize(w as usize, h as usize);
 
         // Start the UI
@@ -221,18 +410,26 @@ impl TermUI {
             // Draw
             draw {
                 self.screen.clear(STYLE_MAIN.1);
-                self.draw_editor(&self.editor, (0, 0), (self.height - 1, self.width - 1));
+                self.draw_views();
+                if let Some(ref message) = self.message {
+                    self.draw_prompt(message);
+                }
             },
 
             // Handle input
             key_press(key) {
+                self.message = None;
+                if key != KeyEvent::new(KeyCode::Char('q'), KeyModifiers::CONTROL) {
+                    self.quit_warned = false;
+                }
+
                 let mut state_changed = true;
                 match key {
                     KeyEvent {
                         code: KeyCode::Char('q'),
                         modifiers: KeyModifiers::CONTROL,
                     } => {
-                        self.quit = true;
+                        self.request_quit();
                         return (LoopStatus::Done, true);
                     }
 
@@ -240,7 +437,7 @@ impl TermUI {
                         code: KeyCode::Char('s'),
                         modifiers: KeyModifiers::CONTROL,
                     } => {
-                        self.editor.save_if_dirty().expect("For some reason the file couldn't be saved.  Also, TODO: this code path shouldn't panic.");
+                        self.editor.save_if_dirty();
                     }
 
                     KeyEvent {
@@ -257,6 +454,13 @@ impl TermUI {
                         self.editor.redo();
                     }
 
+                    KeyEvent {
+                        code: KeyCode::Char('z'),
+                        modifiers: KeyModifiers::ALT,
+                    } => {
+                        self.editor.undo_in_selection();
+                    }
+
                     KeyEvent {
                         code: KeyCode::Char('l'),
                         modifiers: KeyModifiers::CONTROL,
@@ -265,261 +469,2149 @@ impl TermUI {
                     }
 
                     KeyEvent {
-                        code: KeyCode::PageUp,
-                        modifiers: EMPTY_MOD,
+                        code: KeyCode::Char('u'),
+                        modifiers: KeyModifiers::CONTROL,
                     } => {
-                        self.editor.page_up();
+                        self.history_ui_loop();
                     }
 
                     KeyEvent {
-                        code: KeyCode::PageDown,
-                        modifiers: EMPTY_MOD,
+                        code: KeyCode::Char('f'),
+                        modifiers: KeyModifiers::CONTROL,
                     } => {
-                        self.editor.page_down();
+                        self.search_ui_loop(false);
                     }
 
                     KeyEvent {
-                        code: KeyCode::Up,
-                        modifiers: EMPTY_MOD,
+                        code: KeyCode::Char('f'),
+                        modifiers: KeyModifiers::ALT,
                     } => {
-                        self.editor.cursor_up(1);
+                        self.search_ui_loop(true);
                     }
 
                     KeyEvent {
-                        code: KeyCode::Up,
+                        code: KeyCode::Char('r'),
                         modifiers: KeyModifiers::CONTROL,
                     } => {
-                        self.editor.cursor_up(8);
+                        self.replace_ui_loop();
                     }
 
                     KeyEvent {
-                        code: KeyCode::Down,
+                        code: KeyCode::Char('g'),
+                        modifiers: KeyModifiers::ALT,
+                    } => {
+                        self.project_search_ui_loop();
+                    }
+
+                    KeyEvent {
+                        code: KeyCode::F(3),
                         modifiers: EMPTY_MOD,
                     } => {
-                        self.editor.cursor_down(1);
+                        self.editor.next_search_match();
                     }
 
                     KeyEvent {
-                        code: KeyCode::Down,
+                        code: KeyCode::F(3),
+                        modifiers: KeyModifiers::SHIFT,
+                    } => {
+                        self.editor.prev_search_match();
+                    }
+
+                    KeyEvent {
+                        code: KeyCode::Char('b'),
                         modifiers: KeyModifiers::CONTROL,
                     } => {
-                        self.editor.cursor_down(8);
+                        self.buffer_list_ui_loop();
                     }
 
                     KeyEvent {
-                        code: KeyCode::Left,
-                        modifiers: EMPTY_MOD,
+                        code: KeyCode::Char('o'),
+                        modifiers: KeyModifiers::CONTROL,
                     } => {
-                        self.editor.cursor_left(1);
+                        self.open_file_ui_loop();
                     }
 
                     KeyEvent {
-                        code: KeyCode::Right,
-                        modifiers: EMPTY_MOD,
+                        code: KeyCode::Char('e'),
+                        modifiers: KeyModifiers::ALT,
                     } => {
-                        self.editor.cursor_right(1);
+                        self.encoding_ui_loop();
                     }
 
                     KeyEvent {
-                        code: KeyCode::Enter,
-                        modifiers: EMPTY_MOD,
+                        code: KeyCode::Char('l'),
+                        modifiers: KeyModifiers::ALT,
                     } => {
-                        let nl = line_ending_to_str(self.editor.line_ending_type);
-                        self.editor.insert_text_at_cursor(nl);
+                        self.line_ending_ui_loop();
                     }
 
                     KeyEvent {
-                        code: KeyCode::Tab,
-                        modifiers: EMPTY_MOD,
+                        code: KeyCode::Char('i'),
+                        modifiers: KeyModifiers::ALT,
                     } => {
-                        self.editor.insert_tab_at_cursor();
+                        self.indentation_ui_loop();
                     }
 
                     KeyEvent {
-                        code: KeyCode::Backspace,
-                        modifiers: EMPTY_MOD,
+                        code: KeyCode::Char('r'),
+                        modifiers: KeyModifiers::ALT,
                     } => {
-                        self.editor.remove_text_behind_cursor(1);
+                        let message = if self.editor.toggle_read_only() {
+                            "Buffer is now read-only."
+                        } else {
+                            "Buffer is now editable."
+                        };
+                        self.message = Some(message.into());
                     }
 
                     KeyEvent {
-                        code: KeyCode::Delete,
-                        modifiers: EMPTY_MOD,
+                        code: KeyCode::Char('n'),
+                        modifiers: KeyModifiers::ALT,
                     } => {
-                        self.editor.remove_text_in_front_of_cursor(1);
+                        self.editor.add_view();
+                        self.update_view_dims();
                     }
 
-                    // Character
                     KeyEvent {
-                        code: KeyCode::Char(c),
-                        modifiers: EMPTY_MOD,
+                        code: KeyCode::Char('w'),
+                        modifiers: KeyModifiers::ALT,
                     } => {
-                        self.editor.insert_text_at_cursor(&c.to_string()[..]);
+                        self.editor.close_view();
+                        self.update_view_dims();
                     }
 
-                    _ => {
-                        state_changed = false;
+                    KeyEvent {
+                        code: KeyCode::Left,
+                        modifiers: KeyModifiers::ALT,
+                    } => {
+                        self.editor.focus_prev_view();
                     }
-                }
 
-                (LoopStatus::Continue, state_changed)
-            }
-        );
-    }
+                    KeyEvent {
+                        code: KeyCode::Right,
+                        modifiers: KeyModifiers::ALT,
+                    } => {
+                        self.editor.focus_next_view();
+                    }
 
-    fn go_to_line_ui_loop(&mut self) {
-        let mut cancel = false;
-        let prefix = "Jump to line: ";
-        let mut line = String::new();
+                    KeyEvent {
+                        code: KeyCode::PageUp,
+                        modifiers: KeyModifiers::CONTROL,
+                    } => {
+                        self.editor.prev_buffer();
+                    }
 
-        ui_loop!(
-            self,
+                    KeyEvent {
+                        code: KeyCode::PageDown,
+                        modifiers: KeyModifiers::CONTROL,
+                    } => {
+                        self.editor.next_buffer();
+                    }
 
-            // Draw
-            draw {
-                self.screen.clear(STYLE_MAIN.1);
-                self.draw_editor(&self.editor, (0, 0), (self.height - 1, self.width - 1));
-                for i in 0..self.width {
-                    self.screen.draw(i, 0, " ", STYLE_INFO);
-                }
-                self.screen.draw(1, 0, prefix, STYLE_INFO);
-                self.screen.draw(
-                    prefix.len() + 1,
-                    0,
-                    &line[..],
-                    STYLE_INFO,
-                );
-                self.screen.set_cursor(prefix.len() + 1, 0);
-            },
+                    KeyEvent {
+                        code: KeyCode::Char('d'),
+                        modifiers: KeyModifiers::CONTROL,
+                    } => {
+                        self.editor.add_cursor_at_next_match();
+                    }
 
-            // Handle input
-            key_press(key) {
-                let mut state_changed = true;
-                match key {
                     KeyEvent {
-                        code: KeyCode::Char('q'),
+                        code: KeyCode::Char('a'),
                         modifiers: KeyModifiers::CONTROL,
                     } => {
-                        self.quit = true;
-                        return (LoopStatus::Done, true);
+                        self.editor.select_all();
+                    }
+
+                    KeyEvent {
+                        code: KeyCode::Char('w'),
+                        modifiers: KeyModifiers::CONTROL,
+                    } => {
+                        self.editor.select_word();
+                    }
+
+                    KeyEvent {
+                        code: KeyCode::Char('e'),
+                        modifiers: KeyModifiers::CONTROL,
+                    } => {
+                        self.editor.select_line();
                     }
 
                     KeyEvent {
                         code: KeyCode::Esc,
                         modifiers: EMPTY_MOD,
                     } => {
-                        cancel = true;
-                        return (LoopStatus::Done, true);
+                        self.editor.collapse_to_main_cursor();
+                        self.editor.clear_search();
                     }
 
                     KeyEvent {
-                        code: KeyCode::Enter,
+                        code: KeyCode::PageUp,
                         modifiers: EMPTY_MOD,
                     } => {
-                        return (LoopStatus::Done, true);
+                        self.editor.page_up(false);
                     }
 
                     KeyEvent {
-                        code: KeyCode::Backspace,
-                        modifiers: EMPTY_MOD,
+                        code: KeyCode::PageUp,
+                        modifiers: KeyModifiers::SHIFT,
                     } => {
-                        line.pop();
+                        self.editor.page_up(true);
                     }
 
-                    // Character
                     KeyEvent {
-                        code: KeyCode::Char(c),
+                        code: KeyCode::PageDown,
                         modifiers: EMPTY_MOD,
                     } => {
-                        if c.is_numeric() {
-                            line.push(c);
-                        }
+                        self.editor.page_down(false);
                     }
 
-                    _ => {
-                        state_changed = false;
+                    KeyEvent {
+                        code: KeyCode::PageDown,
+                        modifiers: KeyModifiers::SHIFT,
+                    } => {
+                        self.editor.page_down(true);
                     }
-                }
 
-                return (LoopStatus::Continue, state_changed);
-            }
-        );
+                    KeyEvent {
+                        code: KeyCode::Up,
+                        modifiers: EMPTY_MOD,
+                    } => {
+                        self.editor.cursor_up(1, false);
+                    }
 
-        // Jump to line!
-        if !cancel {
-            if let Ok(n) = line.parse::<usize>() {
-                self.editor.jump_to_line(n.saturating_sub(1));
-            }
-        }
-    }
+                    KeyEvent {
+                        code: KeyCode::Up,
+                        modifiers: KeyModifiers::CONTROL,
+                    } => {
+                        self.editor.cursor_up(8, false);
+                    }
 
-    fn draw_editor(&self, editor: &Editor, c1: (usize, usize), c2: (usize, usize)) {
-        // Fill in top row with info line color
-        for i in c1.1..(c2.1 + 1) {
-            self.screen.draw(i, c1.0, " ", STYLE_INFO);
-        }
+                    KeyEvent {
+                        code: KeyCode::Up,
+                        modifiers: KeyModifiers::SHIFT,
+                    } => {
+                        self.editor.cursor_up(1, true);
+                    }
 
-        // Filename and dirty marker
-        let filename = match editor.buffer.path {
-            BufferPath::File(ref p) => format!("{}", p.display()),
-            BufferPath::Temp(i) => format!("Scratch #{}", i + 1),
-        };
-        let dirty_char = if editor.buffer.is_dirty { "*" } else { "" };
-        let name = format!("{}{}", filename, dirty_char);
-        self.screen.draw(c1.1 + 1, c1.0, &name[..], STYLE_INFO);
+                    KeyEvent {
+                        code: KeyCode::Up,
+                        modifiers: CTRL_SHIFT_MOD,
+                    } => {
+                        self.editor.cursor_up(8, true);
+                    }
 
-        // Percentage position in document
-        // TODO: use view instead of cursor for calculation if there is more
-        // than one cursor.
-        let percentage: usize = if editor.buffer.text.len_chars() > 0 {
-            (((editor.buffer.mark_sets[editor.c_msi].main().unwrap().head as f32)
-                / (editor.buffer.text.len_chars() as f32))
-                * 100.0) as usize
-        } else {
-            100
-        };
-        let pstring = format!("{}%", percentage);
-        self.screen.draw(
-            c2.1.saturating_sub(pstring.len()),
-            c1.0,
-            &pstring[..],
-            STYLE_INFO,
-        );
+                    KeyEvent {
+                        code: KeyCode::Up,
+                        modifiers: KeyModifiers::ALT,
+                    } => {
+                        self.editor.add_cursor_above();
+                    }
 
-        // Text encoding info and tab style
-        let nl = match editor.line_ending_type {
-            LineEnding::None => "None",
-            LineEnding::CRLF => "CRLF",
-            LineEnding::LF => "LF",
-            LineEnding::VT => "VT",
-            LineEnding::FF => "FF",
-            LineEnding::CR => "CR",
-            LineEnding::NEL => "NEL",
-            LineEnding::LS => "LS",
-            LineEnding::PS => "PS",
-        };
-        let soft_tabs_str = if editor.soft_tabs { "spaces" } else { "tabs" };
-        let info_line = format!(
-            "UTF8:{}  {}:{}",
-            nl, soft_tabs_str, editor.soft_tab_width as usize
-        );
-        self.screen
-            .draw(c2.1.saturating_sub(30), c1.0, &info_line[..], STYLE_INFO);
+                    KeyEvent {
+                        code: KeyCode::Down,
+                        modifiers: EMPTY_MOD,
+                    } => {
+                        self.editor.cursor_down(1, false);
+                    }
+
+                    KeyEvent {
+                        code: KeyCode::Down,
+                        modifiers: KeyModifiers::ALT,
+                    } => {
+                        self.editor.add_cursor_below();
+                    }
+
+                    KeyEvent {
+                        code: KeyCode::Down,
+                        modifiers: KeyModifiers::CONTROL,
+                    } => {
+                        self.editor.cursor_down(8, false);
+                    }
+
+                    KeyEvent {
+                        code: KeyCode::Down,
+                        modifiers: KeyModifiers::SHIFT,
+                    } => {
+                        self.editor.cursor_down(1, true);
+                    }
+
+                    KeyEvent {
+                        code: KeyCode::Down,
+                        modifiers: CTRL_SHIFT_MOD,
+                    } => {
+                        self.editor.cursor_down(8, true);
+                    }
+
+                    KeyEvent {
+                        code: KeyCode::Left,
+                        modifiers: EMPTY_MOD,
+                    } => {
+                        self.editor.cursor_left(1, false);
+                    }
+
+                    KeyEvent {
+                        code: KeyCode::Left,
+                        modifiers: KeyModifiers::SHIFT,
+                    } => {
+                        self.editor.cursor_left(1, true);
+                    }
+
+                    KeyEvent {
+                        code: KeyCode::Right,
+                        modifiers: EMPTY_MOD,
+                    } => {
+                        self.editor.cursor_right(1, false);
+                    }
+
+                    KeyEvent {
+                        code: KeyCode::Right,
+                        modifiers: KeyModifiers::SHIFT,
+                    } => {
+                        self.editor.cursor_right(1, true);
+                    }
+
+                    KeyEvent {
+                        code: KeyCode::Home,
+                        modifiers: EMPTY_MOD,
+                    } => {
+                        self.editor.cursor_home(false);
+                    }
+
+                    KeyEvent {
+                        code: KeyCode::Home,
+                        modifiers: KeyModifiers::SHIFT,
+                    } => {
+                        self.editor.cursor_home(true);
+                    }
+
+                    KeyEvent {
+                        code: KeyCode::End,
+                        modifiers: EMPTY_MOD,
+                    } => {
+                        self.editor.cursor_end(false);
+                    }
+
+                    KeyEvent {
+                        code: KeyCode::End,
+                        modifiers: KeyModifiers::SHIFT,
+                    } => {
+                        self.editor.cursor_end(true);
+                    }
+
+                    KeyEvent {
+                        code: KeyCode::Enter,
+                        modifiers: EMPTY_MOD,
+                    } => {
+                        let nl = line_ending_to_str(self.editor.view().line_ending_type);
+                        self.editor.insert_text_at_cursor(nl);
+                    }
+
+                    KeyEvent {
+                        code: KeyCode::Tab,
+                        modifiers: EMPTY_MOD,
+                    } => {
+                        self.editor.insert_tab_at_cursor();
+                    }
+
+                    // Shift-Tab.  Some terminals report the shift modifier
+                    // with it and some don't.
+                    KeyEvent {
+                        code: KeyCode::BackTab,
+                        ..
+                    } => {
+                        self.editor.dedent_lines();
+                    }
+
+                    KeyEvent {
+                        code: KeyCode::Backspace,
+                        modifiers: EMPTY_MOD,
+                    } => {
+                        self.editor.remove_text_behind_cursor(1);
+                    }
+
+                    KeyEvent {
+                        code: KeyCode::Delete,
+                        modifiers: EMPTY_MOD,
+                    } => {
+                        self.editor.remove_text_in_front_of_cursor(1);
+                    }
+
+                    // Character
+                    KeyEvent {
+                        code: KeyCode::Char(c),
+                        modifiers: EMPTY_MOD,
+                    } => {
+                        self.editor.insert_text_at_cursor(&c.to_string()[..]);
+                    }
+
+                    _ => {
+                        state_changed = false;
+                    }
+                }
+
+                (LoopStatus::Continue, state_changed)
+            }
+        );
+    }
+
+    fn go_to_line_ui_loop(&mut self) {
+        let mut cancel = false;
+        let prefix = "Jump to line: ";
+        let mut line = String::new();
+
+        ui_loop!(
+            self,
+
+            // Draw
+            draw {
+                self.screen.clear(STYLE_MAIN.1);
+                self.draw_views();
+                let (x, y) = self.draw_prompt(prefix);
+                self.screen.draw(x, y, &line[..], STYLE_INFO);
+                self.screen.set_cursor(x, y);
+            },
+
+            // Handle input
+            key_press(key) {
+                let mut state_changed = true;
+                match key {
+                    KeyEvent {
+                        code: KeyCode::Char('q'),
+                        modifiers: KeyModifiers::CONTROL,
+                    } => {
+                        self.request_quit();
+                        return (LoopStatus::Done, true);
+                    }
+
+                    KeyEvent {
+                        code: KeyCode::Esc,
+                        modifiers: EMPTY_MOD,
+                    } => {
+                        cancel = true;
+                        return (LoopStatus::Done, true);
+                    }
+
+                    KeyEvent {
+                        code: KeyCode::Enter,
+                        modifiers: EMPTY_MOD,
+                    } => {
+                        return (LoopStatus::Done, true);
+                    }
+
+                    KeyEvent {
+                        code: KeyCode::Backspace,
+                        modifiers: EMPTY_MOD,
+                    } => {
+                        line.pop();
+                    }
+
+                    // Character
+                    KeyEvent {
+                        code: KeyCode::Char(c),
+                        modifiers: EMPTY_MOD,
+                    } => {
+                        if c.is_numeric() {
+                            line.push(c);
+                        }
+                    }
+
+                    _ => {
+                        state_changed = false;
+                    }
+                }
+
+                return (LoopStatus::Continue, state_changed);
+            }
+        );
+
+        // Jump to line!
+        if !cancel {
+            if let Ok(n) = line.parse::<usize>() {
+                self.editor.jump_to_line(n.saturating_sub(1));
+            }
+        }
+    }
+
+    /// Searches the current buffer as the user types, moving to the first
+    /// match after where the search started.  Enter keeps the match
+    /// selected, with its matches still highlighted, and Esc goes back to
+    /// where the search started.
+    ///
+    /// If `regex` is set, what's typed is a regex.  Returns whether the
+    /// search was accepted with Enter.
+    fn search_ui_loop(&mut self, regex: bool) -> bool {
+        let prefix = if regex { "Regex search: " } else { "Search: " };
+        let mut pattern = String::new();
+        let mut invalid_regex = false;
+        let mut cancel = false;
+        let saved_cursors = self.editor.save_cursors();
+        let from = saved_cursors.0.main().unwrap().range().start;
+        self.editor.clear_search();
+
+        ui_loop!(
+            self,
+
+            // Draw
+            draw {
+                self.screen.clear(STYLE_MAIN.1);
+                self.draw_views();
+                let (x, y) = self.draw_prompt(prefix);
+                self.screen.draw(x, y, &pattern[..], STYLE_INFO);
+                let status = if invalid_regex {
+                    Some("invalid regex".into())
+                } else {
+                    self.editor
+                        .search_position(self.editor.focus)
+                        .map(format_search_position)
+                };
+                if let Some(status) = status {
+                    let status = format!("   {}", status);
+                    self.screen.draw(x + char_count(&pattern), y, &status, STYLE_INFO);
+                }
+                self.screen.set_cursor(x + char_count(&pattern), y);
+            },
+
+            // Handle input
+            key_press(key) {
+                let mut state_changed = true;
+                let mut pattern_changed = false;
+                match key {
+                    KeyEvent {
+                        code: KeyCode::Char('q'),
+                        modifiers: KeyModifiers::CONTROL,
+                    } => {
+                        self.request_quit();
+                        return (LoopStatus::Done, true);
+                    }
+
+                    KeyEvent {
+                        code: KeyCode::Esc,
+                        modifiers: EMPTY_MOD,
+                    } => {
+                        cancel = true;
+                        self.editor.clear_search();
+                        self.editor.restore_cursors(saved_cursors.clone());
+                        return (LoopStatus::Done, true);
+                    }
+
+                    KeyEvent {
+                        code: KeyCode::Enter,
+                        modifiers: EMPTY_MOD,
+                    } => {
+                        return (LoopStatus::Done, true);
+                    }
+
+                    KeyEvent {
+                        code: KeyCode::Down,
+                        modifiers: EMPTY_MOD,
+                    }
+                    | KeyEvent {
+                        code: KeyCode::F(3),
+                        modifiers: EMPTY_MOD,
+                    } => {
+                        self.editor.next_search_match();
+                    }
+
+                    KeyEvent {
+                        code: KeyCode::Up,
+                        modifiers: EMPTY_MOD,
+                    }
+                    | KeyEvent {
+                        code: KeyCode::F(3),
+                        modifiers: KeyModifiers::SHIFT,
+                    } => {
+                        self.editor.prev_search_match();
+                    }
+
+                    KeyEvent {
+                        code: KeyCode::Backspace,
+                        modifiers: EMPTY_MOD,
+                    } => {
+                        pattern.pop();
+                        pattern_changed = true;
+                    }
+
+                    // Character
+                    KeyEvent {
+                        code: KeyCode::Char(c),
+                        modifiers: EMPTY_MOD,
+                    }
+                    | KeyEvent {
+                        code: KeyCode::Char(c),
+                        modifiers: KeyModifiers::SHIFT,
+                    } => {
+                        pattern.push(c);
+                        pattern_changed = true;
+                    }
+
+                    _ => {
+                        state_changed = false;
+                    }
+                }
+
+                if pattern_changed {
+                    invalid_regex = false;
+                    let found = if pattern.is_empty() {
+                        self.editor.clear_search();
+                        false
+                    } else if regex {
+                        match Regex::new(&pattern) {
+                            Ok(regex) => self.editor.search_regex(regex, from),
+                            Err(_) => {
+                                invalid_regex = true;
+                                self.editor.clear_search();
+                                false
+                            }
+                        }
+                    } else {
+                        self.editor.search(&pattern, from)
+                    };
+                    if !found {
+                        self.editor.restore_cursors(saved_cursors.clone());
+                    }
+                }
+
+                return (LoopStatus::Continue, state_changed);
+            }
+        );
+
+        !cancel && !self.quit && self.editor.search_pattern().is_some()
+    }
+
+    /// Asks for a regex and what to replace its matches with, and then
+    /// steps through the matches from the cursor on, asking whether to
+    /// replace each one.
+    fn replace_ui_loop(&mut self) {
+        if !self.search_ui_loop(true) {
+            return;
+        }
+        let replacement = match self.text_ui_loop("Replace with: ") {
+            Some(replacement) => replacement,
+            None => return,
+        };
+
+        // Where stepping through the matches started.  Once the end of the
+        // buffer is reached, it wraps around to the start and goes on up
+        // to here.
+        let mut origin = self.editor.save_cursors().0.main().unwrap().range().start;
+        let mut wrapped = false;
+        let mut current = self.next_replace_candidate(origin, origin, &mut wrapped);
+        let mut replaced = 0;
+
+        if current.is_none() {
+            self.message = Some("No matches.".into());
+            return;
+        }
+
+        ui_loop!(
+            self,
+
+            // Draw
+            draw {
+                self.screen.clear(STYLE_MAIN.1);
+                self.draw_views();
+                self.draw_prompt(
+                    "Replace this match?   [Y: yes, N: no, A: all remaining, Esc: stop]",
+                );
+            },
+
+            // Handle input
+            key_press(key) {
+                let range = current.clone().unwrap();
+                let next_from = match key {
+                    KeyEvent {
+                        code: KeyCode::Char('q'),
+                        modifiers: KeyModifiers::CONTROL,
+                    } => {
+                        self.request_quit();
+                        return (LoopStatus::Done, true);
+                    }
+
+                    KeyEvent {
+                        code: KeyCode::Esc,
+                        modifiers: EMPTY_MOD,
+                    } => {
+                        return (LoopStatus::Done, true);
+                    }
+
+                    KeyEvent {
+                        code: KeyCode::Char('y'),
+                        modifiers: EMPTY_MOD,
+                    } => match self.editor.replace_selected_search_match(&replacement) {
+                        Some(new) => {
+                            replaced += 1;
+                            if wrapped {
+                                // The replacement was before the origin.
+                                origin = (origin + new.len()).saturating_sub(range.len());
+                            }
+                            // Don't find an empty match here again.
+                            new.end.max(range.start + 1)
+                        }
+                        None => return (LoopStatus::Done, true),
+                    },
+
+                    KeyEvent {
+                        code: KeyCode::Char('n'),
+                        modifiers: EMPTY_MOD,
+                    } => range.start + 1,
+
+                    KeyEvent {
+                        code: KeyCode::Char('a'),
+                        modifiers: EMPTY_MOD,
+                    } => {
+                        let (start, end, wrapped) = (range.start, origin, wrapped);
+                        replaced += self.editor.replace_search_matches(&replacement, |idx| {
+                            if wrapped {
+                                idx >= start && idx < end
+                            } else {
+                                idx >= start || idx < end
+                            }
+                        });
+                        return (LoopStatus::Done, true);
+                    }
+
+                    _ => return (LoopStatus::Continue, false),
+                };
+
+                current = self.next_replace_candidate(next_from, origin, &mut wrapped);
+                if current.is_none() {
+                    return (LoopStatus::Done, true);
+                }
+                return (LoopStatus::Continue, true);
+            }
+        );
+
+        self.message = Some(match replaced {
+            1 => "Replaced 1 match.".into(),
+            n => format!("Replaced {} matches.", n),
+        });
+    }
+
+    /// Selects the next match to ask about replacing, starting at the char
+    /// index `from`, and returns its range.  Wraps around to the start of
+    /// the buffer once, and stops at the char index `origin` after that.
+    fn next_replace_candidate(
+        &mut self,
+        from: usize,
+        origin: usize,
+        wrapped: &mut bool,
+    ) -> Option<Range<usize>> {
+        let from = if *wrapped { from } else { from.max(origin) };
+        match self.editor.select_search_match_after(from) {
+            Some(range) if !*wrapped || range.start < origin => Some(range),
+            Some(_) => None,
+            None if !*wrapped => {
+                *wrapped = true;
+                self.next_replace_candidate(0, origin, wrapped)
+            }
+            None => None,
+        }
+    }
+
+    /// Asks the user to type in a line of text, and returns it, or `None`
+    /// if they cancelled.
+    fn text_ui_loop(&mut self, prefix: &str) -> Option<String> {
+        let mut cancel = false;
+        let mut text = String::new();
+
+        ui_loop!(
+            self,
+
+            // Draw
+            draw {
+                self.screen.clear(STYLE_MAIN.1);
+                self.draw_views();
+                let (x, y) = self.draw_prompt(prefix);
+                self.screen.draw(x, y, &text[..], STYLE_INFO);
+                self.screen.set_cursor(x + char_count(&text), y);
+            },
+
+            // Handle input
+            key_press(key) {
+                let mut state_changed = true;
+                match key {
+                    KeyEvent {
+                        code: KeyCode::Char('q'),
+                        modifiers: KeyModifiers::CONTROL,
+                    } => {
+                        self.request_quit();
+                        cancel = true;
+                        return (LoopStatus::Done, true);
+                    }
+
+                    KeyEvent {
+                        code: KeyCode::Esc,
+                        modifiers: EMPTY_MOD,
+                    } => {
+                        cancel = true;
+                        return (LoopStatus::Done, true);
+                    }
+
+                    KeyEvent {
+                        code: KeyCode::Enter,
+                        modifiers: EMPTY_MOD,
+                    } => {
+                        return (LoopStatus::Done, true);
+                    }
+
+                    KeyEvent {
+                        code: KeyCode::Backspace,
+                        modifiers: EMPTY_MOD,
+                    } => {
+                        text.pop();
+                    }
+
+                    // Character
+                    KeyEvent {
+                        code: KeyCode::Char(c),
+                        modifiers: EMPTY_MOD,
+                    }
+                    | KeyEvent {
+                        code: KeyCode::Char(c),
+                        modifiers: KeyModifiers::SHIFT,
+                    } => {
+                        text.push(c);
+                    }
+
+                    _ => {
+                        state_changed = false;
+                    }
+                }
+
+                return (LoopStatus::Continue, state_changed);
+            }
+        );
+
+        if cancel {
+            None
+        } else {
+            Some(text)
+        }
+    }
+
+    /// Lets the user walk through the buffer's undo history, previewing each
+    /// state in the editor as they go.
+    fn history_ui_loop(&mut self) {
+        let mut cancel = false;
+        let (states, original_state) = self.editor.buffer().history_states();
+        let mut state = original_state;
+
+        ui_loop!(
+            self,
+
+            // Draw
+            draw {
+                self.screen.clear(STYLE_MAIN.1);
+                self.draw_views();
+                let age = match states[state].time {
+                    Some(time) => format_age(time),
+                    None => "initial".into(),
+                };
+                let line = format!(
+                    "Undo history: state {} of {} ({})   [Up/Down: move, Enter: accept, Esc: cancel]",
+                    state,
+                    states.len() - 1,
+                    age,
+                );
+                self.draw_prompt(&line);
+            },
+
+            // Handle input
+            key_press(key) {
+                let mut state_changed = true;
+                match key {
+                    KeyEvent {
+                        code: KeyCode::Char('q'),
+                        modifiers: KeyModifiers::CONTROL,
+                    } => {
+                        self.request_quit();
+                        return (LoopStatus::Done, true);
+                    }
+
+                    KeyEvent {
+                        code: KeyCode::Esc,
+                        modifiers: EMPTY_MOD,
+                    } => {
+                        cancel = true;
+                        return (LoopStatus::Done, true);
+                    }
+
+                    KeyEvent {
+                        code: KeyCode::Enter,
+                        modifiers: EMPTY_MOD,
+                    } => {
+                        return (LoopStatus::Done, true);
+                    }
+
+                    KeyEvent {
+                        code: KeyCode::Up,
+                        modifiers: EMPTY_MOD,
+                    }
+                    | KeyEvent {
+                        code: KeyCode::Left,
+                        modifiers: EMPTY_MOD,
+                    } => {
+                        if state > 0 {
+                            state -= 1;
+                            self.editor.jump_to_history_state(state);
+                        }
+                    }
+
+                    KeyEvent {
+                        code: KeyCode::Down,
+                        modifiers: EMPTY_MOD,
+                    }
+                    | KeyEvent {
+                        code: KeyCode::Right,
+                        modifiers: EMPTY_MOD,
+                    } => {
+                        if state + 1 < states.len() {
+                            state += 1;
+                            self.editor.jump_to_history_state(state);
+                        }
+                    }
+
+                    _ => {
+                        state_changed = false;
+                    }
+                }
+
+                return (LoopStatus::Continue, state_changed);
+            }
+        );
+
+        if cancel {
+            self.editor.jump_to_history_state(original_state);
+        }
+    }
+
+    /// Lets the user pick one of the open buffers to switch to.
+    fn buffer_list_ui_loop(&mut self) {
+        let mut cancel = false;
+        let mut selected = self.editor.view().buf_idx;
+
+        ui_loop!(
+            self,
+
+            // Draw
+            draw {
+                self.screen.clear(STYLE_MAIN.1);
+                self.draw_views();
+
+                // Draw the list over the current view, below its info line,
+                // as many entries as fit.
+                let (c1, c2) = self.view_rects()[self.editor.focus];
+                let count = self.editor.buffers.len();
+                let rows = count.min(c2.0 - c1.0);
+                let first = (selected + 1).saturating_sub(rows);
+                for (row, idx) in (first..(first + rows)).enumerate() {
+                    let style = if idx == selected {
+                        STYLE_CURSOR
+                    } else {
+                        STYLE_INFO
+                    };
+                    for i in c1.1..(c2.1 + 1) {
+                        self.screen.draw(i, c1.0 + row + 1, " ", style);
+                    }
+                    let name = buffer_name(&self.editor.buffers[idx]);
+                    self.screen.draw(c1.1 + 1, c1.0 + row + 1, &name[..], style);
+                }
+
+                let line = format!(
+                    "Open buffers: {}   [Up/Down: move, Enter: switch, Esc: cancel]",
+                    count,
+                );
+                self.draw_prompt(&line);
+            },
+
+            // Handle input
+            key_press(key) {
+                let mut state_changed = true;
+                match key {
+                    KeyEvent {
+                        code: KeyCode::Char('q'),
+                        modifiers: KeyModifiers::CONTROL,
+                    } => {
+                        self.request_quit();
+                        return (LoopStatus::Done, true);
+                    }
+
+                    KeyEvent {
+                        code: KeyCode::Esc,
+                        modifiers: EMPTY_MOD,
+                    } => {
+                        cancel = true;
+                        return (LoopStatus::Done, true);
+                    }
+
+                    KeyEvent {
+                        code: KeyCode::Enter,
+                        modifiers: EMPTY_MOD,
+                    } => {
+                        return (LoopStatus::Done, true);
+                    }
+
+                    KeyEvent {
+                        code: KeyCode::Up,
+                        modifiers: EMPTY_MOD,
+                    } => {
+                        selected = selected.saturating_sub(1);
+                    }
+
+                    KeyEvent {
+                        code: KeyCode::Down,
+                        modifiers: EMPTY_MOD,
+                    } => {
+                        if selected + 1 < self.editor.buffers.len() {
+                            selected += 1;
+                        }
+                    }
+
+                    _ => {
+                        state_changed = false;
+                    }
+                }
+
+                return (LoopStatus::Continue, state_changed);
+            }
+        );
+
+        if !cancel {
+            self.editor.switch_to_buffer(selected);
+        }
+    }
+
+    /// Asks for a regex to search all of the project's files for, and shows
+    /// the matches as they're found.  Leaving the regex empty shows the
+    /// results of the last search again.
+    fn project_search_ui_loop(&mut self) {
+        let pattern = match self.text_ui_loop("Search project (regex): ") {
+            Some(pattern) => pattern,
+            None => return,
+        };
+        if !pattern.is_empty() {
+            let regex = match Regex::new(&pattern) {
+                Ok(regex) => regex,
+                Err(_) => {
+                    self.message = Some("Invalid regex.".into());
+                    return;
+                }
+            };
+            self.project_search = Some(ProjectSearch::start(
+                self.editor.project.project(),
+                &self.editor.buffers,
+                SearchPattern::Regex(regex),
+            ));
+        } else if self.project_search.is_none() {
+            return;
+        }
+
+        self.project_results_ui_loop();
+    }
+
+    /// Lists the matches of the current project search, updating as more
+    /// are found, and opens the chosen one.  Once the search is done, its
+    /// matches can also be replaced.
+    fn project_results_ui_loop(&mut self) {
+        let mut open = false;
+        let mut replace = false;
+        let mut selected: usize = 0;
+
+        ui_loop!(
+            self,
+
+            // Draw
+            draw {
+                self.screen.clear(STYLE_MAIN.1);
+                self.draw_views();
+                let search = self.project_search.as_ref().unwrap();
+
+                // Draw the list over the current view, below its info line,
+                // as many entries as fit.
+                let (c1, c2) = self.view_rects()[self.editor.focus];
+                let width = c2.1 - c1.1 + 1;
+                let count = search.matches.len();
+                let rows = count.min(c2.0 - c1.0);
+                let first = (selected + 1).saturating_sub(rows);
+                for (row, idx) in (first..(first + rows)).enumerate() {
+                    let (style, match_style) = if idx == selected {
+                        (STYLE_CURSOR, STYLE_CURSOR)
+                    } else {
+                        (STYLE_INFO, STYLE_SEARCH_MATCH)
+                    };
+                    let y = c1.0 + row + 1;
+                    for i in c1.1..(c2.1 + 1) {
+                        self.screen.draw(i, y, " ", style);
+                    }
+
+                    // The location, then the line with the match
+                    // highlighted, cut off at the edge of the view.
+                    let m = &search.matches[idx];
+                    let location = format!(
+                        "{}:{}: ",
+                        project_path_name(&self.editor.project.project().directories, &m.path),
+                        m.line_idx + 1
+                    );
+                    let line: Vec<char> = m
+                        .line
+                        .chars()
+                        .map(|c| if c == '\t' { ' ' } else { c })
+                        .collect();
+                    let start = m.line_range.start.min(line.len());
+                    let end = m.line_range.end.min(line.len());
+                    let mut x = c1.1 + 1;
+                    let location: Vec<char> = location.chars().collect();
+                    for &(text, style) in [
+                        (&location[..], style),
+                        (&line[..start], style),
+                        (&line[start..end], match_style),
+                        (&line[end..], style),
+                    ]
+                    .iter()
+                    {
+                        let text: String = text.iter().take((c1.1 + width).saturating_sub(x)).collect();
+                        self.screen.draw(x, y, &text, style);
+                        x += char_count(&text);
+                    }
+                }
+
+                let status = if !search.is_done {
+                    format!(", searching ({} files so far)", search.files_searched)
+                } else if count >= MAX_MATCHES {
+                    format!(", stopped at {}", MAX_MATCHES)
+                } else {
+                    String::new()
+                };
+                let line = format!(
+                    "{} matches in {} files{}   [Up/Down: move, Enter: open, {}Esc: close]",
+                    count,
+                    search.matched_files().len(),
+                    status,
+                    if search.is_done { "R: replace, " } else { "" },
+                );
+                self.draw_prompt(&line);
+            },
+
+            // Handle input
+            key_press(key) {
+                let search = self.project_search.as_ref().unwrap();
+                let count = search.matches.len();
+                let page = self.editor.view().view_dim.0.max(1);
+                let mut state_changed = true;
+                match key {
+                    KeyEvent {
+                        code: KeyCode::Char('q'),
+                        modifiers: KeyModifiers::CONTROL,
+                    } => {
+                        self.request_quit();
+                        return (LoopStatus::Done, true);
+                    }
+
+                    KeyEvent {
+                        code: KeyCode::Esc,
+                        modifiers: EMPTY_MOD,
+                    } => {
+                        return (LoopStatus::Done, true);
+                    }
+
+                    KeyEvent {
+                        code: KeyCode::Enter,
+                        modifiers: EMPTY_MOD,
+                    } => {
+                        if selected < count {
+                            open = true;
+                            return (LoopStatus::Done, true);
+                        }
+                        state_changed = false;
+                    }
+
+                    KeyEvent {
+                        code: KeyCode::Char('r'),
+                        modifiers: EMPTY_MOD,
+                    } => {
+                        if search.is_done && count > 0 {
+                            replace = true;
+                            return (LoopStatus::Done, true);
+                        }
+                        state_changed = false;
+                    }
+
+                    KeyEvent {
+                        code: KeyCode::Up,
+                        modifiers: EMPTY_MOD,
+                    } => {
+                        selected = selected.saturating_sub(1);
+                    }
+
+                    KeyEvent {
+                        code: KeyCode::Down,
+                        modifiers: EMPTY_MOD,
+                    } => {
+                        if selected + 1 < count {
+                            selected += 1;
+                        }
+                    }
+
+                    KeyEvent {
+                        code: KeyCode::PageUp,
+                        modifiers: EMPTY_MOD,
+                    } => {
+                        selected = selected.saturating_sub(page);
+                    }
+
+                    KeyEvent {
+                        code: KeyCode::PageDown,
+                        modifiers: EMPTY_MOD,
+                    } => {
+                        selected = (selected + page).min(count.saturating_sub(1));
+                    }
+
+                    _ => {
+                        state_changed = false;
+                    }
+                }
+
+                return (LoopStatus::Continue, state_changed);
+            }
+        );
+
+        if open {
+            let m = self.project_search.as_ref().unwrap().matches[selected].clone();
+            if let Err(e) = self.editor.open_file_at(&m.path, m.range) {
+                self.message = Some(format!("Couldn't open {}: {}", m.path.to_string_lossy(), e));
+            }
+        } else if replace {
+            self.project_replace_ui_loop();
+        }
+    }
+
+    /// Asks what to replace the matches of the current project search with,
+    /// previews the changes, and makes them if the user confirms.
+    ///
+    /// Files that aren't open are saved right away.  Open buffers are
+    /// edited but left unsaved, like any other edit.
+    fn project_replace_ui_loop(&mut self) {
+        let replacement = match self.text_ui_loop("Replace with: ") {
+            Some(replacement) => replacement,
+            None => return,
+        };
+
+        let plans = {
+            let search = self.project_search.as_ref().unwrap();
+            plan_replacements(
+                &self.editor.buffers,
+                &search.matched_files(),
+                &search.pattern,
+                &replacement,
+            )
+        };
+        let edit_count: usize = plans.iter().map(|plan| plan.edits.len()).sum();
+        if edit_count == 0 {
+            self.message = Some("No matches.".into());
+            return;
+        }
+        let summary = format!("{} matches in {} files", edit_count, plans.len());
+
+        // Preview.
+        let mut diff = Vec::new();
+        for plan in plans.iter() {
+            diff.push(DiffLine::Same(project_path_name(
+                &self.editor.project.project().directories,
+                &plan.path,
+            )));
+            for (line_idx, old, new) in plan.lines.iter() {
+                diff.push(DiffLine::Removed(format!("{}: {}", line_idx + 1, old)));
+                diff.push(DiffLine::Added(format!("{}: {}", line_idx + 1, new)));
+            }
+        }
+        self.diff_ui_loop(&format!("Replacing {}", summary), &diff);
+        if self.quit {
+            return;
+        }
+        let choices = [format!("Replace {}", summary), "Cancel".to_string()];
+        if self.choice_ui_loop("Make these replacements?", &choices, 1) != Some(0) {
+            return;
+        }
+
+        let errors = apply_replacements(&mut self.editor.buffers, &plans);
+        self.message = Some(match errors.first() {
+            None => format!("Replaced {}.", summary),
+            Some((path, e)) => format!(
+                "Couldn't replace in {} file(s), including {}: {}",
+                errors.len(),
+                path.to_string_lossy(),
+                e
+            ),
+        });
+
+        // The results no longer match the text.
+        self.project_search = None;
+    }
+
+    /// Lets the user pick a file of the project to open, by typing parts of
+    /// its path.  Recently opened files are listed first.
+    fn open_file_ui_loop(&mut self) {
+        let mut cancel = false;
+        let mut query = String::new();
+        let mut selected: usize = 0;
+        let mut picks = FilePicks::new(&self.editor);
+
+        ui_loop!(
+            self,
+
+            // Draw
+            draw {
+                picks.update(&self.editor, &query);
+                self.screen.clear(STYLE_MAIN.1);
+                self.draw_views();
+
+                // Draw the list over the current view, below its info line,
+                // as many entries as fit.
+                let (c1, c2) = self.view_rects()[self.editor.focus];
+                let width = c2.1 - c1.1 + 1;
+                let count = picks.matches.len();
+                selected = selected.min(count.saturating_sub(1));
+                let rows = count.min(c2.0 - c1.0);
+                let first = (selected + 1).saturating_sub(rows);
+                for (row, (idx, m)) in picks.matches[first..(first + rows)].iter().enumerate() {
+                    let style = if first + row == selected {
+                        STYLE_CURSOR
+                    } else {
+                        STYLE_INFO
+                    };
+                    let y = c1.0 + row + 1;
+                    for i in c1.1..(c2.1 + 1) {
+                        self.screen.draw(i, y, " ", style);
+                    }
+
+                    // The path with the matched chars highlighted, cut off
+                    // at the edge of the view.
+                    for (i, c) in picks.names[*idx].chars().take(width - 1).enumerate() {
+                        let style = if m.positions.binary_search(&i).is_ok() {
+                            STYLE_SEARCH_MATCH
+                        } else {
+                            style
+                        };
+                        self.screen.draw(c1.1 + 1 + i, y, &c.to_string(), style);
+                    }
+                }
+
+                let (x, y) = self.draw_prompt("Open file: ");
+                self.screen.draw(x, y, &query, STYLE_INFO);
+                let status = format!(
+                    "   {}/{} files{}   [Up/Down: move, Enter: open, Esc: cancel]",
+                    count,
+                    picks.paths.len(),
+                    if !self.editor.project.is_indexed() {
+                        ", indexing"
+                    } else if self.editor.project.is_truncated() {
+                        ", too many to list all"
+                    } else {
+                        ""
+                    },
+                );
+                self.screen.draw(x + char_count(&query), y, &status, STYLE_INFO);
+                self.screen.set_cursor(x + char_count(&query), y);
+            },
+
+            // Handle input
+            key_press(key) {
+                let page = self.editor.view().view_dim.0.max(1);
+                let count = picks.matches.len();
+                let mut state_changed = true;
+                match key {
+                    KeyEvent {
+                        code: KeyCode::Char('q'),
+                        modifiers: KeyModifiers::CONTROL,
+                    } => {
+                        self.request_quit();
+                        cancel = true;
+                        return (LoopStatus::Done, true);
+                    }
+
+                    KeyEvent {
+                        code: KeyCode::Esc,
+                        modifiers: EMPTY_MOD,
+                    } => {
+                        cancel = true;
+                        return (LoopStatus::Done, true);
+                    }
+
+                    KeyEvent {
+                        code: KeyCode::Enter,
+                        modifiers: EMPTY_MOD,
+                    } => {
+                        // Keys can come in faster than the list is redrawn.
+                        picks.update(&self.editor, &query);
+                        if selected < picks.matches.len() {
+                            return (LoopStatus::Done, true);
+                        }
+                        state_changed = false;
+                    }
+
+                    KeyEvent {
+                        code: KeyCode::Up,
+                        modifiers: EMPTY_MOD,
+                    } => {
+                        selected = selected.saturating_sub(1);
+                    }
+
+                    KeyEvent {
+                        code: KeyCode::Down,
+                        modifiers: EMPTY_MOD,
+                    } => {
+                        if selected + 1 < count {
+                            selected += 1;
+                        }
+                    }
+
+                    KeyEvent {
+                        code: KeyCode::PageUp,
+                        modifiers: EMPTY_MOD,
+                    } => {
+                        selected = selected.saturating_sub(page);
+                    }
+
+                    KeyEvent {
+                        code: KeyCode::PageDown,
+                        modifiers: EMPTY_MOD,
+                    } => {
+                        selected = (selected + page).min(count.saturating_sub(1));
+                    }
+
+                    KeyEvent {
+                        code: KeyCode::Backspace,
+                        modifiers: EMPTY_MOD,
+                    } => {
+                        query.pop();
+                        selected = 0;
+                    }
+
+                    // Character
+                    KeyEvent {
+                        code: KeyCode::Char(c),
+                        modifiers: EMPTY_MOD,
+                    }
+                    | KeyEvent {
+                        code: KeyCode::Char(c),
+                        modifiers: KeyModifiers::SHIFT,
+                    } => {
+                        query.push(c);
+                        selected = 0;
+                    }
+
+                    _ => {
+                        state_changed = false;
+                    }
+                }
+
+                return (LoopStatus::Continue, state_changed);
+            }
+        );
+
+        if !cancel {
+            let path = picks.paths[picks.matches[selected].0].clone();
+            if let Err(e) = self.editor.open_file(&path) {
+                self.message = Some(format!("Couldn't open {}: {}", path.to_string_lossy(), e));
+            }
+        }
+    }
+
+    /// Lets the user pick the encoding the current buffer is saved in.
+    fn encoding_ui_loop(&mut self) {
+        // The whole text needs to be there to check that it's encodable.
+        if self.editor.buffer().loading.is_some() {
+            self.message = Some("Can't change the encoding until the file is loaded.".into());
+            return;
+        }
+
+        // Each encoding, with and without a byte order mark where it has
+        // one.  UTF-16 is rarely seen without one, so that's left out.
+        let choices: Vec<(Encoding, bool)> = ALL_ENCODINGS
+            .iter()
+            .flat_map(|&e| match e {
+                Encoding::Utf8 => vec![(e, false), (e, true)],
+                Encoding::Utf16Le | Encoding::Utf16Be => vec![(e, true)],
+                _ => vec![(e, false)],
+            })
+            .collect();
+        let current = {
+            let buffer = self.editor.buffer();
+            (buffer.encoding, buffer.has_bom)
+        };
+        let names: Vec<String> = choices
+            .iter()
+            .map(|&(encoding, has_bom)| encoding_name(encoding, has_bom))
+            .collect();
+        let selected = choices.iter().position(|&c| c == current).unwrap_or(0);
+
+        let (encoding, has_bom) = match self.choice_ui_loop("Save with encoding:", &names, selected)
+        {
+            Some(idx) if choices[idx] != current => choices[idx],
+            _ => return,
+        };
+        let buf_idx = self.editor.view().buf_idx;
+        if let Err(c) = self.editor.buffers[buf_idx].set_encoding(encoding, has_bom) {
+            self.message = Some(format!(
+                "Can't save in {}: the text contains {:?}, which it can't represent.",
+                encoding.name(),
+                c
+            ));
+        }
+    }
+
+    fn line_ending_ui_loop(&mut self) {
+        let names: Vec<String> = ALL_LINE_ENDINGS
+            .iter()
+            .map(|&ending| match ending {
+                LineEnding::None => "None (join all lines)".into(),
+                _ => line_ending_name(ending).into(),
+            })
+            .collect();
+        let current = self.editor.view().line_ending_type;
+        let selected = ALL_LINE_ENDINGS
+            .iter()
+            .position(|&e| e == current)
+            .unwrap_or(0);
+
+        if let Some(idx) = self.choice_ui_loop("Convert line endings to:", &names, selected) {
+            self.editor.convert_line_endings(ALL_LINE_ENDINGS[idx]);
+        }
+    }
+
+    fn indentation_ui_loop(&mut self) {
+        let choices: Vec<(bool, usize)> = [false, true]
+            .iter()
+            .flat_map(|&soft_tabs| (1..=8).map(move |width| (soft_tabs, width)))
+            .collect();
+        let names: Vec<String> = choices
+            .iter()
+            .map(|&(soft_tabs, width)| {
+                let kind = if soft_tabs { "spaces" } else { "tabs" };
+                format!("{}:{}", kind, width)
+            })
+            .collect();
+        let (current, from_width) = {
+            let view = self.editor.view();
+            let width = view.soft_tab_width as usize;
+            ((view.soft_tabs, width), width)
+        };
+        let selected = choices.iter().position(|&c| c == current).unwrap_or(0);
+
+        let prompt = "Re-indent selected lines (or all) with:";
+        if let Some(idx) = self.choice_ui_loop(prompt, &names, selected) {
+            let (soft_tabs, width) = choices[idx];
+            self.editor
+                .convert_indentation(from_width, soft_tabs, width);
+        }
+    }
+
+    /// Lets the user pick one of the given choices from a list drawn over
+    /// the current view, starting with the `selected` one.
+    ///
+    /// Returns the index of the chosen one, or `None` if cancelled.
+    fn choice_ui_loop(
+        &mut self,
+        prompt: &str,
+        choices: &[String],
+        selected: usize,
+    ) -> Option<usize> {
+        let mut cancel = false;
+        let mut selected = selected;
+        let prompt = format!("{}   [Up/Down: move, Enter: choose, Esc: cancel]", prompt);
+
+        ui_loop!(
+            self,
+
+            // Draw
+            draw {
+                self.screen.clear(STYLE_MAIN.1);
+                self.draw_views();
+
+                // Draw the list over the current view, below its info line.
+                let (c1, c2) = self.view_rects()[self.editor.focus];
+                let rows = choices.len().min(c2.0 - c1.0);
+                let first = (selected + 1).saturating_sub(rows);
+                for (row, idx) in (first..(first + rows)).enumerate() {
+                    let style = if idx == selected {
+                        STYLE_CURSOR
+                    } else {
+                        STYLE_INFO
+                    };
+                    for i in c1.1..(c2.1 + 1) {
+                        self.screen.draw(i, c1.0 + row + 1, " ", style);
+                    }
+                    self.screen.draw(c1.1 + 1, c1.0 + row + 1, &choices[idx][..], style);
+                }
+
+                self.draw_prompt(&prompt);
+            },
+
+            // Handle input
+            key_press(key) {
+                let mut state_changed = true;
+                match key {
+                    KeyEvent {
+                        code: KeyCode::Char('q'),
+                        modifiers: KeyModifiers::CONTROL,
+                    } => {
+                        self.request_quit();
+                        cancel = true;
+                        return (LoopStatus::Done, true);
+                    }
+
+                    KeyEvent {
+                        code: KeyCode::Esc,
+                        modifiers: EMPTY_MOD,
+                    } => {
+                        cancel = true;
+                        return (LoopStatus::Done, true);
+                    }
+
+                    KeyEvent {
+                        code: KeyCode::Enter,
+                        modifiers: EMPTY_MOD,
+                    } => {
+                        return (LoopStatus::Done, true);
+                    }
+
+                    KeyEvent {
+                        code: KeyCode::Up,
+                        modifiers: EMPTY_MOD,
+                    } => {
+                        selected = selected.saturating_sub(1);
+                    }
+
+                    KeyEvent {
+                        code: KeyCode::Down,
+                        modifiers: EMPTY_MOD,
+                    } => {
+                        if selected + 1 < choices.len() {
+                            selected += 1;
+                        }
+                    }
+
+                    _ => {
+                        state_changed = false;
+                    }
+                }
+
+                return (LoopStatus::Continue, state_changed);
+            }
+        );
+
+        if cancel {
+            None
+        } else {
+            Some(selected)
+        }
+    }
+
+    /// Asks the user what to do about a file that was modified outside the
+    /// editor while its buffer has unsaved changes.
+    fn disk_change_ui_loop(&mut self, disk_change: DiskChange) {
+        let buf_idx = disk_change.buf_idx;
+        let name = buffer_name(&self.editor.buffers[buf_idx]);
+
+        ui_loop!(
+            self,
+
+            // Draw
+            draw {
+                self.screen.clear(STYLE_MAIN.1);
+                self.draw_views();
+                let line = format!(
+                    "{} changed on disk, but has unsaved changes.   [R: reload, K: keep mine, D: show diff]",
+                    name,
+                );
+                self.draw_prompt(&line);
+            },
+
+            // Handle input
+            key_press(key) {
+                let mut state_changed = true;
+                match key {
+                    KeyEvent {
+                        code: KeyCode::Char('q'),
+                        modifiers: KeyModifiers::CONTROL,
+                    } => {
+                        self.request_quit();
+                        return (LoopStatus::Done, true);
+                    }
+
+                    KeyEvent {
+                        code: KeyCode::Char('r'),
+                        modifiers: EMPTY_MOD,
+                    } => {
+                        self.editor.buffers[buf_idx]
+                            .reload(disk_change.file.clone());
+                        return (LoopStatus::Done, true);
+                    }
+
+                    // Keeping our version means the file on disk is now
+                    // the one we're out of sync with, so we don't ask again
+                    // until it changes again.
+                    KeyEvent {
+                        code: KeyCode::Char('k'),
+                        modifiers: EMPTY_MOD,
+                    }
+                    | KeyEvent {
+                        code: KeyCode::Esc,
+                        modifiers: EMPTY_MOD,
+                    } => {
+                        self.editor.buffers[buf_idx].disk_state = Some(disk_change.file.disk_state);
+                        return (LoopStatus::Done, true);
+                    }
+
+                    KeyEvent {
+                        code: KeyCode::Char('d'),
+                        modifiers: EMPTY_MOD,
+                    } => {
+                        let diff = line_diff(&self.editor.buffers[buf_idx].text, &disk_change.file.text);
+                        self.diff_ui_loop(&format!("{}: -mine +on disk", name), &diff);
+                    }
+
+                    _ => {
+                        state_changed = false;
+                    }
+                }
+
+                return (LoopStatus::Continue, state_changed);
+            }
+        );
+    }
+
+    /// Shows a diff over the current view, scrollable until dismissed.
+    fn diff_ui_loop(&mut self, title: &str, diff: &[DiffLine]) {
+        // Start a few lines above the first change.
+        let mut scroll = diff
+            .iter()
+            .position(|line| !matches!(line, DiffLine::Same(_)))
+            .unwrap_or(0)
+            .saturating_sub(3);
+
+        ui_loop!(
+            self,
+
+            // Draw
+            draw {
+                self.screen.clear(STYLE_MAIN.1);
+                self.draw_views();
+
+                // Draw the diff over the current view, below its info line.
+                let (c1, c2) = self.view_rects()[self.editor.focus];
+                let width = c2.1 - c1.1 + 1;
+                for row in 0..(c2.0 - c1.0) {
+                    let y = c1.0 + row + 1;
+                    for i in c1.1..(c2.1 + 1) {
+                        self.screen.draw(i, y, " ", STYLE_MAIN);
+                    }
+                    let (prefix, text, style) = match diff.get(scroll + row) {
+                        Some(DiffLine::Same(text)) => ("  ", text, STYLE_MAIN),
+                        Some(DiffLine::Removed(text)) => ("- ", text, STYLE_DIFF_REMOVED),
+                        Some(DiffLine::Added(text)) => ("+ ", text, STYLE_DIFF_ADDED),
+                        None => continue,
+                    };
+                    let line: String = prefix
+                        .chars()
+                        .chain(
+                            text.trim_end_matches(|c: char| is_line_ending(&c.to_string()))
+                                .chars(),
+                        )
+                        .map(|c| if c == '\t' { ' ' } else { c })
+                        .take(width)
+                        .collect();
+                    self.screen.draw(c1.1, y, &line, style);
+                }
+
+                let line = format!(
+                    "{}   [Up/Down/PageUp/PageDown: scroll, Esc: back]",
+                    title,
+                );
+                self.draw_prompt(&line);
+            },
+
+            // Handle input
+            key_press(key) {
+                let page = self.editor.view().view_dim.0.max(1);
+                let max_scroll = diff.len().saturating_sub(1);
+                let mut state_changed = true;
+                match key {
+                    KeyEvent {
+                        code: KeyCode::Char('q'),
+                        modifiers: KeyModifiers::CONTROL,
+                    } => {
+                        self.request_quit();
+                        return (LoopStatus::Done, true);
+                    }
+
+                    KeyEvent {
+                        code: KeyCode::Esc,
+                        modifiers: EMPTY_MOD,
+                    }
+                    | KeyEvent {
+                        code: KeyCode::Enter,
+                        modifiers: EMPTY_MOD,
+                    } => {
+                        return (LoopStatus::Done, true);
+                    }
+
+                    KeyEvent {
+                        code: KeyCode::Up,
+                        modifiers: EMPTY_MOD,
+                    } => {
+                        scroll = scroll.saturating_sub(1);
+                    }
+
+                    KeyEvent {
+                        code: KeyCode::Down,
+                        modifiers: EMPTY_MOD,
+                    } => {
+                        scroll = (scroll + 1).min(max_scroll);
+                    }
+
+                    KeyEvent {
+                        code: KeyCode::PageUp,
+                        modifiers: EMPTY_MOD,
+                    } => {
+                        scroll = scroll.saturating_sub(page);
+                    }
+
+                    KeyEvent {
+                        code: KeyCode::PageDown,
+                        modifiers: EMPTY_MOD,
+                    } => {
+                        scroll = (scroll + page).min(max_scroll);
+                    }
+
+                    _ => {
+                        state_changed = false;
+                    }
+                }
+
+                return (LoopStatus::Continue, state_changed);
+            }
+        );
+    }
+
+    /// Returns the screen rectangle of each view, in view order.
+    fn view_rects(&self) -> Vec<((usize, usize), (usize, usize))> {
+        layout(
+            self.editor.views.len(),
+            (self.height, self.width),
+            &self.layout_config,
+        )
+    }
+
+    /// Updates the editor's view dimensions to match the current layout.
+    fn update_view_dims(&mut self) {
+        for (view_idx, (c1, c2)) in self.view_rects().into_iter().enumerate() {
+            self.editor
+                .update_dim(view_idx, c2.0 - c1.0, c2.1 - c1.1 + 1);
+        }
+    }
+
+    /// Draws all of the editor's views.
+    fn draw_views(&self) {
+        for (view_idx, (c1, c2)) in self.view_rects().into_iter().enumerate() {
+            self.draw_editor(&self.editor, view_idx, c1, c2);
+        }
+    }
+
+    /// Does the work that's polled for between input events, and returns
+    /// whether anything changed that needs redrawing.
+    fn poll_background(&mut self) -> bool {
+        if self.quit_signal.load(Ordering::Relaxed) && !self.quit {
+            self.quit_without_asking();
+            return false;
+        }
+
+        let buf_idx = self.editor.view().buf_idx;
+        if let Some(delay) = self.auto_save_delay {
+            if buf_idx != self.focused_buf_idx && self.focused_buf_idx < self.editor.buffers.len() {
+                self.editor.save_buffer(self.focused_buf_idx);
+            }
+            if !self.auto_saved && self.last_input.elapsed() >= delay {
+                self.editor.save_all();
+                self.auto_saved = true;
+            }
+        }
+        self.focused_buf_idx = buf_idx;
+
+        let results = self.editor.buffers.finish_saves();
+        for result in results.iter() {
+            if let Err(ref e) = result.result {
+                self.message = Some(format!(
+                    "Couldn't save {}: {}",
+                    result.path.to_string_lossy(),
+                    e
+                ));
+            }
+        }
+        let mut changed = !results.is_empty();
+        if self.last_session_check.elapsed() >= SESSION_SAVE_INTERVAL {
+            changed |= self.update_session();
+            self.last_session_check = Instant::now();
+        }
+        changed |= self.editor.update_line_ending_status();
+        changed |= self.editor.update_search_matches();
+        if let Some(ref mut search) = self.project_search {
+            changed |= search.poll();
+        }
+
+        // Append text from files being loaded in the background.
+        for event in self.editor.buffers.poll_loads() {
+            changed = true;
+            match event {
+                LoadEvent::Text { buf_idx, first } => {
+                    if first {
+                        self.editor.redetect_buffer_style(buf_idx);
+                        if self.editor.buffers[buf_idx].read_only {
+                            self.message = Some(format!(
+                                "{} looks binary, so it's read-only.  Alt-R to allow editing.",
+                                buffer_name(&self.editor.buffers[buf_idx])
+                            ));
+                        }
+                    }
+                }
+                LoadEvent::Finished {
+                    buf_idx,
+                    result: Err(e),
+                } => {
+                    self.message = Some(format!(
+                        "Couldn't finish loading {}, so it's read-only: {}",
+                        buffer_name(&self.editor.buffers[buf_idx]),
+                        e
+                    ));
+                }
+                LoadEvent::Finished { .. } => {}
+            }
+        }
+        if changed {
+            // The line count, and with it the gutter width, may have
+            // changed.
+            self.update_view_dims();
+        }
+
+        // Pick up files added to or removed from the project.
+        changed |= self.editor.project.poll();
+        self.editor.project.rescan_if_due();
+
+        // Check for files modified outside the editor.  Unmodified buffers
+        // are reloaded automatically, but for modified ones we have to ask.
+        if !self.in_disk_change_prompt && self.last_disk_check.elapsed() >= DISK_CHECK_INTERVAL {
+            let (reloaded, disk_changes) = self.editor.buffers.check_disk_changes();
+            changed |= !reloaded.is_empty();
+
+            self.in_disk_change_prompt = true;
+            for disk_change in disk_changes {
+                self.disk_change_ui_loop(disk_change);
+                changed = true;
+            }
+            self.in_disk_change_prompt = false;
+
+            self.last_disk_check = Instant::now();
+        }
+
+        changed
+    }
+
+    /// Quits the editor, first saving any unsaved edits so they can be
+    /// restored the next time it starts.
+    ///
+    /// If the edits can't be saved, the user is warned instead, and has to
+    /// request quitting a second time to quit anyway.
+    fn request_quit(&mut self) {
+        if self.auto_save_delay.is_some() {
+            self.editor.save_all();
+        }
+
+        // Let saves in progress finish first, so that the session only
+        // holds edits that didn't make it to disk.  Failed saves are
+        // covered by the session, so they needn't hold up quitting.
+        self.editor.buffers.wait_for_saves();
+        self.wait_for_session_save();
+
+        match self.editor.save_session() {
+            Ok(()) => self.quit = true,
+            Err(_) if self.quit_warned => self.quit = true,
+            Err(e) => {
+                self.quit_warned = true;
+                self.message = Some(format!(
+                    "Couldn't save unsaved edits ({}).  Ctrl-Q again to quit anyway.",
+                    e
+                ));
+            }
+        }
+    }
+
+    /// Quits the editor right away, saving any unsaved edits so they can
+    /// be restored the next time it starts, but without saving files or
+    /// telling the user about errors.
+    fn quit_without_asking(&mut self) {
+        // Saves in progress still need to finish, so that the session
+        // doesn't restore edits that did make it to disk.
+        self.editor.buffers.wait_for_saves();
+        self.wait_for_session_save();
+        self.editor.save_session().ok();
+        self.quit = true;
+    }
+
+    /// Starts saving the session in the background if there are unsaved
+    /// edits that the session file doesn't have yet, and returns whether
+    /// there's a new message for the user.
+    ///
+    /// Does nothing while a previous save is still in progress.
+    fn update_session(&mut self) -> bool {
+        let mut changed = false;
+        match self.session_save.take() {
+            Some(save) if !save.is_finished() => {
+                self.session_save = Some(save);
+                return false;
+            }
+            Some(save) => {
+                if let Ok(Err(e)) = save.join() {
+                    // Try again next time.
+                    self.session_key = None;
+                    self.message = Some(format!("Couldn't save unsaved edits: {}", e));
+                    changed = true;
+                }
+            }
+            None => {}
+        }
+
+        let session = self.editor.session();
+        let key = session.key();
+        if self.session_key.as_ref() != Some(&key) {
+            self.session_key = Some(key);
+            self.session_save = Some(thread::spawn(move || session.save()));
+        }
+        changed
+    }
+
+    /// Blocks until the background save of the session, if any, has
+    /// finished.
+    fn wait_for_session_save(&mut self) {
+        if let Some(save) = self.session_save.take() {
+            save.join().ok();
+        }
+    }
+
+    /// Draws a prompt over the info line of the current view, and returns
+    /// the screen position just after the prompt text.
+    fn draw_prompt(&self, text: &str) -> (usize, usize) {
+        let (c1, c2) = self.view_rects()[self.editor.focus];
+        for i in c1.1..(c2.1 + 1) {
+            self.screen.draw(i, c1.0, " ", STYLE_INFO);
+        }
+        self.screen.draw(c1.1 + 1, c1.0, text, STYLE_INFO);
+        (c1.1 + 1 + char_count(text), c1.0)
+    }
+
+    fn draw_editor(
+        &self,
+        editor: &Editor,
+        view_idx: usize,
+        c1: (usize, usize),
+        c2: (usize, usize),
+    ) {
+        let view = &editor.views[view_idx];
+        let buffer = &editor.buffers[view.buf_idx];
+        let style_info = if view_idx == editor.focus {
+            STYLE_INFO
+        } else {
+            STYLE_INFO_UNFOCUSED
+        };
+
+        // Fill in top row with info line color
+        for i in c1.1..(c2.1 + 1) {
+            self.screen.draw(i, c1.0, " ", style_info);
+        }
+
+        // Filename and dirty marker, preceded by the buffer's position in
+        // the buffer list if there's more than one.
+        let name = if editor.buffers.len() > 1 {
+            format!(
+                "[{}/{}] {}",
+                view.buf_idx + 1,
+                editor.buffers.len(),
+                buffer_name(buffer),
+            )
+        } else {
+            buffer_name(buffer)
+        };
+        self.screen.draw(c1.1 + 1, c1.0, &name[..], style_info);
+
+        // Percentage position in document
+        // TODO: use view instead of cursor for calculation if there is more
+        // than one cursor.
+        let percentage: usize = if buffer.text.len_chars() > 0 {
+            (((buffer.mark_sets[view.c_msi].main().unwrap().head as f32)
+                / (buffer.text.len_chars() as f32))
+                * 100.0) as usize
+        } else {
+            100
+        };
+        let pstring = format!("{}%", percentage);
+        self.screen.draw(
+            c2.1.saturating_sub(pstring.len()),
+            c1.0,
+            &pstring[..],
+            style_info,
+        );
+
+        // Text encoding info and tab style
+        let nl = if view.mixed_line_endings {
+            format!("{} (mixed)", line_ending_name(view.line_ending_type))
+        } else {
+            line_ending_name(view.line_ending_type).into()
+        };
+        let soft_tabs_str = if view.soft_tabs { "spaces" } else { "tabs" };
+        let search_str = match editor.search_position(view_idx) {
+            Some(position) => format!("{}  ", format_search_position(position)),
+            None => String::new(),
+        };
+        let info_line = format!(
+            "{}{}:{}  {}:{}",
+            search_str,
+            encoding_name(buffer.encoding, buffer.has_bom),
+            nl,
+            soft_tabs_str,
+            view.soft_tab_width as usize
+        );
+        self.screen.draw(
+            c2.1.saturating_sub(30.max(info_line.len() + 6)),
+            c1.0,
+            &info_line[..],
+            style_info,
+        );
 
         // Draw main text editing area
-        self.draw_editor_text(editor, (c1.0 + 1, c1.1), c2);
+        self.draw_editor_text(editor, view_idx, (c1.0 + 1, c1.1), c2);
     }
 
-    fn draw_editor_text(&self, editor: &Editor, c1: (usize, usize), c2: (usize, usize)) {
-        let view_pos = editor.buffer.mark_sets[editor.v_msi][0].head;
-        let cursors = &editor.buffer.mark_sets[editor.c_msi];
+    fn draw_editor_text(
+        &self,
+        editor: &Editor,
+        view_idx: usize,
+        c1: (usize, usize),
+        c2: (usize, usize),
+    ) {
+        let view = &editor.views[view_idx];
+        let buffer = &editor.buffers[view.buf_idx];
+        let view_pos = buffer.mark_sets[view.v_msi][0].head;
+        let cursors = &buffer.mark_sets[view.c_msi];
+        let search_matches = editor.search_matches(view.buf_idx);
+
+        // Only the current view gets the terminal's cursor.
+        let is_focused = view_idx == editor.focus;
 
         // Calculate all the starting info
-        let gutter_width = editor.editor_dim.1 - editor.view_dim.1;
+        let gutter_width = view.editor_dim.1 - view.view_dim.1;
         let blank_gutter = &"                "[..gutter_width - 1];
-        let line_index = editor.buffer.text.char_to_line(view_pos);
+        let line_index = buffer.text.char_to_line(view_pos);
 
-        let (blocks_iter, char_offset) = editor.formatter.iter(&editor.buffer.text, view_pos);
+        let (blocks_iter, char_offset) = view.formatter.iter(&buffer.text, view_pos);
 
         let vis_line_offset = blocks_iter.clone().next().unwrap().0.vpos(char_offset);
 
@@ -542,6 +2634,7 @@ impl TermUI {
         let mut is_first_loop = true;
         let mut line_num = line_index + 1;
         let mut char_index = view_pos - char_offset;
+        let mut match_idx = search_matches.partition_point(|m| m.end <= char_index);
         for (block_vis_iter, is_line_start) in blocks_iter {
             if is_line_start && !is_first_loop {
                 line_num += 1;
@@ -592,26 +2685,48 @@ impl TermUI {
 
                 // Draw the grapheme to the screen if it's in bounds
                 if (px >= c1.1 as isize) && (py >= c1.0 as isize) && (px <= c2.1 as isize) {
-                    // Check if the character is within a cursor
+                    // Check if the character is at a cursor head or within
+                    // a selection.
                     let mut at_cursor = false;
+                    let mut in_selection = false;
                     for c in cursors.iter() {
-                        if char_index >= c.range().start && char_index <= c.range().end {
+                        if char_index == c.head {
                             at_cursor = true;
-                            self.screen.set_cursor(px as usize, py as usize);
+                            if is_focused {
+                                self.screen.set_cursor(px as usize, py as usize);
+                            }
+                        } else if char_index >= c.range().start && char_index < c.range().end {
+                            in_selection = true;
                         }
                     }
+                    while match_idx < search_matches.len()
+                        && search_matches[match_idx].end <= char_index
+                    {
+                        match_idx += 1;
+                    }
+                    let in_match = match_idx < search_matches.len()
+                        && search_matches[match_idx].start <= char_index;
+                    let style = if in_selection {
+                        STYLE_SELECTION
+                    } else if in_match {
+                        STYLE_SEARCH_MATCH
+                    } else {
+                        STYLE_MAIN
+                    };
 
                     // Actually print the character
                     if is_line_ending(&g) {
                         if at_cursor {
                             self.screen
                                 .draw(px as usize, py as usize, " ", STYLE_CURSOR);
+                        } else if in_selection {
+                            self.screen.draw(px as usize, py as usize, " ", style);
                         }
                     } else if g == "\t" {
                         for i in 0..width {
                             let tpx = px as usize + i;
                             if tpx <= c2.1 {
-                                self.screen.draw(tpx as usize, py as usize, " ", STYLE_MAIN);
+                                self.screen.draw(tpx, py as usize, " ", style);
                             }
                         }
 
@@ -619,11 +2734,27 @@ impl TermUI {
                             self.screen
                                 .draw(px as usize, py as usize, " ", STYLE_CURSOR);
                         }
+                    } else if let Some(byte) = g.chars().next().and_then(char_to_raw_byte) {
+                        // Bytes that aren't valid utf8 are drawn as escapes.
+                        let escape = format!("\\x{:02X}", byte);
+                        let escape_style = Style(STYLE_ESCAPE.0, style.1);
+                        for i in 0..escape.len() {
+                            let tpx = px as usize + i;
+                            if tpx <= c2.1 {
+                                let style = if at_cursor && i == 0 {
+                                    STYLE_CURSOR
+                                } else {
+                                    escape_style
+                                };
+                                self.screen
+                                    .draw(tpx, py as usize, &escape[i..(i + 1)], style);
+                            }
+                        }
                     } else {
                         if at_cursor {
                             self.screen.draw(px as usize, py as usize, &g, STYLE_CURSOR);
                         } else {
-                            self.screen.draw(px as usize, py as usize, &g, STYLE_MAIN);
+                            self.screen.draw(px as usize, py as usize, &g, style);
                         }
                     }
                 }
@@ -638,20 +2769,14 @@ impl TermUI {
         // without going off the bottom of the screen.  So draw the cursor
         // at the end if needed.
 
-        // Check if the character is within a cursor
-        let mut at_cursor = false;
-        for c in cursors.iter() {
-            if char_index >= c.range().start && char_index <= c.range().end {
-                at_cursor = true;
-            }
-        }
+        // Check if there's a cursor head at the end
+        let at_cursor = cursors.iter().any(|c| c.head == char_index);
 
         if at_cursor {
             // Calculate the cell coordinates at which to draw the cursor
-            let pos_x = editor.formatter.get_horizontal(
-                &self.editor.buffer.text,
-                self.editor.buffer.text.len_chars(),
-            );
+            let pos_x = view
+                .formatter
+                .get_horizontal(&buffer.text, buffer.text.len_chars());
             let mut px = pos_x as isize + screen_col;
             let mut py = screen_line - 1;
             if px > c2.1 as isize {
@@ -666,8 +2791,159 @@ impl TermUI {
             {
                 self.screen
                     .draw(px as usize, py as usize, " ", STYLE_CURSOR);
-                self.screen.set_cursor(px as usize, py as usize);
+                if is_focused {
+                    self.screen.set_cursor(px as usize, py as usize);
+                }
+            }
+        }
+    }
+}
+
+/// Returns the name to display for a buffer: its file path or scratch
+/// number, followed by a `*` if it has unsaved changes.
+fn buffer_name(buffer: &Buffer) -> String {
+    let filename = match buffer.path {
+        BufferPath::File(ref p) => format!("{}", p.display()),
+        BufferPath::Temp(i) => format!("Scratch #{}", i + 1),
+    };
+    let dirty_char = if buffer.is_dirty { "*" } else { "" };
+    let status = if let Some(progress) = buffer.loading {
+        format!(" [loading {}%]", progress.percentage())
+    } else if buffer.read_only {
+        " [read-only]".into()
+    } else {
+        String::new()
+    };
+    format!("{}{}{}", filename, dirty_char, status)
+}
+
+/// Formats the position of the main cursor among the current search's
+/// matches, as returned by `Editor::search_position()`, e.g. "3/17".
+/// The files the open file picker picks from, and the ones matching what's
+/// been typed so far.
+struct FilePicks {
+    // The recently opened files other than the current one, followed by
+    // the rest of the project's files, and how each is shown and matched
+    // against.
+    paths: Vec<PathBuf>,
+    names: Vec<String>,
+    recent_count: usize,
+
+    // The project index's `change_count()` as of `paths`, to notice
+    // when it changes.
+    index_change_count: u64,
+
+    // The matches of `query`, as indices into `paths`, recent files first.
+    // `None` if nothing has been matched against the current `paths` yet.
+    query: Option<String>,
+    matches: Vec<(usize, FuzzyMatch)>,
+}
+
+impl FilePicks {
+    fn new(editor: &Editor) -> FilePicks {
+        let current = match editor.buffer().path {
+            BufferPath::File(ref path) => {
+                Some(fs::canonicalize(path).unwrap_or_else(|_| path.clone()))
             }
+            BufferPath::Temp(_) => None,
+        };
+        let recent: Vec<PathBuf> = editor
+            .recent_files()
+            .iter()
+            .filter(|path| Some(*path) != current.as_ref())
+            .cloned()
+            .collect();
+        let skip: HashSet<&PathBuf> = recent.iter().chain(current.iter()).collect();
+        let paths: Vec<PathBuf> = recent
+            .iter()
+            .chain(
+                editor
+                    .project
+                    .files()
+                    .iter()
+                    .filter(|path| !skip.contains(path)),
+            )
+            .cloned()
+            .collect();
+
+        let directories = &editor.project.project().directories;
+        FilePicks {
+            names: paths
+                .iter()
+                .map(|path| project_path_name(directories, path))
+                .collect(),
+            recent_count: recent.len(),
+            paths,
+            index_change_count: editor.project.change_count(),
+            query: None,
+            matches: Vec::new(),
+        }
+    }
+
+    /// Brings the paths up to date with the project's index, and the
+    /// matches up to date with `query`.
+    fn update(&mut self, editor: &Editor, query: &str) {
+        if editor.project.change_count() != self.index_change_count {
+            *self = FilePicks::new(editor);
         }
+
+        // Adding to the query can only narrow down the matches, so then
+        // only the previous matches need matching again.
+        self.matches = match self.query {
+            Some(ref prev) if prev == query => return,
+            Some(ref prev) if query.starts_with(prev.as_str()) => {
+                let matches = std::mem::take(&mut self.matches);
+                filter(query, &self.names, matches.into_iter().map(|(idx, _)| idx))
+            }
+            _ => filter(query, &self.names, 0..self.paths.len()),
+        };
+        let recent_count = self.recent_count;
+        self.matches.sort_by_key(|(idx, _)| *idx >= recent_count);
+        self.query = Some(query.into());
+    }
+}
+
+/// Returns how to show `path` in lists of project files: relative to the
+/// first of `directories` it's in, if any.
+fn project_path_name(directories: &[std::path::PathBuf], path: &Path) -> String {
+    directories
+        .iter()
+        .find_map(|dir| path.strip_prefix(dir).ok())
+        .unwrap_or(path)
+        .to_string_lossy()
+        .into()
+}
+
+fn format_search_position((idx, count): (Option<usize>, usize)) -> String {
+    match idx {
+        Some(idx) => format!("{}/{}", idx + 1, count),
+        None => format!("-/{}", count),
+    }
+}
+
+/// Returns the name to display for an encoding, noting the byte order
+/// mark if there is one.
+fn encoding_name(encoding: Encoding, has_bom: bool) -> String {
+    if has_bom {
+        format!("{} BOM", encoding.name())
+    } else {
+        encoding.name().into()
+    }
+}
+
+/// Formats how long ago the given time was, e.g. "5m ago".
+fn format_age(time: SystemTime) -> String {
+    let secs = SystemTime::now()
+        .duration_since(time)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    if secs < 60 {
+        format!("{}s ago", secs)
+    } else if secs < 60 * 60 {
+        format!("{}m ago", secs / 60)
+    } else if secs < 60 * 60 * 24 {
+        format!("{}h ago", secs / (60 * 60))
+    } else {
+        format!("{}d ago", secs / (60 * 60 * 24))
     }
 }
@@ -1,4 +1,3 @@
-use std;
 use std::cell::{Cell, RefCell};
 use std::io;
 use std::io::{BufWriter, Write};
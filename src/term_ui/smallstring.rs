@@ -79,15 +79,11 @@ impl SmallString {
         self.buffer.reserve(amt);
 
         ptr::copy(
-            self.buffer.as_ptr().offset(idx as isize),
-            self.buffer.as_mut_ptr().offset((idx + amt) as isize),
+            self.buffer.as_ptr().add(idx),
+            self.buffer.as_mut_ptr().add(idx + amt),
             len - idx,
         );
-        ptr::copy(
-            bytes.as_ptr(),
-            self.buffer.as_mut_ptr().offset(idx as isize),
-            amt,
-        );
+        ptr::copy(bytes.as_ptr(), self.buffer.as_mut_ptr().add(idx), amt);
         self.buffer.set_len(len + amt);
     }
 
@@ -98,8 +94,8 @@ impl SmallString {
         let len = self.len();
         let amt = end - start;
         ptr::copy(
-            self.buffer.as_ptr().offset(end as isize),
-            self.buffer.as_mut_ptr().offset(start as isize),
+            self.buffer.as_ptr().add(end),
+            self.buffer.as_mut_ptr().add(start),
             len - end,
         );
         self.buffer.set_len(len - amt);
@@ -113,7 +109,7 @@ impl std::cmp::PartialEq for SmallString {
     }
 }
 
-impl<'a> PartialEq<SmallString> for &'a str {
+impl PartialEq<SmallString> for &str {
     fn eq(&self, other: &SmallString) -> bool {
         *self == (other as &str)
     }
@@ -137,7 +133,7 @@ impl std::fmt::Debug for SmallString {
     }
 }
 
-impl<'a> From<&'a str> for SmallString {
+impl From<&str> for SmallString {
     fn from(s: &str) -> Self {
         Self::from_str(s)
     }
@@ -1,10 +1,21 @@
-use std::path::PathBuf;
+use std::{
+    collections::VecDeque,
+    fs, io,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
 
 use ropey::Rope;
 
 use crate::{
-    history::{Edit, History},
-    marks::MarkSet,
+    encoding::{decode_detected, looks_binary, Encoding},
+    hash::{hash, LedHash256},
+    history::{History, HistoryMode, HistoryState},
+    loader::LoadProgress,
+    marks::{Mark, MarkSet},
+    saver::SaveJob,
+    state::history_path,
+    transaction::Transaction,
 };
 
 /// A path for an open text buffer.
@@ -17,6 +28,85 @@ pub enum BufferPath {
     Temp(usize),   // A temporary buffer, with a number ID.
 }
 
+/// How many of the most recent text changes a buffer keeps track of.  See
+/// `Buffer::changes_since()`.
+const MAX_RECORDED_CHANGES: usize = 256;
+
+/// A change to a buffer's text: the chars in `start..old_end` were replaced
+/// by the chars now in `start..new_end`.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct TextChange {
+    pub start: usize,
+    pub old_end: usize,
+    pub new_end: usize,
+}
+
+/// The state of a file on disk, as of when a buffer last loaded or saved
+/// it.
+///
+/// The modification time and size are cheap to check, so they're used to
+/// tell when the file may have changed.  The hash tells whether it
+/// actually did.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct DiskState {
+    pub modified: Option<SystemTime>,
+    pub size: u64,
+    pub hash: [u8; 32],
+}
+
+impl DiskState {
+    /// Creates a `DiskState` from the current metadata of the file at
+    /// `path`, and the hash of its contents.
+    pub fn from_file(path: &Path, hash: [u8; 32]) -> io::Result<DiskState> {
+        let metadata = fs::metadata(path)?;
+        Ok(DiskState {
+            modified: metadata.modified().ok(),
+            size: metadata.len(),
+            hash,
+        })
+    }
+
+    /// Returns whether the file at `path` may have changed since this
+    /// state was recorded, judging by its metadata.
+    pub fn may_differ_from_file(&self, path: &Path) -> io::Result<bool> {
+        let metadata = fs::metadata(path)?;
+        Ok(metadata.len() != self.size || metadata.modified().ok() != self.modified)
+    }
+}
+
+/// A file's contents as loaded from disk.
+#[derive(Debug, Clone)]
+pub struct LoadedFile {
+    pub text: Rope,
+    pub disk_state: DiskState,
+    pub encoding: Encoding,
+    pub has_bom: bool,
+    pub is_binary: bool, // Whether the contents look like binary data.
+}
+
+impl LoadedFile {
+    /// Loads the file at `path`, detecting and decoding its encoding.
+    pub fn load(path: &Path) -> io::Result<LoadedFile> {
+        // Get the metadata before reading, so that if the file changes
+        // in-between, it's noticed by the next check for changes.
+        let metadata = fs::metadata(path)?;
+        let bytes = fs::read(path)?;
+        let (text, encoding, has_bom) = decode_detected(&bytes);
+
+        Ok(LoadedFile {
+            disk_state: DiskState {
+                modified: metadata.modified().ok(),
+                size: metadata.len(),
+                hash: hash(text.as_bytes()),
+            },
+            text: Rope::from(text),
+            encoding,
+            has_bom,
+            is_binary: looks_binary(&bytes, encoding),
+        })
+    }
+}
+
 /// An open text buffer, currently being edited.
 #[derive(Debug, Clone)]
 pub struct Buffer {
@@ -24,18 +114,80 @@ pub struct Buffer {
     pub is_dirty: bool,          // Is this buffer currently out of sync with disk.
     pub text: Rope,              // The actual text content.
     pub mark_sets: Vec<MarkSet>, // MarkSets for cursors, view positions, etc.
-    history: History,
+
+    // The state of the file on disk as of when it was last loaded or
+    // saved.  `None` if the buffer has never been on disk.
+    pub disk_state: Option<DiskState>,
+
+    // The encoding the text is saved in, and whether it's saved with a
+    // byte order mark.
+    pub encoding: Encoding,
+    pub has_bom: bool,
+
+    // When set, all edits, undos, and redos are ignored.
+    pub read_only: bool,
+
+    // Set while the file is still being loaded in the background, during
+    // which the buffer can't be edited either.
+    pub loading: Option<LoadProgress>,
+
+    pub(crate) history: History,
+
+    // Incremented on every change to the text, including undo and redo.
+    pub(crate) edit_count: u64,
+
+    // The most recent changes to the text, each with the edit count right
+    // after it, and the edit count since which all changes are recorded.
+    changes: VecDeque<(u64, TextChange)>,
+    changes_recorded_since: u64,
 }
 
 impl Buffer {
     pub fn new(text: Rope, path: BufferPath) -> Buffer {
         Buffer {
-            path: path,
+            path,
             is_dirty: false,
-            text: text,
+            text,
             mark_sets: Vec::new(),
+            disk_state: None,
+            encoding: Encoding::Utf8,
+            has_bom: false,
+            read_only: false,
+            loading: None,
             history: History::new(),
+            edit_count: 0,
+            changes: VecDeque::new(),
+            changes_recorded_since: 0,
+        }
+    }
+
+    /// Returns whether edits to the buffer are currently allowed, i.e.
+    /// it isn't read-only or still loading.
+    pub fn is_editable(&self) -> bool {
+        !self.read_only && self.loading.is_none()
+    }
+
+    /// Returns a count that changes with every change to the text, for
+    /// telling whether it changed since it was last looked at.
+    pub fn edit_count(&self) -> u64 {
+        self.edit_count
+    }
+
+    /// Returns the changes made to the text since the edit count was
+    /// `edit_count`, in the order they were made.
+    ///
+    /// Only the most recent changes are kept track of, so this returns
+    /// `None` if changes since then may have been forgotten.
+    pub fn changes_since(&self, edit_count: u64) -> Option<impl Iterator<Item = TextChange> + '_> {
+        if edit_count < self.changes_recorded_since {
+            return None;
         }
+        Some(
+            self.changes
+                .iter()
+                .filter(move |(count, _)| *count > edit_count)
+                .map(|(_, change)| *change),
+        )
     }
 
     /// Replaces the given range of chars with the given text.
@@ -43,8 +195,6 @@ impl Buffer {
     /// The range does not have to be ordered (i.e. the first component can be
     /// greater than the second).
     pub fn edit(&mut self, char_idx_range: (usize, usize), text: &str) {
-        self.is_dirty = true;
-
         // Get the range, properly ordered.
         let (start, end) = if char_idx_range.0 < char_idx_range.1 {
             (char_idx_range.0, char_idx_range.1)
@@ -52,122 +202,502 @@ impl Buffer {
             (char_idx_range.1, char_idx_range.0)
         };
 
-        // Update undo stack.
-        if char_idx_range.0 == char_idx_range.1 {
-            // Fast-path for insertion-only edits.
-            self.history.push_edit(Edit {
-                char_idx: start,
-                from: String::new(),
-                to: text.into(),
-            });
-        } else {
-            self.history.push_edit(Edit {
-                char_idx: start,
-                from: self.text.slice(start..end).into(),
-                to: text.into(),
-            });
+        let old: String = self.text.slice(start..end).into();
+        self.apply_transaction(Transaction::from_edit(
+            self.text.char_to_byte(start),
+            &old,
+            text,
+        ));
+    }
+
+    /// Performs a set of simultaneous edits as a single atomic edit.
+    ///
+    /// Takes an iterator that yields `(char_idx_range, text)` items,
+    /// where each item replaces the given range of chars with the given
+    /// text.  The ranges must be correctly ordered, non-overlapping, and
+    /// yielded in text order.  Like `Transaction::from_ordered_edit_set()`,
+    /// all ranges are relative to the text before _any_ of the edits are
+    /// applied.
+    pub fn edit_ordered_set<'a, I>(&mut self, edits: I)
+    where
+        I: IntoIterator<Item = ((usize, usize), &'a str)>,
+    {
+        let edits: Vec<(usize, String, &str)> = edits
+            .into_iter()
+            .map(|((start, end), text)| {
+                debug_assert!(start <= end);
+                (
+                    self.text.char_to_byte(start),
+                    self.text.slice(start..end).into(),
+                    text,
+                )
+            })
+            .collect();
+
+        self.apply_transaction(Transaction::from_ordered_edit_set(
+            edits
+                .iter()
+                .map(|(byte_idx, old, new)| (*byte_idx, &old[..], *new)),
+        ));
+    }
+
+    /// Performs one edit per mark of the given cursor mark set as a single
+    /// atomic edit, and places each cursor at the end of its edit's new
+    /// text.
+    ///
+    /// `edits` must contain one `(char_idx_range, text)` item for each
+    /// cursor, in the same order as the cursors.  Ranges that overlap a
+    /// previous edit's range are trimmed so that the edits are disjoint.
+    ///
+    /// The cursors from before and after the edit are recorded in the undo
+    /// history, so that undo and redo can restore them.
+    pub fn edit_at_cursors(
+        &mut self,
+        cursors_msi: usize,
+        mut edits: Vec<((usize, usize), String)>,
+    ) {
+        debug_assert_eq!(edits.len(), self.mark_sets[cursors_msi].marks.len());
+
+        // Make sure the edits don't overlap.
+        let mut last_end = 0;
+        for ((start, end), _) in edits.iter_mut() {
+            *start = (*start).max(last_end);
+            *end = (*end).max(*start);
+            last_end = *end;
         }
 
-        // Update mark sets.
-        let post_len = text.chars().count();
-        for mark_set in self.mark_sets.iter_mut() {
-            for mark in mark_set.iter_mut() {
-                *mark = mark.edit((start, end), post_len);
-            }
+        // Compute the new cursor positions.
+        let mut cursors_after = self.mark_sets[cursors_msi].clone();
+        let mut len_delta = 0isize;
+        cursors_after.marks = edits
+            .iter()
+            .map(|((start, end), text)| {
+                let len = text.chars().count();
+                let pos = (*start as isize + len_delta) as usize + len;
+                len_delta += len as isize - (end - start) as isize;
+                Mark::new(pos, pos)
+            })
+            .collect();
+        cursors_after.make_consistent();
 
-            mark_set.make_consistent();
+        // Build the transaction.
+        let edits: Vec<(usize, String, &str)> = edits
+            .iter()
+            .map(|((start, end), text)| {
+                (
+                    self.text.char_to_byte(*start),
+                    self.text.slice(*start..*end).into(),
+                    &text[..],
+                )
+            })
+            .collect();
+        let trans = Transaction::from_ordered_edit_set(
+            edits
+                .iter()
+                .map(|(byte_idx, old, new)| (*byte_idx, &old[..], *new)),
+        );
+
+        self.apply_transaction_at_cursors(trans, cursors_msi, Some(cursors_after));
+    }
+
+    /// Applies a Transaction to the buffer as a single atomic edit.
+    ///
+    /// The text and all mark sets are updated, and the Transaction is
+    /// added to the undo history as a single undo step.
+    pub fn apply_transaction(&mut self, trans: Transaction) {
+        if !self.is_editable() || trans.is_empty() {
+            return;
         }
 
-        // Do removal if needed.
-        if start != end {
-            self.text.remove(start..end);
+        self.apply_without_history(&trans);
+        self.history.push_edit(trans, None);
+    }
+
+    /// Like `apply_transaction()`, but also records the cursors of the
+    /// given mark set in the undo history, so that undo and redo can
+    /// restore them.
+    ///
+    /// If `cursors_after` is given, the cursors are set to it after the
+    /// edit.  Otherwise they are updated like any other mark set.
+    pub fn apply_transaction_at_cursors(
+        &mut self,
+        trans: Transaction,
+        cursors_msi: usize,
+        cursors_after: Option<MarkSet>,
+    ) {
+        if !self.is_editable() {
+            return;
+        }
+        if trans.is_empty() {
+            if let Some(cursors) = cursors_after {
+                self.mark_sets[cursors_msi] = cursors;
+            }
+            return;
         }
 
-        // Do insertion if needed.
-        if !text.is_empty() {
-            self.text.insert(start, text);
+        let cursors_before = self.mark_sets[cursors_msi].clone();
+        self.apply_without_history(&trans);
+        if let Some(cursors) = cursors_after {
+            self.mark_sets[cursors_msi] = cursors;
         }
+        let cursors_after = self.mark_sets[cursors_msi].clone();
+
+        self.history
+            .push_edit(trans, Some((cursors_before, cursors_after)));
     }
 
-    /// Un-does the last edit if there is one, and returns the range of the
-    /// edited characters which can be used for e.g. placing a cursor or moving
-    /// the view.
-    ///
-    /// Returns None if there is no edit to undo.
-    pub fn undo(&mut self) -> Option<(usize, usize)> {
-        if let Some(ed) = self.history.undo() {
-            self.is_dirty = true;
-
-            let pre_len = ed.to.chars().count();
-            let post_len = ed.from.chars().count();
-            let (start, end) = (ed.char_idx, ed.char_idx + pre_len);
-
-            // Update mark sets.
-            for mark_set in self.mark_sets.iter_mut() {
-                for mark in mark_set.iter_mut() {
-                    *mark = mark.edit((start, end), post_len);
-                }
+    /// Replaces the buffer's text with its file's contents as loaded from
+    /// disk, and marks the buffer clean.
+    ///
+    /// Only the part of the text that actually differs is replaced, so
+    /// marks outside of it stay where they are.  The change is recorded
+    /// in the undo history as an undo step of its own.  This works even
+    /// if the buffer isn't editable.
+    pub fn reload(&mut self, file: LoadedFile) {
+        let trans = reload_edit(&self.text, &file.text);
+        self.reload_with_edit(file, trans);
+    }
 
-                mark_set.make_consistent();
-            }
+    /// Like `reload()`, but with the edit from the buffer's text to the
+    /// file's already computed by `reload_edit()`.
+    pub(crate) fn reload_with_edit(&mut self, file: LoadedFile, trans: Transaction) {
+        if !trans.is_empty() {
+            self.history.break_group();
+            self.apply_without_history(&trans);
+            self.history.push_edit(trans, None);
+            self.history.break_group();
+        }
 
-            // Do removal if needed.
-            if start != end {
-                self.text.remove(start..end);
-            }
+        self.is_dirty = false;
+        self.disk_state = Some(file.disk_state);
+        self.encoding = file.encoding;
+        self.has_bom = file.has_bom;
+    }
 
-            // Do insertion if needed.
-            if !ed.from.is_empty() {
-                self.text.insert(start, &ed.from);
-            }
+    /// Sets the encoding the buffer is saved in.
+    ///
+    /// Fails with the first character of the text that the encoding can't
+    /// represent, if any.
+    pub fn set_encoding(&mut self, encoding: Encoding, has_bom: bool) -> Result<(), char> {
+        for chunk in self.text.chunks() {
+            encoding.check_encodable(chunk)?;
+        }
 
-            return Some((start, start + post_len));
-        } else {
+        self.encoding = encoding;
+        self.has_bom = has_bom && !encoding.bom().is_empty();
+
+        // The file needs saving again, but the text itself hasn't changed.
+        self.is_dirty = true;
+        self.edit_count += 1;
+
+        Ok(())
+    }
+
+    /// Un-does the last edit if there is one, and returns the range of the
+    /// edited characters which can be used for e.g. moving the view.
+    ///
+    /// The cursors in the given mark set are restored to where they were
+    /// before the edit.  If that wasn't recorded, they're reduced to a
+    /// single cursor at the end of the edited range.
+    ///
+    /// Returns None if there is no edit to undo, or the buffer isn't
+    /// editable.
+    pub fn undo(&mut self, cursors_msi: usize) -> Option<(usize, usize)> {
+        if !self.is_editable() {
             return None;
         }
+        let (trans, cursors) = match self.history.undo() {
+            Some((trans, cursors)) => (trans.invert(), cursors.cloned()),
+            None => return None,
+        };
+        let range = self.apply_without_history(&trans);
+        self.restore_cursors(cursors_msi, cursors, range.1);
+        Some(range)
     }
 
     /// Re-does the last edit if there is one, and returns the range of the
-    /// edited characters which can be used for e.g. placing a cursor or moving
-    /// the view.
-    ///
-    /// Returns None if there is no edit to redo.
-    pub fn redo(&mut self) -> Option<(usize, usize)> {
-        if let Some(ed) = self.history.redo() {
-            self.is_dirty = true;
-
-            let pre_len = ed.from.chars().count();
-            let post_len = ed.to.chars().count();
-            let (start, end) = (ed.char_idx, ed.char_idx + pre_len);
-
-            // Update mark sets.
-            for mark_set in self.mark_sets.iter_mut() {
-                for mark in mark_set.iter_mut() {
-                    *mark = mark.edit((start, end), post_len);
-                }
+    /// edited characters which can be used for e.g. moving the view.
+    ///
+    /// The cursors in the given mark set are restored to where they were
+    /// after the edit.  If that wasn't recorded, they're reduced to a
+    /// single cursor at the end of the edited range.
+    ///
+    /// Returns None if there is no edit to redo, or the buffer isn't
+    /// editable.
+    pub fn redo(&mut self, cursors_msi: usize) -> Option<(usize, usize)> {
+        if !self.is_editable() {
+            return None;
+        }
+        let (trans, cursors) = match self.history.redo() {
+            Some((trans, cursors)) => (trans.clone(), cursors.cloned()),
+            None => return None,
+        };
+        let range = self.apply_without_history(&trans);
+        self.restore_cursors(cursors_msi, cursors, range.1);
+        Some(range)
+    }
 
-                mark_set.make_consistent();
-            }
+    /// Un-does the most recent edit that touches the given char range,
+    /// leaving all other edits intact, and returns the range of the edited
+    /// characters.
+    ///
+    /// This is recorded in the undo history as a new edit, so it can itself
+    /// be undone.  Calling it repeatedly walks further back through the
+    /// edits touching the range.
+    ///
+    /// Returns None if there is no such edit.
+    ///
+    /// The cursors in the given mark set are recorded in the undo history
+    /// like with `apply_transaction_at_cursors()`.
+    pub fn undo_in_range(
+        &mut self,
+        cursors_msi: usize,
+        char_idx_range: (usize, usize),
+    ) -> Option<(usize, usize)> {
+        if !self.is_editable() {
+            return None;
+        }
+        let byte_range = (
+            self.text.char_to_byte(char_idx_range.0),
+            self.text.char_to_byte(char_idx_range.1),
+        );
+        if let Some(undo) = self.history.selective_undo(byte_range) {
+            let cursors_before = self.mark_sets[cursors_msi].clone();
+            let range = self.apply_without_history(&undo.trans);
+            let cursors_after = self.mark_sets[cursors_msi].clone();
+            self.history
+                .push_selective_undo(undo, Some((cursors_before, cursors_after)));
+            Some(range)
+        } else {
+            None
+        }
+    }
 
-            // Do removal if needed.
-            if start != end {
-                self.text.remove(start..end);
-            }
+    /// Sets how the undo history behaves when editing after an undo.
+    pub fn set_history_mode(&mut self, mode: HistoryMode) {
+        self.history.set_mode(mode);
+    }
 
-            // Do insertion if needed.
-            if !ed.to.is_empty() {
-                self.text.insert(start, &ed.to);
-            }
+    /// Sets the maximum idle time between consecutive typed insertions or
+    /// deletions for them to be grouped into a single undo step.
+    pub fn set_undo_group_timeout(&mut self, timeout: Duration) {
+        self.history.set_group_timeout(timeout);
+    }
 
-            return Some((start, start + post_len));
-        } else {
+    /// Ends the current undo step, so that the next edit always starts a
+    /// new one.
+    pub fn break_undo_group(&mut self) {
+        self.history.break_group();
+    }
+
+    /// Returns information about all states in the undo history, and the
+    /// index of the current state.
+    pub fn history_states(&self) -> (Vec<HistoryState>, usize) {
+        (self.history.states(), self.history.position())
+    }
+
+    /// Moves to the given state of the undo history, and returns the range
+    /// of the edited characters which can be used for e.g. moving the view.
+    ///
+    /// The cursors in the given mark set are restored like with `undo()`
+    /// and `redo()`.
+    ///
+    /// Returns None if the state doesn't exist or is already the current
+    /// state.
+    pub fn jump_to_history_state(
+        &mut self,
+        cursors_msi: usize,
+        state: usize,
+    ) -> Option<(usize, usize)> {
+        if !self.is_editable() {
             return None;
         }
+        if let Some((trans, cursors)) = self.history.jump_to_state(state) {
+            let range = self.apply_without_history(&trans);
+            self.restore_cursors(cursors_msi, cursors, range.1);
+            Some(range)
+        } else {
+            None
+        }
     }
 
     /// Creates a new empty mark set, and returns the set index.
     pub fn add_mark_set(&mut self) -> usize {
         self.mark_sets.push(MarkSet::new());
-        return self.mark_sets.len() - 1;
+        self.mark_sets.len() - 1
+    }
+
+    /// Computes the hash of the buffer's text content.
+    pub fn content_hash(&self) -> [u8; 32] {
+        let mut h = LedHash256::new();
+        for chunk in self.text.chunks() {
+            h.update(chunk.as_bytes());
+        }
+        h.finish()
+    }
+
+    /// Returns a snapshot of the buffer for saving it to its file, or
+    /// `None` if the buffer isn't a file.
+    ///
+    /// The snapshot includes the undo history, which is stored alongside
+    /// the saved text.  Neither is copied: the text and the history's
+    /// entries are shared with the buffer, so this is cheap even for large
+    /// buffers with long histories.
+    pub fn save_job(&self) -> Option<SaveJob> {
+        if let BufferPath::File(ref file_path) = self.path {
+            Some(SaveJob {
+                path: file_path.clone(),
+                text: self.text.clone(),
+                encoding: self.encoding,
+                has_bom: self.has_bom,
+                edit_count: self.edit_count,
+                history: self.history.clone(),
+            })
+        } else {
+            None
+        }
     }
+
+    /// Writes the buffer's undo history to the per-user state directory,
+    /// so that it can be restored with `load_history()` the next time the
+    /// file is opened.
+    ///
+    /// This should be called right after the buffer is saved, since the
+    /// stored history is only considered valid for the text as it is on
+    /// disk.  Does nothing for buffers that aren't files.
+    pub fn save_history(&self) -> io::Result<()> {
+        if let BufferPath::File(ref file_path) = self.path {
+            if let Some(path) = history_path(file_path) {
+                self.history.save(&path, &self.content_hash())?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Restores the buffer's undo history from the per-user state
+    /// directory, replacing the current history.
+    ///
+    /// Returns whether any history was restored.  Stored history is
+    /// discarded if the file has changed since it was written.
+    pub fn load_history(&mut self) -> io::Result<bool> {
+        if let BufferPath::File(ref file_path) = self.path {
+            if let Some(path) = history_path(file_path) {
+                // The text of a clean buffer is what was loaded or saved,
+                // so its hash is already known.
+                let hash = match self.disk_state {
+                    Some(ref disk_state) if !self.is_dirty => disk_state.hash,
+                    _ => self.content_hash(),
+                };
+                if let Some(mut history) = History::load(&path, &hash)? {
+                    history.set_mode(self.history.mode());
+                    history.set_group_timeout(self.history.group_timeout());
+                    self.history = history;
+                    return Ok(true);
+                }
+            }
+        }
+        Ok(false)
+    }
+
+    //----------------------------------------------------
+    // Helper methods
+
+    /// Sets the cursors in the given mark set to cursors recorded in the
+    /// undo history, or if there are none, to a single cursor at
+    /// `fallback_pos`.
+    fn restore_cursors(
+        &mut self,
+        cursors_msi: usize,
+        cursors: Option<MarkSet>,
+        fallback_pos: usize,
+    ) {
+        let len = self.text.len_chars();
+        let set = &mut self.mark_sets[cursors_msi];
+        if let Some(cursors) = cursors {
+            *set = cursors;
+            for mark in set.iter_mut() {
+                mark.head = mark.head.min(len);
+                mark.tail = mark.tail.min(len);
+                mark.hh_pos = None;
+            }
+            set.make_consistent();
+        } else {
+            set.clear();
+            set.add_mark(Mark::new(fallback_pos, fallback_pos));
+        }
+    }
+
+    /// Records a change to the text, made along with incrementing the
+    /// edit count.  See `changes_since()`.
+    pub(crate) fn record_change(&mut self, change: TextChange) {
+        self.changes.push_back((self.edit_count, change));
+        if self.changes.len() > MAX_RECORDED_CHANGES {
+            let (count, _) = self.changes.pop_front().unwrap();
+            self.changes_recorded_since = count;
+        }
+    }
+
+    /// Applies a Transaction to the text and mark sets without touching
+    /// the undo history, and returns the char range spanning the changes.
+    fn apply_without_history(&mut self, trans: &Transaction) -> (usize, usize) {
+        self.is_dirty = true;
+        self.edit_count += 1;
+
+        // Update mark sets.
+        for mark_set in self.mark_sets.iter_mut() {
+            trans.apply_to_marks(mark_set, &self.text);
+        }
+
+        // Update text.
+        let old_len = self.text.len_chars();
+        trans.apply_to_text(&mut self.text);
+
+        let (start, end) = match trans.changed_byte_range() {
+            Some((start, end)) => (self.text.byte_to_char(start), self.text.byte_to_char(end)),
+            None => return (0, 0),
+        };
+
+        // The text after the changes is as it was, so it's as long as it
+        // was before them, too.
+        let old_end = old_len - (self.text.len_chars() - end);
+        self.record_change(TextChange {
+            start,
+            old_end,
+            new_end: end,
+        });
+
+        (start, end)
+    }
+}
+
+//----------------------------------------------------------------------
+
+/// Builds the edit that turns `old` into `new`, replacing only the part
+/// between their common prefix and suffix.
+///
+/// This takes time proportional to the length of the texts, so for big
+/// files it's done on a worker thread, see `Editor::check_disk_changes()`.
+pub(crate) fn reload_edit(old: &Rope, new: &Rope) -> Transaction {
+    // Find the common prefix and suffix.
+    let prefix = old
+        .chars()
+        .zip(new.chars())
+        .take_while(|(a, b)| a == b)
+        .count();
+    let max_suffix = old.len_chars().min(new.len_chars()) - prefix;
+    let mut suffix = 0;
+    {
+        let mut old_chars = old.chars_at(old.len_chars());
+        let mut new_chars = new.chars_at(new.len_chars());
+        while suffix < max_suffix && old_chars.prev() == new_chars.prev() {
+            suffix += 1;
+        }
+    }
+
+    let old_end = old.len_chars() - suffix;
+    let new_end = new.len_chars() - suffix;
+    let old_text: String = old.slice(prefix..old_end).into();
+    let new_text: String = new.slice(prefix..new_end).into();
+
+    Transaction::from_edit(old.char_to_byte(prefix), &old_text, &new_text)
 }
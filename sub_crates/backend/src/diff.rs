@@ -0,0 +1,235 @@
+//! Line-based diffing of texts.
+
+use ropey::Rope;
+
+/// The most differing lines the diff search will handle before giving up
+/// and treating everything in-between the common start and end as
+/// replaced.  This bounds the time and memory spent on very different
+/// texts.
+const MAX_EDIT_DISTANCE: usize = 2000;
+
+/// A line in a diff between two texts.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum DiffLine {
+    Same(String),    // In both texts.
+    Removed(String), // Only in the old text.
+    Added(String),   // Only in the new text.
+}
+
+/// Computes a line-based diff that turns `old` into `new`.
+///
+/// Lines include their line endings, if any.
+pub fn line_diff(old: &Rope, new: &Rope) -> Vec<DiffLine> {
+    let old = lines(old);
+    let new = lines(new);
+
+    // Lines at the start and end that are the same in both are split off
+    // first, since that's cheap and usually most of the text.
+    let prefix = old
+        .iter()
+        .zip(new.iter())
+        .take_while(|(a, b)| a == b)
+        .count();
+    let suffix = old[prefix..]
+        .iter()
+        .rev()
+        .zip(new[prefix..].iter().rev())
+        .take_while(|(a, b)| a == b)
+        .count();
+
+    let mut diff: Vec<DiffLine> = old[..prefix].iter().cloned().map(DiffLine::Same).collect();
+    let old_mid = &old[prefix..(old.len() - suffix)];
+    let new_mid = &new[prefix..(new.len() - suffix)];
+    match myers_diff(old_mid, new_mid) {
+        Some(mid) => diff.extend(mid),
+        None => {
+            diff.extend(old_mid.iter().cloned().map(DiffLine::Removed));
+            diff.extend(new_mid.iter().cloned().map(DiffLine::Added));
+        }
+    }
+    diff.extend(
+        old[(old.len() - suffix)..]
+            .iter()
+            .cloned()
+            .map(DiffLine::Same),
+    );
+
+    diff
+}
+
+/// Returns the lines of `text`, without the empty line Ropey yields after
+/// a final line ending.
+fn lines(text: &Rope) -> Vec<String> {
+    let mut lines: Vec<String> = text.lines().map(String::from).collect();
+    if lines.last().map(|l| l.is_empty()).unwrap_or(false) {
+        lines.pop();
+    }
+    lines
+}
+
+/// Computes a minimal diff between `a` and `b` with Myers' algorithm.
+///
+/// Returns `None` if they differ by more than `MAX_EDIT_DISTANCE` lines.
+fn myers_diff(a: &[String], b: &[String]) -> Option<Vec<DiffLine>> {
+    let n = a.len() as isize;
+    let m = b.len() as isize;
+
+    // `trace[d][k + d]` is the furthest x reached on diagonal k (where
+    // k = x - y) with d differing lines.
+    let mut trace: Vec<Vec<isize>> = Vec::new();
+    let mut d = 0isize;
+    'search: loop {
+        if d as usize > MAX_EDIT_DISTANCE {
+            return None;
+        }
+
+        let mut v = vec![0isize; (d * 2 + 1) as usize];
+        for k in (-d..=d).step_by(2) {
+            let mut x = if d == 0 {
+                0
+            } else if k == -d || (k != d && prev_x(&trace, d, k - 1) < prev_x(&trace, d, k + 1)) {
+                // Down from diagonal k + 1, i.e. a line added.
+                prev_x(&trace, d, k + 1)
+            } else {
+                // Right from diagonal k - 1, i.e. a line removed.
+                prev_x(&trace, d, k - 1) + 1
+            };
+            let mut y = x - k;
+            while x < n && y < m && a[x as usize] == b[y as usize] {
+                x += 1;
+                y += 1;
+            }
+            v[(k + d) as usize] = x;
+
+            if x >= n && y >= m {
+                trace.push(v);
+                break 'search;
+            }
+        }
+        trace.push(v);
+        d += 1;
+    }
+
+    // Walk back through the trace to build the diff, in reverse.
+    let mut diff = Vec::new();
+    let mut x = n;
+    let mut y = m;
+    for d in (0..trace.len() as isize).rev() {
+        let k = x - y;
+        let down =
+            d > 0 && (k == -d || (k != d && prev_x(&trace, d, k - 1) < prev_x(&trace, d, k + 1)));
+
+        // Where the snake of matching lines that ends at (x, y) starts.
+        let (start_x, start_y) = if d == 0 {
+            (0, 0)
+        } else if down {
+            let px = prev_x(&trace, d, k + 1);
+            (px, px - k)
+        } else {
+            let px = prev_x(&trace, d, k - 1);
+            (px + 1, px + 1 - k)
+        };
+
+        while x > start_x && y > start_y {
+            diff.push(DiffLine::Same(a[(x - 1) as usize].clone()));
+            x -= 1;
+            y -= 1;
+        }
+        if d > 0 {
+            if down {
+                diff.push(DiffLine::Added(b[(y - 1) as usize].clone()));
+                y -= 1;
+            } else {
+                diff.push(DiffLine::Removed(a[(x - 1) as usize].clone()));
+                x -= 1;
+            }
+        }
+    }
+
+    diff.reverse();
+    Some(diff)
+}
+
+/// Returns the furthest x reached on diagonal `k` with `d - 1` differing
+/// lines.
+fn prev_x(trace: &[Vec<isize>], d: isize, k: isize) -> isize {
+    trace[(d - 1) as usize][(k + d - 1) as usize]
+}
+
+//----------------------------------------------------------------------
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use DiffLine::*;
+
+    fn same(s: &str) -> DiffLine {
+        Same(s.into())
+    }
+    fn removed(s: &str) -> DiffLine {
+        Removed(s.into())
+    }
+    fn added(s: &str) -> DiffLine {
+        Added(s.into())
+    }
+
+    #[test]
+    fn line_diff_01() {
+        let old = Rope::from_str("a\nb\nc\nd\n");
+        let new = Rope::from_str("a\nc\nd\ne\n");
+        assert_eq!(
+            line_diff(&old, &new),
+            vec![
+                same("a\n"),
+                removed("b\n"),
+                same("c\n"),
+                same("d\n"),
+                added("e\n")
+            ]
+        );
+    }
+
+    #[test]
+    fn line_diff_02() {
+        let old = Rope::from_str("one\ntwo\nthree");
+        let new = Rope::from_str("one\n2\nthree");
+        assert_eq!(
+            line_diff(&old, &new),
+            vec![same("one\n"), removed("two\n"), added("2\n"), same("three")]
+        );
+    }
+
+    #[test]
+    fn line_diff_empty() {
+        let old = Rope::from_str("");
+        let new = Rope::from_str("x\ny\n");
+        assert_eq!(line_diff(&old, &new), vec![added("x\n"), added("y\n")]);
+        assert_eq!(line_diff(&new, &old), vec![removed("x\n"), removed("y\n")]);
+        assert_eq!(line_diff(&new, &new), vec![same("x\n"), same("y\n")]);
+    }
+
+    #[test]
+    fn line_diff_reconstructs() {
+        let old = Rope::from_str("a\nb\nc\na\nb\nb\na\n");
+        let new = Rope::from_str("c\nb\na\nb\na\nc\n");
+        let diff = line_diff(&old, &new);
+        let mut old_s = String::new();
+        let mut new_s = String::new();
+        for line in diff.iter() {
+            match line {
+                Same(l) => {
+                    old_s.push_str(l);
+                    new_s.push_str(l);
+                }
+                Removed(l) => old_s.push_str(l),
+                Added(l) => new_s.push_str(l),
+            }
+        }
+        assert_eq!(old_s, old.to_string());
+        assert_eq!(new_s, new.to_string());
+
+        // Myers' algorithm gives a minimal diff, which is 5 lines here.
+        let changes = diff.iter().filter(|l| !matches!(l, Same(_))).count();
+        assert_eq!(changes, 5);
+    }
+}
@@ -1,9 +1,683 @@
-use crate::buffer::Buffer;
+use std::{
+    fs::{self, File},
+    io,
+    path::{Path, PathBuf},
+    sync::mpsc::{channel, Receiver, TryRecvError},
+    thread,
+};
+
+use ropey::Rope;
+
+use crate::{
+    buffer::{reload_edit, Buffer, BufferPath, DiskState, LoadedFile, TextChange},
+    loader::{Load, LoadEvent, LoadMessage, LoadProgress, BACKGROUND_LOAD_SIZE, MAX_QUEUED_CHUNKS},
+    saver::{SaveResult, Saver},
+    transaction::Transaction,
+};
 
 /// A struct holding the current editor state.
 ///
 /// The Editor represents all currently open buffers available for editing.
+/// Buffers are identified by their index, which stays the same until a
+/// buffer before them is closed.
 #[derive(Debug)]
 pub struct Editor {
     open_buffers: Vec<Buffer>,
+    next_temp_id: usize,
+    saver: Saver,
+    loads: Vec<Load>,
+    disk_checks: Vec<DiskCheck>,
+}
+
+impl Default for Editor {
+    fn default() -> Editor {
+        Editor::new()
+    }
+}
+
+impl Editor {
+    pub fn new() -> Editor {
+        Editor {
+            open_buffers: Vec::new(),
+            next_temp_id: 0,
+            saver: Saver::new(),
+            loads: Vec::new(),
+            disk_checks: Vec::new(),
+        }
+    }
+
+    /// Opens the file at the given path, and returns the index of its
+    /// buffer.
+    ///
+    /// If the file is already open, its existing buffer is used instead.
+    /// The file's undo history from previous sessions is restored if
+    /// available.
+    pub fn open_file(&mut self, path: &Path) -> io::Result<usize> {
+        if let Some(idx) = self.find_file(path) {
+            return Ok(idx);
+        }
+
+        // Big files are loaded in the background, see `poll_loads()`.
+        // The file is opened here, so that e.g. a missing file is still
+        // reported right away.
+        let file = File::open(path)?;
+        let size = file.metadata()?.len();
+        if size >= BACKGROUND_LOAD_SIZE {
+            let mut buffer = Buffer::new(Rope::new(), BufferPath::File(path.into()));
+            buffer.loading = Some(LoadProgress {
+                bytes_loaded: 0,
+                total_bytes: size,
+            });
+            self.loads.push(Load::start(path, file));
+            return Ok(self.add_buffer(buffer));
+        }
+        drop(file);
+
+        let file = LoadedFile::load(path)?;
+        let mut buffer = Buffer::new(file.text, BufferPath::File(path.into()));
+        buffer.disk_state = Some(file.disk_state);
+        buffer.encoding = file.encoding;
+        buffer.has_bom = file.has_bom;
+
+        // Binary files are opened read-only, so that they aren't
+        // accidentally mangled.
+        buffer.read_only = file.is_binary;
+
+        // A missing or unreadable history shouldn't keep the file from
+        // opening.
+        buffer.load_history().ok();
+
+        Ok(self.add_buffer(buffer))
+    }
+
+    /// Creates a new empty temporary buffer, and returns its index.
+    pub fn new_temp_buffer(&mut self) -> usize {
+        let buffer = Buffer::new("".into(), BufferPath::Temp(self.next_temp_id));
+        self.add_buffer(buffer)
+    }
+
+    /// Returns a temporary buffer id that isn't used by any open buffer.
+    pub fn unused_temp_id(&self) -> usize {
+        self.next_temp_id
+    }
+
+    /// Adds an already-created buffer to the open buffers, and returns its
+    /// index.
+    pub fn add_buffer(&mut self, buffer: Buffer) -> usize {
+        if let BufferPath::Temp(id) = buffer.path {
+            self.next_temp_id = self.next_temp_id.max(id + 1);
+        }
+        self.open_buffers.push(buffer);
+        self.open_buffers.len() - 1
+    }
+
+    /// Closes the buffer at the given index, and returns it.
+    ///
+    /// This does not save the buffer: that's up to the caller.  The indices
+    /// of all buffers after it shift down by one.
+    pub fn close_buffer(&mut self, idx: usize) -> Buffer {
+        let buffer = self.open_buffers.remove(idx);
+        if let BufferPath::File(ref path) = buffer.path {
+            // Stops the load, if the file is still loading.
+            self.loads.retain(|load| load.path != *path);
+        }
+        buffer
+    }
+
+    /// Returns the number of open buffers.
+    pub fn len(&self) -> usize {
+        self.open_buffers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.open_buffers.is_empty()
+    }
+
+    /// Returns an iterator over the open buffers, in index order.
+    pub fn iter(&self) -> std::slice::Iter<'_, Buffer> {
+        self.open_buffers.iter()
+    }
+
+    pub fn iter_mut(&mut self) -> std::slice::IterMut<'_, Buffer> {
+        self.open_buffers.iter_mut()
+    }
+
+    /// Returns the index of the buffer with the given path, if it's open.
+    ///
+    /// File paths are compared by the file they refer to, so e.g.
+    /// `foo.txt` and `./foo.txt` find the same buffer.
+    pub fn find(&self, path: &BufferPath) -> Option<usize> {
+        match *path {
+            BufferPath::File(ref p) => self.find_file(p),
+            BufferPath::Temp(_) => self.open_buffers.iter().position(|b| b.path == *path),
+        }
+    }
+
+    /// Returns the buffer with the given path, if it's open.
+    pub fn get(&self, path: &BufferPath) -> Option<&Buffer> {
+        self.find(path).map(|idx| &self.open_buffers[idx])
+    }
+
+    /// Returns the buffer with the given path, if it's open.
+    pub fn get_mut(&mut self, path: &BufferPath) -> Option<&mut Buffer> {
+        self.find(path).map(move |idx| &mut self.open_buffers[idx])
+    }
+
+    /// Starts saving the buffer at the given index in the background, if
+    /// it's a file with unsaved changes.
+    ///
+    /// The buffer is marked clean once the save finishes, see
+    /// `finish_saves()`.
+    pub fn save_buffer(&mut self, idx: usize) {
+        let buffer = &self.open_buffers[idx];
+        if !buffer.is_dirty || buffer.loading.is_some() {
+            return;
+        }
+        if let Some(job) = buffer.save_job() {
+            if !self.saver.is_pending(&job.path, job.edit_count) {
+                self.saver.queue(job);
+            }
+        }
+    }
+
+    /// Starts saving all file buffers with unsaved changes in the
+    /// background.
+    pub fn save_all(&mut self) {
+        for idx in 0..self.open_buffers.len() {
+            self.save_buffer(idx);
+        }
+    }
+
+    /// Applies the results of any finished background saves to their
+    /// buffers, and returns them.  Doesn't block.
+    ///
+    /// A buffer is marked clean if it wasn't edited since its save
+    /// started.
+    pub fn finish_saves(&mut self) -> Vec<SaveResult> {
+        let results = self.saver.finished();
+        self.apply_save_results(&results);
+        results
+    }
+
+    /// Like `finish_saves()`, but first waits for all background saves to
+    /// finish.
+    pub fn wait_for_saves(&mut self) -> Vec<SaveResult> {
+        let results = self.saver.wait();
+        self.apply_save_results(&results);
+        results
+    }
+
+    /// Waits for the background save of the buffer at the given index to
+    /// finish, if one of its current text is queued or running, and returns
+    /// its result.
+    ///
+    /// Unlike `wait_for_saves()`, this leaves the results of other saves
+    /// for the next `finish_saves()` to return.
+    pub fn wait_for_save(&mut self, idx: usize) -> Option<SaveResult> {
+        let buffer = &self.open_buffers[idx];
+        let result = match buffer.path {
+            BufferPath::File(ref path) => self.saver.wait_for(path, buffer.edit_count)?,
+            BufferPath::Temp(_) => return None,
+        };
+        self.apply_save_results(std::slice::from_ref(&result));
+        Some(result)
+    }
+
+    /// Appends any text loaded in the background to its buffers, and
+    /// returns what happened.  Doesn't block.
+    pub fn poll_loads(&mut self) -> Vec<LoadEvent> {
+        let mut events = Vec::new();
+        let mut i = 0;
+        while i < self.loads.len() {
+            // Handle at most as many messages as can be queued, so that a
+            // fast worker thread can't keep this from returning.
+            let buf_idx = self.find_load(i);
+            let mut got_text = false;
+            let mut finished = None;
+            for _ in 0..=MAX_QUEUED_CHUNKS {
+                match self.loads[i].messages.try_recv() {
+                    Ok(LoadMessage::Finished(result)) => {
+                        finished = Some(result);
+                        break;
+                    }
+                    Ok(message) => {
+                        got_text |= self.apply_load_message(buf_idx, message);
+                    }
+                    Err(TryRecvError::Empty) => break,
+                    Err(TryRecvError::Disconnected) => {
+                        finished = Some(Err(io::Error::new(
+                            io::ErrorKind::Other,
+                            "loading stopped unexpectedly",
+                        )));
+                        break;
+                    }
+                }
+            }
+
+            if got_text {
+                events.push(LoadEvent::Text {
+                    buf_idx,
+                    first: !self.loads[i].received_text,
+                });
+                self.loads[i].received_text = true;
+            }
+            if let Some(result) = finished {
+                self.loads.remove(i);
+                events.push(LoadEvent::Finished {
+                    buf_idx,
+                    result: self.finish_load(buf_idx, result),
+                });
+            } else {
+                i += 1;
+            }
+        }
+        events
+    }
+
+    /// Blocks until the buffer at the given index has finished loading, if
+    /// it's being loaded in the background.
+    pub fn wait_for_load(&mut self, idx: usize) -> io::Result<()> {
+        let path = match self.open_buffers[idx].path {
+            BufferPath::File(ref path) => path.clone(),
+            BufferPath::Temp(_) => return Ok(()),
+        };
+        let i = match self.loads.iter().position(|load| load.path == path) {
+            Some(i) => i,
+            None => return Ok(()),
+        };
+
+        let load = self.loads.remove(i);
+        for message in load.messages.iter() {
+            if let LoadMessage::Finished(result) = message {
+                return self.finish_load(idx, result);
+            }
+            self.apply_load_message(idx, message);
+        }
+        self.finish_load(
+            idx,
+            Err(io::Error::new(
+                io::ErrorKind::Other,
+                "loading stopped unexpectedly",
+            )),
+        )
+    }
+
+    /// Checks whether any open files have changed on disk since their
+    /// buffers last loaded or saved them.
+    ///
+    /// Buffers without unsaved changes are reloaded right away, and their
+    /// indices returned in the first list.  Buffers with unsaved changes
+    /// are left alone, and the new file contents returned in the second
+    /// list for the caller to decide what to do with.
+    ///
+    /// Files that have gone missing or can't be read are skipped, as are
+    /// files that are currently being saved.  Changed files of at least
+    /// `BACKGROUND_LOAD_SIZE` bytes are loaded on a worker thread, so
+    /// they're only returned by a later call, once they've loaded.
+    pub fn check_disk_changes(&mut self) -> (Vec<usize>, Vec<DiskChange>) {
+        let mut reloaded = Vec::new();
+        let mut changed = Vec::new();
+
+        // Files loaded by earlier calls.
+        let mut i = 0;
+        while i < self.disk_checks.len() {
+            let result = match self.disk_checks[i].result.try_recv() {
+                Ok(result) => result,
+                Err(TryRecvError::Empty) => {
+                    i += 1;
+                    continue;
+                }
+                Err(TryRecvError::Disconnected) => Err(io::Error::new(
+                    io::ErrorKind::Other,
+                    "loading stopped unexpectedly",
+                )),
+            };
+            let check = self.disk_checks.remove(i);
+            let (file, trans) = match result {
+                Ok(loaded) => loaded,
+                Err(_) => continue,
+            };
+
+            // If the file changed again while it was being loaded, it's
+            // left for the next call to pick up.
+            let idx = match self.find_file(&check.path) {
+                Some(idx) => idx,
+                None => continue,
+            };
+            if self.saver.is_saving(&check.path)
+                || file
+                    .disk_state
+                    .may_differ_from_file(&check.path)
+                    .unwrap_or(true)
+            {
+                continue;
+            }
+
+            // The edit for reloading only applies to the text it was
+            // computed from.
+            let trans =
+                Some(trans).filter(|_| self.open_buffers[idx].edit_count == check.edit_count);
+            self.apply_disk_change(idx, file, trans, &mut reloaded, &mut changed);
+        }
+
+        for idx in 0..self.open_buffers.len() {
+            let buffer = &self.open_buffers[idx];
+            let path = match buffer.path {
+                BufferPath::File(ref p) => p.clone(),
+                BufferPath::Temp(_) => continue,
+            };
+            let disk_state = match buffer.disk_state {
+                Some(disk_state) => disk_state,
+                None => continue,
+            };
+            if self.saver.is_saving(&path)
+                || self.disk_checks.iter().any(|c| c.path == path)
+                || !disk_state.may_differ_from_file(&path).unwrap_or(false)
+            {
+                continue;
+            }
+
+            let size = match fs::metadata(&path) {
+                Ok(metadata) => metadata.len(),
+                Err(_) => continue,
+            };
+            if size >= BACKGROUND_LOAD_SIZE {
+                self.disk_checks.push(DiskCheck::start(path, buffer));
+                continue;
+            }
+
+            let file = match LoadedFile::load(&path) {
+                Ok(file) => file,
+                Err(_) => continue,
+            };
+            self.apply_disk_change(idx, file, None, &mut reloaded, &mut changed);
+        }
+
+        (reloaded, changed)
+    }
+
+    //----------------------------------------------------
+    // Helper methods
+
+    /// Handles the file of the buffer at `idx` having been loaded by
+    /// `check_disk_changes()`, optionally with the edit for reloading the
+    /// buffer already computed.
+    fn apply_disk_change(
+        &mut self,
+        idx: usize,
+        file: LoadedFile,
+        trans: Option<Transaction>,
+        reloaded: &mut Vec<usize>,
+        changed: &mut Vec<DiskChange>,
+    ) {
+        let buffer = &mut self.open_buffers[idx];
+        if buffer.disk_state.map(|d| d.hash) == Some(file.disk_state.hash) {
+            // Only the metadata changed, e.g. from the file being
+            // touched.
+            buffer.disk_state = Some(file.disk_state);
+        } else if !buffer.is_dirty {
+            match trans {
+                Some(trans) => buffer.reload_with_edit(file, trans),
+                None => buffer.reload(file),
+            }
+            reloaded.push(idx);
+        } else {
+            changed.push(DiskChange { buf_idx: idx, file });
+        }
+    }
+
+    fn apply_save_results(&mut self, results: &[SaveResult]) {
+        for result in results.iter() {
+            let disk_state = match result.result {
+                Ok(disk_state) => disk_state,
+                Err(_) => continue,
+            };
+            if let Some(idx) = self.find_file(&result.path) {
+                let buffer = &mut self.open_buffers[idx];
+                buffer.disk_state = Some(disk_state);
+                if buffer.edit_count == result.edit_count {
+                    buffer.is_dirty = false;
+                }
+            }
+        }
+    }
+
+    /// Returns the index of the buffer that the load at index `i` is
+    /// loading into.
+    fn find_load(&self, i: usize) -> usize {
+        let path = &self.loads[i].path;
+        self.open_buffers
+            .iter()
+            .position(|b| match b.path {
+                BufferPath::File(ref p) => p == path,
+                BufferPath::Temp(_) => false,
+            })
+            .unwrap()
+    }
+
+    /// Applies a message from a background load, other than the final
+    /// one, to the buffer at `idx`.  Returns whether text was appended.
+    fn apply_load_message(&mut self, idx: usize, message: LoadMessage) -> bool {
+        let buffer = &mut self.open_buffers[idx];
+        match message {
+            LoadMessage::Started {
+                encoding,
+                has_bom,
+                is_binary,
+            } => {
+                buffer.encoding = encoding;
+                buffer.has_bom = has_bom;
+                buffer.read_only = is_binary;
+                false
+            }
+            LoadMessage::Text(text, bytes_loaded) => {
+                // Appending to a rope doesn't touch the existing text, so
+                // this is cheap regardless of how much is loaded already.
+                let start = buffer.text.len_chars();
+                buffer.text.append(Rope::from(text));
+                buffer.edit_count += 1;
+                buffer.record_change(TextChange {
+                    start,
+                    old_end: start,
+                    new_end: buffer.text.len_chars(),
+                });
+                if let Some(ref mut progress) = buffer.loading {
+                    progress.bytes_loaded = bytes_loaded;
+                }
+                true
+            }
+            LoadMessage::Finished(_) => false,
+        }
+    }
+
+    /// Finishes a background load of the buffer at `idx`, given its result.
+    fn finish_load(&mut self, idx: usize, result: io::Result<DiskState>) -> io::Result<()> {
+        let buffer = &mut self.open_buffers[idx];
+        buffer.loading = None;
+        match result {
+            Ok(disk_state) => {
+                buffer.disk_state = Some(disk_state);
+                buffer.load_history().ok();
+                Ok(())
+            }
+            Err(e) => {
+                buffer.read_only = true;
+                Err(e)
+            }
+        }
+    }
+
+    fn find_file(&self, path: &Path) -> Option<usize> {
+        let canonical = fs::canonicalize(path).ok();
+        self.open_buffers.iter().position(|b| match b.path {
+            BufferPath::File(ref p) => {
+                p == path || (canonical.is_some() && fs::canonicalize(p).ok() == canonical)
+            }
+            BufferPath::Temp(_) => false,
+        })
+    }
+}
+
+/// New contents of a file that changed on disk while its buffer had
+/// unsaved changes.
+#[derive(Debug)]
+pub struct DiskChange {
+    pub buf_idx: usize,
+    pub file: LoadedFile,
+}
+
+/// A changed file being loaded on a worker thread for
+/// `Editor::check_disk_changes()`.
+#[derive(Debug)]
+struct DiskCheck {
+    path: PathBuf,
+
+    // The buffer's edit count when the load started, and the loaded file
+    // along with the edit that turns the buffer's text at that point into
+    // the file's.
+    edit_count: u64,
+    result: Receiver<io::Result<(LoadedFile, Transaction)>>,
+}
+
+impl DiskCheck {
+    fn start(path: PathBuf, buffer: &Buffer) -> DiskCheck {
+        let (send, recv) = channel();
+        let text = buffer.text.clone();
+        let worker_path = path.clone();
+        thread::spawn(move || {
+            let result = LoadedFile::load(&worker_path).map(|file| {
+                let trans = reload_edit(&text, &file.text);
+                (file, trans)
+            });
+            send.send(result).ok();
+        });
+
+        DiskCheck {
+            path,
+            edit_count: buffer.edit_count,
+            result: recv,
+        }
+    }
+}
+
+impl std::ops::Index<usize> for Editor {
+    type Output = Buffer;
+
+    fn index(&self, index: usize) -> &Buffer {
+        &self.open_buffers[index]
+    }
+}
+
+impl std::ops::IndexMut<usize> for Editor {
+    fn index_mut(&mut self, index: usize) -> &mut Buffer {
+        &mut self.open_buffers[index]
+    }
+}
+
+//----------------------------------------------------------------------
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::marks::Mark;
+
+    #[test]
+    fn disk_changes() {
+        let dir =
+            std::env::temp_dir().join(format!("led_test_disk_changes_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let file_path = dir.join("file.txt");
+        fs::write(&file_path, "Hello world!").unwrap();
+
+        let mut ed = Editor::new();
+        let idx = ed.open_file(&file_path).unwrap();
+        ed[idx].add_mark_set();
+        ed[idx].mark_sets[0].add_mark(Mark::new(7, 11));
+        assert_eq!(ed.check_disk_changes().0, Vec::<usize>::new());
+
+        // Unmodified buffers are reloaded, keeping their marks on the same
+        // text.
+        fs::write(&file_path, "Hello big world!").unwrap();
+        let (reloaded, changed) = ed.check_disk_changes();
+        assert_eq!(reloaded, vec![idx]);
+        assert!(changed.is_empty());
+        assert_eq!(ed[idx].text, "Hello big world!");
+        assert!(!ed[idx].is_dirty);
+        let mark = ed[idx].mark_sets[0].main().unwrap();
+        assert_eq!((mark.head, mark.tail), (11, 15));
+
+        // Modified buffers are left alone.
+        ed[idx].edit_at_cursors(0, vec![((10, 15), "planet".into())]);
+        fs::write(&file_path, "Goodbye world!").unwrap();
+        let (reloaded, changed) = ed.check_disk_changes();
+        assert!(reloaded.is_empty());
+        assert_eq!(changed.len(), 1);
+        assert_eq!(changed[0].file.text, "Goodbye world!");
+        assert_eq!(ed[idx].text, "Hello big planet!");
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn background_load() {
+        let dir =
+            std::env::temp_dir().join(format!("led_test_background_load_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let file_path = dir.join("big.txt");
+        let line = "h\u{e9}llo w\u{f6}rld \u{1f600}\n";
+        let text = line.repeat(BACKGROUND_LOAD_SIZE as usize / line.len() + 1000);
+        fs::write(&file_path, &text).unwrap();
+
+        let mut ed = Editor::new();
+        let idx = ed.open_file(&file_path).unwrap();
+        assert!(ed[idx].loading.is_some());
+        assert!(!ed[idx].is_editable());
+
+        let mut first_text = 0;
+        loop {
+            let events = ed.poll_loads();
+            let finished = events.iter().any(|e| match e {
+                LoadEvent::Text { first, .. } => {
+                    first_text += *first as usize;
+                    false
+                }
+                LoadEvent::Finished { result, .. } => {
+                    assert!(result.is_ok());
+                    true
+                }
+            });
+            if finished {
+                break;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(1));
+        }
+
+        assert_eq!(first_text, 1);
+        assert!(ed[idx].loading.is_none());
+        assert!(ed[idx].is_editable());
+        assert!(!ed[idx].is_dirty);
+        assert!(ed[idx].text == text);
+        assert_eq!(ed[idx].disk_state.unwrap().hash, ed[idx].content_hash());
+
+        // Changes on disk are loaded in the background too, and picked up
+        // by a later check.
+        let text = text + "The end.\n";
+        fs::write(&file_path, &text).unwrap();
+        let (reloaded, changed) = ed.check_disk_changes();
+        assert!(reloaded.is_empty() && changed.is_empty());
+        loop {
+            let (reloaded, changed) = ed.check_disk_changes();
+            assert!(changed.is_empty());
+            if !reloaded.is_empty() {
+                assert_eq!(reloaded, vec![idx]);
+                break;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(1));
+        }
+        assert!(ed[idx].text == text);
+        assert!(!ed[idx].is_dirty);
+        assert_eq!(ed[idx].disk_state.unwrap().hash, ed[idx].content_hash());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
 }
@@ -0,0 +1,537 @@
+//! Detecting, decoding, and encoding the text encodings of files.
+//!
+//! Buffers always hold their text as UTF-8, so files in other encodings
+//! are decoded when loaded and encoded again when saved.
+//!
+//! Bytes that aren't valid in a UTF-8 file are decoded to "raw byte"
+//! chars, one per byte, from a range of the Unicode private use area.
+//! They're encoded back to the original bytes when saving, so that such
+//! files survive editing byte-for-byte.  The catch is that files actually
+//! containing chars from that range are saved with them as raw bytes, but
+//! that range is vanishingly unlikely to be used in practice.
+
+/// A text encoding that files can be loaded and saved in.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Encoding {
+    Utf8,
+    Utf16Le,
+    Utf16Be,
+    Latin1,      // ISO-8859-1.
+    Windows1252, // Latin-1 with printable characters in place of the C1 controls.
+}
+
+/// All supported encodings, in the order they're presented to the user.
+pub const ALL_ENCODINGS: [Encoding; 5] = [
+    Encoding::Utf8,
+    Encoding::Utf16Le,
+    Encoding::Utf16Be,
+    Encoding::Latin1,
+    Encoding::Windows1252,
+];
+
+impl Encoding {
+    /// Returns the name of the encoding, for display.
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Encoding::Utf8 => "UTF-8",
+            Encoding::Utf16Le => "UTF-16LE",
+            Encoding::Utf16Be => "UTF-16BE",
+            Encoding::Latin1 => "Latin-1",
+            Encoding::Windows1252 => "Windows-1252",
+        }
+    }
+
+    /// Returns the byte order mark of the encoding, which is empty for
+    /// encodings that don't have one.
+    pub fn bom(&self) -> &'static [u8] {
+        match *self {
+            Encoding::Utf8 => &[0xEF, 0xBB, 0xBF],
+            Encoding::Utf16Le => &[0xFF, 0xFE],
+            Encoding::Utf16Be => &[0xFE, 0xFF],
+            Encoding::Latin1 | Encoding::Windows1252 => &[],
+        }
+    }
+
+    /// Returns whether the encoding can represent every character of the
+    /// given text, and if not, the first character it can't.
+    pub fn check_encodable(&self, text: &str) -> Result<(), char> {
+        match *self {
+            Encoding::Utf8 | Encoding::Utf16Le | Encoding::Utf16Be => Ok(()),
+            Encoding::Latin1 | Encoding::Windows1252 => {
+                match text.chars().find(|&c| self.encode_char(c).is_none()) {
+                    Some(c) => Err(c),
+                    None => Ok(()),
+                }
+            }
+        }
+    }
+
+    /// Encodes `text` in this encoding, appending it to `out`.
+    ///
+    /// Returns the first character that can't be encoded, if any.  `out`
+    /// is left partially written in that case.
+    pub fn encode(&self, text: &str, out: &mut Vec<u8>) -> Result<(), char> {
+        match *self {
+            Encoding::Utf8 => {
+                if text.chars().any(|c| char_to_raw_byte(c).is_some()) {
+                    for c in text.chars() {
+                        match char_to_raw_byte(c) {
+                            Some(byte) => out.push(byte),
+                            None => out.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes()),
+                        }
+                    }
+                } else {
+                    out.extend_from_slice(text.as_bytes());
+                }
+            }
+            Encoding::Utf16Le => {
+                for unit in text.encode_utf16() {
+                    out.extend_from_slice(&unit.to_le_bytes());
+                }
+            }
+            Encoding::Utf16Be => {
+                for unit in text.encode_utf16() {
+                    out.extend_from_slice(&unit.to_be_bytes());
+                }
+            }
+            Encoding::Latin1 | Encoding::Windows1252 => {
+                for c in text.chars() {
+                    out.push(self.encode_char(c).ok_or(c)?);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Decodes `bytes`, which must not include a byte order mark.
+    ///
+    /// Invalid bytes in UTF-8 are decoded to raw byte chars.  Anything else
+    /// that can't be decoded is replaced with U+FFFD.
+    pub fn decode(&self, bytes: &[u8]) -> String {
+        match *self {
+            Encoding::Utf8 => {
+                let mut text = String::with_capacity(bytes.len());
+                let mut bytes = bytes;
+                loop {
+                    match std::str::from_utf8(bytes) {
+                        Ok(valid) => {
+                            text.push_str(valid);
+                            break;
+                        }
+                        Err(e) => {
+                            let (valid, rest) = bytes.split_at(e.valid_up_to());
+                            text.push_str(std::str::from_utf8(valid).unwrap());
+                            let invalid_len = e.error_len().unwrap_or(rest.len());
+                            text.extend(rest[..invalid_len].iter().map(|&b| raw_byte_char(b)));
+                            bytes = &rest[invalid_len..];
+                        }
+                    }
+                }
+                text
+            }
+            Encoding::Utf16Le | Encoding::Utf16Be => {
+                let units = bytes.chunks(2).map(|pair| {
+                    if pair.len() < 2 {
+                        // A dangling odd byte.
+                        0xFFFD
+                    } else if *self == Encoding::Utf16Le {
+                        u16::from_le_bytes([pair[0], pair[1]])
+                    } else {
+                        u16::from_be_bytes([pair[0], pair[1]])
+                    }
+                });
+                std::char::decode_utf16(units)
+                    .map(|c| c.unwrap_or(std::char::REPLACEMENT_CHARACTER))
+                    .collect()
+            }
+            Encoding::Latin1 => bytes.iter().map(|&b| b as char).collect(),
+            Encoding::Windows1252 => bytes
+                .iter()
+                .map(|&b| match b {
+                    0x80..=0x9F => WINDOWS_1252_HIGH[(b - 0x80) as usize],
+                    _ => b as char,
+                })
+                .collect(),
+        }
+    }
+
+    //----------------------------------------------------
+    // Helper methods
+
+    /// Encodes a single character in a single-byte encoding.
+    fn encode_char(&self, c: char) -> Option<u8> {
+        let code = c as u32;
+        match *self {
+            Encoding::Latin1 if code <= 0xFF => Some(code as u8),
+            Encoding::Windows1252 => {
+                if code < 0x80 || (0xA0..=0xFF).contains(&code) {
+                    Some(code as u8)
+                } else {
+                    WINDOWS_1252_HIGH
+                        .iter()
+                        .position(|&h| h == c && h != UNDEFINED)
+                        .map(|i| 0x80 + i as u8)
+                }
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Decodes text that arrives in chunks, e.g. while reading a file.
+///
+/// A chunk may end partway through a character, in which case that
+/// character is decoded along with the next chunk.
+#[derive(Debug)]
+pub struct Decoder {
+    encoding: Encoding,
+    pending: Vec<u8>, // Bytes of an incomplete character.
+}
+
+impl Decoder {
+    pub fn new(encoding: Encoding) -> Decoder {
+        Decoder {
+            encoding,
+            pending: Vec::new(),
+        }
+    }
+
+    /// Decodes the next chunk of bytes.
+    ///
+    /// `is_last` should be set for the last chunk, so that an incomplete
+    /// character at its end is decoded rather than held back.
+    pub fn decode(&mut self, bytes: &[u8], is_last: bool) -> String {
+        let mut data = std::mem::take(&mut self.pending);
+        data.extend_from_slice(bytes);
+        let split = if is_last {
+            data.len()
+        } else {
+            data.len() - incomplete_tail_len(self.encoding, &data)
+        };
+        self.pending = data[split..].to_vec();
+        self.encoding.decode(&data[..split])
+    }
+}
+
+/// Returns the raw byte char that the given byte is decoded to when it
+/// isn't valid UTF-8.
+pub fn raw_byte_char(byte: u8) -> char {
+    std::char::from_u32(RAW_BYTE_BASE + byte as u32).unwrap()
+}
+
+/// If the given char is a raw byte char, returns its byte.
+pub fn char_to_raw_byte(c: char) -> Option<u8> {
+    let code = c as u32;
+    if (RAW_BYTE_BASE..=RAW_BYTE_BASE + 0xFF).contains(&code) {
+        Some((code - RAW_BYTE_BASE) as u8)
+    } else {
+        None
+    }
+}
+
+/// Returns whether a file's contents look like binary data rather than
+/// text, i.e. contain NUL bytes without being UTF-16.
+pub fn looks_binary(bytes: &[u8], encoding: Encoding) -> bool {
+    match encoding {
+        Encoding::Utf16Le | Encoding::Utf16Be => false,
+        _ => bytes.contains(&0),
+    }
+}
+
+/// Detects the encoding of a file's contents.
+///
+/// Returns the encoding, and whether the contents start with its byte
+/// order mark.  Files are assumed to be UTF-8 unless they look like
+/// UTF-16 or clearly look like a legacy single-byte encoding.  Binary
+/// files are treated as UTF-8, since that keeps them intact via raw byte
+/// chars.
+pub fn detect(bytes: &[u8]) -> (Encoding, bool) {
+    for &encoding in [Encoding::Utf8, Encoding::Utf16Le, Encoding::Utf16Be].iter() {
+        if bytes.starts_with(encoding.bom()) {
+            return (encoding, true);
+        }
+    }
+
+    // UTF-16 without a byte order mark.  Text in it is typically mostly
+    // ASCII, which shows up as every other byte being zero.  This is
+    // checked before UTF-8, since zero bytes are valid UTF-8.
+    if bytes.len() >= 2 && bytes.len() % 2 == 0 {
+        let pairs = bytes.len() / 2;
+        let even_zeros = bytes.iter().step_by(2).filter(|&&b| b == 0).count();
+        let odd_zeros = bytes.iter().skip(1).step_by(2).filter(|&&b| b == 0).count();
+        if odd_zeros * 2 > pairs && even_zeros * 8 < pairs {
+            return (Encoding::Utf16Le, false);
+        }
+        if even_zeros * 2 > pairs && odd_zeros * 8 < pairs {
+            return (Encoding::Utf16Be, false);
+        }
+    }
+
+    if bytes.contains(&0) || looks_like_utf8(bytes) {
+        return (Encoding::Utf8, false);
+    }
+
+    // Windows-1252 is far more common than Latin-1 in practice, but leaves
+    // a few bytes undefined.  Latin-1 can represent any byte.
+    let windows_1252_ok = bytes.iter().all(|&b| match b {
+        0x80..=0x9F => WINDOWS_1252_HIGH[(b - 0x80) as usize] != UNDEFINED,
+        _ => true,
+    });
+    if windows_1252_ok {
+        (Encoding::Windows1252, false)
+    } else {
+        (Encoding::Latin1, false)
+    }
+}
+
+/// Returns whether `bytes` should be treated as UTF-8.
+///
+/// That's the case unless they have several bytes that are invalid in
+/// UTF-8 and no valid non-ASCII sequences at all.  Text in legacy
+/// encodings rarely happens to contain valid UTF-8 sequences, and a few
+/// stray bytes in otherwise valid UTF-8 are better kept as raw byte chars
+/// than taken as a sign of a different encoding.
+fn looks_like_utf8(bytes: &[u8]) -> bool {
+    let mut valid_sequences = 0;
+    let mut invalid_bytes = 0;
+    let mut bytes = bytes;
+    loop {
+        let (valid, rest) = match std::str::from_utf8(bytes) {
+            Ok(valid) => (valid, &[][..]),
+            Err(e) => {
+                let (valid, rest) = bytes.split_at(e.valid_up_to());
+                (std::str::from_utf8(valid).unwrap(), rest)
+            }
+        };
+        valid_sequences += valid.chars().filter(|c| !c.is_ascii()).count();
+        if rest.is_empty() {
+            break;
+        }
+        invalid_bytes += 1;
+        bytes = &rest[1..];
+    }
+    valid_sequences > 0 || invalid_bytes < MIN_LEGACY_BYTES
+}
+
+/// Returns how many bytes at the end of `bytes` are the start of an
+/// incomplete character in the given encoding.
+fn incomplete_tail_len(encoding: Encoding, bytes: &[u8]) -> usize {
+    match encoding {
+        Encoding::Utf8 => {
+            // Look back for the lead byte of the last sequence, and check
+            // if the sequence is cut short.
+            for i in 1..=bytes.len().min(3) {
+                let b = bytes[bytes.len() - i];
+                if b & 0xC0 == 0x80 {
+                    continue;
+                }
+                let needed = match b {
+                    0xF0..=0xFF => 4,
+                    0xE0..=0xEF => 3,
+                    0xC0..=0xDF => 2,
+                    _ => 1,
+                };
+                return if needed > i { i } else { 0 };
+            }
+            0
+        }
+        Encoding::Utf16Le | Encoding::Utf16Be => {
+            // An odd byte, and a high surrogate whose pair hasn't arrived.
+            let odd = bytes.len() % 2;
+            let end = bytes.len() - odd;
+            if end >= 2 {
+                let pair = [bytes[end - 2], bytes[end - 1]];
+                let unit = if encoding == Encoding::Utf16Le {
+                    u16::from_le_bytes(pair)
+                } else {
+                    u16::from_be_bytes(pair)
+                };
+                if (0xD800..0xDC00).contains(&unit) {
+                    return odd + 2;
+                }
+            }
+            odd
+        }
+        Encoding::Latin1 | Encoding::Windows1252 => 0,
+    }
+}
+
+/// Detects the encoding of a file's contents and decodes them.
+///
+/// Returns the decoded text, its encoding, and whether the contents
+/// started with a byte order mark.
+pub fn decode_detected(bytes: &[u8]) -> (String, Encoding, bool) {
+    let (encoding, has_bom) = detect(bytes);
+    let bom_len = if has_bom { encoding.bom().len() } else { 0 };
+    (encoding.decode(&bytes[bom_len..]), encoding, has_bom)
+}
+
+// The first of the private use chars that raw bytes are decoded to.
+const RAW_BYTE_BASE: u32 = 0x10FE00;
+
+// How many bytes that are invalid in UTF-8 a file needs before it's
+// taken to be in a legacy encoding.
+const MIN_LEGACY_BYTES: usize = 3;
+
+// Placeholder for the bytes that Windows-1252 leaves undefined.
+const UNDEFINED: char = '\u{FFFD}';
+
+/// The characters of bytes 0x80 through 0x9F in Windows-1252.
+const WINDOWS_1252_HIGH: [char; 32] = [
+    '\u{20AC}', UNDEFINED, '\u{201A}', '\u{0192}', '\u{201E}', '\u{2026}', '\u{2020}', '\u{2021}',
+    '\u{02C6}', '\u{2030}', '\u{0160}', '\u{2039}', '\u{0152}', UNDEFINED, '\u{017D}', UNDEFINED,
+    UNDEFINED, '\u{2018}', '\u{2019}', '\u{201C}', '\u{201D}', '\u{2022}', '\u{2013}', '\u{2014}',
+    '\u{02DC}', '\u{2122}', '\u{0161}', '\u{203A}', '\u{0153}', UNDEFINED, '\u{017E}', '\u{0178}',
+];
+
+//----------------------------------------------------------------------
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn detect_bom() {
+        assert_eq!(detect(b"\xEF\xBB\xBFhi"), (Encoding::Utf8, true));
+        assert_eq!(detect(b"\xFF\xFEh\x00i\x00"), (Encoding::Utf16Le, true));
+        assert_eq!(detect(b"\xFE\xFF\x00h\x00i"), (Encoding::Utf16Be, true));
+    }
+
+    #[test]
+    fn detect_without_bom() {
+        assert_eq!(detect("héllo".as_bytes()), (Encoding::Utf8, false));
+        assert_eq!(detect(b""), (Encoding::Utf8, false));
+        assert_eq!(
+            detect(b"h\x00e\x00l\x00l\x00o\x00"),
+            (Encoding::Utf16Le, false)
+        );
+        assert_eq!(
+            detect(b"\x00h\x00e\x00l\x00l\x00o"),
+            (Encoding::Utf16Be, false)
+        );
+        assert_eq!(
+            detect(b"caf\xE9 \x93quoted\x94"),
+            (Encoding::Windows1252, false)
+        );
+        assert_eq!(detect(b"caf\xE9 na\xEFve \x81"), (Encoding::Latin1, false));
+    }
+
+    #[test]
+    fn detect_stray_invalid_byte() {
+        // A single invalid byte in otherwise ASCII text isn't enough to
+        // go on, so it's kept as a raw byte in UTF-8.
+        let bytes = b"fn main() {\n    println!(\"hi\xFF\");\n}\n";
+        let (text, encoding, has_bom) = decode_detected(bytes);
+        assert_eq!((encoding, has_bom), (Encoding::Utf8, false));
+        assert!(text.contains(&format!("hi{}\"", raw_byte_char(0xFF))));
+
+        let mut out = Vec::new();
+        encoding.encode(&text, &mut out).unwrap();
+        assert_eq!(&out[..], &bytes[..]);
+    }
+
+    #[test]
+    fn decode_01() {
+        assert_eq!(
+            decode_detected(b"\xFF\xFEh\x00\xE9\x00"),
+            ("hé".into(), Encoding::Utf16Le, true)
+        );
+        assert_eq!(
+            decode_detected(b"caf\xE9 \x93hi\x94"),
+            (
+                "café \u{201C}hi\u{201D}".into(),
+                Encoding::Windows1252,
+                false
+            )
+        );
+        assert_eq!(Encoding::Latin1.decode(b"\x93"), "\u{93}");
+    }
+
+    #[test]
+    fn round_trip() {
+        let text = "Hello, wörld! \u{201C}€\u{201D}";
+        for &encoding in ALL_ENCODINGS.iter() {
+            if encoding.check_encodable(text).is_err() {
+                continue;
+            }
+            let mut bytes = Vec::new();
+            encoding.encode(text, &mut bytes).unwrap();
+            assert_eq!(encoding.decode(&bytes), text, "{}", encoding.name());
+        }
+    }
+
+    #[test]
+    fn invalid_utf8_round_trip() {
+        let bytes = b"h\xC3\xA9llo \xFF w\xC3\xB6rld \xE2\x80\x9Cq\xE2\x80\x9D \xC3";
+        let (text, encoding, has_bom) = decode_detected(bytes);
+        assert_eq!((encoding, has_bom), (Encoding::Utf8, false));
+        assert_eq!(
+            text.chars()
+                .filter(|&c| char_to_raw_byte(c).is_some())
+                .count(),
+            2
+        );
+        assert!(text.starts_with("héllo "));
+
+        let mut out = Vec::new();
+        encoding.encode(&text, &mut out).unwrap();
+        assert_eq!(&out[..], &bytes[..]);
+    }
+
+    #[test]
+    fn binary() {
+        let bytes = b"\x7FELF\x02\x01\x01\x00\x00\x00\xFF\xFE";
+        let (encoding, _) = detect(bytes);
+        assert_eq!(encoding, Encoding::Utf8);
+        assert!(looks_binary(bytes, encoding));
+        assert!(!looks_binary(b"h\x00i\x00", Encoding::Utf16Le));
+
+        let mut out = Vec::new();
+        encoding.encode(&encoding.decode(bytes), &mut out).unwrap();
+        assert_eq!(&out[..], &bytes[..]);
+    }
+
+    #[test]
+    fn decode_in_chunks() {
+        let text = "a\u{e9}\u{201c}\u{1f600}b";
+        let mut utf16 = Vec::new();
+        Encoding::Utf16Be.encode(text, &mut utf16).unwrap();
+        let cases = [
+            (Encoding::Utf8, text.as_bytes()),
+            (Encoding::Utf16Be, &utf16[..]),
+        ];
+
+        for (encoding, bytes) in cases.iter() {
+            // Splitting anywhere gives the same text as decoding at once.
+            for chunk_len in 1..bytes.len() {
+                let mut decoder = Decoder::new(*encoding);
+                let mut decoded = String::new();
+                let mut chunks = bytes.chunks(chunk_len).peekable();
+                while let Some(chunk) = chunks.next() {
+                    decoded.push_str(&decoder.decode(chunk, chunks.peek().is_none()));
+                }
+                assert_eq!(decoded, text);
+            }
+        }
+
+        // A truncated character at the very end is still decoded.
+        let mut decoder = Decoder::new(Encoding::Utf8);
+        assert_eq!(decoder.decode(b"a\xE2\x80", false), "a");
+        assert_eq!(
+            decoder.decode(b"", true),
+            format!("{}{}", raw_byte_char(0xE2), raw_byte_char(0x80))
+        );
+    }
+
+    #[test]
+    fn not_encodable() {
+        assert_eq!(Encoding::Latin1.check_encodable("a€b"), Err('€'));
+        assert_eq!(Encoding::Windows1252.check_encodable("a€b"), Ok(()));
+        assert_eq!(
+            Encoding::Windows1252.check_encodable("a\u{81}b"),
+            Err('\u{81}')
+        );
+        assert_eq!(Encoding::Windows1252.check_encodable("日本"), Err('日'));
+        assert_eq!(Encoding::Utf16Le.check_encodable("日本"), Ok(()));
+    }
+}
@@ -1,14 +1,14 @@
-/// A 256-bit non-cryptographic hash function for data identification.
-///
-/// This uses the MIX function, constants, and permutation patterns
-/// from Skein v1.3, but is otherwise largely unrelated--in particular
-/// it does not use sub-keys, tweak values, or UBI from Skein.
-///
-/// This implementation assumes support for 64-bit unsigned integers.
-///
-/// This implementation should work on platforms of any endianness,
-/// but has only been tested on little endian platforms.  Running the
-/// unit tests on a big-endian platform can verify.
+//! A 256-bit non-cryptographic hash function for data identification.
+//!
+//! This uses the MIX function, constants, and permutation patterns
+//! from Skein v1.3, but is otherwise largely unrelated--in particular
+//! it does not use sub-keys, tweak values, or UBI from Skein.
+//!
+//! This implementation assumes support for 64-bit unsigned integers.
+//!
+//! This implementation should work on platforms of any endianness,
+//! but has only been tested on little endian platforms.  Running the
+//! unit tests on a big-endian platform can verify.
 
 const BLOCK_SIZE: usize = 256 / 8; // Block size of the hash, in bytes
 
@@ -30,6 +30,12 @@ pub struct LedHash256 {
     message_length: u64,   // Accumulates the total message length, in bytes.
 }
 
+impl Default for LedHash256 {
+    fn default() -> LedHash256 {
+        LedHash256::new()
+    }
+}
+
 impl LedHash256 {
     pub fn new() -> LedHash256 {
         LedHash256 {
@@ -60,7 +66,7 @@ impl LedHash256 {
             } else {
                 // Fill the buffer.
                 let n = (BLOCK_SIZE - self.buf_length).min(data.len());
-                (&mut self.buf[self.buf_length..(self.buf_length + n)]).copy_from_slice(&data[..n]);
+                self.buf[self.buf_length..(self.buf_length + n)].copy_from_slice(&data[..n]);
                 data = &data[n..];
                 self.buf_length += n;
             }
@@ -71,7 +77,7 @@ impl LedHash256 {
     pub fn finish(mut self) -> [u8; BLOCK_SIZE] {
         // Hash the remaining bytes if there are any.
         if self.buf_length > 0 {
-            for i in (&mut self.buf[self.buf_length..]).iter_mut() {
+            for i in self.buf[self.buf_length..].iter_mut() {
                 *i = 0;
             }
             self.mix_buffer_into_state();
@@ -80,7 +86,7 @@ impl LedHash256 {
 
         // Hash the message length, in bits.
         self.buf[..8].copy_from_slice(&(self.message_length * 8).to_le_bytes());
-        for i in (&mut self.buf[8..]).iter_mut() {
+        for i in self.buf[8..].iter_mut() {
             *i = 0;
         }
         self.mix_buffer_into_state();
@@ -91,7 +97,7 @@ impl LedHash256 {
         digest[8..16].copy_from_slice(&self.state[1].to_le_bytes());
         digest[16..24].copy_from_slice(&self.state[2].to_le_bytes());
         digest[24..32].copy_from_slice(&self.state[3].to_le_bytes());
-        return digest;
+        digest
     }
 
     /// The main mix function.  Mixes the buffer into the hash state.
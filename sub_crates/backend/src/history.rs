@@ -1,7 +1,160 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fs::{self, File},
+    io::{self, BufReader, BufWriter, Read, Write},
+    path::Path,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use unicode_segmentation::UnicodeSegmentation;
+
+use crate::{
+    marks::MarkSet,
+    serialize::{invalid_data, read_u64, read_usize, write_u64},
+    transaction::Transaction,
+};
+
+const HISTORY_FILE_MAGIC: &[u8; 8] = b"LEDHIST5";
+
+/// The default maximum idle time between edits for them to be grouped
+/// into a single undo step.
+const DEFAULT_GROUP_TIMEOUT: Duration = Duration::from_millis(1000);
+
+/// How the history behaves when new edits are made after undoing.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum HistoryMode {
+    /// Standard undo: new edits discard any undone states.
+    Linear,
+
+    /// Undoing walks back through the history without discarding
+    /// anything.  When a new edit is made, walking back to the current
+    /// state is itself recorded as an edit, so every earlier state
+    /// remains reachable.
+    WalkBack,
+}
+
+/// Information about a single state in the history.
+///
+/// State `0` is the initial state, and each subsequent state is the
+/// result of one edit.
+#[derive(Debug, Copy, Clone)]
+pub struct HistoryState {
+    /// When the state was created.  `None` for the initial state.
+    pub time: Option<SystemTime>,
+
+    /// The byte range of the state's text that was changed by the edit
+    /// that led to it.  `None` for the initial state.
+    pub changed_byte_range: Option<(usize, usize)>,
+}
+
 #[derive(Debug, Clone)]
 pub struct History {
-    edits: Vec<Edit>,
+    // The entries are shared between clones, so that snapshotting the
+    // history (e.g. for saving it in the background) stays cheap.
+    edits: Vec<Arc<Entry>>,
     position: usize, // Where we are in the history.
+    mode: HistoryMode,
+
+    // For grouping consecutive edits into a single undo step.
+    group: Option<Group>,
+    group_timeout: Duration,
+}
+
+#[derive(Debug, Clone)]
+struct Entry {
+    trans: Transaction,
+    time: SystemTime,
+
+    // If this edit was made by `push_selective_undo()`, the index of the
+    // edit it reverted, and which of that edit's parts (as indices into
+    // its `edits()`) it reverted.
+    reverts: Option<(usize, Vec<usize>)>,
+
+    // The cursors from just before and just after the edit, if known.
+    cursors: Option<(MarkSet, MarkSet)>,
+}
+
+/// An edit built by `History::selective_undo()`.  Once applied, it should
+/// be passed to `History::push_selective_undo()`.
+#[derive(Debug, Clone)]
+pub struct SelectiveUndo {
+    pub trans: Transaction,
+
+    // The index of the edit being reverted, and which of its parts.
+    reverted_idx: usize,
+    reverted_parts: Vec<usize>,
+}
+
+/// The kind of undo step the most recent edit started or continued.
+///
+/// Only edits that insert or delete a single grapheme at each of their
+/// ranges are grouped, so e.g. pastes are always their own undo step.
+/// Edits with several ranges, as made with multiple cursors, are grouped
+/// when each range continues the corresponding range of the previous edit.
+#[derive(Debug, Clone)]
+enum Group {
+    /// Insertions.  `ends` are the byte indices just after the last
+    /// insertions, one per range.
+    Insert { ends: Vec<usize> },
+
+    /// Deletions.  `ats` are the byte indices of the last deletions, one
+    /// per range.
+    Delete { ats: Vec<usize> },
+}
+
+impl Group {
+    /// Returns the group that the given edit would start, if any.
+    fn from_edit(edit: &Transaction) -> Option<Group> {
+        let mut ends = Vec::new();
+        let mut ats = Vec::new();
+        let mut delta = 0isize; // Where the edit's ranges end up after it.
+        for (byte_idx, old, new) in edit.edits() {
+            let at = (byte_idx as isize + delta) as usize;
+            delta += new.len() as isize - old.len() as isize;
+            if old.is_empty() && new.graphemes(true).count() == 1 {
+                ends.push(at + new.len());
+            } else if new.is_empty() && old.graphemes(true).count() == 1 {
+                ats.push(at);
+            } else {
+                return None;
+            }
+        }
+
+        match (ends.is_empty(), ats.is_empty()) {
+            (false, true) => Some(Group::Insert { ends }),
+            (true, false) => Some(Group::Delete { ats }),
+            _ => None,
+        }
+    }
+
+    /// Returns whether `next` continues this group.
+    fn is_continued_by(&self, next: &Transaction) -> bool {
+        match (self, Group::from_edit(next)) {
+            (Group::Insert { ends }, Some(Group::Insert { ends: next_ends }))
+                if next_ends.len() == ends.len() =>
+            {
+                next.edits()
+                    .zip(ends.iter())
+                    .all(|((byte_idx, _, _), &end)| byte_idx == end)
+            }
+            (Group::Delete { ats }, Some(Group::Delete { ats: next_ats }))
+                if next_ats.len() == ats.len() =>
+            {
+                // Either forward deletion or backspacing.
+                next.edits()
+                    .zip(ats.iter())
+                    .all(|((byte_idx, old, _), &at)| byte_idx == at || byte_idx + old.len() == at)
+            }
+            _ => false,
+        }
+    }
+}
+
+impl Default for History {
+    fn default() -> History {
+        History::new()
+    }
 }
 
 impl History {
@@ -9,38 +162,767 @@ impl History {
         History {
             edits: Vec::new(),
             position: 0,
+            mode: HistoryMode::Linear,
+            group: None,
+            group_timeout: DEFAULT_GROUP_TIMEOUT,
+        }
+    }
+
+    pub fn mode(&self) -> HistoryMode {
+        self.mode
+    }
+
+    pub fn set_mode(&mut self, mode: HistoryMode) {
+        self.mode = mode;
+    }
+
+    pub fn group_timeout(&self) -> Duration {
+        self.group_timeout
+    }
+
+    /// Sets the maximum idle time between consecutive edits for them to
+    /// be grouped into a single undo step.
+    pub fn set_group_timeout(&mut self, timeout: Duration) {
+        self.group_timeout = timeout;
+    }
+
+    /// Ends the current undo step, so that the next edit starts a new one
+    /// even if it would otherwise be grouped with the previous edit.
+    pub fn break_group(&mut self) {
+        self.group = None;
+    }
+
+    /// Pushes an edit onto the history.
+    ///
+    /// Consecutive single-grapheme insertions or deletions at adjacent
+    /// positions (at each cursor, for multi-cursor edits) are grouped into
+    /// a single undo step, as long as they happen within the group timeout
+    /// of each other.  All other edits are their own undo step.
+    ///
+    /// `cursors` are the states of the cursors just before and just after
+    /// the edit, which undo and redo hand back for restoring the cursors.
+    /// When the edit is grouped with the previous one, the group keeps its
+    /// original "before" cursors.
+    pub fn push_edit(&mut self, edit: Transaction, cursors: Option<(MarkSet, MarkSet)>) {
+        let next_group = Group::from_edit(&edit);
+
+        let continues_group = self.position == self.edits.len()
+            && self
+                .group
+                .as_ref()
+                .map(|g| g.is_continued_by(&edit))
+                .unwrap_or(false)
+            && self
+                .edits
+                .last()
+                .and_then(|e| e.time.elapsed().ok())
+                .map(|elapsed| elapsed <= self.group_timeout)
+                .unwrap_or(false);
+
+        if continues_group {
+            let last = Arc::make_mut(self.edits.last_mut().unwrap());
+            last.trans = last.trans.compose(&edit);
+            last.time = SystemTime::now();
+            last.cursors = match (last.cursors.take(), cursors) {
+                (Some((before, _)), Some((_, after))) => Some((before, after)),
+                _ => None,
+            };
+        } else {
+            self.push_entry(edit, None, cursors);
         }
+
+        self.group = next_group;
     }
 
-    pub fn push_edit(&mut self, edit: Edit) {
-        self.edits.truncate(self.position);
-        self.edits.push(edit);
-        self.position += 1;
+    fn push_entry(
+        &mut self,
+        edit: Transaction,
+        reverts: Option<(usize, Vec<usize>)>,
+        cursors: Option<(MarkSet, MarkSet)>,
+    ) {
+        self.group = None;
+        if self.mode == HistoryMode::WalkBack && self.position < self.edits.len() {
+            // Rather than discarding the undone states, record walking back
+            // to the current state as an edit of its own.
+            let revert = self.transaction_between(self.edits.len(), self.position);
+            let revert_cursors = match (
+                self.cursors_at_state(self.edits.len(), false),
+                self.cursors_at_state(self.position, true),
+            ) {
+                (Some(before), Some(after)) => Some((before.clone(), after.clone())),
+                _ => None,
+            };
+            self.edits.push(Arc::new(Entry {
+                trans: revert,
+                time: SystemTime::now(),
+                reverts: None,
+                cursors: revert_cursors,
+            }));
+        } else {
+            self.edits.truncate(self.position);
+        }
+        self.edits.push(Arc::new(Entry {
+            trans: edit,
+            time: SystemTime::now(),
+            reverts,
+            cursors,
+        }));
+        self.position = self.edits.len();
     }
 
-    pub fn undo(&mut self) -> Option<&Edit> {
+    /// Steps back one state, and returns the edit to un-do along with the
+    /// cursors from before that edit, if they were recorded.
+    pub fn undo(&mut self) -> Option<(&Transaction, Option<&MarkSet>)> {
+        self.group = None;
         if self.position > 0 {
             self.position -= 1;
-            Some(&self.edits[self.position])
+            Some((
+                &self.edits[self.position].trans,
+                self.cursors_at_state(self.position, true),
+            ))
         } else {
             None
         }
     }
 
-    pub fn redo(&mut self) -> Option<&Edit> {
+    /// Steps forward one state, and returns the edit to re-do along with
+    /// the cursors from after that edit, if they were recorded.
+    pub fn redo(&mut self) -> Option<(&Transaction, Option<&MarkSet>)> {
+        self.group = None;
         if self.position < self.edits.len() {
-            let edit = &self.edits[self.position];
             self.position += 1;
-            Some(edit)
+            Some((
+                &self.edits[self.position - 1].trans,
+                self.cursors_at_state(self.position, false),
+            ))
         } else {
             None
         }
     }
+
+    /// Returns the index of the current state.
+    pub fn position(&self) -> usize {
+        self.position
+    }
+
+    /// Returns information about all states in the history, in order.
+    pub fn states(&self) -> Vec<HistoryState> {
+        std::iter::once(HistoryState {
+            time: None,
+            changed_byte_range: None,
+        })
+        .chain(self.edits.iter().map(|entry| HistoryState {
+            time: Some(entry.time),
+            changed_byte_range: entry.trans.changed_byte_range(),
+        }))
+        .collect()
+    }
+
+    /// Moves to the given state, and returns a Transaction that transforms
+    /// the text of the current state into the text of that state, along
+    /// with the cursors recorded for that state, if any.
+    ///
+    /// Like `undo()` and `redo()`, this doesn't discard anything: it only
+    /// changes the current position in the history.
+    ///
+    /// Returns None if the state doesn't exist or is already the current
+    /// state.
+    pub fn jump_to_state(&mut self, state: usize) -> Option<(Transaction, Option<MarkSet>)> {
+        if state > self.edits.len() || state == self.position {
+            return None;
+        }
+
+        let trans = self.transaction_between(self.position, state);
+        let cursors = self.cursors_at_state(state, state < self.position).cloned();
+        self.position = state;
+        self.group = None;
+        Some((trans, cursors))
+    }
+
+    /// Builds a Transaction that reverts the most recent edit touching the
+    /// given byte range of the current state's text, while leaving all
+    /// other edits intact.
+    ///
+    /// Only the parts of that edit that touch the range and haven't since
+    /// been modified by later edits are reverted.  Parts that were already
+    /// reverted this way are skipped, so repeated selective undos walk
+    /// further back through the history, and the other parts of e.g. a
+    /// multi-cursor edit can still be reverted from another range.
+    ///
+    /// Returns None if there is no such edit.
+    pub fn selective_undo(&self, range: (usize, usize)) -> Option<SelectiveUndo> {
+        let current = &self.edits[..self.position];
+        let touches = |r: (usize, usize)| {
+            if r.0 == r.1 || range.0 == range.1 {
+                r.0 <= range.1 && r.1 >= range.0
+            } else {
+                r.0 < range.1 && r.1 > range.0
+            }
+        };
+
+        // The parts of each edit that have already been reverted.
+        let mut reverted: HashMap<usize, HashSet<usize>> = HashMap::new();
+        for (idx, parts) in current.iter().filter_map(|e| e.reverts.as_ref()) {
+            reverted
+                .entry(*idx)
+                .or_default()
+                .extend(parts.iter().copied());
+        }
+
+        for (idx, entry) in current.iter().enumerate().rev() {
+            if entry.reverts.is_some() {
+                continue;
+            }
+            let already_reverted = reverted.get(&idx);
+
+            // Find the parts of the edit, in current text coordinates, that
+            // touch the range and haven't been modified or reverted since.
+            let mut reverts = Vec::new();
+            let mut parts = Vec::new();
+            let mut delta = 0isize;
+            for (part, (byte_idx, old, new)) in entry.trans.edits().enumerate() {
+                let start = (byte_idx as isize + delta) as usize;
+                delta += new.len() as isize - old.len() as isize;
+                if already_reverted.is_some_and(|p| p.contains(&part)) {
+                    continue;
+                }
+
+                let mut r = Some((start, start + new.len()));
+                for later in current[(idx + 1)..].iter() {
+                    r = r.and_then(|r| later.trans.map_byte_range(r));
+                }
+                if let Some(r) = r {
+                    if touches(r) {
+                        reverts.push((r.0, new, old));
+                        parts.push(part);
+                    }
+                }
+            }
+
+            if !reverts.is_empty() {
+                return Some(SelectiveUndo {
+                    trans: Transaction::from_ordered_edit_set(reverts.into_iter()),
+                    reverted_idx: idx,
+                    reverted_parts: parts,
+                });
+            }
+        }
+
+        None
+    }
+
+    /// Pushes an edit created by `selective_undo()` onto the history.
+    ///
+    /// `cursors` are as in `push_edit()`.
+    pub fn push_selective_undo(
+        &mut self,
+        undo: SelectiveUndo,
+        cursors: Option<(MarkSet, MarkSet)>,
+    ) {
+        self.push_entry(
+            undo.trans,
+            Some((undo.reverted_idx, undo.reverted_parts)),
+            cursors,
+        );
+    }
+
+    /// Writes the history to the given file path, creating parent
+    /// directories as needed.
+    ///
+    /// `content_hash` should be the hash of the text the history's current
+    /// position corresponds to, and is used by `load()` to detect stale
+    /// history.
+    pub fn save(&self, path: &Path, content_hash: &[u8; 32]) -> io::Result<()> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+
+        // Write to a temporary file first, so that a crash partway through
+        // doesn't clobber the previous history.
+        let temp_path = path.with_extension("tmp");
+        {
+            let mut f = BufWriter::new(File::create(&temp_path)?);
+            f.write_all(HISTORY_FILE_MAGIC)?;
+            f.write_all(content_hash)?;
+            self.write_to(&mut f)?;
+            f.flush()?;
+        }
+        fs::rename(&temp_path, path)
+    }
+
+    /// Reads a history written by `save()`.
+    ///
+    /// Returns `Ok(None)` if there is no stored history, or if it was
+    /// stored for text other than that with the given `content_hash`.
+    /// Stale history is deleted.
+    pub fn load(path: &Path, content_hash: &[u8; 32]) -> io::Result<Option<History>> {
+        let mut f = match File::open(path) {
+            Ok(f) => BufReader::new(f),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+
+        let mut magic = [0u8; 8];
+        f.read_exact(&mut magic)?;
+        if &magic != HISTORY_FILE_MAGIC {
+            return Err(invalid_data("not a led history file"));
+        }
+
+        let mut stored_hash = [0u8; 32];
+        f.read_exact(&mut stored_hash)?;
+        if &stored_hash != content_hash {
+            drop(f);
+            fs::remove_file(path)?;
+            return Ok(None);
+        }
+
+        History::read_from(&mut f).map(Some)
+    }
+
+    /// Writes the history's edits and current position in led's binary
+    /// state format.
+    ///
+    /// The mode and group timeout are settings rather than state, and
+    /// aren't written.
+    pub fn write_to<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        write_u64(w, self.position as u64)?;
+        write_u64(w, self.edits.len() as u64)?;
+        for entry in self.edits.iter() {
+            let time = entry
+                .time
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap_or_default();
+            write_u64(w, time.as_secs())?;
+            write_u64(w, time.subsec_nanos() as u64)?;
+            if let Some((idx, ref parts)) = entry.reverts {
+                write_u64(w, idx as u64 + 1)?;
+                write_u64(w, parts.len() as u64)?;
+                for &part in parts.iter() {
+                    write_u64(w, part as u64)?;
+                }
+            } else {
+                write_u64(w, 0)?;
+            }
+            entry.trans.write_to(w)?;
+            if let Some((ref before, ref after)) = entry.cursors {
+                write_u64(w, 1)?;
+                before.write_to(w)?;
+                after.write_to(w)?;
+            } else {
+                write_u64(w, 0)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Reads a history written by `write_to()`.
+    ///
+    /// Returns an error if the data is malformed.
+    pub fn read_from<R: Read>(r: &mut R) -> io::Result<History> {
+        let position = read_usize(r)?;
+        let edit_count = read_usize(r)?;
+        if position > edit_count {
+            return Err(invalid_data("invalid history position"));
+        }
+        let mut edits = Vec::new();
+        for _ in 0..edit_count {
+            let secs = read_u64(r)?;
+            let nanos = read_u64(r)?.min(999_999_999);
+            let time = SystemTime::UNIX_EPOCH + Duration::new(secs, nanos as u32);
+            let reverts = match read_u64(r)? {
+                0 => None,
+                n => {
+                    let part_count = read_usize(r)?;
+                    let mut parts = Vec::new();
+                    for _ in 0..part_count {
+                        parts.push(read_usize(r)?);
+                    }
+                    Some((n as usize - 1, parts))
+                }
+            };
+            let trans = Transaction::read_from(r)?;
+            let cursors = match read_u64(r)? {
+                0 => None,
+                _ => Some((MarkSet::read_from(r)?, MarkSet::read_from(r)?)),
+            };
+            edits.push(Arc::new(Entry {
+                trans,
+                time,
+                reverts,
+                cursors,
+            }));
+        }
+
+        Ok(History {
+            edits,
+            position,
+            mode: HistoryMode::Linear,
+            group: None,
+            group_timeout: DEFAULT_GROUP_TIMEOUT,
+        })
+    }
+
+    //----------------------------------------------------
+    // Helper methods
+
+    /// Returns the cursors recorded for the given state.
+    ///
+    /// A state can have two sets of recorded cursors: those from after the
+    /// edit leading to it, and those from before the edit leading away
+    /// from it.  `arriving_backward` selects the latter, which is what
+    /// undo should restore, whereas redo should restore the former.  If
+    /// the preferred set wasn't recorded, the other one is used.
+    fn cursors_at_state(&self, state: usize, arriving_backward: bool) -> Option<&MarkSet> {
+        let before_next = self
+            .edits
+            .get(state)
+            .and_then(|e| e.cursors.as_ref())
+            .map(|c| &c.0);
+        let after_prev = if state > 0 {
+            self.edits[state - 1].cursors.as_ref().map(|c| &c.1)
+        } else {
+            None
+        };
+
+        if arriving_backward {
+            before_next.or(after_prev)
+        } else {
+            after_prev.or(before_next)
+        }
+    }
+
+    /// Builds a Transaction that transforms the text of state `from` into
+    /// the text of state `to`.
+    fn transaction_between(&self, from: usize, to: usize) -> Transaction {
+        let mut trans = Transaction::new();
+        if to < from {
+            for entry in self.edits[to..from].iter().rev() {
+                trans = trans.compose(&entry.trans.invert());
+            }
+        } else {
+            for entry in self.edits[from..to].iter() {
+                trans = trans.compose(&entry.trans);
+            }
+        }
+        trans
+    }
 }
 
-#[derive(Debug, Clone)]
-pub struct Edit {
-    pub char_idx: usize,
-    pub from: String,
-    pub to: String,
+//----------------------------------------------------------------------
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::marks::Mark;
+    use ropey::Rope;
+
+    fn temp_history_path(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir()
+            .join(format!("led_test_{}", std::process::id()))
+            .join(name)
+    }
+
+    fn text_at_state(history: &mut History, text: &mut Rope, state: usize) -> String {
+        if let Some((trans, _)) = history.jump_to_state(state) {
+            trans.apply_to_text(text);
+        }
+        text.to_string()
+    }
+
+    #[test]
+    fn walk_back_keeps_undone_states() {
+        let mut history = History::new();
+        history.set_mode(HistoryMode::WalkBack);
+        let mut text = Rope::from_str("");
+
+        let edits = [
+            Transaction::from_edit(0, "", "ab"),
+            Transaction::from_edit(2, "", "cd"),
+            Transaction::from_edit(4, "", "ef"),
+        ];
+        for edit in edits.iter() {
+            edit.apply_to_text(&mut text);
+            history.push_edit(edit.clone(), None);
+        }
+
+        // Undo twice, then make a new edit.
+        for _ in 0..2 {
+            history.undo().unwrap().0.invert().apply_to_text(&mut text);
+        }
+        assert_eq!(text, "ab");
+        let edit = Transaction::from_edit(2, "", "xy");
+        edit.apply_to_text(&mut text);
+        history.push_edit(edit, None);
+        assert_eq!(text, "abxy");
+
+        // All earlier states should still be reachable.
+        assert_eq!(history.states().len(), 6);
+        assert_eq!(text_at_state(&mut history, &mut text, 3), "abcdef");
+        assert_eq!(text_at_state(&mut history, &mut text, 4), "ab");
+        assert_eq!(text_at_state(&mut history, &mut text, 0), "");
+        assert_eq!(text_at_state(&mut history, &mut text, 5), "abxy");
+    }
+
+    #[test]
+    fn selective_undo() {
+        let mut history = History::new();
+        let mut text = Rope::from_str("one two three");
+
+        let edits = [
+            Transaction::from_edit(0, "one", "ONE"),
+            Transaction::from_edit(8, "three", "THREE"),
+            Transaction::from_edit(4, "two", "2"),
+        ];
+        for edit in edits.iter() {
+            edit.apply_to_text(&mut text);
+            history.push_edit(edit.clone(), None);
+        }
+        assert_eq!(text, "ONE 2 THREE");
+
+        // Undo within "ONE", leaving the later edits intact.
+        let undo = history.selective_undo((0, 3)).unwrap();
+        assert_eq!(undo.reverted_idx, 0);
+        undo.trans.apply_to_text(&mut text);
+        history.push_selective_undo(undo, None);
+        assert_eq!(text, "one 2 THREE");
+
+        // Nothing left to undo there.
+        assert!(history.selective_undo((0, 3)).is_none());
+
+        // Undo an edit whose position was shifted by a later edit.
+        let undo = history.selective_undo((6, 6)).unwrap();
+        assert_eq!(undo.reverted_idx, 1);
+        undo.trans.apply_to_text(&mut text);
+        history.push_selective_undo(undo, None);
+        assert_eq!(text, "one 2 three");
+
+        // The regular undo undoes the selective undos.
+        history.undo().unwrap().0.invert().apply_to_text(&mut text);
+        assert_eq!(text, "one 2 THREE");
+    }
+
+    #[test]
+    fn selective_undo_parts() {
+        let mut history = History::new();
+        let mut text = Rope::from_str("one two");
+
+        // A two-cursor edit.
+        let edit = Transaction::from_ordered_edit_set(
+            vec![(0, "one", "ONE"), (4, "two", "TWO")].into_iter(),
+        );
+        edit.apply_to_text(&mut text);
+        history.push_edit(edit, None);
+        assert_eq!(text, "ONE TWO");
+
+        // Undo each half in its own selection.
+        let undo = history.selective_undo((4, 7)).unwrap();
+        undo.trans.apply_to_text(&mut text);
+        history.push_selective_undo(undo, None);
+        assert_eq!(text, "ONE two");
+        assert!(history.selective_undo((4, 7)).is_none());
+
+        let undo = history.selective_undo((0, 3)).unwrap();
+        assert_eq!(undo.reverted_idx, 0);
+        undo.trans.apply_to_text(&mut text);
+        history.push_selective_undo(undo, None);
+        assert_eq!(text, "one two");
+        assert!(history.selective_undo((0, 7)).is_none());
+
+        // Which parts were reverted survives saving and loading.
+        let mut data = Vec::new();
+        history.write_to(&mut data).unwrap();
+        let loaded = History::read_from(&mut &data[..]).unwrap();
+        assert!(loaded.selective_undo((0, 7)).is_none());
+    }
+
+    #[test]
+    fn linear_discards_undone_states() {
+        let mut history = History::new();
+        history.push_edit(Transaction::from_edit(0, "", "aa"), None);
+        history.push_edit(Transaction::from_edit(2, "", "bb"), None);
+        history.undo();
+        history.push_edit(Transaction::from_edit(2, "", "xx"), None);
+
+        assert_eq!(history.states().len(), 3);
+        assert_eq!(history.position(), 2);
+    }
+
+    #[test]
+    fn group_typing() {
+        let mut history = History::new();
+        let mut text = Rope::from_str("");
+        for (i, c) in "Hello".chars().enumerate() {
+            let edit = Transaction::from_edit(i, "", &c.to_string());
+            edit.apply_to_text(&mut text);
+            history.push_edit(edit, None);
+        }
+
+        // Non-adjacent insertion starts a new group.
+        let edit = Transaction::from_edit(0, "", ">");
+        edit.apply_to_text(&mut text);
+        history.push_edit(edit, None);
+
+        assert_eq!(text, ">Hello");
+        assert_eq!(history.states().len(), 3);
+        history.undo().unwrap().0.invert().apply_to_text(&mut text);
+        assert_eq!(text, "Hello");
+        history.undo().unwrap().0.invert().apply_to_text(&mut text);
+        assert_eq!(text, "");
+    }
+
+    #[test]
+    fn group_deletion() {
+        let mut history = History::new();
+        let mut text = Rope::from_str("abcdef");
+
+        // Backspace twice from the end, then delete forward at the start.
+        for edit in [
+            Transaction::from_edit(5, "f", ""),
+            Transaction::from_edit(4, "e", ""),
+            Transaction::from_edit(0, "a", ""),
+            Transaction::from_edit(0, "b", ""),
+        ]
+        .iter()
+        {
+            edit.apply_to_text(&mut text);
+            history.push_edit(edit.clone(), None);
+        }
+
+        assert_eq!(text, "cd");
+        assert_eq!(history.states().len(), 3);
+        history.undo().unwrap().0.invert().apply_to_text(&mut text);
+        assert_eq!(text, "abcd");
+    }
+
+    #[test]
+    fn group_multi_cursor_typing() {
+        let mut history = History::new();
+        let mut text = Rope::from_str("a\nb\n");
+
+        // Type "xy" at the end of both lines, then backspace once.
+        for edit in [
+            Transaction::from_ordered_edit_set(vec![(1, "", "x"), (3, "", "x")].into_iter()),
+            Transaction::from_ordered_edit_set(vec![(2, "", "y"), (5, "", "y")].into_iter()),
+            Transaction::from_ordered_edit_set(vec![(2, "y", ""), (6, "y", "")].into_iter()),
+        ]
+        .iter()
+        {
+            edit.apply_to_text(&mut text);
+            history.push_edit(edit.clone(), None);
+        }
+        assert_eq!(text, "ax\nbx\n");
+        assert_eq!(history.states().len(), 3);
+
+        // Typing at only one of the cursors starts a new step.
+        let edit = Transaction::from_edit(2, "", "z");
+        edit.apply_to_text(&mut text);
+        history.push_edit(edit, None);
+        assert_eq!(history.states().len(), 4);
+
+        history.undo().unwrap().0.invert().apply_to_text(&mut text);
+        history.undo().unwrap().0.invert().apply_to_text(&mut text);
+        assert_eq!(text, "axy\nbxy\n");
+        history.undo().unwrap().0.invert().apply_to_text(&mut text);
+        assert_eq!(text, "a\nb\n");
+    }
+
+    #[test]
+    fn group_not_across_kinds_or_timeout() {
+        let mut history = History::new();
+        history.push_edit(Transaction::from_edit(0, "", "a"), None);
+        history.push_edit(Transaction::from_edit(1, "", "b"), None);
+        history.push_edit(Transaction::from_edit(1, "b", ""), None);
+        assert_eq!(history.states().len(), 3);
+
+        // Multi-grapheme edits (e.g. pastes) are always their own step.
+        history.push_edit(Transaction::from_edit(1, "", "xyz"), None);
+        history.push_edit(Transaction::from_edit(4, "", "w"), None);
+        assert_eq!(history.states().len(), 5);
+
+        history.set_group_timeout(Duration::from_millis(0));
+        std::thread::sleep(Duration::from_millis(5));
+        history.push_edit(Transaction::from_edit(5, "", "v"), None);
+        assert_eq!(history.states().len(), 6);
+    }
+
+    #[test]
+    fn save_load_round_trip() {
+        let path = temp_history_path("round_trip.hist");
+        let mut history = History::new();
+        history.push_edit(Transaction::from_edit(0, "", "Hello"), None);
+        history.push_edit(Transaction::from_edit(5, "", " world!"), None);
+        history.undo();
+        history.save(&path, &[1; 32]).unwrap();
+
+        let mut loaded = History::load(&path, &[1; 32]).unwrap().unwrap();
+        let mut text = Rope::from_str("Hello");
+        loaded.redo().unwrap().0.apply_to_text(&mut text);
+        assert_eq!(text, "Hello world!");
+        assert!(loaded.redo().is_none());
+
+        for _ in 0..2 {
+            let trans = loaded.undo().unwrap().0.invert();
+            trans.apply_to_text(&mut text);
+        }
+        assert_eq!(text, "");
+        assert!(loaded.undo().is_none());
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    fn cursors_at(positions: &[usize]) -> MarkSet {
+        let mut set = MarkSet::new();
+        for &pos in positions {
+            set.add_mark(Mark::new(pos, pos));
+        }
+        set
+    }
+
+    fn heads(set: Option<&MarkSet>) -> Vec<usize> {
+        set.unwrap().iter().map(|m| m.head).collect()
+    }
+
+    #[test]
+    fn cursors_restored() {
+        let mut history = History::new();
+        history.push_edit(
+            Transaction::from_ordered_edit_set([(0, "", "aa"), (5, "", "bb")].iter().copied()),
+            Some((cursors_at(&[0, 5]), cursors_at(&[2, 9]))),
+        );
+        history.push_edit(
+            Transaction::from_edit(2, "", "x"),
+            Some((cursors_at(&[2]), cursors_at(&[3]))),
+        );
+        history.push_edit(
+            Transaction::from_edit(3, "", "y"),
+            Some((cursors_at(&[3]), cursors_at(&[4]))),
+        );
+
+        // The two typed characters are one undo step, spanning from the
+        // first's "before" to the last's "after".
+        assert_eq!(heads(history.undo().unwrap().1), vec![2]);
+        assert_eq!(heads(history.undo().unwrap().1), vec![0, 5]);
+        assert_eq!(heads(history.redo().unwrap().1), vec![2, 9]);
+        assert_eq!(heads(history.redo().unwrap().1), vec![4]);
+
+        let (_, cursors) = history.jump_to_state(0).unwrap();
+        assert_eq!(heads(cursors.as_ref()), vec![0, 5]);
+    }
+
+    #[test]
+    fn load_stale() {
+        let path = temp_history_path("stale.hist");
+        let mut history = History::new();
+        history.push_edit(Transaction::from_edit(0, "", "Hello"), None);
+        history.save(&path, &[1; 32]).unwrap();
+
+        assert!(History::load(&path, &[2; 32]).unwrap().is_none());
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn load_missing() {
+        let path = temp_history_path("missing.hist");
+        assert!(History::load(&path, &[1; 32]).unwrap().is_none());
+    }
 }
@@ -1,10 +1,18 @@
+extern crate ignore;
 extern crate ropey;
 extern crate unicode_segmentation;
 
 pub mod buffer;
+pub mod diff;
 pub mod editor;
+pub mod encoding;
 pub mod hash;
 pub mod history;
+pub mod loader;
 pub mod marks;
 pub mod project;
+pub mod saver;
+mod serialize;
+pub mod session;
+pub mod state;
 pub mod transaction;
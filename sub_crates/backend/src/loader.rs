@@ -0,0 +1,177 @@
+//! Loading large files on a background thread.
+//!
+//! Reading and decoding a multi-gigabyte file takes a while, so files of
+//! at least `BACKGROUND_LOAD_SIZE` bytes are read by a worker thread in
+//! chunks instead.  Their buffer is opened right away, and the text is
+//! appended to it as it arrives, so the start of the file can be shown
+//! long before the rest is loaded.  Only a few chunks are queued at a time,
+//! so a slow consumer doesn't end up with the whole file in the queue.
+
+use std::{
+    fs::File,
+    io::{self, Read},
+    path::{Path, PathBuf},
+    sync::mpsc::{sync_channel, Receiver, SyncSender},
+    thread,
+};
+
+use crate::{
+    buffer::DiskState,
+    encoding::{detect, looks_binary, Decoder, Encoding},
+    hash::LedHash256,
+};
+
+/// Files at least this big are loaded in the background.
+pub const BACKGROUND_LOAD_SIZE: u64 = 8 << 20;
+
+/// How many bytes are read and decoded at a time.  The encoding is
+/// detected from the first chunk.
+const CHUNK_SIZE: usize = 1 << 20;
+
+/// How many decoded chunks can wait to be appended to the buffer before
+/// the worker thread stops reading.
+pub(crate) const MAX_QUEUED_CHUNKS: usize = 4;
+
+/// How far along a background load is.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct LoadProgress {
+    pub bytes_loaded: u64,
+    pub total_bytes: u64,
+}
+
+impl LoadProgress {
+    /// Returns the loaded fraction as a percentage from 0 to 100.
+    pub fn percentage(&self) -> usize {
+        (self.bytes_loaded.min(self.total_bytes) * 100)
+            .checked_div(self.total_bytes)
+            .unwrap_or(100) as usize
+    }
+}
+
+/// Something that happened to a buffer being loaded in the background,
+/// as returned by `Editor::poll_loads()`.
+#[derive(Debug)]
+pub enum LoadEvent {
+    /// More text was appended to the buffer at `buf_idx`.  `first` is set
+    /// the first time, when the start of the file becomes available.
+    Text { buf_idx: usize, first: bool },
+
+    /// The buffer at `buf_idx` finished loading.  If loading failed, the
+    /// buffer holds whatever was loaded before the error, and is made
+    /// read-only so the partial text doesn't get saved over the file.
+    Finished {
+        buf_idx: usize,
+        result: io::Result<()>,
+    },
+}
+
+/// A message from a worker thread about the file it's loading.
+#[derive(Debug)]
+pub(crate) enum LoadMessage {
+    // Sent first, once the encoding has been detected.
+    Started {
+        encoding: Encoding,
+        has_bom: bool,
+        is_binary: bool,
+    },
+
+    // The next piece of the text, and how many bytes have been read so far.
+    Text(String, u64),
+
+    // Sent last, with the state of the file as loaded.
+    Finished(io::Result<DiskState>),
+}
+
+/// A file being loaded on a worker thread.
+#[derive(Debug)]
+pub(crate) struct Load {
+    pub(crate) path: PathBuf,
+    pub(crate) messages: Receiver<LoadMessage>,
+    pub(crate) received_text: bool,
+}
+
+impl Load {
+    /// Starts loading the already opened `file` at `path`.
+    ///
+    /// The worker thread stops early if the `Load` is dropped.
+    pub(crate) fn start(path: &Path, file: File) -> Load {
+        let (send, recv) = sync_channel(MAX_QUEUED_CHUNKS);
+        thread::spawn(move || {
+            let result = load_in_chunks(file, &send);
+            send.send(LoadMessage::Finished(result)).ok();
+        });
+
+        Load {
+            path: path.into(),
+            messages: recv,
+            received_text: false,
+        }
+    }
+}
+
+//----------------------------------------------------------------------
+
+/// Reads, decodes, and sends the contents of `file`, and returns its state
+/// as loaded.
+fn load_in_chunks(mut file: File, send: &SyncSender<LoadMessage>) -> io::Result<DiskState> {
+    // Get the metadata before reading, so that if the file changes
+    // in-between, it's noticed by the next check for changes.
+    let metadata = file.metadata()?;
+
+    let mut buf = vec![0u8; CHUNK_SIZE];
+    let mut len = read_chunk(&mut file, &mut buf)?;
+    let (encoding, has_bom) = detect(&buf[..len]);
+    send_or_cancel(
+        send,
+        LoadMessage::Started {
+            encoding,
+            has_bom,
+            is_binary: looks_binary(&buf[..len], encoding),
+        },
+    )?;
+
+    let mut decoder = Decoder::new(encoding);
+    let mut hasher = LedHash256::new();
+    let mut start = if has_bom { encoding.bom().len() } else { 0 };
+    let mut bytes_read = len as u64;
+    loop {
+        let is_last = len < buf.len();
+        let text = decoder.decode(&buf[start..len], is_last);
+        hasher.update(text.as_bytes());
+        send_or_cancel(send, LoadMessage::Text(text, bytes_read))?;
+        if is_last {
+            break;
+        }
+
+        len = read_chunk(&mut file, &mut buf)?;
+        start = 0;
+        bytes_read += len as u64;
+    }
+
+    Ok(DiskState {
+        modified: metadata.modified().ok(),
+        size: metadata.len(),
+        hash: hasher.finish(),
+    })
+}
+
+/// Reads until `buf` is full or the end of the file is reached, and
+/// returns the number of bytes read.
+fn read_chunk(file: &mut File, buf: &mut [u8]) -> io::Result<usize> {
+    let mut len = 0;
+    while len < buf.len() {
+        match file.read(&mut buf[len..]) {
+            Ok(0) => break,
+            Ok(n) => len += n,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(len)
+}
+
+/// Sends a message, failing if the receiving `Load` has been dropped.
+fn send_or_cancel(send: &SyncSender<LoadMessage>, message: LoadMessage) -> io::Result<()> {
+    send.send(message)
+        .map_err(|_| io::Error::new(io::ErrorKind::Other, "load cancelled"))
+}
@@ -1,3 +1,7 @@
+use std::io::{self, Read, Write};
+
+use crate::serialize::{invalid_data, read_usize, write_u64};
+
 /// A mark on a piece of text, useful for representing cursors, selections, and
 /// general positions within a piece of text.
 ///
@@ -22,8 +26,8 @@ pub struct Mark {
 impl Mark {
     pub fn new(head: usize, tail: usize) -> Mark {
         Mark {
-            head: head,
-            tail: tail,
+            head,
+            tail,
             hh_pos: None,
         }
     }
@@ -94,8 +98,8 @@ impl Mark {
         };
 
         Mark {
-            head: head,
-            tail: tail,
+            head,
+            tail,
             hh_pos: None,
         }
     }
@@ -119,6 +123,12 @@ pub struct MarkSet {
     pub marks: Vec<Mark>,
 }
 
+impl Default for MarkSet {
+    fn default() -> MarkSet {
+        MarkSet::new()
+    }
+}
+
 impl MarkSet {
     /// Creates an empty MarkSet.
     pub fn new() -> MarkSet {
@@ -140,7 +150,7 @@ impl MarkSet {
 
     /// Returns the main mark, if it exists.
     pub fn main(&self) -> Option<Mark> {
-        self.marks.get(self.main_mark_idx).map(|m| *m)
+        self.marks.get(self.main_mark_idx).copied()
     }
 
     /// Removes all marks except the main one.
@@ -226,11 +236,43 @@ impl MarkSet {
         self.marks.truncate(i1 + 1);
     }
 
-    pub fn iter(&self) -> std::slice::Iter<Mark> {
+    /// Writes the MarkSet in led's binary state format.
+    ///
+    /// The marks' `hh_pos` is not written.
+    pub fn write_to<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        write_u64(w, self.main_mark_idx as u64)?;
+        write_u64(w, self.marks.len() as u64)?;
+        for mark in self.marks.iter() {
+            write_u64(w, mark.head as u64)?;
+            write_u64(w, mark.tail as u64)?;
+        }
+        Ok(())
+    }
+
+    /// Reads a MarkSet written by `write_to()`.
+    ///
+    /// Returns an error if the data is malformed.
+    pub fn read_from<R: Read>(r: &mut R) -> io::Result<MarkSet> {
+        let main_mark_idx = read_usize(r)?;
+        let len = read_usize(r)?;
+        if main_mark_idx >= len.max(1) {
+            return Err(invalid_data("invalid main mark index"));
+        }
+        let mut set = MarkSet::new();
+        set.main_mark_idx = main_mark_idx;
+        for _ in 0..len {
+            let head = read_usize(r)?;
+            let tail = read_usize(r)?;
+            set.marks.push(Mark::new(head, tail));
+        }
+        Ok(set)
+    }
+
+    pub fn iter(&self) -> std::slice::Iter<'_, Mark> {
         self.marks.iter()
     }
 
-    pub fn iter_mut(&mut self) -> std::slice::IterMut<Mark> {
+    pub fn iter_mut(&mut self) -> std::slice::IterMut<'_, Mark> {
         self.marks.iter_mut()
     }
 }
@@ -1,11 +1,406 @@
-use std::path::PathBuf;
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+    sync::mpsc::{channel, Receiver, TryRecvError},
+    thread,
+    time::{Duration, Instant},
+};
+
+use ignore::WalkBuilder;
+
+/// The most files a `ProjectIndex` lists.  A project with more than that
+/// is probably something like a home directory, which isn't worth keeping
+/// a full index of.
+const MAX_INDEXED_FILES: usize = 100_000;
+
+/// The shortest and longest times to wait between automatic rescans of a
+/// project, see `ProjectIndex::rescan_if_due()`.
+const MIN_RESCAN_INTERVAL: Duration = Duration::from_secs(5);
+const MAX_RESCAN_INTERVAL: Duration = Duration::from_secs(300);
+
+/// How many times as long as the last scan took to wait, at least, before
+/// rescanning automatically.
+const RESCAN_INTERVAL_FACTOR: u32 = 20;
 
 /// The set of files and directories specified as part of a project.
 ///
 /// These represent the items explicitly added by the user.  Files
 /// that are already under one of the `directories` aren't duplicated
 /// in the `files` list.
+#[derive(Debug, Clone)]
 pub struct ProjectSet {
     pub directories: Vec<PathBuf>,
     pub files: Vec<PathBuf>,
 }
+
+impl Default for ProjectSet {
+    fn default() -> ProjectSet {
+        ProjectSet::new()
+    }
+}
+
+impl ProjectSet {
+    /// Creates an empty project.
+    pub fn new() -> ProjectSet {
+        ProjectSet {
+            directories: Vec::new(),
+            files: Vec::new(),
+        }
+    }
+
+    /// Loads a project file.
+    ///
+    /// A project file lists the project's files and directories, one per
+    /// line, relative to the directory the project file is in.  Empty
+    /// lines and lines starting with `#` are ignored.
+    pub fn load(path: &Path) -> io::Result<ProjectSet> {
+        let text = fs::read_to_string(path)?;
+        let base = path.parent().unwrap_or_else(|| Path::new(""));
+        let mut project = ProjectSet::new();
+        for line in text.lines().map(|line| line.trim()) {
+            if !line.is_empty() && !line.starts_with('#') {
+                project.add(&base.join(line))?;
+            }
+        }
+        Ok(project)
+    }
+
+    /// Adds a file or directory to the project.
+    ///
+    /// Paths are made absolute, and anything already covered by one of
+    /// the project's directories is left out.  Fails if the path doesn't
+    /// exist.
+    pub fn add(&mut self, path: &Path) -> io::Result<()> {
+        let path = fs::canonicalize(path)?;
+        if self.directories.iter().any(|dir| path.starts_with(dir)) {
+            return Ok(());
+        }
+        if path.is_dir() {
+            self.directories.retain(|dir| !dir.starts_with(&path));
+            self.files.retain(|file| !file.starts_with(&path));
+            self.directories.push(path);
+        } else if !self.files.contains(&path) {
+            self.files.push(path);
+        }
+        Ok(())
+    }
+
+    /// Returns whether there's nothing in the project.
+    pub fn is_empty(&self) -> bool {
+        self.directories.is_empty() && self.files.is_empty()
+    }
+
+    /// Returns the paths of all files in the project: the explicitly added
+    /// files, followed by the files under each of the directories,
+    /// recursively.
+    ///
+    /// Files and directories ignored by `.gitignore` or `.ignore` files
+    /// are skipped, as are hidden ones (whose names start with a `.`),
+    /// such as version control directories.  So are directories that
+    /// can't be read, and symlinks to directories, which could otherwise
+    /// make the walk go in circles.
+    pub fn list_files(&self) -> Vec<PathBuf> {
+        self.list_files_up_to(usize::MAX).0
+    }
+
+    /// Like `list_files()`, but stops after `max_files` files.  Also
+    /// returns whether it stopped early.
+    fn list_files_up_to(&self, max_files: usize) -> (Vec<PathBuf>, bool) {
+        let mut files: Vec<PathBuf> = self.files.iter().take(max_files).cloned().collect();
+        let mut truncated = files.len() < self.files.len();
+        for dir in self.directories.iter() {
+            if truncated {
+                break;
+            }
+            truncated = !list_dir_files(dir, &mut files, max_files);
+        }
+        (files, truncated)
+    }
+}
+
+/// An index of the files in a project, kept up to date by periodically
+/// re-listing them in the background.
+pub struct ProjectIndex {
+    project: ProjectSet,
+    files: Vec<PathBuf>,
+    is_indexed: bool,   // Whether `files` has been filled in yet.
+    is_truncated: bool, // Whether `files` stops at `MAX_INDEXED_FILES`.
+    change_count: u64,  // Incremented whenever `files` changes.
+
+    // When the last scan finished, and how long to wait after that before
+    // rescanning automatically.
+    last_scan: Option<Instant>,
+    rescan_interval: Duration,
+
+    // The scan in progress, if any, and when the latest scan started.
+    // Scans send the files they found, and whether they stopped early.
+    scan: Option<Receiver<(Vec<PathBuf>, bool)>>,
+    scan_started: Instant,
+}
+
+impl ProjectIndex {
+    /// Creates an index of `project`'s files, and starts filling it in.
+    pub fn new(project: ProjectSet) -> ProjectIndex {
+        let mut index = ProjectIndex {
+            project,
+            files: Vec::new(),
+            is_indexed: false,
+            is_truncated: false,
+            change_count: 0,
+            last_scan: None,
+            rescan_interval: MIN_RESCAN_INTERVAL,
+            scan: None,
+            scan_started: Instant::now(),
+        };
+        index.rescan();
+        index
+    }
+
+    /// The project being indexed.
+    pub fn project(&self) -> &ProjectSet {
+        &self.project
+    }
+
+    /// The project's files, as of the last finished scan.  See
+    /// `ProjectSet::list_files()` for which files are included.
+    pub fn files(&self) -> &[PathBuf] {
+        &self.files
+    }
+
+    /// Returns whether the first scan of the project has finished.
+    pub fn is_indexed(&self) -> bool {
+        self.is_indexed
+    }
+
+    /// Returns whether the project has more files than are indexed, in
+    /// which case only the first `MAX_INDEXED_FILES` of them are.
+    pub fn is_truncated(&self) -> bool {
+        self.is_truncated
+    }
+
+    /// Returns how many times the indexed files have changed, for
+    /// noticing when they have.
+    pub fn change_count(&self) -> u64 {
+        self.change_count
+    }
+
+    /// Starts re-listing the project's files in the background, to pick up
+    /// files that were added or removed.  Does nothing if a scan is already
+    /// in progress.  See `poll()`.
+    pub fn rescan(&mut self) {
+        if self.scan.is_some() {
+            return;
+        }
+        let (send, recv) = channel();
+        let project = self.project.clone();
+        thread::spawn(move || {
+            send.send(project.list_files_up_to(MAX_INDEXED_FILES)).ok();
+        });
+        self.scan = Some(recv);
+        self.scan_started = Instant::now();
+    }
+
+    /// Starts a rescan if it's been long enough since the last one.  Meant
+    /// to be called periodically, to keep the index up to date.
+    ///
+    /// Rescans are spaced out further the longer scans take and the longer
+    /// nothing changes, so that big projects aren't re-walked constantly.
+    /// A project with too many files to index in full isn't rescanned
+    /// automatically at all.
+    pub fn rescan_if_due(&mut self) {
+        if self.is_truncated {
+            return;
+        }
+        match self.last_scan {
+            Some(time) if time.elapsed() >= self.rescan_interval => self.rescan(),
+            _ => {}
+        }
+    }
+
+    /// Takes the results of the scan in progress, if it's finished.
+    /// Doesn't block.  Returns whether the indexed files changed.
+    pub fn poll(&mut self) -> bool {
+        let result = match self.scan.as_ref().map(|scan| scan.try_recv()) {
+            Some(Ok(result)) => result,
+            Some(Err(TryRecvError::Disconnected)) => (Vec::new(), false),
+            Some(Err(TryRecvError::Empty)) | None => return false,
+        };
+        self.scan = None;
+        self.finish_scan(result)
+    }
+
+    /// Blocks until the scan in progress, if any, has finished, and
+    /// returns whether the indexed files changed.
+    pub fn wait_for_scan(&mut self) -> bool {
+        let result = match self.scan.take() {
+            Some(scan) => scan.recv().unwrap_or_default(),
+            None => return false,
+        };
+        self.finish_scan(result)
+    }
+
+    fn finish_scan(&mut self, result: (Vec<PathBuf>, bool)) -> bool {
+        let (files, is_truncated) = result;
+        let changed = !self.is_indexed || files != self.files;
+        if changed {
+            self.change_count += 1;
+        }
+        self.files = files;
+        self.is_indexed = true;
+        self.is_truncated = is_truncated;
+
+        // Back off while nothing changes.
+        let interval = if changed {
+            MIN_RESCAN_INTERVAL
+        } else {
+            self.rescan_interval * 2
+        };
+        self.rescan_interval = interval
+            .max(self.scan_started.elapsed() * RESCAN_INTERVAL_FACTOR)
+            .min(MAX_RESCAN_INTERVAL);
+        self.last_scan = Some(Instant::now());
+
+        changed
+    }
+}
+
+//----------------------------------------------------------------------
+
+/// Appends the paths of the files under `dir` to `files`, recursively and
+/// in sorted order, until there are `max_files` of them.  Returns whether
+/// all of them were added.  See `ProjectSet::list_files()`.
+fn list_dir_files(dir: &Path, files: &mut Vec<PathBuf>, max_files: usize) -> bool {
+    let walk = WalkBuilder::new(dir)
+        .hidden(true)
+        .ignore(true)
+        .git_ignore(true)
+        .git_exclude(true)
+        .require_git(false)
+        .follow_links(false)
+        .sort_by_file_name(|a, b| a.cmp(b))
+        .build();
+
+    for entry in walk.filter_map(|entry| entry.ok()) {
+        let path = entry.path();
+        let is_file = match entry.file_type() {
+            Some(t) if t.is_file() => true,
+            Some(t) => t.is_symlink() && path.is_file(),
+            None => false,
+        };
+        if is_file {
+            if files.len() >= max_files {
+                return false;
+            }
+            files.push(path.into());
+        }
+    }
+    true
+}
+
+//----------------------------------------------------------------------
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn list_files() {
+        let dir = std::env::temp_dir().join(format!("led_test_project_{}", std::process::id()));
+        fs::create_dir_all(dir.join("src/sub")).unwrap();
+        fs::create_dir_all(dir.join(".git")).unwrap();
+        fs::create_dir_all(dir.join("target")).unwrap();
+        fs::write(dir.join("b.txt"), "").unwrap();
+        fs::write(dir.join("a.txt"), "").unwrap();
+        fs::write(dir.join(".hidden"), "").unwrap();
+        fs::write(dir.join(".git/config"), "").unwrap();
+        fs::write(dir.join("src/sub/c.txt"), "").unwrap();
+        fs::write(dir.join("src/sub/d.log"), "").unwrap();
+        fs::write(dir.join("src/e.tmp"), "").unwrap();
+        fs::write(dir.join("target/f.txt"), "").unwrap();
+        fs::write(dir.join(".gitignore"), "target/\n*.log\n").unwrap();
+        fs::write(dir.join("src/.ignore"), "*.tmp\n").unwrap();
+
+        let project = ProjectSet {
+            directories: vec![dir.clone()],
+            files: vec!["other.txt".into()],
+        };
+        assert_eq!(
+            project.list_files(),
+            vec![
+                PathBuf::from("other.txt"),
+                dir.join("a.txt"),
+                dir.join("b.txt"),
+                dir.join("src/sub/c.txt"),
+            ]
+        );
+        assert_eq!(
+            project.list_files_up_to(3),
+            (
+                vec![
+                    PathBuf::from("other.txt"),
+                    dir.join("a.txt"),
+                    dir.join("b.txt"),
+                ],
+                true
+            )
+        );
+        assert!(!project.list_files_up_to(4).1);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn load_and_index() {
+        let dir =
+            std::env::temp_dir().join(format!("led_test_project_index_{}", std::process::id()));
+        fs::create_dir_all(dir.join("src")).unwrap();
+        fs::write(dir.join("src/a.txt"), "").unwrap();
+        fs::write(dir.join("src/b.txt"), "").unwrap();
+        fs::write(dir.join("notes.txt"), "").unwrap();
+        fs::write(
+            dir.join("led-project"),
+            "# Sources\nsrc\n\nnotes.txt\nsrc/a.txt\n",
+        )
+        .unwrap();
+
+        let project = ProjectSet::load(&dir.join("led-project")).unwrap();
+        let dir = fs::canonicalize(&dir).unwrap();
+        assert_eq!(project.directories, vec![dir.join("src")]);
+        assert_eq!(project.files, vec![dir.join("notes.txt")]);
+        assert!(ProjectSet::load(&dir.join("missing")).is_err());
+
+        let mut index = ProjectIndex::new(project);
+        index.wait_for_scan();
+        assert!(index.is_indexed());
+        assert_eq!(
+            index.files(),
+            &[
+                dir.join("notes.txt"),
+                dir.join("src/a.txt"),
+                dir.join("src/b.txt")
+            ][..]
+        );
+
+        // Files added and removed are picked up by the next scan.
+        fs::remove_file(dir.join("src/a.txt")).unwrap();
+        fs::write(dir.join("src/c.txt"), "").unwrap();
+        index.rescan();
+        assert!(index.wait_for_scan());
+        assert_eq!(
+            index.files(),
+            &[
+                dir.join("notes.txt"),
+                dir.join("src/b.txt"),
+                dir.join("src/c.txt")
+            ][..]
+        );
+        assert_eq!(index.rescan_interval, MIN_RESCAN_INTERVAL);
+        index.rescan();
+        assert!(!index.wait_for_scan());
+
+        // Rescanning backs off while nothing changes.
+        assert_eq!(index.rescan_interval, MIN_RESCAN_INTERVAL * 2);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,530 @@
+//! Saving buffers to disk on a background thread.
+//!
+//! Writing a large file to a slow disk can take a while, so saves are
+//! handed off to a worker thread as snapshots of the buffer's content.
+//! The buffer can keep being edited in the meantime, and is only
+//! considered clean once a save of its current content has finished.
+
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, BufWriter, Write},
+    path::{Path, PathBuf},
+    sync::mpsc::{channel, Receiver, Sender},
+    thread,
+};
+
+use ropey::Rope;
+
+use crate::{
+    buffer::DiskState, encoding::Encoding, hash::LedHash256, history::History, state::history_path,
+};
+
+/// A snapshot of a buffer's content, to be written to its file.
+#[derive(Debug)]
+pub struct SaveJob {
+    pub path: PathBuf,
+    pub text: Rope,
+    pub encoding: Encoding,
+    pub has_bom: bool,
+
+    // The buffer's edit count at the time of the snapshot, for telling
+    // whether the buffer has been edited since.
+    pub(crate) edit_count: u64,
+
+    // The buffer's undo history, which is stored alongside the saved text.
+    pub(crate) history: History,
+}
+
+impl SaveJob {
+    /// Writes the text to the file, and returns the state of the file
+    /// after writing.
+    ///
+    /// The undo history is stored as well, but since the text itself is
+    /// already saved at that point, failing to store the history isn't
+    /// treated as a failed save.
+    pub fn run(&self) -> io::Result<DiskState> {
+        let hash = write_file(&self.path, &self.text, self.encoding, self.has_bom)?;
+        let disk_state = DiskState::from_file(&self.path, hash)?;
+
+        if let Some(path) = history_path(&self.path) {
+            self.history.save(&path, &hash).ok();
+        }
+
+        Ok(disk_state)
+    }
+}
+
+/// The outcome of a `SaveJob`.
+#[derive(Debug)]
+pub struct SaveResult {
+    pub path: PathBuf,
+    pub(crate) edit_count: u64,
+
+    /// The state of the file after saving, or the error that kept the
+    /// save from completing.
+    pub result: io::Result<DiskState>,
+}
+
+/// Runs `SaveJob`s in order on a background thread.
+#[derive(Debug)]
+pub struct Saver {
+    jobs: Sender<SaveJob>,
+    results: Receiver<SaveResult>,
+
+    // The path and edit count of each queued or running job.
+    pending: Vec<(PathBuf, u64)>,
+
+    // Results of jobs that had to be run on the calling thread, or that
+    // `wait_for()` received for other jobs, and that `finished()` hasn't
+    // returned yet.
+    unreported: Vec<SaveResult>,
+}
+
+impl Default for Saver {
+    fn default() -> Saver {
+        Saver::new()
+    }
+}
+
+impl Saver {
+    pub fn new() -> Saver {
+        let (jobs_send, jobs_recv) = channel::<SaveJob>();
+        let (results_send, results_recv) = channel();
+
+        // The thread exits once the Saver, and with it the job sender, is
+        // dropped.
+        thread::spawn(move || {
+            for job in jobs_recv.iter() {
+                let result = job.run();
+                let sent = results_send.send(SaveResult {
+                    path: job.path,
+                    edit_count: job.edit_count,
+                    result,
+                });
+                if sent.is_err() {
+                    break;
+                }
+            }
+        });
+
+        Saver {
+            jobs: jobs_send,
+            results: results_recv,
+            pending: Vec::new(),
+            unreported: Vec::new(),
+        }
+    }
+
+    /// Queues a job to be run after any already queued ones.
+    pub fn queue(&mut self, job: SaveJob) {
+        self.pending.push((job.path.clone(), job.edit_count));
+        if let Err(e) = self.jobs.send(job) {
+            // The worker thread is gone, which can only happen if it
+            // panicked.  Run the job here instead, so it isn't lost.
+            let job = e.0;
+            let result = job.run();
+            self.pending.pop();
+            self.unreported.push(SaveResult {
+                path: job.path,
+                edit_count: job.edit_count,
+                result,
+            });
+        }
+    }
+
+    /// Returns whether a save of the given path at the given edit count
+    /// is queued or running.
+    pub fn is_pending(&self, path: &Path, edit_count: u64) -> bool {
+        self.pending
+            .iter()
+            .any(|(p, n)| p == path && *n == edit_count)
+    }
+
+    /// Returns whether any save of the given path is queued or running.
+    pub fn is_saving(&self, path: &Path) -> bool {
+        self.pending.iter().any(|(p, _)| p == path)
+    }
+
+    /// Returns whether any saves are queued or running.
+    pub fn is_busy(&self) -> bool {
+        !self.pending.is_empty()
+    }
+
+    /// Returns the results of the saves that have finished since the last
+    /// call, without blocking.
+    pub fn finished(&mut self) -> Vec<SaveResult> {
+        let mut results = std::mem::take(&mut self.unreported);
+        while let Ok(result) = self.results.try_recv() {
+            results.push(result);
+        }
+        self.remove_pending(&results);
+        results
+    }
+
+    /// Blocks until the save of the given path at the given edit count has
+    /// finished, and returns its result.  Returns `None` if no such save is
+    /// queued or running, and none has finished since the last call to
+    /// `finished()`.
+    ///
+    /// The results of other saves that finish in the meantime are kept for
+    /// `finished()` to return.
+    pub fn wait_for(&mut self, path: &Path, edit_count: u64) -> Option<SaveResult> {
+        let is_job = |r: &SaveResult| r.path == path && r.edit_count == edit_count;
+        if let Some(i) = self.unreported.iter().position(is_job) {
+            return Some(self.unreported.remove(i));
+        }
+        while self.is_pending(path, edit_count) {
+            match self.results.recv() {
+                Ok(result) => {
+                    self.remove_pending(std::slice::from_ref(&result));
+                    if is_job(&result) {
+                        return Some(result);
+                    }
+                    self.unreported.push(result);
+                }
+                Err(_) => {
+                    // The worker thread is gone, so nothing else will
+                    // finish.
+                    self.pending.clear();
+                }
+            }
+        }
+        None
+    }
+
+    /// Blocks until all queued saves have finished, and returns the
+    /// results of the saves that have finished since the last call.
+    pub fn wait(&mut self) -> Vec<SaveResult> {
+        let mut results = self.finished();
+        while self.is_busy() {
+            match self.results.recv() {
+                Ok(result) => {
+                    self.remove_pending(std::slice::from_ref(&result));
+                    results.push(result);
+                }
+                Err(_) => {
+                    // The worker thread is gone, so nothing else will
+                    // finish.
+                    self.pending.clear();
+                }
+            }
+        }
+        results
+    }
+
+    //----------------------------------------------------
+    // Helper methods
+
+    fn remove_pending(&mut self, results: &[SaveResult]) {
+        for result in results.iter() {
+            if let Some(i) = self
+                .pending
+                .iter()
+                .position(|(p, n)| *p == result.path && *n == result.edit_count)
+            {
+                self.pending.remove(i);
+            }
+        }
+    }
+}
+
+//----------------------------------------------------------------------
+
+/// How many symlinks to follow before giving up, e.g. due to a loop.
+const MAX_SYMLINK_DEPTH: usize = 40;
+
+/// How much of the original file name to use in a temporary file's name.
+const MAX_TEMP_NAME_PREFIX_LEN: usize = 200;
+
+/// Writes `text` to the file at `path` in the given encoding, and returns
+/// the hash of the text.
+///
+/// The text is written to a temporary file in the same directory, synced
+/// to disk, and then renamed over the original.  That way a crash or a
+/// full disk partway through can't leave behind a truncated file.  The
+/// original's permissions and ownership are carried over, and if `path`
+/// is a symlink, the link's target is written.
+///
+/// If we aren't allowed to create files in the directory, the original is
+/// overwritten in place instead, since that's the only way to save it at
+/// all.  Any other failure, including not being able to give the new file
+/// the original's ownership or permissions, is returned as an error and
+/// leaves the original untouched.
+fn write_file(path: &Path, text: &Rope, encoding: Encoding, has_bom: bool) -> io::Result<[u8; 32]> {
+    let path = resolve_symlinks(path)?;
+    let metadata = match fs::metadata(&path) {
+        Ok(metadata) => Some(metadata),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+        Err(e) => return Err(e),
+    };
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir.to_path_buf(),
+        _ => PathBuf::from("."),
+    };
+
+    let (temp_path, temp_file) = match create_temp_file(&dir, &path) {
+        Ok(temp) => temp,
+        Err(e) if e.kind() == io::ErrorKind::PermissionDenied => {
+            return write_in_place(&path, text, encoding, has_bom);
+        }
+        Err(e) => return Err(e),
+    };
+
+    if let Some(ref metadata) = metadata {
+        let preserved = copy_ownership(&temp_file, metadata)
+            .and_then(|_| temp_file.set_permissions(metadata.permissions()));
+        if let Err(e) = preserved {
+            drop(temp_file);
+            fs::remove_file(&temp_path).ok();
+            return Err(io::Error::new(
+                e.kind(),
+                format!(
+                    "can't give the saved file the original's owner and permissions: {}",
+                    e
+                ),
+            ));
+        }
+    }
+
+    let result = write_synced(temp_file, text, encoding, has_bom)
+        .and_then(|hash| fs::rename(&temp_path, &path).map(|_| hash));
+    match result {
+        Ok(hash) => {
+            sync_dir(&dir);
+            Ok(hash)
+        }
+        Err(e) => {
+            fs::remove_file(&temp_path).ok();
+            Err(e)
+        }
+    }
+}
+
+/// Overwrites the file at `path` with `text` in the given encoding, and
+/// returns the hash of the text.
+fn write_in_place(
+    path: &Path,
+    text: &Rope,
+    encoding: Encoding,
+    has_bom: bool,
+) -> io::Result<[u8; 32]> {
+    let file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(path)?;
+    write_synced(file, text, encoding, has_bom)
+}
+
+/// Writes `text` to `file` in the given encoding and syncs it to disk,
+/// and returns the hash of the text.
+fn write_synced(
+    file: File,
+    text: &Rope,
+    encoding: Encoding,
+    has_bom: bool,
+) -> io::Result<[u8; 32]> {
+    let mut hash = LedHash256::new();
+    let mut f = BufWriter::new(file);
+    if has_bom {
+        f.write_all(encoding.bom())?;
+    }
+    let mut bytes = Vec::new();
+    for chunk in text.chunks() {
+        bytes.clear();
+        encoding.encode(chunk, &mut bytes).map_err(|c| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{:?} can't be encoded in {}", c, encoding.name()),
+            )
+        })?;
+        f.write_all(&bytes)?;
+        hash.update(chunk.as_bytes());
+    }
+    let file = f.into_inner().map_err(|e| e.into_error())?;
+    file.sync_all()?;
+    Ok(hash.finish())
+}
+
+/// Creates a new, uniquely named file in `dir` to write the contents of
+/// `path` to before renaming it over `path`.
+fn create_temp_file(dir: &Path, path: &Path) -> io::Result<(PathBuf, File)> {
+    let mut name = path
+        .file_name()
+        .map(|n| n.to_string_lossy().into_owned())
+        .unwrap_or_default();
+
+    // Leave room for the rest of the temporary file's name, since file
+    // names are commonly limited to 255 bytes.
+    while name.len() > MAX_TEMP_NAME_PREFIX_LEN {
+        name.pop();
+    }
+
+    let mut n = 0;
+    loop {
+        let temp_path = dir.join(format!(".{}.{}-{}.led-tmp", name, std::process::id(), n));
+        match OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&temp_path)
+        {
+            Ok(file) => return Ok((temp_path, file)),
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists && n < 100 => n += 1,
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Follows `path` through any symlinks, and returns the path of the
+/// final target, which may not exist.
+fn resolve_symlinks(path: &Path) -> io::Result<PathBuf> {
+    let mut path = path.to_path_buf();
+    for _ in 0..MAX_SYMLINK_DEPTH {
+        match fs::symlink_metadata(&path) {
+            Ok(metadata) if metadata.file_type().is_symlink() => {
+                // Relative link targets are relative to the link's
+                // directory, and `join()` leaves absolute ones as-is.
+                let target = fs::read_link(&path)?;
+                path = match path.parent() {
+                    Some(dir) => dir.join(target),
+                    None => target,
+                };
+            }
+            _ => return Ok(path),
+        }
+    }
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "too many levels of symbolic links",
+    ))
+}
+
+/// Gives `file` the owner and group from `metadata`, if it doesn't
+/// already have them.
+#[cfg(unix)]
+fn copy_ownership(file: &File, metadata: &fs::Metadata) -> io::Result<()> {
+    use std::os::unix::fs::{fchown, MetadataExt};
+
+    let current = file.metadata()?;
+    if current.uid() == metadata.uid() && current.gid() == metadata.gid() {
+        return Ok(());
+    }
+    fchown(file, Some(metadata.uid()), Some(metadata.gid()))
+}
+
+#[cfg(not(unix))]
+fn copy_ownership(_file: &File, _metadata: &fs::Metadata) -> io::Result<()> {
+    Ok(())
+}
+
+/// Syncs a directory to disk, so that a rename within it is durable.
+/// This is best-effort, since not all platforms and file systems support
+/// it.
+#[cfg(unix)]
+fn sync_dir(dir: &Path) {
+    if let Ok(dir) = File::open(dir) {
+        dir.sync_all().ok();
+    }
+}
+
+#[cfg(not(unix))]
+fn sync_dir(_dir: &Path) {}
+
+//----------------------------------------------------------------------
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{editor::Editor, marks::Mark};
+
+    #[test]
+    fn background_save() {
+        let dir = std::env::temp_dir().join(format!("led_test_saver_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let file_path = dir.join("file.txt");
+        fs::write(&file_path, "Hello world!").unwrap();
+
+        let mut ed = Editor::new();
+        let idx = ed.open_file(&file_path).unwrap();
+        ed[idx].add_mark_set();
+        ed[idx].mark_sets[0].add_mark(Mark::new(0, 0));
+
+        ed[idx].edit_at_cursors(0, vec![((5, 5), ",".into())]);
+        ed.save_buffer(idx);
+        let results = ed.wait_for_saves();
+        assert_eq!(results.len(), 1);
+        assert!(results[0].result.is_ok());
+        assert!(!ed[idx].is_dirty);
+        assert_eq!(
+            ed[idx].disk_state.map(|d| d.hash),
+            Some(ed[idx].content_hash())
+        );
+        assert_eq!(fs::read_to_string(&file_path).unwrap(), "Hello, world!");
+
+        // Edits made while a save is in progress keep the buffer dirty.
+        ed[idx].edit_at_cursors(0, vec![((0, 0), "Oh, ".into())]);
+        ed.save_buffer(idx);
+        ed[idx].edit_at_cursors(0, vec![((13, 13), "...".into())]);
+        ed.wait_for_saves();
+        assert!(ed[idx].is_dirty);
+        assert_eq!(fs::read_to_string(&file_path).unwrap(), "Oh, Hello, world!");
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn save_through_symlink_keeps_permissions() {
+        use std::os::unix::fs::{symlink, PermissionsExt};
+
+        let dir = std::env::temp_dir().join(format!("led_test_saver_link_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let target = dir.join("target.txt");
+        let link = dir.join("link.txt");
+        fs::write(&target, "Hello world!").unwrap();
+        fs::set_permissions(&target, fs::Permissions::from_mode(0o640)).unwrap();
+        symlink("target.txt", &link).unwrap();
+
+        write_file(
+            &link,
+            &Rope::from_str("Goodbye world!"),
+            Encoding::Utf8,
+            false,
+        )
+        .unwrap();
+
+        assert!(fs::symlink_metadata(&link)
+            .unwrap()
+            .file_type()
+            .is_symlink());
+        assert_eq!(fs::read_to_string(&target).unwrap(), "Goodbye world!");
+        let mode = fs::metadata(&target).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o640);
+
+        // No temporary files are left behind.
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 2);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn save_file_with_long_name() {
+        let dir = std::env::temp_dir().join(format!("led_test_saver_long_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let file_path = dir.join("a".repeat(250));
+        fs::write(&file_path, "Hello world!").unwrap();
+
+        write_file(
+            &file_path,
+            &Rope::from_str("Goodbye world!"),
+            Encoding::Utf8,
+            false,
+        )
+        .unwrap();
+
+        assert_eq!(fs::read_to_string(&file_path).unwrap(), "Goodbye world!");
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,47 @@
+//! Helpers for reading and writing led's simple binary state formats.
+//!
+//! All integers are stored as little-endian `u64`s, and byte strings are
+//! stored as a `u64` length followed by the bytes.
+
+use std::io::{self, Read, Write};
+
+pub(crate) fn write_u64<W: Write>(w: &mut W, n: u64) -> io::Result<()> {
+    w.write_all(&n.to_le_bytes())
+}
+
+pub(crate) fn read_u64<R: Read>(r: &mut R) -> io::Result<u64> {
+    let mut bytes = [0u8; 8];
+    r.read_exact(&mut bytes)?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+pub(crate) fn read_usize<R: Read>(r: &mut R) -> io::Result<usize> {
+    let n = read_u64(r)?;
+    if n > usize::MAX as u64 {
+        return Err(invalid_data("integer out of range"));
+    }
+    Ok(n as usize)
+}
+
+pub(crate) fn write_bytes<W: Write>(w: &mut W, bytes: &[u8]) -> io::Result<()> {
+    write_u64(w, bytes.len() as u64)?;
+    w.write_all(bytes)
+}
+
+pub(crate) fn read_bytes<R: Read>(r: &mut R) -> io::Result<Vec<u8>> {
+    let len = read_u64(r)?;
+    let mut bytes = Vec::new();
+    r.take(len).read_to_end(&mut bytes)?;
+    if bytes.len() as u64 != len {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok(bytes)
+}
+
+pub(crate) fn read_string<R: Read>(r: &mut R) -> io::Result<String> {
+    String::from_utf8(read_bytes(r)?).map_err(|_| invalid_data("invalid utf8"))
+}
+
+pub(crate) fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
@@ -0,0 +1,376 @@
+//! Persisting unsaved edits across editor restarts.
+//!
+//! The text, mark sets, and undo history of every buffer with unsaved
+//! edits are written to the session file in the state directory, both
+//! while editing and when the editor quits, and restored the next time
+//! the editor starts.  File buffers also store the content hash of the
+//! file as they last saw it on disk, so that edits aren't silently
+//! restored on top of a file that has since changed.
+
+use std::{
+    fs::{self, File},
+    io::{self, BufReader, BufWriter, Read, Write},
+    path::{Path, PathBuf},
+};
+
+use ropey::Rope;
+
+use crate::{
+    buffer::{Buffer, BufferPath},
+    editor::Editor,
+    history::History,
+    marks::MarkSet,
+    serialize::{invalid_data, read_string, read_u64, read_usize, write_bytes, write_u64},
+    state::session_path,
+};
+
+const SESSION_FILE_MAGIC: &[u8; 8] = b"LEDSESS2";
+
+/// Saves the unsaved state of the given editor's buffers to the session
+/// file, replacing any previously saved session.
+///
+/// This includes file buffers with unsaved changes and non-empty
+/// temporary buffers.  If there are none, the session file is removed.
+pub fn save_session(editor: &Editor) -> io::Result<()> {
+    Session::new(editor).save()
+}
+
+/// A snapshot of the unsaved state of an editor's buffers, for saving it
+/// to the session file later or on another thread.
+///
+/// Taking a snapshot is cheap: buffer text and undo history are shared
+/// with the buffers rather than copied.
+pub struct Session {
+    buffers: Vec<SessionBuffer>,
+}
+
+struct SessionBuffer {
+    path: BufferPath,
+    disk_hash: Option<[u8; 32]>,
+    edit_count: u64,
+    text: Rope,
+    mark_sets: Vec<MarkSet>,
+    history: History,
+}
+
+impl Session {
+    /// Takes a snapshot of the buffers `save_session()` would save.
+    pub fn new(editor: &Editor) -> Session {
+        let buffers = editor
+            .iter()
+            .filter(|b| match b.path {
+                BufferPath::File(_) => b.is_dirty,
+                BufferPath::Temp(_) => b.text.len_bytes() > 0,
+            })
+            .map(|b| SessionBuffer {
+                path: b.path.clone(),
+                disk_hash: b.disk_state.map(|d| d.hash),
+                edit_count: b.edit_count,
+                text: b.text.clone(),
+                mark_sets: b.mark_sets.clone(),
+                history: b.history.clone(),
+            })
+            .collect();
+
+        Session { buffers }
+    }
+
+    /// Returns which buffers are in the snapshot, and at which edit count.
+    ///
+    /// Snapshots with the same key hold the same edits, so this can be
+    /// used to skip saving when nothing changed since the last save.
+    pub fn key(&self) -> Vec<(BufferPath, u64)> {
+        self.buffers
+            .iter()
+            .map(|b| (b.path.clone(), b.edit_count))
+            .collect()
+    }
+
+    /// Saves the snapshot to the session file, replacing any previously
+    /// saved session.
+    pub fn save(&self) -> io::Result<()> {
+        match session_path() {
+            Some(path) => self.save_to(&path),
+            None => Ok(()),
+        }
+    }
+
+    fn save_to(&self, path: &Path) -> io::Result<()> {
+        if self.buffers.is_empty() {
+            return match fs::remove_file(path) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+                _ => Ok(()),
+            };
+        }
+
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+
+        // Write to a temporary file first, so that a crash partway through
+        // doesn't clobber the previous session.
+        let temp_path = path.with_extension("tmp");
+        {
+            let mut f = BufWriter::new(File::create(&temp_path)?);
+            f.write_all(SESSION_FILE_MAGIC)?;
+            write_u64(&mut f, self.buffers.len() as u64)?;
+            for buffer in self.buffers.iter() {
+                write_buffer(&mut f, buffer)?;
+            }
+            f.flush()?;
+        }
+        fs::rename(&temp_path, path)
+    }
+}
+
+/// Restores the session saved by `save_session()` into the given editor.
+///
+/// Unsaved edits to files are applied to the file's buffer, opening the
+/// file if it isn't already open.  However, if the file has changed on
+/// disk since the session was saved, the file is left as it is on disk
+/// and the unsaved edits are restored into a new temporary buffer
+/// instead.  Returns the paths of such files.
+pub fn restore_session(editor: &mut Editor) -> io::Result<Vec<PathBuf>> {
+    match session_path() {
+        Some(path) => restore_session_from(editor, &path),
+        None => Ok(Vec::new()),
+    }
+}
+
+//----------------------------------------------------------------------
+
+fn restore_session_from(editor: &mut Editor, path: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut f = match File::open(path) {
+        Ok(f) => BufReader::new(f),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+
+    let mut magic = [0u8; 8];
+    f.read_exact(&mut magic)?;
+    if &magic != SESSION_FILE_MAGIC {
+        return Err(invalid_data("not a led session file"));
+    }
+
+    // Read everything before touching the editor, so that a corrupt
+    // session file doesn't leave things half-restored.
+    let count = read_usize(&mut f)?;
+    let mut stored = Vec::new();
+    for _ in 0..count {
+        stored.push(read_buffer(&mut f)?);
+    }
+
+    let mut changed_files = Vec::new();
+    for s in stored {
+        let path = s.path.clone();
+        match path {
+            BufferPath::Temp(id) => {
+                let id = if editor.find(&path).is_some() {
+                    editor.unused_temp_id()
+                } else {
+                    id
+                };
+                editor.add_buffer(s.into_buffer(BufferPath::Temp(id)));
+            }
+
+            BufferPath::File(ref file_path) => {
+                let idx = editor
+                    .find(&path)
+                    .or_else(|| editor.open_file(file_path).ok());
+                if let Some(idx) = idx {
+                    // The file's hash is needed to tell whether it changed.
+                    editor.wait_for_load(idx).ok();
+                }
+                match idx {
+                    Some(idx) if editor[idx].disk_state.map(|d| d.hash) == s.disk_hash => {
+                        let buffer_path = editor[idx].path.clone();
+                        let disk_state = editor[idx].disk_state;
+                        let (encoding, has_bom) = (editor[idx].encoding, editor[idx].has_bom);
+                        editor[idx] = s.into_buffer(buffer_path);
+                        editor[idx].disk_state = disk_state;
+                        editor[idx].encoding = encoding;
+                        editor[idx].has_bom = has_bom;
+                    }
+
+                    Some(_) => {
+                        changed_files.push(file_path.clone());
+                        let id = editor.unused_temp_id();
+                        editor.add_buffer(s.into_buffer(BufferPath::Temp(id)));
+                    }
+
+                    // The file no longer exists, so restore the buffer
+                    // as-is, to be written anew when saved.
+                    None => {
+                        editor.add_buffer(s.into_buffer(path.clone()));
+                    }
+                }
+            }
+        }
+    }
+
+    Ok(changed_files)
+}
+
+/// A buffer's state as stored in the session file.
+struct StoredBuffer {
+    path: BufferPath,
+    disk_hash: Option<[u8; 32]>,
+    text: String,
+    mark_sets: Vec<MarkSet>,
+    history: History,
+}
+
+impl StoredBuffer {
+    fn into_buffer(self, path: BufferPath) -> Buffer {
+        let mut buffer = Buffer::new(Rope::from_str(&self.text), path);
+        buffer.is_dirty = true;
+        buffer.history = self.history;
+
+        // Make sure the marks are within the text, in case of a
+        // malformed session file.
+        let len = buffer.text.len_chars();
+        buffer.mark_sets = self.mark_sets;
+        for set in buffer.mark_sets.iter_mut() {
+            for mark in set.iter_mut() {
+                mark.head = mark.head.min(len);
+                mark.tail = mark.tail.min(len);
+            }
+            set.make_consistent();
+        }
+
+        buffer
+    }
+}
+
+fn write_buffer<W: Write>(w: &mut W, buffer: &SessionBuffer) -> io::Result<()> {
+    match buffer.path {
+        BufferPath::File(ref p) => {
+            // Store an absolute path, so the session can be restored
+            // regardless of the working directory.
+            let p = fs::canonicalize(p).unwrap_or_else(|_| p.clone());
+            write_u64(w, 0)?;
+            write_bytes(w, p.to_string_lossy().as_bytes())?;
+        }
+        BufferPath::Temp(id) => {
+            write_u64(w, 1)?;
+            write_u64(w, id as u64)?;
+        }
+    }
+
+    if let Some(ref hash) = buffer.disk_hash {
+        write_u64(w, 1)?;
+        w.write_all(hash)?;
+    } else {
+        write_u64(w, 0)?;
+    }
+
+    // Same layout as `write_bytes()`, but without collecting the text
+    // into a single string first.
+    write_u64(w, buffer.text.len_bytes() as u64)?;
+    for chunk in buffer.text.chunks() {
+        w.write_all(chunk.as_bytes())?;
+    }
+
+    write_u64(w, buffer.mark_sets.len() as u64)?;
+    for set in buffer.mark_sets.iter() {
+        set.write_to(w)?;
+    }
+
+    buffer.history.write_to(w)
+}
+
+fn read_buffer<R: Read>(r: &mut R) -> io::Result<StoredBuffer> {
+    let path = match read_u64(r)? {
+        0 => BufferPath::File(Path::new(&read_string(r)?).into()),
+        1 => BufferPath::Temp(read_usize(r)?),
+        _ => return Err(invalid_data("invalid buffer path kind")),
+    };
+
+    let disk_hash = match read_u64(r)? {
+        0 => None,
+        _ => {
+            let mut hash = [0u8; 32];
+            r.read_exact(&mut hash)?;
+            Some(hash)
+        }
+    };
+
+    let text = read_string(r)?;
+
+    let set_count = read_usize(r)?;
+    let mut mark_sets = Vec::new();
+    for _ in 0..set_count {
+        mark_sets.push(MarkSet::read_from(r)?);
+    }
+
+    let history = History::read_from(r)?;
+
+    Ok(StoredBuffer {
+        path,
+        disk_hash,
+        text,
+        mark_sets,
+        history,
+    })
+}
+
+//----------------------------------------------------------------------
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::marks::Mark;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("led_test_{}_{}", name, std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn round_trip() {
+        let dir = temp_dir("session_round_trip");
+        let session = dir.join("session");
+        let file_path = dir.join("file.txt");
+        fs::write(&file_path, "Hello world!").unwrap();
+
+        let mut ed = Editor::new();
+        let file_idx = ed.open_file(&file_path).unwrap();
+        let temp_idx = ed.new_temp_buffer();
+        for idx in [file_idx, temp_idx].iter() {
+            ed[*idx].add_mark_set();
+            ed[*idx].mark_sets[0].add_mark(Mark::new(0, 0));
+        }
+        ed[file_idx].edit_at_cursors(0, vec![((5, 5), ",".into())]);
+        ed[temp_idx].edit_at_cursors(0, vec![((0, 0), "Scratch".into())]);
+        Session::new(&ed).save_to(&session).unwrap();
+
+        let mut ed2 = Editor::new();
+        let changed = restore_session_from(&mut ed2, &session).unwrap();
+        assert!(changed.is_empty());
+        assert_eq!(ed2.len(), 2);
+        let file_idx = ed2.find(&BufferPath::File(file_path.clone())).unwrap();
+        assert_eq!(ed2[file_idx].text, "Hello, world!");
+        assert!(ed2[file_idx].is_dirty);
+        assert_eq!(ed2[file_idx].mark_sets[0].main().unwrap().head, 6);
+        let temp_idx = ed2.find(&BufferPath::Temp(0)).unwrap();
+        assert_eq!(ed2[temp_idx].text, "Scratch");
+
+        // Undo history comes along too.
+        ed2[file_idx].undo(0);
+        assert_eq!(ed2[file_idx].text, "Hello world!");
+
+        // If the file changes on disk, the edits go to a temp buffer.
+        fs::write(&file_path, "Goodbye world!").unwrap();
+        let mut ed3 = Editor::new();
+        let changed = restore_session_from(&mut ed3, &session).unwrap();
+        assert_eq!(changed, vec![fs::canonicalize(&file_path).unwrap()]);
+        assert_eq!(ed3.len(), 3);
+        let file_idx = ed3.find(&BufferPath::File(file_path.clone())).unwrap();
+        assert_eq!(ed3[file_idx].text, "Goodbye world!");
+        assert!(!ed3[file_idx].is_dirty);
+        assert!(ed3.iter().any(|b| b.text == "Hello, world!"));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,67 @@
+//! Locations for led's persistent per-user state, such as undo history
+//! and the editing session.
+
+use std::{
+    env,
+    path::{Path, PathBuf},
+};
+
+use crate::hash::hash;
+
+/// Returns the directory where led stores its per-user state, if one
+/// can be determined.
+///
+/// In order of preference, this is `$LED_STATE_DIR`,
+/// `$XDG_STATE_HOME/led`, `$HOME/.local/state/led`, or
+/// `%LOCALAPPDATA%\led`.  The directory is not created by this function.
+pub fn state_dir() -> Option<PathBuf> {
+    if let Some(dir) = env::var_os("LED_STATE_DIR").filter(|d| !d.is_empty()) {
+        return Some(PathBuf::from(dir));
+    }
+    if let Some(dir) = env::var_os("XDG_STATE_HOME").filter(|d| !d.is_empty()) {
+        return Some(PathBuf::from(dir).join("led"));
+    }
+    if let Some(dir) = env::var_os("HOME").filter(|d| !d.is_empty()) {
+        return Some(PathBuf::from(dir).join(".local").join("state").join("led"));
+    }
+    if let Some(dir) = env::var_os("LOCALAPPDATA").filter(|d| !d.is_empty()) {
+        return Some(PathBuf::from(dir).join("led"));
+    }
+    None
+}
+
+/// Returns the path of the stored undo history for the given file.
+///
+/// The history is keyed by the file's canonical path, so the file must
+/// exist.
+pub fn history_path(file_path: &Path) -> Option<PathBuf> {
+    let canonical = file_path.canonicalize().ok()?;
+    Some(
+        state_dir()?
+            .join("history")
+            .join(path_key(&canonical) + ".hist"),
+    )
+}
+
+/// Returns the path of the stored editing session, which holds the
+/// unsaved edits of the buffers open when the editor last quit.
+pub fn session_path() -> Option<PathBuf> {
+    Some(state_dir()?.join("session"))
+}
+
+/// Returns a string suitable for use as a file name that uniquely
+/// identifies the given path.
+pub(crate) fn path_key(path: &Path) -> String {
+    digest_to_hex(&hash(path.to_string_lossy().as_bytes()))
+}
+
+pub(crate) fn digest_to_hex(digest: &[u8]) -> String {
+    const HEX_CHARS: &[u8; 16] = b"0123456789abcdef";
+
+    let mut s = String::with_capacity(digest.len() * 2);
+    for byte in digest.iter() {
+        s.push(HEX_CHARS[(byte >> 4) as usize] as char);
+        s.push(HEX_CHARS[(byte & 0b00001111) as usize] as char);
+    }
+    s
+}
@@ -1,6 +1,11 @@
+use std::io::{self, Read, Write};
+
 use ropey::Rope;
 
-use crate::marks::MarkSet;
+use crate::{
+    marks::MarkSet,
+    serialize::{invalid_data, read_string, read_usize, write_bytes, write_u64},
+};
 
 #[derive(Debug, Clone, Copy)]
 enum Op {
@@ -23,6 +28,12 @@ pub struct Transaction {
     buffer: String,
 }
 
+impl Default for Transaction {
+    fn default() -> Transaction {
+        Transaction::new()
+    }
+}
+
 impl Transaction {
     pub fn new() -> Transaction {
         Transaction {
@@ -47,10 +58,7 @@ impl Transaction {
             },
         ];
 
-        Transaction {
-            ops: ops,
-            buffer: buffer,
-        }
+        Transaction { ops, buffer }
     }
 
     /// Creates a Transaction from a sorted, non-overlapping set of
@@ -101,98 +109,120 @@ impl Transaction {
         use Op::*;
 
         let mut trans = Transaction::new();
-        let mut ops1 = self.ops.iter();
-        let mut ops2 = other.ops.iter();
+        let mut ops1 = self.ops.iter().copied();
+        let mut ops2 = other.ops.iter().copied();
         let mut op1 = ops1.next();
         let mut op2 = ops2.next();
-        let mut range1 = (0, 0);
-        let mut range2 = (0, 0);
 
+        // The old and new text of the replacement currently being built.
+        // Consecutive changes from either source transaction are merged
+        // into a single replacement, which is flushed whenever a stretch
+        // of text is retained by both.
+        let mut old_text = String::new();
+        let mut new_text = String::new();
+
+        // A note on terminology: `self` transforms text A into text B,
+        // and `other` transforms text B into text C.  The loop below walks
+        // both transactions in lock-step over text B, with `self`'s
+        // output side and `other`'s input side consumed in tandem.
         loop {
             match (op1, op2) {
                 //-------------------------------
-                // Move past the unchanged bits.
-                (Some(Retain { byte_count: bc }), _) => {
-                    range1.1 += bc;
+                // Changes that don't consume any of text B can be
+                // handled immediately.
+                (Some(Replace { old, new }), _) if new.0 == new.1 => {
+                    old_text.push_str(&self.buffer[old.0..old.1]);
                     op1 = ops1.next();
                 }
 
-                (_, Some(Retain { byte_count: bc })) => {
-                    range2.1 += bc;
+                (_, Some(Replace { old, new })) if old.0 == old.1 => {
+                    new_text.push_str(&other.buffer[new.0..new.1]);
                     op2 = ops2.next();
                 }
 
-                //-----------------------------------------------------
-                // Handle changes when there are still changes in both
-                // source transactions.
-                (
-                    Some(Replace {
-                        old: old1,
-                        new: new1,
-                    }),
-                    Some(Replace {
-                        old: old2,
-                        new: new2,
-                    }),
-                ) => {
-                    // This is the complex case.
-                    todo!()
+                //-------------------------------
+                // Text retained by both.
+                (Some(Retain { byte_count: bc1 }), Some(Retain { byte_count: bc2 })) => {
+                    let n = bc1.min(bc2);
+                    trans.push_replace(&mut old_text, &mut new_text);
+                    trans.push_retain(n);
+
+                    op1 = advance_retain(bc1, n).or_else(|| ops1.next());
+                    op2 = advance_retain(bc2, n).or_else(|| ops2.next());
                 }
 
-                //------------------------------------------------------
-                // Handle changes when there are only changes remaining
-                // in one of the source transactions.
-                (Some(Replace { old, new }), None) => {
-                    if range1.0 < range1.1 {
-                        trans.ops.push(Retain {
-                            byte_count: range1.1 - range1.0,
-                        });
-                        range1.0 = range1.1;
-                    }
-
-                    let ti = trans.buffer.len();
-                    trans.buffer.push_str(&self.buffer[old.0..old.1]);
-                    let old_range = (ti, ti + (old.1 - old.0));
-
-                    let ti = trans.buffer.len();
-                    trans.buffer.push_str(&self.buffer[new.0..new.1]);
-                    let new_range = (ti, ti + (new.1 - new.0));
+                (Some(Retain { byte_count }), None) => {
+                    trans.push_replace(&mut old_text, &mut new_text);
+                    trans.push_retain(byte_count);
+                    op1 = ops1.next();
+                }
 
-                    trans.ops.push(Replace {
-                        old: old_range,
-                        new: new_range,
-                    });
+                (None, Some(Retain { byte_count })) => {
+                    trans.push_replace(&mut old_text, &mut new_text);
+                    trans.push_retain(byte_count);
+                    op2 = ops2.next();
+                }
 
-                    op1 = ops1.next();
+                //-------------------------------
+                // Text retained by `self` but replaced by `other`.  The
+                // replaced text in B is original text from A.
+                (Some(Retain { byte_count }), Some(Replace { old, new })) => {
+                    let n = byte_count.min(old.1 - old.0);
+                    old_text.push_str(&other.buffer[old.0..(old.0 + n)]);
+
+                    op1 = advance_retain(byte_count, n).or_else(|| ops1.next());
+                    op2 = advance_replace_old(old, new, n, &other.buffer, &mut new_text)
+                        .or_else(|| ops2.next());
                 }
 
                 (None, Some(Replace { old, new })) => {
-                    if range2.0 < range2.1 {
-                        trans.ops.push(Retain {
-                            byte_count: range2.1 - range2.0,
-                        });
-                        range2.0 = range2.1;
-                    }
+                    old_text.push_str(&other.buffer[old.0..old.1]);
+                    new_text.push_str(&other.buffer[new.0..new.1]);
+                    op2 = ops2.next();
+                }
 
-                    let ti = trans.buffer.len();
-                    trans.buffer.push_str(&other.buffer[old.0..old.1]);
-                    let old_range = (ti, ti + (old.1 - old.0));
+                //-------------------------------
+                // Text inserted by `self` and retained by `other`.
+                (Some(Replace { old, new }), Some(Retain { byte_count })) => {
+                    let n = byte_count.min(new.1 - new.0);
+                    new_text.push_str(&self.buffer[new.0..(new.0 + n)]);
+
+                    op1 = advance_replace_new(old, new, n, &self.buffer, &mut old_text)
+                        .or_else(|| ops1.next());
+                    op2 = advance_retain(byte_count, n).or_else(|| ops2.next());
+                }
 
-                    let ti = trans.buffer.len();
-                    trans.buffer.push_str(&other.buffer[new.0..new.1]);
-                    let new_range = (ti, ti + (new.1 - new.0));
+                (Some(Replace { old, new }), None) => {
+                    old_text.push_str(&self.buffer[old.0..old.1]);
+                    new_text.push_str(&self.buffer[new.0..new.1]);
+                    op1 = ops1.next();
+                }
 
-                    trans.ops.push(Replace {
-                        old: old_range,
-                        new: new_range,
-                    });
+                //-------------------------------
+                // Text inserted by `self` and then replaced by `other`.
+                // The overlapping part cancels out.
+                (
+                    Some(Replace {
+                        old: old1,
+                        new: new1,
+                    }),
+                    Some(Replace {
+                        old: old2,
+                        new: new2,
+                    }),
+                ) => {
+                    let n = (new1.1 - new1.0).min(old2.1 - old2.0);
 
-                    op2 = ops2.next();
+                    op1 = advance_replace_new(old1, new1, n, &self.buffer, &mut old_text)
+                        .or_else(|| ops1.next());
+                    op2 = advance_replace_old(old2, new2, n, &other.buffer, &mut new_text)
+                        .or_else(|| ops2.next());
                 }
 
                 //-------
                 // Done.
                 (None, None) => {
+                    trans.push_replace(&mut old_text, &mut new_text);
                     break;
                 }
             }
@@ -246,7 +276,7 @@ impl Transaction {
                         text.insert(char_i, new);
                     }
 
-                    i = i + new.len();
+                    i += new.len();
                 }
             }
         }
@@ -254,7 +284,444 @@ impl Transaction {
     }
 
     /// Applies the Transaction to a set of Marks.
-    pub fn apply_to_marks(&self, _marks: &mut MarkSet) {
-        todo!()
+    ///
+    /// Marks are specified in char indices, so `text` is needed to map
+    /// between those and the byte indices of the Transaction.  It must be
+    /// the text the Transaction applies to, in its state _before_ the
+    /// Transaction is applied.
+    ///
+    /// Marks are updated the same way as `Mark::edit()` updates them:
+    /// positions at the start of a replaced range stay put, and positions
+    /// inside or at the end of a replaced range move to the end of the
+    /// replacement text.
+    pub fn apply_to_marks(&self, marks: &mut MarkSet, text: &Rope) {
+        // Collect all of the head and tail positions, sorted, so we can
+        // update them all in a single pass over the ops.
+        let mut positions: Vec<(usize, usize)> = Vec::with_capacity(marks.marks.len() * 2);
+        for (i, mark) in marks.iter().enumerate() {
+            positions.push((mark.head, i * 2));
+            positions.push((mark.tail, i * 2 + 1));
+        }
+        positions.sort_unstable();
+
+        let mut pi = 0;
+        let mut byte_i = 0;
+        let mut char_i = 0;
+        let mut char_delta = 0isize;
+        for op in self.ops.iter() {
+            match *op {
+                Op::Retain { byte_count } => {
+                    byte_i += byte_count;
+                    char_i = text.byte_to_char(byte_i);
+                }
+                Op::Replace { old, new } => {
+                    let old_char_len = self.buffer[old.0..old.1].chars().count();
+                    let new_char_len = self.buffer[new.0..new.1].chars().count();
+                    let start = char_i;
+                    let end = char_i + old_char_len;
+
+                    // Before or at the start of the replaced range.
+                    while pi < positions.len() && positions[pi].0 <= start {
+                        positions[pi].0 = (positions[pi].0 as isize + char_delta) as usize;
+                        pi += 1;
+                    }
+
+                    // Inside or at the end of the replaced range.
+                    while pi < positions.len() && positions[pi].0 <= end {
+                        positions[pi].0 = (start as isize + char_delta) as usize + new_char_len;
+                        pi += 1;
+                    }
+
+                    char_delta += new_char_len as isize - old_char_len as isize;
+                    byte_i += old.1 - old.0;
+                    char_i = end;
+                }
+            }
+        }
+        for pos in positions[pi..].iter_mut() {
+            pos.0 = (pos.0 as isize + char_delta) as usize;
+        }
+
+        // Write the updated positions back.
+        for (pos, slot) in positions {
+            let mark = &mut marks[slot / 2];
+            if slot % 2 == 0 {
+                mark.head = pos;
+            } else {
+                mark.tail = pos;
+            }
+            mark.hh_pos = None;
+        }
+
+        marks.make_consistent();
+    }
+
+    /// Returns the byte range of the text, _after_ the Transaction is
+    /// applied, that spans all of the changes it made.
+    ///
+    /// Returns None if the Transaction doesn't change anything.
+    pub fn changed_byte_range(&self) -> Option<(usize, usize)> {
+        let mut range = None;
+        let mut i = 0;
+        for op in self.ops.iter() {
+            match *op {
+                Op::Retain { byte_count } => {
+                    i += byte_count;
+                }
+                Op::Replace { new, .. } => {
+                    let start = range.map(|r: (usize, usize)| r.0).unwrap_or(i);
+                    i += new.1 - new.0;
+                    range = Some((start, i));
+                }
+            }
+        }
+        range
+    }
+
+    /// Returns an iterator over the individual edits of the Transaction.
+    ///
+    /// Yields `(byte_index, old_text, new_text)` tuples in text order, in
+    /// the same form that `from_ordered_edit_set()` takes: the byte indices
+    /// are relative to the text _before_ the Transaction is applied.
+    pub fn edits(&self) -> impl Iterator<Item = (usize, &str, &str)> + '_ {
+        let mut i = 0;
+        self.ops.iter().filter_map(move |op| match *op {
+            Op::Retain { byte_count } => {
+                i += byte_count;
+                None
+            }
+            Op::Replace { old, new } => {
+                let byte_idx = i;
+                i += old.1 - old.0;
+                Some((
+                    byte_idx,
+                    &self.buffer[old.0..old.1],
+                    &self.buffer[new.0..new.1],
+                ))
+            }
+        })
+    }
+
+    /// Maps a byte range of the text before the Transaction is applied to
+    /// the corresponding range after it is applied.
+    ///
+    /// Returns None if the Transaction changes any of the text within the
+    /// range.  Insertions exactly at the start of the range are considered
+    /// to be before it, and insertions exactly at the end are considered to
+    /// be after it.
+    pub fn map_byte_range(&self, range: (usize, usize)) -> Option<(usize, usize)> {
+        let mut delta = 0isize;
+        for (byte_idx, old, new) in self.edits() {
+            let end = byte_idx + old.len();
+            if byte_idx >= range.1 && (byte_idx > range.0 || !old.is_empty()) {
+                // At or after the end of the range.  Since edits are in
+                // order, nothing else can affect the range.
+                break;
+            } else if end <= range.0 {
+                // Before the range.
+                delta += new.len() as isize - old.len() as isize;
+            } else {
+                // Overlaps the range.
+                return None;
+            }
+        }
+
+        Some((
+            (range.0 as isize + delta) as usize,
+            (range.1 as isize + delta) as usize,
+        ))
+    }
+
+    /// Returns whether the Transaction is a no-op.
+    pub fn is_empty(&self) -> bool {
+        self.ops.iter().all(|op| match *op {
+            Op::Retain { .. } => true,
+            Op::Replace { old, new } => old.0 == old.1 && new.0 == new.1,
+        })
+    }
+
+    /// Writes the Transaction in led's binary state format.
+    pub fn write_to<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        write_bytes(w, self.buffer.as_bytes())?;
+        write_u64(w, self.ops.len() as u64)?;
+        for op in self.ops.iter() {
+            match *op {
+                Op::Retain { byte_count } => {
+                    w.write_all(&[0])?;
+                    write_u64(w, byte_count as u64)?;
+                }
+                Op::Replace { old, new } => {
+                    w.write_all(&[1])?;
+                    write_u64(w, old.0 as u64)?;
+                    write_u64(w, old.1 as u64)?;
+                    write_u64(w, new.0 as u64)?;
+                    write_u64(w, new.1 as u64)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Reads a Transaction written by `write_to()`.
+    ///
+    /// Returns an error if the data is malformed.
+    pub fn read_from<R: Read>(r: &mut R) -> io::Result<Transaction> {
+        let buffer = read_string(r)?;
+        let op_count = read_usize(r)?;
+
+        let valid_range = |range: (usize, usize)| {
+            range.0 <= range.1
+                && range.1 <= buffer.len()
+                && buffer.is_char_boundary(range.0)
+                && buffer.is_char_boundary(range.1)
+        };
+
+        let mut ops = Vec::new();
+        for _ in 0..op_count {
+            let mut tag = [0u8];
+            r.read_exact(&mut tag)?;
+            match tag[0] {
+                0 => ops.push(Op::Retain {
+                    byte_count: read_usize(r)?,
+                }),
+                1 => {
+                    let old = (read_usize(r)?, read_usize(r)?);
+                    let new = (read_usize(r)?, read_usize(r)?);
+                    if !valid_range(old) || !valid_range(new) {
+                        return Err(invalid_data("invalid transaction range"));
+                    }
+                    ops.push(Op::Replace { old, new });
+                }
+                _ => return Err(invalid_data("invalid transaction op")),
+            }
+        }
+
+        Ok(Transaction { ops, buffer })
+    }
+
+    //----------------------------------------------------
+    // Helper methods
+
+    /// Pushes a retain op, merging it with the previous op if that was
+    /// also a retain.
+    fn push_retain(&mut self, byte_count: usize) {
+        if byte_count == 0 {
+            return;
+        }
+        if let Some(Op::Retain {
+            byte_count: ref mut bc,
+        }) = self.ops.last_mut()
+        {
+            *bc += byte_count;
+        } else {
+            self.ops.push(Op::Retain { byte_count });
+        }
+    }
+
+    /// Pushes a replace op built from the given strings, and clears them.
+    ///
+    /// Does nothing if both strings are empty.
+    fn push_replace(&mut self, old: &mut String, new: &mut String) {
+        if old.is_empty() && new.is_empty() {
+            return;
+        }
+
+        let old_range = (self.buffer.len(), self.buffer.len() + old.len());
+        self.buffer.push_str(old);
+        let new_range = (self.buffer.len(), self.buffer.len() + new.len());
+        self.buffer.push_str(new);
+
+        self.ops.push(Op::Replace {
+            old: old_range,
+            new: new_range,
+        });
+
+        old.clear();
+        new.clear();
+    }
+}
+
+//----------------------------------------------------------------------
+// Helpers for `Transaction::compose()`.  Each of these consumes `n`
+// bytes of the given op, and returns what's left of it, if anything.
+
+fn advance_retain(byte_count: usize, n: usize) -> Option<Op> {
+    if byte_count > n {
+        Some(Op::Retain {
+            byte_count: byte_count - n,
+        })
+    } else {
+        None
+    }
+}
+
+/// Consumes `n` bytes of the old (input) side of a replace op.  The
+/// first time any of it is consumed, the op's new text is pushed onto
+/// `new_text`.
+fn advance_replace_old(
+    old: (usize, usize),
+    new: (usize, usize),
+    n: usize,
+    buffer: &str,
+    new_text: &mut String,
+) -> Option<Op> {
+    if new.0 != new.1 {
+        new_text.push_str(&buffer[new.0..new.1]);
+    }
+    let old = (old.0 + n, old.1);
+    if old.0 < old.1 {
+        // Mark the new text as already pushed.
+        Some(Op::Replace {
+            old,
+            new: (new.1, new.1),
+        })
+    } else {
+        None
+    }
+}
+
+/// Consumes `n` bytes of the new (output) side of a replace op.  The
+/// first time any of it is consumed, the op's old text is pushed onto
+/// `old_text`.
+fn advance_replace_new(
+    old: (usize, usize),
+    new: (usize, usize),
+    n: usize,
+    buffer: &str,
+    old_text: &mut String,
+) -> Option<Op> {
+    if old.0 != old.1 {
+        old_text.push_str(&buffer[old.0..old.1]);
+    }
+    let new = (new.0 + n, new.1);
+    if new.0 < new.1 {
+        // Mark the old text as already pushed.
+        Some(Op::Replace {
+            old: (old.1, old.1),
+            new,
+        })
+    } else {
+        None
+    }
+}
+
+//----------------------------------------------------------------------
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::marks::Mark;
+
+    fn apply(trans: &Transaction, text: &str) -> String {
+        let mut rope = Rope::from_str(text);
+        trans.apply_to_text(&mut rope);
+        rope.into()
+    }
+
+    #[test]
+    fn compose_non_overlapping() {
+        let t1 = Transaction::from_edit(0, "Hello", "Goodbye");
+        let t2 = Transaction::from_edit(8, "world", "moon");
+        let trans = t1.compose(&t2);
+
+        assert_eq!(apply(&trans, "Hello world!"), "Goodbye moon!");
+        assert_eq!(apply(&trans.invert(), "Goodbye moon!"), "Hello world!");
+    }
+
+    #[test]
+    fn compose_reverse_order() {
+        let t1 = Transaction::from_edit(6, "world", "moon");
+        let t2 = Transaction::from_edit(0, "Hello", "Goodbye");
+        let trans = t1.compose(&t2);
+
+        assert_eq!(apply(&trans, "Hello world!"), "Goodbye moon!");
+        assert_eq!(apply(&trans.invert(), "Goodbye moon!"), "Hello world!");
+    }
+
+    #[test]
+    fn compose_overlapping() {
+        let t1 = Transaction::from_edit(2, "llo wo", "y there, wo");
+        let t2 = Transaction::from_edit(0, "Hey there", "Hi");
+        let trans = t1.compose(&t2);
+
+        assert_eq!(apply(&trans, "Hello world!"), "Hi, world!");
+        assert_eq!(apply(&trans.invert(), "Hi, world!"), "Hello world!");
+    }
+
+    #[test]
+    fn compose_insert_then_delete() {
+        let t1 = Transaction::from_edit(5, "", "abc");
+        let t2 = Transaction::from_edit(5, "abc", "");
+        let trans = t1.compose(&t2);
+
+        assert_eq!(apply(&trans, "Hello world!"), "Hello world!");
+        assert!(trans.is_empty());
+    }
+
+    #[test]
+    fn compose_consecutive_typing() {
+        let mut trans = Transaction::new();
+        let mut text = String::from("ab");
+        for (i, c) in ["x", "y", "z"].iter().enumerate() {
+            trans = trans.compose(&Transaction::from_edit(1 + i, "", c));
+            text = apply(&trans, "ab");
+        }
+
+        assert_eq!(text, "axyzb");
+        assert_eq!(apply(&trans.invert(), "axyzb"), "ab");
+    }
+
+    #[test]
+    fn compose_edit_sets() {
+        let t1 = Transaction::from_ordered_edit_set(
+            [(0, "", "// "), (4, "", "// "), (8, "", "// ")]
+                .iter()
+                .copied(),
+        );
+        let t2 = Transaction::from_ordered_edit_set(
+            [(3, "a", "A"), (11, "c", "B"), (19, "e", "C")]
+                .iter()
+                .copied(),
+        );
+        let trans = t1.compose(&t2);
+
+        assert_eq!(apply(&trans, "abc\nbcd\ncde"), "// Abc\n// bBd\n// cdC");
+        assert_eq!(
+            apply(&trans.invert(), "// Abc\n// bBd\n// cdC"),
+            "abc\nbcd\ncde"
+        );
+    }
+
+    #[test]
+    fn apply_to_marks_01() {
+        let text = Rope::from_str("Hello world!");
+        let trans = Transaction::from_ordered_edit_set(
+            [(0, "Hello", "Hi"), (6, "world", "moon")].iter().copied(),
+        );
+        let mut marks = MarkSet::new();
+        marks.add_mark(Mark::new(0, 0));
+        marks.add_mark(Mark::new(3, 2));
+        marks.add_mark(Mark::new(5, 6));
+        marks.add_mark(Mark::new(11, 12));
+
+        trans.apply_to_marks(&mut marks, &text);
+
+        let positions: Vec<_> = marks.iter().map(|m| (m.head, m.tail)).collect();
+        assert_eq!(positions, vec![(0, 0), (3, 2), (7, 8)]);
+    }
+
+    #[test]
+    fn apply_to_marks_multi_byte() {
+        let text = Rope::from_str("今日は world!");
+        let trans = Transaction::from_edit("今日は ".len(), "world", "moon");
+        let mut marks = MarkSet::new();
+        marks.add_mark(Mark::new(1, 1));
+        marks.add_mark(Mark::new(4, 4));
+        marks.add_mark(Mark::new(10, 10));
+
+        trans.apply_to_marks(&mut marks, &text);
+
+        let positions: Vec<_> = marks.iter().map(|m| (m.head, m.tail)).collect();
+        assert_eq!(positions, vec![(1, 1), (4, 4), (9, 9)]);
     }
 }